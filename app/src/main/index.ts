@@ -143,10 +143,12 @@ const setupBackendServer = async (appPath: string, backendRootPath: string, user
     `surf-backend${isDev ? '-dev' : ''}${isWindows() ? '.exe' : ''}`
   )
 
+  const embeddingIndexConfig = userConfig.settings?.embedding_index
   surfBackendManager = new SurfBackendServerManager(backendServerPath, [
     backendRootPath,
     'false',
-    isDev ? CONFIG.embeddingModelMode : userConfig.settings?.embedding_model
+    isDev ? CONFIG.embeddingModelMode : userConfig.settings?.embedding_model,
+    ...(embeddingIndexConfig ? [JSON.stringify(embeddingIndexConfig)] : [])
   ])
 
   surfBackendManager
@@ -25,7 +25,8 @@ enum ResourceProcessingStateType {
 }
 
 enum EventBusMessageType {
-  ResourceProcessingMessage = 'ResourceProcessingMessage'
+  ResourceProcessingMessage = 'ResourceProcessingMessage',
+  AIChatTitleMessage = 'AIChatTitleMessage'
 }
 
 type ResourceProcessingState =
@@ -34,11 +35,18 @@ type ResourceProcessingState =
   | { type: ResourceProcessingStateType.Failed; message: string }
   | { type: ResourceProcessingStateType.Finished }
 
-type EventBusMessage = {
-  type: EventBusMessageType.ResourceProcessingMessage
-  resource_id: string
-  status: ResourceProcessingState
-}
+type EventBusMessage =
+  | {
+      type: EventBusMessageType.ResourceProcessingMessage
+      resource_id: string
+      status: ResourceProcessingState
+    }
+  | {
+      type: EventBusMessageType.AIChatTitleMessage
+      session_id: string
+      title: string
+      tags: string[]
+    }
 
 export type SFFSOptions = {
   num_worker_threads?: number
@@ -114,6 +122,16 @@ export const initSFFS = (opts?: SFFSOptions) => {
     return false
   }
 
+  const isAIChatTitleMessage = (obj: any): boolean => {
+    return (
+      !!obj &&
+      typeof obj.session_id === 'string' &&
+      typeof obj.title === 'string' &&
+      Array.isArray(obj.tags) &&
+      obj.tags.every((tag: any) => typeof tag === 'string')
+    )
+  }
+
   const parseEventBusMessage = (event: string): EventBusMessage => {
     const obj = JSON.parse(event)
 
@@ -125,6 +143,9 @@ export const initSFFS = (opts?: SFFSOptions) => {
       case EventBusMessageType.ResourceProcessingMessage:
         if (isResourceProcessingMessage(obj)) return obj as EventBusMessage
         throw new Error(`event bus message doesn't match type ${obj.type}`)
+      case EventBusMessageType.AIChatTitleMessage:
+        if (isAIChatTitleMessage(obj)) return obj as EventBusMessage
+        throw new Error(`event bus message doesn't match type ${obj.type}`)
     }
 
     throw new Error(`invalid event bus message type: ${obj.type}`)
@@ -0,0 +1,125 @@
+use crate::{BackendError, BackendResult};
+use fastembed::{ImageEmbedding, ImageInitOptions, InitOptions, TextEmbedding};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, instrument};
+
+const IMAGE_MODEL: fastembed::ImageEmbeddingModel = fastembed::ImageEmbeddingModel::ClipVitB32;
+// the text tower of the same CLIP model, so text queries land in the image space
+const IMAGE_TEXT_MODEL: fastembed::EmbeddingModel = fastembed::EmbeddingModel::ClipVitB32;
+
+struct ClipModels {
+    vision: ImageEmbedding,
+    text: TextEmbedding,
+}
+
+/// CLIP style image/text embedding model backing the image index.
+///
+/// Like the reranker it is loaded on first use, users without image resources
+/// never pay for it.
+pub struct ImageEmbeddingModel {
+    cache_dir: PathBuf,
+    models: Mutex<Option<Arc<ClipModels>>>,
+}
+
+impl ImageEmbeddingModel {
+    pub fn new(cache_dir: &Path) -> Self {
+        Self {
+            cache_dir: cache_dir.to_path_buf(),
+            models: Mutex::new(None),
+        }
+    }
+
+    pub fn get_embedding_dim() -> usize {
+        ImageEmbedding::get_model_info(&IMAGE_MODEL).dim
+    }
+
+    fn models(&self) -> BackendResult<Arc<ClipModels>> {
+        let mut models = self
+            .models
+            .lock()
+            .map_err(|e| BackendError::GenericError(format!("image model lock poisoned: {}", e)))?;
+
+        if let Some(models) = models.as_ref() {
+            return Ok(Arc::clone(models));
+        }
+
+        info!(model = ?IMAGE_MODEL, "loading image embedding model");
+        let vision = ImageEmbedding::try_new(ImageInitOptions {
+            model_name: IMAGE_MODEL,
+            show_download_progress: false,
+            cache_dir: self.cache_dir.clone(),
+            ..Default::default()
+        })
+        .map_err(|e| BackendError::GenericError(e.to_string()))?;
+        let text = TextEmbedding::try_new(InitOptions {
+            model_name: IMAGE_TEXT_MODEL,
+            show_download_progress: false,
+            cache_dir: self.cache_dir.clone(),
+            ..Default::default()
+        })
+        .map_err(|e| BackendError::GenericError(e.to_string()))?;
+
+        let loaded = Arc::new(ClipModels { vision, text });
+        *models = Some(Arc::clone(&loaded));
+        Ok(loaded)
+    }
+
+    #[instrument(level = "debug", skip(self, image_paths), fields(count = image_paths.len()))]
+    pub fn encode_images(&self, image_paths: &[String]) -> BackendResult<Vec<Vec<f32>>> {
+        if image_paths.is_empty() {
+            return Ok(vec![]);
+        }
+        self.models()?
+            .vision
+            .embed(image_paths.to_vec(), None)
+            .map_err(|e| {
+                error!("Failed to encode {} images: {}", image_paths.len(), e);
+                BackendError::GenericError(format!("Error encoding images: {}", e))
+            })
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub fn encode_image(&self, image_path: &str) -> BackendResult<Vec<f32>> {
+        single_embedding(self.encode_images(&[image_path.to_string()])?)
+    }
+
+    #[instrument(level = "debug", skip(self, query))]
+    pub fn encode_query(&self, query: &str) -> BackendResult<Vec<f32>> {
+        let embeddings = self
+            .models()?
+            .text
+            .embed(vec![query.to_string()], None)
+            .map_err(|e| {
+                error!("Failed to encode image query: {}", e);
+                BackendError::GenericError(format!("Error encoding image query: {}", e))
+            })?;
+        single_embedding(embeddings)
+    }
+}
+
+// the model returns one embedding per input, anything else is an error rather than a panic
+fn single_embedding(embeddings: Vec<Vec<f32>>) -> BackendResult<Vec<f32>> {
+    match <[Vec<f32>; 1]>::try_from(embeddings) {
+        Ok([embedding]) => Ok(embedding),
+        Err(embeddings) => Err(BackendError::GenericError(format!(
+            "expected one embedding, got {}",
+            embeddings.len()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_embedding() {
+        assert_eq!(
+            single_embedding(vec![vec![1.0, 2.0]]).unwrap(),
+            vec![1.0, 2.0]
+        );
+        assert!(single_embedding(vec![]).is_err());
+        assert!(single_embedding(vec![vec![1.0], vec![2.0]]).is_err());
+    }
+}
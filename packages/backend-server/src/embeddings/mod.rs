@@ -1,3 +1,6 @@
 pub mod chunking;
+pub mod image;
 pub mod model;
+pub mod queue;
+pub mod reranker;
 pub mod store;
@@ -66,12 +66,17 @@ impl EmbeddingModel {
         TextEmbedding::get_model_info(&self.model_name).dim
     }
 
+    /// Encodes all sentences in a single model batch, callers should go through
+    /// `EncodeQueue` which keeps batches bounded.
     #[instrument(level = "debug", skip(self, sentences), fields(count = sentences.len()))]
     pub fn encode(&self, sentences: &[String]) -> BackendResult<Vec<Vec<f32>>> {
-        self.model.embed(sentences.to_vec(), Some(1)).map_err(|e| {
-            error!("Failed to encode {} sentences: {}", sentences.len(), e);
-            BackendError::GenericError(format!("Error encoding sentences: {}", e))
-        })
+        let batch_size = sentences.len().max(1);
+        self.model
+            .embed(sentences.to_vec(), Some(batch_size))
+            .map_err(|e| {
+                error!("Failed to encode {} sentences: {}", sentences.len(), e);
+                BackendError::GenericError(format!("Error encoding sentences: {}", e))
+            })
     }
 
     pub fn encode_single(&self, sentence: &str) -> BackendResult<Vec<f32>> {
@@ -0,0 +1,532 @@
+use crate::embeddings::model::EmbeddingModel;
+use crate::{BackendError, BackendResult};
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, instrument, warn};
+
+/// Which lane a request is queued on.
+///
+/// Interactive requests (search queries, similarity checks) are always batched
+/// before bulk ones (resource imports), so a large import can only delay a search
+/// by at most one model batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodePriority {
+    Interactive,
+    Bulk,
+}
+
+/// Anything that turns sentences into embeddings, one per sentence in order.
+pub trait Encoder: Send + Sync + 'static {
+    fn encode(&self, sentences: &[String]) -> BackendResult<Vec<Vec<f32>>>;
+}
+
+impl Encoder for EmbeddingModel {
+    fn encode(&self, sentences: &[String]) -> BackendResult<Vec<Vec<f32>>> {
+        EmbeddingModel::encode(self, sentences)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncodeQueueConfig {
+    /// Maximum number of sentences passed to the model in a single call.
+    pub max_batch_size: usize,
+    /// How long the oldest request waits for others to fill up a batch.
+    pub max_latency: Duration,
+    /// Maximum number of queued interactive sentences before new requests are rejected.
+    pub max_interactive_sentences: usize,
+    /// Maximum number of queued bulk sentences before new requests are rejected.
+    pub max_bulk_sentences: usize,
+}
+
+impl Default for EncodeQueueConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 16,
+            max_latency: Duration::from_millis(10),
+            max_interactive_sentences: 1024,
+            max_bulk_sentences: 512,
+        }
+    }
+}
+
+type EncodeResponse = mpsc::Sender<BackendResult<Vec<Vec<f32>>>>;
+
+struct EncodeJob {
+    sentences: Vec<String>,
+    embeddings: Vec<Vec<f32>>,
+    enqueued_at: Instant,
+    response: EncodeResponse,
+}
+
+impl EncodeJob {
+    fn remaining(&self) -> usize {
+        self.sentences.len() - self.embeddings.len()
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    interactive: VecDeque<EncodeJob>,
+    bulk: VecDeque<EncodeJob>,
+    // set once the worker thread is gone, nothing would ever answer new jobs
+    closed: bool,
+}
+
+impl QueueState {
+    fn is_empty(&self) -> bool {
+        self.interactive.is_empty() && self.bulk.is_empty()
+    }
+
+    fn lane_sentences(&self, priority: EncodePriority) -> usize {
+        let lane = match priority {
+            EncodePriority::Interactive => &self.interactive,
+            EncodePriority::Bulk => &self.bulk,
+        };
+        lane.iter().map(|job| job.remaining()).sum()
+    }
+
+    fn pending_sentences(&self) -> usize {
+        self.interactive
+            .iter()
+            .chain(self.bulk.iter())
+            .map(|job| job.remaining())
+            .sum()
+    }
+
+    fn oldest(&self) -> Option<Instant> {
+        self.interactive
+            .iter()
+            .chain(self.bulk.iter())
+            .map(|job| job.enqueued_at)
+            .min()
+    }
+
+    fn lane(&mut self, priority: EncodePriority) -> &mut VecDeque<EncodeJob> {
+        match priority {
+            EncodePriority::Interactive => &mut self.interactive,
+            EncodePriority::Bulk => &mut self.bulk,
+        }
+    }
+}
+
+struct Shared {
+    config: EncodeQueueConfig,
+    state: Mutex<QueueState>,
+    available: Condvar,
+}
+
+/// Central queue in front of the embedding model.
+///
+/// Client threads submit sentences and block on the result while a single worker
+/// thread coalesces concurrent requests into model sized batches.
+#[derive(Clone)]
+pub struct EncodeQueue {
+    shared: Arc<Shared>,
+}
+
+/// A job taken off the queue for the current batch, with how many of its
+/// remaining sentences are part of it.
+struct BatchEntry {
+    job: EncodeJob,
+    priority: EncodePriority,
+    count: usize,
+}
+
+// closes the queue when the worker thread exits, also when it panics, so
+// queued and future requests fail instead of waiting forever
+struct WorkerGuard(Arc<Shared>);
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        let mut state = self
+            .0
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.closed = true;
+        // dropping the jobs drops their response senders and wakes up the callers
+        state.interactive.clear();
+        state.bulk.clear();
+    }
+}
+
+impl EncodeQueue {
+    pub fn new<E: Encoder>(model: Arc<E>, config: EncodeQueueConfig) -> Self {
+        let shared = Arc::new(Shared {
+            config,
+            state: Mutex::new(QueueState::default()),
+            available: Condvar::new(),
+        });
+
+        let worker_shared = Arc::clone(&shared);
+        std::thread::Builder::new()
+            .name("encode-queue".to_string())
+            .spawn(move || {
+                let guard = WorkerGuard(worker_shared);
+                Self::run(&guard.0, model.as_ref())
+            })
+            .expect("failed to spawn encode queue worker");
+
+        Self { shared }
+    }
+
+    /// Encodes `sentences`, waiting for the batch they end up in.
+    ///
+    /// Returns `BackendError::Busy` right away if the lane for `priority` is full. A
+    /// request larger than the lane limit is only accepted into an empty lane, so it
+    /// is delayed instead of being rejected forever.
+    #[instrument(level = "debug", skip(self, sentences), fields(count = sentences.len()))]
+    pub fn encode(
+        &self,
+        sentences: Vec<String>,
+        priority: EncodePriority,
+    ) -> BackendResult<Vec<Vec<f32>>> {
+        if sentences.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (response_tx, response_rx) = mpsc::channel();
+        {
+            let mut state = self.lock_state()?;
+            if state.closed {
+                return Err(worker_stopped());
+            }
+            let max_sentences = match priority {
+                EncodePriority::Interactive => self.shared.config.max_interactive_sentences,
+                EncodePriority::Bulk => self.shared.config.max_bulk_sentences,
+            };
+            let queued = state.lane_sentences(priority);
+            if queued > 0 && queued + sentences.len() > max_sentences {
+                warn!(?priority, queued, "encode queue full");
+                return Err(BackendError::Busy(format!(
+                    "{:?} encode queue is full",
+                    priority
+                )));
+            }
+            state.lane(priority).push_back(EncodeJob {
+                embeddings: Vec::with_capacity(sentences.len()),
+                sentences,
+                enqueued_at: Instant::now(),
+                response: response_tx,
+            });
+        }
+        self.shared.available.notify_one();
+
+        // the sender is dropped without an answer if the worker thread died
+        response_rx.recv().map_err(|_| worker_stopped())?
+    }
+
+    pub fn encode_single(&self, sentence: &str) -> BackendResult<Vec<f32>> {
+        self.encode(vec![sentence.to_string()], EncodePriority::Interactive)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| BackendError::GenericError("model returned no embedding".to_string()))
+    }
+
+    fn lock_state(&self) -> BackendResult<std::sync::MutexGuard<'_, QueueState>> {
+        self.shared
+            .state
+            .lock()
+            .map_err(|e| BackendError::GenericError(format!("encode queue poisoned: {}", e)))
+    }
+
+    fn run(shared: &Shared, model: &dyn Encoder) {
+        loop {
+            let batch = match Self::next_batch(shared) {
+                Some(batch) => batch,
+                None => {
+                    error!("encode queue lock poisoned, stopping worker");
+                    return;
+                }
+            };
+            Self::process_batch(shared, model, batch);
+        }
+    }
+
+    /// Waits until either a full batch is pending or the oldest request has waited
+    /// `max_latency`, then takes up to `max_batch_size` sentences, interactive first.
+    fn next_batch(shared: &Shared) -> Option<Vec<BatchEntry>> {
+        let config = &shared.config;
+        let mut state = shared.state.lock().ok()?;
+
+        while state.is_empty() {
+            state = shared.available.wait(state).ok()?;
+        }
+
+        loop {
+            if state.pending_sentences() >= config.max_batch_size {
+                break;
+            }
+            let deadline = match state.oldest() {
+                Some(oldest) => oldest + config.max_latency,
+                None => break,
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = shared.available.wait_timeout(state, deadline - now).ok()?.0;
+        }
+
+        let mut batch = vec![];
+        let mut budget = config.max_batch_size;
+        for priority in [EncodePriority::Interactive, EncodePriority::Bulk] {
+            while budget > 0 {
+                let job = match state.lane(priority).pop_front() {
+                    Some(job) => job,
+                    None => break,
+                };
+                let count = job.remaining().min(budget);
+                budget -= count;
+                batch.push(BatchEntry {
+                    job,
+                    priority,
+                    count,
+                });
+            }
+        }
+        Some(batch)
+    }
+
+    #[instrument(level = "debug", skip(shared, model, batch), fields(jobs = batch.len()))]
+    fn process_batch(shared: &Shared, model: &dyn Encoder, mut batch: Vec<BatchEntry>) {
+        let sentences: Vec<String> = batch
+            .iter()
+            .flat_map(|entry| {
+                let start = entry.job.embeddings.len();
+                entry.job.sentences[start..start + entry.count]
+                    .iter()
+                    .cloned()
+            })
+            .collect();
+        debug!(sentences = sentences.len(), "encoding batch");
+
+        let result = model.encode(&sentences).and_then(|embeddings| {
+            // a short answer would leave jobs that never complete
+            if embeddings.len() != sentences.len() {
+                return Err(BackendError::GenericError(format!(
+                    "model returned {} embeddings for {} sentences",
+                    embeddings.len(),
+                    sentences.len()
+                )));
+            }
+            Ok(embeddings)
+        });
+        let mut embeddings = match result {
+            Ok(embeddings) => embeddings.into_iter(),
+            Err(e) => {
+                let message = e.to_string();
+                for entry in batch {
+                    let _ = entry
+                        .job
+                        .response
+                        .send(Err(BackendError::GenericError(message.clone())));
+                }
+                return;
+            }
+        };
+
+        let mut unfinished = vec![];
+        for entry in batch.iter_mut() {
+            entry
+                .job
+                .embeddings
+                .extend(embeddings.by_ref().take(entry.count));
+        }
+        for entry in batch {
+            if entry.job.remaining() == 0 {
+                let _ = entry.job.response.send(Ok(entry.job.embeddings));
+            } else {
+                unfinished.push(entry);
+            }
+        }
+
+        if unfinished.is_empty() {
+            return;
+        }
+        // partially encoded jobs go back to the front of their lane so they keep
+        // their place ahead of newer requests of the same priority
+        if let Ok(mut state) = shared.state.lock() {
+            for entry in unfinished.into_iter().rev() {
+                state.lane(entry.priority).push_front(entry.job);
+            }
+        }
+    }
+}
+
+fn worker_stopped() -> BackendError {
+    BackendError::GenericError("encode queue worker stopped".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // embeds every sentence as its length, blocks while the gate is closed
+    struct FakeEncoder {
+        open: Mutex<bool>,
+        opened: Condvar,
+        started: Mutex<Option<mpsc::Sender<()>>>,
+        panic: AtomicBool,
+    }
+
+    impl FakeEncoder {
+        fn new(open: bool) -> Arc<Self> {
+            Arc::new(Self {
+                open: Mutex::new(open),
+                opened: Condvar::new(),
+                started: Mutex::new(None),
+                panic: AtomicBool::new(false),
+            })
+        }
+
+        fn notify_started(&self) -> mpsc::Receiver<()> {
+            let (tx, rx) = mpsc::channel();
+            *self.started.lock().unwrap() = Some(tx);
+            rx
+        }
+
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+        }
+    }
+
+    impl Encoder for FakeEncoder {
+        fn encode(&self, sentences: &[String]) -> BackendResult<Vec<Vec<f32>>> {
+            if let Some(started) = self.started.lock().unwrap().as_ref() {
+                let _ = started.send(());
+            }
+            if self.panic.load(Ordering::SeqCst) {
+                panic!("model crashed");
+            }
+            let mut open = self.open.lock().unwrap();
+            while !*open {
+                open = self.opened.wait(open).unwrap();
+            }
+            Ok(sentences.iter().map(|s| vec![s.len() as f32]).collect())
+        }
+    }
+
+    fn config() -> EncodeQueueConfig {
+        EncodeQueueConfig {
+            max_batch_size: 2,
+            max_latency: Duration::from_millis(1),
+            max_interactive_sentences: 4,
+            max_bulk_sentences: 3,
+        }
+    }
+
+    fn sentences(lengths: &[usize]) -> Vec<String> {
+        lengths.iter().map(|len| "x".repeat(*len)).collect()
+    }
+
+    fn lengths(embeddings: Vec<Vec<f32>>) -> Vec<usize> {
+        embeddings.iter().map(|e| e[0] as usize).collect()
+    }
+
+    #[test]
+    fn test_encode_keeps_order_across_batches() {
+        // room for every request at once, a full lane would fail the test
+        // depending on how fast the worker drains it
+        let config = EncodeQueueConfig {
+            max_interactive_sentences: 6,
+            max_bulk_sentences: 6,
+            ..config()
+        };
+        let queue = EncodeQueue::new(FakeEncoder::new(true), config);
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let queue = queue.clone();
+                std::thread::spawn(move || {
+                    let input = [1 + i, 5 + i, 9 + i];
+                    let priority = match i % 2 {
+                        0 => EncodePriority::Interactive,
+                        _ => EncodePriority::Bulk,
+                    };
+                    (input, queue.encode(sentences(&input), priority))
+                })
+            })
+            .collect();
+        for handle in handles {
+            let (input, result) = handle.join().unwrap();
+            assert_eq!(lengths(result.unwrap()), input.to_vec());
+        }
+        assert_eq!(queue.encode_single("abc").unwrap(), vec![3.0]);
+        assert!(queue
+            .encode(vec![], EncodePriority::Bulk)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_encode_rejects_when_lane_is_full() {
+        let encoder = FakeEncoder::new(false);
+        let started = encoder.notify_started();
+        let queue = EncodeQueue::new(Arc::clone(&encoder), config());
+
+        // taken by the worker, which then blocks on the closed gate
+        let first = {
+            let queue = queue.clone();
+            std::thread::spawn(move || queue.encode(sentences(&[1]), EncodePriority::Bulk))
+        };
+        started.recv().unwrap();
+
+        // fills the bulk lane, sentences count towards the limit, not requests
+        let second = {
+            let queue = queue.clone();
+            std::thread::spawn(move || queue.encode(sentences(&[2, 3, 4]), EncodePriority::Bulk))
+        };
+        while queue
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .lane_sentences(EncodePriority::Bulk)
+            < 3
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(
+            queue.encode(sentences(&[5]), EncodePriority::Bulk),
+            Err(BackendError::Busy(_))
+        ));
+
+        // other lanes are not affected
+        let interactive = {
+            let queue = queue.clone();
+            std::thread::spawn(move || queue.encode(sentences(&[6]), EncodePriority::Interactive))
+        };
+
+        encoder.open();
+        assert_eq!(lengths(first.join().unwrap().unwrap()), vec![1]);
+        assert_eq!(lengths(second.join().unwrap().unwrap()), vec![2, 3, 4]);
+        assert_eq!(lengths(interactive.join().unwrap().unwrap()), vec![6]);
+
+        // a request larger than the limit gets into an empty lane
+        assert_eq!(
+            lengths(
+                queue
+                    .encode(sentences(&[1, 2, 3, 4, 5]), EncodePriority::Bulk)
+                    .unwrap()
+            ),
+            vec![1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_encode_fails_when_worker_stops() {
+        let encoder = FakeEncoder::new(true);
+        encoder.panic.store(true, Ordering::SeqCst);
+        let queue = EncodeQueue::new(encoder, config());
+
+        // the job in the crashed batch and every later one fail instead of blocking
+        assert!(queue.encode_single("abc").is_err());
+        assert!(queue
+            .encode(sentences(&[1, 2]), EncodePriority::Bulk)
+            .is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::embeddings::model::EmbeddingModelMode;
+use crate::{BackendError, BackendResult};
+use fastembed::{RerankInitOptions, TextRerank};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, instrument};
+
+impl From<&EmbeddingModelMode> for fastembed::RerankerModel {
+    fn from(mode: &EmbeddingModelMode) -> Self {
+        match mode {
+            EmbeddingModelMode::Default
+            | EmbeddingModelMode::EnglishSmall
+            | EmbeddingModelMode::EnglishLarge => fastembed::RerankerModel::BGERerankerBase,
+            EmbeddingModelMode::MultilingualSmall | EmbeddingModelMode::MultilingualLarge => {
+                fastembed::RerankerModel::BGERerankerV2M3
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RerankScore {
+    pub index: usize,
+    pub score: f32,
+}
+
+/// Cross-encoder that scores (query, document) pairs.
+///
+/// The model is only loaded on the first rerank request since most sessions never
+/// use it and it is about as large as the embedding model itself.
+pub struct Reranker {
+    cache_dir: PathBuf,
+    model_name: fastembed::RerankerModel,
+    model: Mutex<Option<Arc<TextRerank>>>,
+}
+
+impl Reranker {
+    pub fn new(cache_dir: &Path, mode: &EmbeddingModelMode) -> Self {
+        Self {
+            cache_dir: cache_dir.to_path_buf(),
+            model_name: mode.into(),
+            model: Mutex::new(None),
+        }
+    }
+
+    fn model(&self) -> BackendResult<Arc<TextRerank>> {
+        let mut model = self
+            .model
+            .lock()
+            .map_err(|e| BackendError::GenericError(format!("reranker lock poisoned: {}", e)))?;
+
+        if let Some(model) = model.as_ref() {
+            return Ok(Arc::clone(model));
+        }
+
+        info!(model = ?self.model_name, "loading reranker model");
+        let options = RerankInitOptions {
+            model_name: self.model_name.clone(),
+            show_download_progress: false,
+            cache_dir: self.cache_dir.clone(),
+            ..Default::default()
+        };
+        let loaded = Arc::new(
+            TextRerank::try_new(options).map_err(|e| BackendError::GenericError(e.to_string()))?,
+        );
+        *model = Some(Arc::clone(&loaded));
+        Ok(loaded)
+    }
+
+    /// Scores every doc against `query`, returns the best `top_k` sorted by
+    /// descending relevance.
+    #[instrument(level = "debug", skip(self, query, docs), fields(count = docs.len()))]
+    pub fn rerank(
+        &self,
+        query: &str,
+        docs: &[String],
+        top_k: Option<usize>,
+    ) -> BackendResult<Vec<RerankScore>> {
+        if docs.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let docs: Vec<&str> = docs.iter().map(|doc| doc.as_str()).collect();
+        let results = self
+            .model()?
+            .rerank(query, docs, false, None)
+            .map_err(|e| {
+                error!("Failed to rerank documents: {}", e);
+                BackendError::GenericError(format!("Error reranking documents: {}", e))
+            })?;
+
+        let mut scores: Vec<RerankScore> = results
+            .into_iter()
+            .map(|result| RerankScore {
+                index: result.index,
+                score: result.score,
+            })
+            .collect();
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+        if let Some(top_k) = top_k {
+            scores.truncate(top_k);
+        }
+        Ok(scores)
+    }
+}
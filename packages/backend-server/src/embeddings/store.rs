@@ -1,6 +1,9 @@
 use crate::{BackendError, BackendResult};
 use serde::{Deserialize, Serialize};
-use tracing::{error, instrument, warn};
+use std::cell::Cell;
+use std::path::Path;
+use strum_macros::{Display, EnumString};
+use tracing::{error, info, instrument, warn};
 use usearch::{Index, IndexOptions, MetricKind, ScalarKind};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -9,44 +12,236 @@ pub struct DocsSimilarity {
     pub similarity: f32,
 }
 
-fn new_index(embeddings_dim: &usize) -> BackendResult<Index> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum IndexQuantization {
+    F32,
+    F16,
+    I8,
+}
+
+impl From<IndexQuantization> for ScalarKind {
+    fn from(quantization: IndexQuantization) -> Self {
+        match quantization {
+            IndexQuantization::F32 => ScalarKind::F32,
+            IndexQuantization::F16 => ScalarKind::F16,
+            IndexQuantization::I8 => ScalarKind::I8,
+        }
+    }
+}
+
+/// Index settings chosen when the index is first created.
+///
+/// The config is persisted next to the index file, quantization, connectivity and
+/// `expansion_add` are fixed once vectors have been added. `expansion_search` only
+/// affects queries and can be changed on an existing index.
+///
+/// A value of `0` for the HNSW parameters lets usearch pick its own default.
+///
+/// `view` is a per-session option and is never persisted: when set, the index is
+/// memory-mapped instead of loaded into RAM and only promoted to an in-memory copy
+/// once the first mutation arrives.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexConfig {
+    pub quantization: IndexQuantization,
+    pub connectivity: usize,
+    pub expansion_add: usize,
+    pub expansion_search: usize,
+    #[serde(skip_serializing)]
+    pub view: bool,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            quantization: IndexQuantization::F32,
+            connectivity: 0,
+            expansion_add: 0,
+            expansion_search: 0,
+            view: false,
+        }
+    }
+}
+
+impl IndexConfig {
+    fn config_path(index_path: &str) -> String {
+        format!("{}.config.json", index_path)
+    }
+
+    fn load(index_path: &str) -> BackendResult<Option<Self>> {
+        let config_path = Self::config_path(index_path);
+        if !Path::new(&config_path).exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(config_path)?;
+        Ok(Some(serde_json::from_str(&contents)?))
+    }
+
+    fn save(&self, index_path: &str) -> BackendResult<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        std::fs::write(Self::config_path(index_path), contents)?;
+        Ok(())
+    }
+
+    /// Resolves the config to use for the index at `index_path`.
+    ///
+    /// An existing index keeps the settings it was built with, indexes created before
+    /// the config was persisted are assumed to use the defaults. Only `expansion_search`
+    /// and `view` are taken from the requested config for an existing index.
+    fn resolve(index_path: &str, requested: &IndexConfig) -> BackendResult<Self> {
+        if let Some(stored) = Self::load(index_path)? {
+            if stored.quantization != requested.quantization
+                || stored.connectivity != requested.connectivity
+                || stored.expansion_add != requested.expansion_add
+            {
+                warn!(
+                    ?stored,
+                    ?requested,
+                    "index config differs from the stored one, keeping the stored config"
+                );
+            }
+            return Ok(Self {
+                expansion_search: requested.expansion_search,
+                view: requested.view,
+                ..stored
+            });
+        }
+
+        if Path::new(index_path).exists() {
+            info!("index has no stored config, assuming defaults");
+            return Ok(Self {
+                expansion_search: requested.expansion_search,
+                view: requested.view,
+                ..Default::default()
+            });
+        }
+
+        Ok(requested.clone())
+    }
+}
+
+fn new_index(embeddings_dim: &usize, config: &IndexConfig) -> BackendResult<Index> {
     let options = IndexOptions {
         dimensions: *embeddings_dim,
         metric: MetricKind::Cos,
-        quantization: ScalarKind::F32,
+        quantization: config.quantization.into(),
+        connectivity: config.connectivity,
+        expansion_add: config.expansion_add,
+        expansion_search: config.expansion_search,
         ..Default::default()
     };
 
     Index::new(&options).map_err(|e| e.into())
 }
 
+fn open_index(index: &Index, index_path: &str, view: bool) -> BackendResult<()> {
+    if view {
+        index.view(index_path)?;
+    } else {
+        index.load(index_path)?;
+    }
+    Ok(())
+}
+
 pub struct EmbeddingsStore {
     embedding_dim: usize,
     index_path: String,
+    config: IndexConfig,
     index: Index,
+    // true while the index is still memory-mapped and has not been promoted
+    viewed: Cell<bool>,
 }
 
 impl EmbeddingsStore {
-    pub fn new(index_path: &str, embeddings_dim: &usize) -> BackendResult<Self> {
-        let index = new_index(embeddings_dim)?;
-
-        if let Err(e) = index.load(index_path) {
+    pub fn new(
+        index_path: &str,
+        embeddings_dim: &usize,
+        config: &IndexConfig,
+    ) -> BackendResult<Self> {
+        let config = IndexConfig::resolve(index_path, config)?;
+        let index = new_index(embeddings_dim, &config)?;
+
+        if let Err(e) = open_index(&index, index_path, config.view) {
             warn!("Index not found, creating new one: {}", e);
             index.save(index_path)?;
+            if config.view {
+                index.view(index_path)?;
+            }
+        }
+        // loading or viewing restores the expansion_search the index was saved with
+        if config.expansion_search > 0 {
+            index.change_expansion_search(config.expansion_search);
         }
+        config.save(index_path)?;
+
+        info!(
+            ?config,
+            viewed = config.view,
+            size = index.size(),
+            memory_usage = index.memory_usage(),
+            "embeddings store ready"
+        );
 
         Ok(Self {
             embedding_dim: *embeddings_dim,
             index,
+            viewed: Cell::new(config.view),
+            config,
             index_path: index_path.to_string(),
         })
     }
 
+    #[cfg(test)]
+    pub fn config(&self) -> &IndexConfig {
+        &self.config
+    }
+
+    #[cfg(test)]
+    pub fn memory_usage(&self) -> usize {
+        self.index.memory_usage()
+    }
+
+    pub fn is_viewed(&self) -> bool {
+        self.viewed.get()
+    }
+
+    /// Promotes a memory-mapped index to an in-memory copy so it can be mutated.
+    ///
+    /// The file on disk is only written on the following `save`, so searches that
+    /// happened before the promotion never observe a partially written index.
+    fn ensure_writable(&self) -> BackendResult<()> {
+        if !self.viewed.get() {
+            return Ok(());
+        }
+
+        info!(
+            size = self.index.size(),
+            "promoting viewed index to memory for mutation"
+        );
+        self.index.load(&self.index_path)?;
+        self.viewed.set(false);
+        self.apply_expansion_search();
+        Ok(())
+    }
+
+    // loading or viewing restores the expansion_search the index was saved with
+    fn apply_expansion_search(&self) {
+        if self.config.expansion_search > 0 {
+            self.index
+                .change_expansion_search(self.config.expansion_search);
+        }
+    }
+
     fn reload(&self) -> BackendResult<()> {
-        self.index.load(&self.index_path).map_err(|e| e.into())
+        open_index(&self.index, &self.index_path, self.viewed.get())?;
+        self.apply_expansion_search();
+        Ok(())
     }
 
     pub fn add(&self, id: u64, embedding: &[f32]) -> BackendResult<()> {
+        self.ensure_writable()?;
         self.index.reserve(self.index.size() + 1)?;
         self.index.add(id, embedding)?;
         self.index.save(&self.index_path)?;
@@ -56,6 +251,7 @@ impl EmbeddingsStore {
     #[instrument(level = "debug", skip(self, embeddings), fields(count = ids.len()))]
     pub fn batch_add(&self, ids: Vec<u64>, embeddings: &[Vec<f32>]) -> BackendResult<()> {
         self.validate_inputs(&ids, embeddings)?;
+        self.ensure_writable()?;
 
         match self.execute_batch_add(&ids, embeddings) {
             Ok(_) => {
@@ -108,6 +304,7 @@ impl EmbeddingsStore {
     }
 
     pub fn remove(&self, id: u64) -> BackendResult<()> {
+        self.ensure_writable()?;
         self.index.remove(id)?;
         self.index.save(&self.index_path)?;
         Ok(())
@@ -115,6 +312,7 @@ impl EmbeddingsStore {
 
     #[instrument(level = "debug", skip(self), fields(count = ids.len()))]
     pub fn batch_remove(&self, ids: Vec<u64>) -> BackendResult<()> {
+        self.ensure_writable()?;
         for id in ids.iter() {
             self.index.remove(*id)?;
         }
@@ -163,7 +361,7 @@ impl EmbeddingsStore {
         threshold: &f32,
         num_docs: &usize,
     ) -> BackendResult<Vec<DocsSimilarity>> {
-        let index = new_index(&self.embedding_dim)?;
+        let index = new_index(&self.embedding_dim, &self.config)?;
         let index_size = embeddings.len();
 
         index.reserve(index_size)?;
@@ -204,9 +402,11 @@ mod tests {
     impl NeedsCleanup {
         // must be called before the store is created
         fn new(index_path: &str) -> Self {
-            if let Err(error) = std::fs::remove_file(index_path) {
-                if error.kind() != std::io::ErrorKind::NotFound {
-                    panic!("Failed to remove existing test index");
+            for path in [index_path.to_string(), IndexConfig::config_path(index_path)] {
+                if let Err(error) = std::fs::remove_file(path) {
+                    if error.kind() != std::io::ErrorKind::NotFound {
+                        panic!("Failed to remove existing test index");
+                    }
                 }
             }
             Self {
@@ -218,6 +418,8 @@ mod tests {
     impl Drop for NeedsCleanup {
         fn drop(&mut self) {
             std::fs::remove_file(&self.index_path).expect("Failed to remove test index");
+            std::fs::remove_file(IndexConfig::config_path(&self.index_path))
+                .expect("Failed to remove test index config");
         }
     }
 
@@ -227,7 +429,7 @@ mod tests {
         let test_db = ".test_sanity_docs_similarity.usearch";
         // must be called before the store is created
         let _cleanup = NeedsCleanup::new(test_db);
-        let store = EmbeddingsStore::new(test_db, &2).unwrap();
+        let store = EmbeddingsStore::new(test_db, &2, &IndexConfig::default()).unwrap();
         let query = vec![0.1, 0.1];
         let docs = vec![
             vec![0.1, 0.1],
@@ -249,7 +451,7 @@ mod tests {
         let test_db = ".test_rollback.usearch";
         // must be called before the store is created
         let _cleanup = NeedsCleanup::new(test_db);
-        let store = EmbeddingsStore::new(test_db, &1).unwrap();
+        let store = EmbeddingsStore::new(test_db, &1, &IndexConfig::default()).unwrap();
 
         let old_state: HashMap<u64, Vec<f32>> = HashMap::from([(1, vec![1.0]), (2, vec![2.0])]);
         let new_state: HashMap<u64, Vec<f32>> = HashMap::from([(3, vec![3.0]), (4, vec![4.0])]);
@@ -290,4 +492,163 @@ mod tests {
             assert!(!store.index.contains(key));
         }
     }
+
+    #[test]
+    #[serial]
+    fn test_config_persisted_with_index() {
+        let test_db = ".test_config_persisted.usearch";
+        // must be called before the store is created
+        let _cleanup = NeedsCleanup::new(test_db);
+        let config = IndexConfig {
+            quantization: IndexQuantization::F16,
+            connectivity: 8,
+            expansion_add: 64,
+            expansion_search: 32,
+            ..Default::default()
+        };
+
+        {
+            let store = EmbeddingsStore::new(test_db, &2, &config).unwrap();
+            store.add(1, &[0.1, 0.2]).unwrap();
+        }
+
+        // reopening with a different config keeps the stored build settings
+        let requested = IndexConfig {
+            expansion_search: 128,
+            ..Default::default()
+        };
+        let store = EmbeddingsStore::new(test_db, &2, &requested).unwrap();
+        assert_eq!(store.config().quantization, IndexQuantization::F16);
+        assert_eq!(store.config().connectivity, 8);
+        assert_eq!(store.config().expansion_add, 64);
+        assert_eq!(store.config().expansion_search, 128);
+        assert!(store.index.contains(1));
+    }
+
+    #[test]
+    #[serial]
+    fn test_view_promotes_on_mutation() {
+        let test_db = ".test_view_promotes.usearch";
+        // must be called before the store is created
+        let _cleanup = NeedsCleanup::new(test_db);
+
+        {
+            let store = EmbeddingsStore::new(test_db, &2, &IndexConfig::default()).unwrap();
+            store.add(1, &[0.1, 0.2]).unwrap();
+            store.add(2, &[0.9, 0.1]).unwrap();
+        }
+
+        let config = IndexConfig {
+            view: true,
+            ..Default::default()
+        };
+        let store = EmbeddingsStore::new(test_db, &2, &config).unwrap();
+        assert!(store.is_viewed());
+        assert_eq!(store.search(&[0.1, 0.2], 1).unwrap(), vec![1]);
+
+        store.add(3, &[0.5, 0.5]).unwrap();
+        assert!(!store.is_viewed());
+        for key in [1, 2, 3] {
+            assert!(store.index.contains(key));
+        }
+
+        // the view flag is a session option and must not be persisted
+        let stored = IndexConfig::load(test_db).unwrap().unwrap();
+        assert!(!stored.view);
+    }
+
+    /// Small deterministic xorshift generator so the benchmark corpus is reproducible
+    /// without pulling in a rand dependency.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next_f32(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+        }
+
+        fn vector(&mut self, dim: usize) -> Vec<f32> {
+            (0..dim).map(|_| self.next_f32()).collect()
+        }
+    }
+
+    fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+        let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+        1.0 - dot / (norm_a * norm_b)
+    }
+
+    // too slow for every test run, run with
+    // `cargo test bench_quantization_recall -- --ignored --nocapture` to see the report
+    #[test]
+    #[ignore]
+    fn bench_quantization_recall() {
+        const DIM: usize = 384;
+        const CORPUS_SIZE: usize = 5_000;
+        const NUM_QUERIES: usize = 50;
+        const K: usize = 10;
+
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        let corpus: Vec<Vec<f32>> = (0..CORPUS_SIZE).map(|_| rng.vector(DIM)).collect();
+        let queries: Vec<Vec<f32>> = (0..NUM_QUERIES).map(|_| rng.vector(DIM)).collect();
+
+        let ground_truth: Vec<Vec<u64>> = queries
+            .iter()
+            .map(|query| {
+                let mut distances: Vec<(u64, f32)> = corpus
+                    .iter()
+                    .enumerate()
+                    .map(|(i, doc)| (i as u64, cosine_distance(query, doc)))
+                    .collect();
+                distances.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                distances.iter().take(K).map(|(key, _)| *key).collect()
+            })
+            .collect();
+
+        println!(
+            "quantization | recall@{} | memory (bytes) | serialized (bytes)",
+            K
+        );
+        for quantization in [
+            IndexQuantization::F32,
+            IndexQuantization::F16,
+            IndexQuantization::I8,
+        ] {
+            let config = IndexConfig {
+                quantization,
+                ..Default::default()
+            };
+            let index = new_index(&DIM, &config).unwrap();
+            index.reserve(CORPUS_SIZE).unwrap();
+            for (i, doc) in corpus.iter().enumerate() {
+                index.add(i as u64, doc).unwrap();
+            }
+
+            let mut hits = 0;
+            for (query, expected) in queries.iter().zip(ground_truth.iter()) {
+                let results = index.search(query, K).unwrap();
+                hits += results.keys.iter().filter(|k| expected.contains(k)).count();
+            }
+            let recall = hits as f32 / (NUM_QUERIES * K) as f32;
+
+            println!(
+                "{:>12} | {:>9.3} | {:>14} | {:>18}",
+                quantization,
+                recall,
+                index.memory_usage(),
+                index.serialized_length()
+            );
+            // random vectors are the worst case for HNSW, this is only a sanity floor
+            assert!(
+                recall >= 0.5,
+                "recall@{} for {} dropped to {}",
+                K,
+                quantization,
+                recall
+            );
+        }
+    }
 }
@@ -2,6 +2,7 @@ pub mod embeddings;
 pub mod server;
 
 use crate::embeddings::model::EmbeddingModelMode;
+use crate::embeddings::store::IndexConfig;
 use crate::server::LocalAIServer;
 use std::path::Path;
 use std::str::FromStr;
@@ -21,6 +22,8 @@ pub enum BackendError {
     MspcSendError(#[from] std::sync::mpsc::SendError<crate::server::message::Message>),
     #[error("Mspc recv error: {0}")]
     MspcRecvError(#[from] std::sync::mpsc::RecvError),
+    #[error("Busy: {0}")]
+    Busy(String),
     #[error("Generic error: {0}")]
     GenericError(String),
 }
@@ -44,9 +47,9 @@ fn main() {
         .ok();
 
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 4 {
+    if args.len() != 4 && args.len() != 5 {
         eprintln!(
-            "Usage: {} <root_path> <local_llm_mode> <embedding_model_mode>",
+            "Usage: {} <root_path> <local_llm_mode> <embedding_model_mode> [index_config_json]",
             args[0]
         );
         std::process::exit(1);
@@ -55,6 +58,7 @@ fn main() {
     let root_path = Path::new(&args[1]);
     let socket_path = Path::join(root_path, "sffs-ai.sock");
     let index_path = Path::join(root_path, "index.usearch");
+    let image_index_path = Path::join(root_path, "image-index.usearch");
     let model_cache_dir = Path::join(root_path, "fastembed-cache");
     let local_llm_mode = match args[2].as_str() {
         "true" => true,
@@ -75,16 +79,30 @@ fn main() {
         }
     };
 
+    // build settings only apply when the index is first created, see `IndexConfig`
+    let index_config: IndexConfig = match args.get(4) {
+        Some(raw) => match serde_json::from_str(raw) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Bad index_config_json: {:#?}, error: {:#?}", raw, e);
+                std::process::exit(1);
+            }
+        },
+        None => IndexConfig::default(),
+    };
+
     info!(
-        "started with socket_path: {:#?}, local_llm_mode: {:#?}",
-        socket_path, local_llm_mode
+        "started with socket_path: {:#?}, local_llm_mode: {:#?}, index_config: {:?}",
+        socket_path, local_llm_mode, index_config
     );
     let server = LocalAIServer::new(
         &socket_path,
         &index_path,
+        &image_index_path,
         &model_cache_dir,
         local_llm_mode,
         embedding_model_mode,
+        index_config,
     )
     .expect("failed to create new server");
 
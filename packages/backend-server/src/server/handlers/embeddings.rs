@@ -7,9 +7,11 @@ use tracing::{error, instrument, warn};
 use uds_windows::UnixStream;
 
 use super::{try_stream_write_all, try_stream_write_all_bytes};
-use crate::embeddings::model::EmbeddingModel;
-use crate::server::message::Message;
-use crate::BackendResult;
+use crate::embeddings::image::ImageEmbeddingModel;
+use crate::embeddings::queue::{EncodePriority, EncodeQueue};
+use crate::embeddings::reranker::Reranker;
+use crate::server::message::{IndexNamespace, Message};
+use crate::{BackendError, BackendResult};
 
 use super::send_done;
 
@@ -29,6 +31,30 @@ pub struct FilteredSearchRequest {
     threshold: Option<f32>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpsertImageEmbeddingsRequest {
+    pub old_keys: Vec<i64>,
+    pub new_keys: Vec<i64>,
+    pub image_paths: Vec<String>,
+}
+
+/// Searches the image index either by a text query or by a reference image.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageSearchRequest {
+    query: Option<String>,
+    image_path: Option<String>,
+    num_docs: usize,
+    keys: Vec<u64>,
+    threshold: Option<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RerankRequest {
+    query: String,
+    docs: Vec<String>,
+    top_k: Option<usize>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpsertEmbeddingsRequest {
     pub old_keys: Vec<i64>,
@@ -54,18 +80,18 @@ fn send_to_main_thread(
 
 #[instrument(
     level = "trace",
-    skip(main_thread_tx, stream, embedding_model, client_message)
+    skip(main_thread_tx, stream, encode_queue, client_message)
 )]
 pub fn handle_get_docs_similarity(
     main_thread_tx: Sender<Message>,
     stream: &UnixStream,
-    embedding_model: &EmbeddingModel,
+    encode_queue: &EncodeQueue,
     client_message: &str,
 ) -> BackendResult<()> {
     let request = serde_json::from_str::<DocsSimilarityRequest>(client_message)?;
 
-    let query_embedding = embedding_model.encode_single(&request.query)?;
-    let doc_embeddings = embedding_model.encode(&request.docs)?;
+    let query_embedding = encode_queue.encode_single(&request.query)?;
+    let doc_embeddings = encode_queue.encode(request.docs, EncodePriority::Interactive)?;
 
     let (response_tx, response_rx) = std::sync::mpsc::channel();
     send_to_main_thread(
@@ -94,14 +120,14 @@ pub fn handle_get_docs_similarity(
     Ok(())
 }
 
-#[instrument(level = "trace", skip(stream, embedding_model, client_message))]
+#[instrument(level = "trace", skip(stream, encode_queue, client_message))]
 pub fn handle_encode_sentences(
     stream: &UnixStream,
-    embedding_model: &EmbeddingModel,
+    encode_queue: &EncodeQueue,
     client_message: &str,
 ) -> BackendResult<()> {
     let sentences = serde_json::from_str::<Vec<String>>(client_message)?;
-    let embeddings = embedding_model.encode(&sentences)?;
+    let embeddings = encode_queue.encode(sentences, EncodePriority::Interactive)?;
     let embeddings = serde_json::to_vec(&embeddings)?;
 
     try_stream_write_all_bytes(stream, &embeddings);
@@ -111,23 +137,24 @@ pub fn handle_encode_sentences(
 
 #[instrument(
     level = "trace",
-    skip(main_thread_tx, stream, embedding_model, client_message)
+    skip(main_thread_tx, stream, encode_queue, client_message)
 )]
 pub fn handle_filtered_search(
     main_thread_tx: Sender<Message>,
     stream: &UnixStream,
-    embedding_model: &EmbeddingModel,
+    encode_queue: &EncodeQueue,
     client_message: &str,
 ) -> BackendResult<()> {
     let request = serde_json::from_str::<FilteredSearchRequest>(client_message)?;
 
-    let query_embedding = embedding_model.encode_single(&request.query)?;
+    let query_embedding = encode_queue.encode_single(&request.query)?;
     let (response_tx, response_rx) = std::sync::mpsc::channel();
 
     send_to_main_thread(
         &main_thread_tx,
         Message::FilteredSearch(
             response_tx,
+            IndexNamespace::Text,
             query_embedding,
             request.num_docs,
             request.keys.to_vec(),
@@ -154,23 +181,24 @@ pub fn handle_filtered_search(
 
 #[instrument(
     level = "trace",
-    skip(main_thread_tx, stream, embedding_model, client_message)
+    skip(main_thread_tx, stream, encode_queue, client_message)
 )]
 pub fn handle_upsert_embeddings(
     main_thread_tx: Sender<Message>,
     stream: &UnixStream,
-    embedding_model: &EmbeddingModel,
+    encode_queue: &EncodeQueue,
     client_message: &str,
 ) -> BackendResult<()> {
     let request = serde_json::from_str::<UpsertEmbeddingsRequest>(client_message)?;
 
-    let embeddings = embedding_model.encode(&request.chunks)?;
+    let embeddings = encode_queue.encode(request.chunks, EncodePriority::Bulk)?;
     let (response_tx, response_rx) = std::sync::mpsc::channel();
 
     send_to_main_thread(
         &main_thread_tx,
         Message::BatchRemoveEmbeddings(
             response_tx.clone(),
+            IndexNamespace::Text,
             request.old_keys.iter().map(|&x| x as u64).collect(),
         ),
         stream,
@@ -189,6 +217,7 @@ pub fn handle_upsert_embeddings(
             &main_thread_tx,
             Message::BatchAddEmbeddings(
                 response_tx,
+                IndexNamespace::Text,
                 request.new_keys.iter().map(|&x| x as u64).collect(),
                 embeddings,
                 10,
@@ -209,3 +238,135 @@ pub fn handle_upsert_embeddings(
     send_done(stream);
     Ok(())
 }
+
+#[instrument(level = "trace", skip(stream, reranker, client_message))]
+pub fn handle_rerank(
+    stream: &UnixStream,
+    reranker: &Reranker,
+    client_message: &str,
+) -> BackendResult<()> {
+    let request = serde_json::from_str::<RerankRequest>(client_message)?;
+    let scores = reranker.rerank(&request.query, &request.docs, request.top_k)?;
+    let scores = serde_json::to_vec(&scores)?;
+
+    try_stream_write_all_bytes(stream, &scores);
+    send_done(stream);
+    Ok(())
+}
+
+#[instrument(
+    level = "trace",
+    skip(main_thread_tx, stream, image_model, client_message)
+)]
+pub fn handle_upsert_image_embeddings(
+    main_thread_tx: Sender<Message>,
+    stream: &UnixStream,
+    image_model: &ImageEmbeddingModel,
+    client_message: &str,
+) -> BackendResult<()> {
+    let request = serde_json::from_str::<UpsertImageEmbeddingsRequest>(client_message)?;
+
+    if request.new_keys.len() != request.image_paths.len() {
+        return Err(BackendError::GenericError(
+            "new_keys and image_paths must have the same length".to_string(),
+        ));
+    }
+    let embeddings = image_model.encode_images(&request.image_paths)?;
+    let (response_tx, response_rx) = std::sync::mpsc::channel();
+
+    send_to_main_thread(
+        &main_thread_tx,
+        Message::BatchRemoveEmbeddings(
+            response_tx.clone(),
+            IndexNamespace::Image,
+            request.old_keys.iter().map(|&x| x as u64).collect(),
+        ),
+        stream,
+    )?;
+
+    match response_rx.recv()? {
+        Ok(_) => (),
+        Err(e) => {
+            error!(?e, "failed to remove old image embeddings");
+            return Err(e);
+        }
+    };
+
+    if !request.new_keys.is_empty() {
+        send_to_main_thread(
+            &main_thread_tx,
+            Message::BatchAddEmbeddings(
+                response_tx,
+                IndexNamespace::Image,
+                request.new_keys.iter().map(|&x| x as u64).collect(),
+                embeddings,
+                10,
+            ),
+            stream,
+        )?;
+
+        match response_rx.recv()? {
+            Ok(_) => (),
+            Err(e) => {
+                error!(?e, "failed to add new image embeddings");
+                return Err(e);
+            }
+        }
+    }
+
+    try_stream_write_all(stream, "ok");
+    send_done(stream);
+    Ok(())
+}
+
+#[instrument(
+    level = "trace",
+    skip(main_thread_tx, stream, image_model, client_message)
+)]
+pub fn handle_image_search(
+    main_thread_tx: Sender<Message>,
+    stream: &UnixStream,
+    image_model: &ImageEmbeddingModel,
+    client_message: &str,
+) -> BackendResult<()> {
+    let request = serde_json::from_str::<ImageSearchRequest>(client_message)?;
+
+    let query_embedding = match (&request.query, &request.image_path) {
+        (_, Some(image_path)) => image_model.encode_image(image_path)?,
+        (Some(query), None) => image_model.encode_query(query)?,
+        (None, None) => {
+            return Err(BackendError::GenericError(
+                "image search needs either a query or an image_path".to_string(),
+            ))
+        }
+    };
+    let (response_tx, response_rx) = std::sync::mpsc::channel();
+
+    send_to_main_thread(
+        &main_thread_tx,
+        Message::FilteredSearch(
+            response_tx,
+            IndexNamespace::Image,
+            query_embedding,
+            request.num_docs,
+            request.keys,
+            request.threshold,
+        ),
+        stream,
+    )?;
+
+    let search_results = match response_rx.recv()? {
+        Ok(search_results) => search_results,
+        Err(e) => {
+            error!(?e, "error processing image search request");
+            return Err(e);
+        }
+    };
+
+    let search_results: Vec<i64> = search_results.iter().map(|id| *id as i64).collect();
+    let search_results = serde_json::to_vec(&search_results)?;
+
+    try_stream_write_all_bytes(stream, &search_results);
+    send_done(stream);
+    Ok(())
+}
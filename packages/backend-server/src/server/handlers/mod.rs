@@ -1,12 +1,14 @@
 mod embeddings;
 mod requests;
 
-use crate::embeddings::model::EmbeddingModel;
+use crate::embeddings::image::ImageEmbeddingModel;
+use crate::embeddings::queue::EncodeQueue;
+use crate::embeddings::reranker::Reranker;
 use crate::server::message::Message;
-use crate::BackendResult;
+use crate::{BackendError, BackendResult};
 use embeddings::{
     handle_encode_sentences, handle_filtered_search, handle_get_docs_similarity,
-    handle_upsert_embeddings,
+    handle_image_search, handle_rerank, handle_upsert_embeddings, handle_upsert_image_embeddings,
 };
 use requests::Requests;
 use std::io::{Read, Write};
@@ -72,10 +74,25 @@ pub fn try_stream_write_all_bytes(mut stream: &UnixStream, bytes: &[u8]) {
     }
 }
 
-#[instrument(level = "trace", skip(main_thread_tx, embedding_model, stream))]
+/// Writes the error response for a failed request, busy errors get a fixed message
+/// so clients can tell them apart and retry later.
+#[instrument(level = "trace", skip(stream))]
+fn send_error(stream: &UnixStream, error: &BackendError) {
+    match error {
+        BackendError::Busy(_) => try_stream_write_all(stream, "error: busy"),
+        _ => try_stream_write_all(stream, &format!("error: {:#?}", error)),
+    }
+}
+
+#[instrument(
+    level = "trace",
+    skip(main_thread_tx, encode_queue, reranker, image_model, stream)
+)]
 pub fn handle_client(
     main_thread_tx: Sender<Message>,
-    embedding_model: &EmbeddingModel,
+    encode_queue: &EncodeQueue,
+    reranker: &Reranker,
+    image_model: &ImageEmbeddingModel,
     stream: UnixStream,
 ) -> BackendResult<()> {
     let mut client_message_buffer = String::new();
@@ -121,41 +138,64 @@ pub fn handle_client(
             if let Err(e) = handle_get_docs_similarity(
                 main_thread_tx,
                 &stream,
-                embedding_model,
+                encode_queue,
                 &client_message_buffer,
             ) {
                 error!(?e, "get docs similarity request failed");
-                try_stream_write_all(&stream, &format!("error: {:#?}", e));
+                send_error(&stream, &e);
             }
         }
         Requests::EncodeSentences => {
-            if let Err(e) =
-                handle_encode_sentences(&stream, embedding_model, &client_message_buffer)
-            {
+            if let Err(e) = handle_encode_sentences(&stream, encode_queue, &client_message_buffer) {
                 error!(?e, "encode sentences request failed");
-                try_stream_write_all(&stream, &format!("error: {:#?}", e));
+                send_error(&stream, &e);
             }
         }
         Requests::FilteredSearch => {
             if let Err(e) = handle_filtered_search(
                 main_thread_tx,
                 &stream,
-                embedding_model,
+                encode_queue,
                 &client_message_buffer,
             ) {
                 error!(?e, "filtered search request failed");
-                try_stream_write_all(&stream, &format!("error: {:#?}", e));
+                send_error(&stream, &e);
             }
         }
         Requests::UpsertEmbeddings => {
             if let Err(e) = handle_upsert_embeddings(
                 main_thread_tx,
                 &stream,
-                embedding_model,
+                encode_queue,
                 &client_message_buffer,
             ) {
                 error!(?e, "upsert embeddings request failed");
-                try_stream_write_all(&stream, &format!("error: {:#?}", e));
+                send_error(&stream, &e);
+            }
+        }
+        Requests::Rerank => {
+            if let Err(e) = handle_rerank(&stream, reranker, &client_message_buffer) {
+                error!(?e, "rerank request failed");
+                send_error(&stream, &e);
+            }
+        }
+        Requests::UpsertImageEmbeddings => {
+            if let Err(e) = handle_upsert_image_embeddings(
+                main_thread_tx,
+                &stream,
+                image_model,
+                &client_message_buffer,
+            ) {
+                error!(?e, "upsert image embeddings request failed");
+                send_error(&stream, &e);
+            }
+        }
+        Requests::ImageSearch => {
+            if let Err(e) =
+                handle_image_search(main_thread_tx, &stream, image_model, &client_message_buffer)
+            {
+                error!(?e, "image search request failed");
+                send_error(&stream, &e);
             }
         }
     }
@@ -8,4 +8,7 @@ pub enum Requests {
     EncodeSentences,
     FilteredSearch,
     UpsertEmbeddings,
+    Rerank,
+    UpsertImageEmbeddings,
+    ImageSearch,
 }
@@ -1,14 +1,29 @@
 use crate::{embeddings::store::DocsSimilarity, BackendResult};
 use std::sync::mpsc::Sender;
 
+/// Which index a message targets, image embeddings live in their own index
+/// since they come from a different model and embedding space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexNamespace {
+    Text,
+    Image,
+}
+
 #[derive(Debug)]
 pub enum Message {
     AddEmbedding(Sender<BackendResult<()>>, u64, Vec<f32>),
     RemoveEmbedding(Sender<BackendResult<()>>, u64),
-    BatchAddEmbeddings(Sender<BackendResult<()>>, Vec<u64>, Vec<Vec<f32>>, usize),
-    BatchRemoveEmbeddings(Sender<BackendResult<()>>, Vec<u64>),
+    BatchAddEmbeddings(
+        Sender<BackendResult<()>>,
+        IndexNamespace,
+        Vec<u64>,
+        Vec<Vec<f32>>,
+        usize,
+    ),
+    BatchRemoveEmbeddings(Sender<BackendResult<()>>, IndexNamespace, Vec<u64>),
     FilteredSearch(
         Sender<BackendResult<Vec<u64>>>,
+        IndexNamespace,
         Vec<f32>,
         usize,
         Vec<u64>,
@@ -9,18 +9,26 @@ use tracing::{error, info, instrument};
 #[cfg(target_os = "windows")]
 use uds_windows::UnixListener;
 
+use crate::embeddings::image::ImageEmbeddingModel;
 use crate::embeddings::model::{EmbeddingModel, EmbeddingModelMode};
-use crate::embeddings::store::EmbeddingsStore;
+use crate::embeddings::queue::{EncodeQueue, EncodeQueueConfig};
+use crate::embeddings::reranker::Reranker;
+use crate::embeddings::store::{EmbeddingsStore, IndexConfig};
 use crate::{BackendError, BackendResult};
 use handlers::handle_client;
-use message::Message;
+use message::{IndexNamespace, Message};
 
 use std::sync::{mpsc, Arc};
 
 pub struct LocalAIServer {
     socket_path: String,
     index_path: String,
-    embedding_model: Arc<EmbeddingModel>,
+    image_index_path: String,
+    index_config: IndexConfig,
+    embedding_dim: usize,
+    encode_queue: EncodeQueue,
+    reranker: Arc<Reranker>,
+    image_model: Arc<ImageEmbeddingModel>,
     listener: UnixListener,
 }
 
@@ -29,9 +37,11 @@ impl LocalAIServer {
     pub fn new(
         socket_path: &PathBuf,
         index_path: &PathBuf,
+        image_index_path: &PathBuf,
         model_cache_dir: &Path,
         local_llm: bool,
         embedding_model_mode: EmbeddingModelMode,
+        index_config: IndexConfig,
     ) -> BackendResult<Self> {
         if socket_path.exists() {
             fs::remove_file(socket_path)?;
@@ -45,15 +55,24 @@ impl LocalAIServer {
             ));
         }
 
+        let reranker = Arc::new(Reranker::new(model_cache_dir, &embedding_model_mode));
+        let image_model = Arc::new(ImageEmbeddingModel::new(model_cache_dir));
         let embedding_model = Arc::new(EmbeddingModel::new_remote(
             model_cache_dir,
             embedding_model_mode,
         )?);
+        let embedding_dim = embedding_model.get_embedding_dim();
+        let encode_queue = EncodeQueue::new(embedding_model, EncodeQueueConfig::default());
 
         Ok(Self {
             socket_path: socket_path.to_string_lossy().to_string(),
             index_path: index_path.to_string_lossy().to_string(),
-            embedding_model,
+            image_index_path: image_index_path.to_string_lossy().to_string(),
+            index_config,
+            embedding_dim,
+            encode_queue,
+            reranker,
+            image_model,
             listener,
         })
     }
@@ -64,19 +83,39 @@ impl LocalAIServer {
         }
     }
 
-    #[instrument(level = "trace", skip(rx, embedding_dim, index_path))]
+    #[instrument(
+        level = "trace",
+        skip(rx, embedding_dim, index_path, image_index_path, index_config)
+    )]
     fn handle_main_thread_messages(
         rx: mpsc::Receiver<Message>,
         index_path: &str,
+        image_index_path: &str,
         embedding_dim: &usize,
+        index_config: &IndexConfig,
     ) {
-        let embeddings_store = match EmbeddingsStore::new(index_path, embedding_dim) {
+        let embeddings_store = match EmbeddingsStore::new(index_path, embedding_dim, index_config) {
             Ok(store) => store,
             Err(e) => {
                 error!(?e, "failed to create embeddings store");
                 return;
             }
         };
+        let image_embeddings_store = match EmbeddingsStore::new(
+            image_index_path,
+            &ImageEmbeddingModel::get_embedding_dim(),
+            index_config,
+        ) {
+            Ok(store) => store,
+            Err(e) => {
+                error!(?e, "failed to create image embeddings store");
+                return;
+            }
+        };
+        let store = |namespace: IndexNamespace| match namespace {
+            IndexNamespace::Text => &embeddings_store,
+            IndexNamespace::Image => &image_embeddings_store,
+        };
 
         loop {
             let msg = match rx.recv() {
@@ -94,16 +133,23 @@ impl LocalAIServer {
                 Message::RemoveEmbedding(sender, id) => {
                     Self::try_send(sender, embeddings_store.remove(id));
                 }
-                Message::BatchAddEmbeddings(sender, ids, embeddings, _size) => {
-                    Self::try_send(sender, embeddings_store.batch_add(ids, &embeddings));
+                Message::BatchAddEmbeddings(sender, namespace, ids, embeddings, _size) => {
+                    Self::try_send(sender, store(namespace).batch_add(ids, &embeddings));
                 }
-                Message::BatchRemoveEmbeddings(sender, ids) => {
-                    Self::try_send(sender, embeddings_store.batch_remove(ids));
+                Message::BatchRemoveEmbeddings(sender, namespace, ids) => {
+                    Self::try_send(sender, store(namespace).batch_remove(ids));
                 }
-                Message::FilteredSearch(sender, query, num_docs, filter_ids, threshold) => {
+                Message::FilteredSearch(
+                    sender,
+                    namespace,
+                    query,
+                    num_docs,
+                    filter_ids,
+                    threshold,
+                ) => {
                     Self::try_send(
                         sender,
-                        embeddings_store.filtered_search(&query, num_docs, &filter_ids, &threshold),
+                        store(namespace).filtered_search(&query, num_docs, &filter_ids, &threshold),
                     );
                 }
                 Message::GetDocsSimilarity(sender, query, docs, threshold, num_docs) => {
@@ -121,21 +167,33 @@ impl LocalAIServer {
         let (tx, rx) = mpsc::channel();
 
         let index_path = self.index_path.clone();
-        let embedding_dim = self.embedding_model.get_embedding_dim();
+        let image_index_path = self.image_index_path.clone();
+        let index_config = self.index_config.clone();
+        let embedding_dim = self.embedding_dim;
 
         std::thread::spawn(move || {
-            Self::handle_main_thread_messages(rx, &index_path, &embedding_dim)
+            Self::handle_main_thread_messages(
+                rx,
+                &index_path,
+                &image_index_path,
+                &embedding_dim,
+                &index_config,
+            )
         });
 
         info!("listening for incoming connections");
         for stream in self.listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    let embedding_model = Arc::clone(&self.embedding_model);
+                    let encode_queue = self.encode_queue.clone();
+                    let reranker = Arc::clone(&self.reranker);
+                    let image_model = Arc::clone(&self.image_model);
                     let tx = tx.clone();
 
                     std::thread::spawn(move || {
-                        if let Err(e) = handle_client(tx, &embedding_model, stream) {
+                        if let Err(e) =
+                            handle_client(tx, &encode_queue, &reranker, &image_model, stream)
+                        {
                             error!(?e, "client handler error");
                         }
                     });
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS llm_usage (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    model TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL DEFAULT 0,
+    completion_tokens INTEGER NOT NULL DEFAULT 0,
+    cached_tokens INTEGER NOT NULL DEFAULT 0,
+    latency_ms INTEGER NOT NULL DEFAULT 0,
+    streamed INTEGER NOT NULL DEFAULT 0,
+    custom_key INTEGER NOT NULL DEFAULT 0,
+    session_id TEXT,
+    note_id TEXT,
+    agent TEXT,
+    created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS llm_usage_created_at_index ON llm_usage(created_at);
+
+-- overrides of the built in price table, in USD per million tokens
+CREATE TABLE IF NOT EXISTS llm_model_prices (
+    model TEXT PRIMARY KEY,
+    input_per_mtok REAL NOT NULL,
+    output_per_mtok REAL NOT NULL,
+    cached_input_per_mtok REAL NOT NULL
+);
@@ -0,0 +1,11 @@
+-- summaries of the older turns of chat sessions, sent instead of them, one per
+-- message they end with, so that every branch keeps the summary of the turns
+-- it shares with the others
+CREATE TABLE IF NOT EXISTS ai_session_summaries (
+    ai_session_id TEXT NOT NULL REFERENCES ai_sessions(id) ON DELETE CASCADE,
+    -- last message the summary covers
+    message_id TEXT NOT NULL,
+    summary TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (ai_session_id, message_id)
+);
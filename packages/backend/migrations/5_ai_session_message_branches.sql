@@ -0,0 +1,31 @@
+ALTER TABLE ai_session_messages ADD COLUMN id TEXT DEFAULT NULL;
+-- the message this one follows, NULL for the first message of a branch at the
+-- start of the session
+ALTER TABLE ai_session_messages ADD COLUMN parent_id TEXT DEFAULT NULL;
+-- last message of the branch that is shown and continued
+ALTER TABLE ai_sessions ADD COLUMN active_message_id TEXT DEFAULT NULL;
+
+UPDATE ai_session_messages SET id = lower(hex(randomblob(16))) WHERE id IS NULL;
+
+-- existing sessions are a single branch in the order the messages were saved,
+-- the rowid orders messages saved within the same timestamp
+UPDATE ai_session_messages SET parent_id = (
+    SELECT p.id FROM ai_session_messages p
+    WHERE p.ai_session_id = ai_session_messages.ai_session_id
+    AND (
+        p.created_at < ai_session_messages.created_at
+        OR (p.created_at = ai_session_messages.created_at AND p.rowid < ai_session_messages.rowid)
+    )
+    ORDER BY p.created_at DESC, p.rowid DESC
+    LIMIT 1
+);
+
+UPDATE ai_sessions SET active_message_id = (
+    SELECT m.id FROM ai_session_messages m
+    WHERE m.ai_session_id = ai_sessions.id
+    ORDER BY m.created_at DESC, m.rowid DESC
+    LIMIT 1
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS ai_session_messages_id_index ON ai_session_messages(id);
+CREATE INDEX IF NOT EXISTS ai_session_messages_parent_id_index ON ai_session_messages(ai_session_id, parent_id);
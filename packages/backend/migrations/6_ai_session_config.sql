@@ -0,0 +1,2 @@
+-- JSON of the session's default model, pinned resources and retrieval settings
+ALTER TABLE ai_sessions ADD COLUMN config TEXT NOT NULL DEFAULT '{}';
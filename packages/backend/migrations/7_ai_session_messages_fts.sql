@@ -0,0 +1,26 @@
+-- full-text index over the queries and answers of chat sessions, joined to
+-- ai_session_messages on the message id
+CREATE VIRTUAL TABLE IF NOT EXISTS ai_session_messages_fts USING fts5(
+    message_id UNINDEXED,
+    ai_session_id UNINDEXED,
+    content,
+    tokenize="trigram"
+);
+
+INSERT INTO ai_session_messages_fts (rowid, message_id, ai_session_id, content)
+SELECT rowid, id, ai_session_id, content FROM ai_session_messages
+WHERE is_context = 0 AND msg_type = 'text';
+
+-- vector index keys of the embedded chunks of chat answers. Resource and chat
+-- embeddings share one vector index, the keys start at 2^40 so that they never
+-- collide with the rowids of embedding_resources, and are never reused so that
+-- a vector left behind by a deleted chat can't be found under a new key
+CREATE TABLE IF NOT EXISTS ai_session_message_embeddings (
+    key INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id TEXT NOT NULL,
+    ai_session_id TEXT NOT NULL REFERENCES ai_sessions(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS ai_session_message_embeddings_session_index ON ai_session_message_embeddings(ai_session_id);
+
+INSERT INTO sqlite_sequence (name, seq) VALUES ('ai_session_message_embeddings', 1099511627775);
@@ -0,0 +1,2 @@
+-- JSON array of the topic tags of a session
+ALTER TABLE ai_sessions ADD COLUMN tags TEXT NOT NULL DEFAULT '[]';
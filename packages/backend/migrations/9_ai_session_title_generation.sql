@@ -0,0 +1,3 @@
+-- progress of the generated title of a session: empty until it's attempted,
+-- then 'pending', 'done' or 'failed'. A session is titled at most once
+ALTER TABLE ai_sessions ADD COLUMN title_generation TEXT NOT NULL DEFAULT '';
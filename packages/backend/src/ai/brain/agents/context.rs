@@ -13,6 +13,7 @@ pub trait ContextManager {
     fn get_citation(&self, key: &str, message_id: &str, cited_text: &str) -> BackendResult<String>;
 }
 
+#[derive(Default)]
 pub struct MockContextManager;
 
 impl MockContextManager {
@@ -67,6 +67,7 @@ impl Tool for RemoveContextMessagesTool {
 }
 */
 
+#[derive(Default)]
 pub struct PopulateContextContentTool {}
 
 impl PopulateContextContentTool {
@@ -125,6 +126,7 @@ impl Tool for PopulateContextContentTool {
     }
 }
 
+#[derive(Default)]
 pub struct AddResourcesTool {}
 
 impl AddResourcesTool {
@@ -183,6 +185,7 @@ impl Tool for AddResourcesTool {
     }
 }
 
+#[derive(Default)]
 pub struct AddUrlsTool {}
 
 impl AddUrlsTool {
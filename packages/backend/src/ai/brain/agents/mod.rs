@@ -10,27 +10,21 @@ use tools::{FunctionCall, Tool, ToolCall, ToolResult};
 
 use quick_xml::events::Event;
 use quick_xml::reader::Reader;
-use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tracing::instrument::WithSubscriber;
 
 use crate::ai::brain::agents::context::ContextManager;
 use crate::ai::brain::agents::io::StatusMessage;
 use crate::ai::llm::client::{
-    CancellationToken, ChatCompletionProvider, ChatCompletionStream, Model,
+    CancellationToken, ChatCompletionChunk, ChatCompletionProvider, ChatCompletionRequest,
+    ChatCompletionStream, Model, ToolCallBuilder,
 };
-use crate::ai::llm::models::MessageRole;
+use crate::ai::llm::models::{MessageRole, ToolDefinition};
+use crate::ai::llm::usage::UsageTag;
 use crate::BackendResult;
 use crate::{ai::llm::models::Message, BackendError};
 
 use std::collections::{HashMap, HashSet};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ToolCallRequest {
-    name: String,
-    parameters: serde_json::Value,
-}
-
 #[derive(Debug)]
 enum LLMResponse {
     ToolCalls(Vec<ToolCall>),
@@ -127,6 +121,8 @@ impl Agent {
         self.tools.insert(name, tool);
     }
 
+    /// Runs the agent loop, has to be called from a thread that entered a tokio
+    /// runtime (e.g. `Worker::async_runtime`) but not from within async code.
     pub fn execute(
         &self,
         config: ExecuteConfig,
@@ -134,15 +130,22 @@ impl Agent {
         context_manager: &mut dyn ContextManager,
         cancellation_token: CancellationToken,
     ) -> BackendResult<AgentResult> {
+        // models with native tool calling get the tools as definitions, others have
+        // to describe their calls in XML
+        let tool_definitions = self.tool_definitions(config.allowed_tools.as_ref());
+        let native_tools = config.model.supports_tools() && !tool_definitions.is_empty();
+
         let system_messages = vec![Message::new_system(&self.build_system_prompt(
-            config.system_message_preamble,
-            config.allowed_tools,
+            config.system_message_preamble.clone(),
+            config.allowed_tools.clone(),
             self.config.write_final_response_to_io,
+            native_tools,
         ))];
 
         let user_msg = Message::new_user(&config.user_message);
         let io_id = io.get_id();
         let mut tool_usage_history = Vec::new();
+        let runtime = tokio::runtime::Handle::current();
 
         for iteration in 0..self.config.max_iterations {
             if cancellation_token.is_cancelled() {
@@ -179,15 +182,66 @@ impl Agent {
             // User message (always last)
             messages.push(user_msg.clone());
 
-            let stream = self.client.create_streaming_chat_completion(
-                messages,
-                &config.model,
-                config.custom_key.as_deref(),
-                None, // No response format needed for XML
-                cancellation_token.clone(),
-            )?;
+            if native_tools {
+                let (response, tool_calls) = match runtime.block_on(async {
+                    let request = ChatCompletionRequest::new(messages, &config.model)
+                        .with_custom_key(config.custom_key.clone())
+                        .with_tools(tool_definitions.clone())
+                        .with_usage_tag(UsageTag::agent(&self.config.name));
+                    let stream = self
+                        .client
+                        .complete_streaming(request, cancellation_token.clone())
+                        .await?;
+                    self.process_streaming_response_native(stream, io, context_manager)
+                        .await
+                }) {
+                    Err(BackendError::CancelledError) => return Ok(AgentResult::Cancelled),
+                    result => result?,
+                };
+
+                if tool_calls.is_empty() {
+                    tracing::info!("Agent: {}, final response: {}", self.config.name, response);
+                    return Ok(AgentResult::Success(response));
+                }
+
+                let mut tool_results = Vec::new();
+                for tool_call in tool_calls.iter() {
+                    let status = match self.execute_tool_call(
+                        tool_call,
+                        config.execution_id.clone(),
+                        config.model.clone(),
+                        config.custom_key.clone(),
+                        io,
+                        context_manager,
+                        cancellation_token.clone(),
+                    ) {
+                        Ok(result) => result.status,
+                        // e.g. the model made up a tool, let it know instead of failing
+                        Err(e) => format!("Error: {}", e),
+                    };
+                    tool_results.push(Message::new_tool_result(&tool_call.id, &status));
+                }
+
+                tool_usage_history.push(Message::new_assistant_tool_calls(&response, tool_calls));
+                tool_usage_history.extend(tool_results);
+                continue;
+            }
 
-            let response = self.process_streaming_response_xml(stream, io, context_manager)?;
+            let response = match runtime.block_on(async {
+                // no response format needed for XML
+                let request = ChatCompletionRequest::new(messages, &config.model)
+                    .with_custom_key(config.custom_key.clone())
+                    .with_usage_tag(UsageTag::agent(&self.config.name));
+                let stream = self
+                    .client
+                    .complete_streaming(request, cancellation_token.clone())
+                    .await?;
+                self.process_streaming_response_xml(stream, io, context_manager)
+                    .await
+            }) {
+                Err(BackendError::CancelledError) => return Ok(AgentResult::Cancelled),
+                result => result?,
+            };
 
             tracing::debug!("Agent: {}, LLM Response: {}", self.config.name, response);
 
@@ -211,8 +265,10 @@ impl Agent {
                     // Add to persistent tool usage history
                     tool_usage_history.push(Message::new_assistant(&response));
                     for tool_result in tool_results {
-                        tool_usage_history
-                            .push(Message::new_tool(&tool_result.name, &tool_result.status));
+                        tool_usage_history.push(Message::new_user(&format!(
+                            "<tool_result name=\"{}\">{}</tool_result>",
+                            tool_result.name, tool_result.status
+                        )));
                     }
                 }
                 LLMResponse::FinalResponse(final_response) => {
@@ -261,7 +317,7 @@ impl Agent {
         )))
     }
 
-    fn process_streaming_response_xml(
+    async fn process_streaming_response_xml(
         &self,
         mut stream: ChatCompletionStream,
         io: &dyn AgentIO,
@@ -271,32 +327,64 @@ impl Agent {
         let mut current_content = String::new();
         let mut state = StreamingState::WaitingForTag;
 
-        loop {
-            match stream.next() {
-                Some(result) => match result {
-                    Ok(chunk) => {
-                        full_response.push_str(&chunk);
-                        current_content.push_str(&chunk);
-
-                        self.process_xml_chunk(
-                            &mut current_content,
-                            &mut state,
-                            io,
-                            context_manager,
-                        )?;
-                    }
-                    Err(e) => {
-                        tracing::error!("Error in streaming chunk: {}", e);
-                        return Err(e);
-                    }
-                },
-                None => break,
+        while let Some(result) = stream.next_text().await {
+            match result {
+                Ok(chunk) => {
+                    full_response.push_str(&chunk);
+                    current_content.push_str(&chunk);
+
+                    self.process_xml_chunk(&mut current_content, &mut state, io, context_manager)?;
+                }
+                Err(e) => {
+                    tracing::error!("Error in streaming chunk: {}", e);
+                    return Err(e);
+                }
             }
         }
 
         Ok(full_response)
     }
 
+    /// Streams the text of a native tool calling response to the io as the final
+    /// answer (with citations) while collecting the requested tool calls.
+    async fn process_streaming_response_native(
+        &self,
+        mut stream: ChatCompletionStream,
+        io: &dyn AgentIO,
+        context_manager: &dyn ContextManager,
+    ) -> BackendResult<(String, Vec<ToolCall>)> {
+        let mut full_response = String::new();
+        let mut current_content = String::new();
+        let mut state = StreamingState::InFinalAnswer;
+        let mut tool_calls = ToolCallBuilder::default();
+
+        while let Some(chunk) = stream.next_chunk().await {
+            match chunk {
+                Ok(ChatCompletionChunk::Text(text)) => {
+                    full_response.push_str(&text);
+                    current_content.push_str(&text);
+
+                    self.process_xml_chunk(&mut current_content, &mut state, io, context_manager)?;
+                }
+                Ok(ChatCompletionChunk::ToolCall(chunk)) => tool_calls.push(chunk),
+                Err(e) => {
+                    tracing::error!("Error in streaming chunk: {}", e);
+                    return Err(e);
+                }
+            }
+        }
+
+        // there is no closing tag, whatever was held back for a partial tag is text
+        if matches!(state, StreamingState::InFinalAnswer)
+            && self.config.write_final_response_to_io
+            && !current_content.is_empty()
+        {
+            io.write(&current_content)?;
+        }
+
+        Ok((full_response, tool_calls.finish()))
+    }
+
     fn process_xml_chunk(
         &self,
         content: &mut String,
@@ -524,6 +612,7 @@ impl Agent {
         preamble: Option<String>,
         allowed_tools: Option<HashSet<String>>,
         user_facing_final_answer: bool,
+        native_tools: bool,
     ) -> String {
         let preamble_text = preamble.unwrap_or_default();
 
@@ -532,12 +621,17 @@ impl Agent {
 
 The user is editing a main note/document that also tracks your progress and tool outputs. This helps you understand what has been accomplished and what still needs to be done. Pay attention to the document to avoid repeating actions and build upon previous work.
 
-As the user is in a note/document, only provide information that should be put into the document in the <final_answer> tag.
+As the user is in a note/document, only provide information that should be put into the document in {final_answer_location}.
 
 {preamble_text}
 
 "#,
             base_prompt = self.config.system_prompt,
+            final_answer_location = if native_tools {
+                "your final answer"
+            } else {
+                "the <final_answer> tag"
+            },
             preamble_text = preamble_text
         );
 
@@ -549,6 +643,23 @@ ALWAYS USE HTML TAGS FOR FORMATTING!"
             "Keep this brief - just indicate completion (e.g., 'I'm done')"
         };
 
+        if native_tools {
+            // the tools themselves are passed as definitions with the request
+            return format!(
+                r#"{base_instructions}
+
+RESPONSE FORMAT:
+Call the provided tools when you need to, YOU CAN'T USE THE SAME TOOL TWICE IN A SINGLE ITERATION.
+Once the task is complete, respond without calling any tools:
+
+{final_answer_instructions}
+
+IMPORTANT:
+- Citations should use <citation><context_id>id</context_id><cited_text>text</cited_text></citation> tags, referencing context message IDs and including the specific text being cited"#,
+                final_answer_instructions = final_answer_instructions,
+            );
+        }
+
         if self.tools.is_empty() {
             return format!(
                 r#"{base_instructions}
@@ -635,6 +746,22 @@ IMPORTANT:
         )
     }
 
+    fn tool_definitions(&self, allowed_tools: Option<&HashSet<String>>) -> Vec<ToolDefinition> {
+        let mut definitions = self
+            .tools
+            .values()
+            .filter(|tool| allowed_tools.is_none_or(|allowed| allowed.contains(tool.name())))
+            .map(|tool| ToolDefinition {
+                name: tool.name().to_string(),
+                description: tool.description().to_string(),
+                parameters: tool.parameters_schema(),
+            })
+            .collect::<Vec<_>>();
+        // stable order keeps the request prefix cacheable
+        definitions.sort_by(|a, b| a.name.cmp(&b.name));
+        definitions
+    }
+
     fn create_tools_description(&self, allowed_tools: Option<HashSet<String>>) -> String {
         self.tools
             .values()
@@ -702,21 +829,19 @@ IMPORTANT:
                 Ok(Event::Start(e)) => {
                     if e.name().as_ref() == b"tool" {
                         // Extract tool name from attributes
-                        for attr in e.attributes() {
-                            if let Ok(attr) = attr {
-                                if attr.key.as_ref() == b"name" {
-                                    current_tool_name = Some(
-                                        std::str::from_utf8(&attr.value)
-                                            .map_err(|e| {
-                                                BackendError::GenericError(format!(
-                                                    "Invalid UTF-8 in tool name: {}",
-                                                    e
-                                                ))
-                                            })?
-                                            .to_string(),
-                                    );
-                                    break;
-                                }
+                        for attr in e.attributes().flatten() {
+                            if attr.key.as_ref() == b"name" {
+                                current_tool_name = Some(
+                                    std::str::from_utf8(&attr.value)
+                                        .map_err(|e| {
+                                            BackendError::GenericError(format!(
+                                                "Invalid UTF-8 in tool name: {}",
+                                                e
+                                            ))
+                                        })?
+                                        .to_string(),
+                                );
+                                break;
                             }
                         }
                         current_tool_content.clear();
@@ -736,6 +861,8 @@ IMPORTANT:
                             }
 
                             let tool_call = ToolCall {
+                                // xml calls have no ids, at most one call per tool
+                                id: tool_name.clone(),
                                 r#type: "function".to_string(),
                                 function: FunctionCall {
                                     name: tool_name.clone(),
@@ -766,6 +893,7 @@ IMPORTANT:
         Ok(tool_calls)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn execute_tool_call(
         &self,
         tool_call: &ToolCall,
@@ -841,7 +969,11 @@ IMPORTANT:
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ai::llm::{client::CancellationToken, models::Message};
+    use crate::ai::brain::agents::context::MockContextManager;
+    use crate::ai::brain::agents::io::MemoryIO;
+    use crate::ai::llm::client::{CancellationToken, Provider, ToolCallChunk};
+    use crate::ai::llm::models::Message;
+    use futures::future::BoxFuture;
     use serde_json::json;
     use std::sync::Arc;
     use tokio;
@@ -918,7 +1050,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_xml_tool_call_parsing() {
-        let agent = create_test_agent(vec![], None);
+        let mut agent = create_test_agent(vec![], None);
+        agent.add_tool(Box::new(MockTool::new("search", "Searches")));
 
         let response = r#"<tool_calls>
 <tool name="search">
@@ -960,7 +1093,9 @@ This is the final answer to the user's question.
 
     #[tokio::test]
     async fn test_xml_multiple_tool_calls() {
-        let agent = create_test_agent(vec![], None);
+        let mut agent = create_test_agent(vec![], None);
+        agent.add_tool(Box::new(MockTool::new("search", "Searches")));
+        agent.add_tool(Box::new(MockTool::new("process", "Processes")));
 
         let response = r#"<tool_calls>
 <tool name="search">
@@ -973,14 +1108,45 @@ This is the final answer to the user's question.
 
         match agent.parse_xml_response(response).unwrap() {
             LLMResponse::ToolCalls(calls) => {
-                assert_eq!(calls.len(), 2);
-                assert_eq!(calls[0].function.name, "search");
-                assert_eq!(calls[1].function.name, "process");
+                // the calls aren't ordered
+                let mut names: Vec<_> = calls.iter().map(|c| c.function.name.as_str()).collect();
+                names.sort();
+                assert_eq!(names, vec!["process", "search"]);
             }
             _ => panic!("Expected tool calls"),
         }
     }
 
+    #[test]
+    fn test_tool_definitions_respect_allowed_tools() {
+        let mut agent = create_test_agent(vec![], None);
+        agent.add_tool(Box::new(MockTool::new("search", "Searches")));
+        agent.add_tool(Box::new(MockTool::new("process", "Processes")));
+
+        let definitions = agent.tool_definitions(None);
+        assert_eq!(definitions.len(), 2);
+        assert_eq!(definitions[0].name, "process");
+        assert_eq!(definitions[1].parameters["required"][0], "query");
+
+        let allowed = HashSet::from(["search".to_string()]);
+        let definitions = agent.tool_definitions(Some(&allowed));
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].name, "search");
+    }
+
+    #[test]
+    fn test_native_system_prompt_has_no_xml_format() {
+        let mut agent = create_test_agent(vec![], None);
+        agent.add_tool(Box::new(MockTool::new("search", "Searches")));
+
+        let prompt = agent.build_system_prompt(None, None, true, true);
+        assert!(!prompt.contains("<tool_calls>"));
+        assert!(!prompt.contains("<final_answer>"));
+
+        let prompt = agent.build_system_prompt(None, None, true, false);
+        assert!(prompt.contains("<tool_calls>"));
+    }
+
     #[tokio::test]
     async fn test_xml_final_answer_with_citation() {
         let agent = create_test_agent(vec![], None);
@@ -1002,55 +1168,170 @@ This is some text with a citation <citation>
         }
     }
 
+    fn test_execute_config(model: Model) -> ExecuteConfig {
+        ExecuteConfig {
+            execution_id: "execution".to_string(),
+            user_message: "Find something about rust".to_string(),
+            system_message_preamble: None,
+            model,
+            custom_key: None,
+            allowed_tools: None,
+        }
+    }
+
+    // execute blocks on the runtime it is called from, so it can't run inside
+    // an async test
+    fn execute(agent: &Agent, config: ExecuteConfig, io: &MemoryIO) -> AgentResult {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        agent
+            .execute(
+                config,
+                io,
+                &mut MockContextManager::new(),
+                CancellationToken::new(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_execute_native_tool_call() {
+        let client = MockLLMClient::new(vec![
+            vec![ChatCompletionChunk::ToolCall(ToolCallChunk {
+                index: 0,
+                id: Some("call_1".to_string()),
+                name: Some("search".to_string()),
+                arguments: r#"{"query": "rust"}"#.to_string(),
+            })],
+            vec![
+                ChatCompletionChunk::Text("Found ".to_string()),
+                ChatCompletionChunk::Text("it.".to_string()),
+            ],
+        ]);
+        let mut agent = Agent::new(
+            Arc::new(client),
+            AgentConfig {
+                write_status_to_io: false,
+                ..Default::default()
+            },
+        );
+        agent.add_tool(Box::new(MockTool::new("search", "Searches")));
+        let io = MemoryIO::new();
+
+        match execute(&agent, test_execute_config(Model::GPT4o), &io) {
+            AgentResult::Success(response) => assert_eq!(response, "Found it."),
+            result => panic!("expected a final response, got {:?}", result),
+        }
+        assert_eq!(
+            io.read().unwrap(),
+            "Executing search with query: rustFound it."
+        );
+    }
+
+    #[test]
+    fn test_execute_xml_tool_call() {
+        let client = MockLLMClient::from_texts(&[
+            r#"<tool_calls><tool name="search">{"query": "rust"}</tool></tool_calls>"#,
+            "<final_answer>Found it.</final_answer>",
+        ]);
+        let mut agent = Agent::new(
+            Arc::new(client),
+            AgentConfig {
+                write_status_to_io: false,
+                ..Default::default()
+            },
+        );
+        agent.add_tool(Box::new(MockTool::new("search", "Searches")));
+        let io = MemoryIO::new();
+
+        // custom models aren't assumed to support native tool calls
+        let model = Model::Custom {
+            name: "local".to_string(),
+            provider: Provider::Custom("http://localhost".to_string()),
+            max_tokens: 8000,
+            vision: false,
+        };
+        match execute(&agent, test_execute_config(model), &io) {
+            AgentResult::Success(response) => assert_eq!(response.trim(), "Found it."),
+            result => panic!("expected a final response, got {:?}", result),
+        }
+        assert!(io
+            .read()
+            .unwrap()
+            .contains("Executing search with query: rust"));
+    }
+
     // Helper function for tests
     fn create_test_agent(responses: Vec<String>, config: Option<AgentConfig>) -> Agent {
         let config = config.unwrap_or_default();
-        Agent::new(Arc::new(MockLLMClient::new(responses)), config)
+        let responses: Vec<&str> = responses.iter().map(|r| r.as_str()).collect();
+        Agent::new(Arc::new(MockLLMClient::from_texts(&responses)), config)
     }
 
-    // Note: MockLLMClient would need to be updated to implement streaming
+    // answers every request with the next of its responses, a final answer once
+    // they are used up
     struct MockLLMClient {
-        responses: Vec<String>,
+        responses: Vec<Vec<ChatCompletionChunk>>,
         current_response: std::sync::Mutex<usize>,
     }
 
     impl MockLLMClient {
-        fn new(responses: Vec<String>) -> Self {
+        fn new(responses: Vec<Vec<ChatCompletionChunk>>) -> Self {
             Self {
                 responses,
                 current_response: std::sync::Mutex::new(0),
             }
         }
-    }
 
-    impl ChatCompletionProvider for MockLLMClient {
-        fn create_chat_completion(
-            &self,
-            _messages: Vec<Message>,
-            _model: &Model,
-            _custom_key: Option<&str>,
-            _response_format: Option<serde_json::Value>,
-        ) -> BackendResult<String> {
+        fn from_texts(responses: &[&str]) -> Self {
+            Self::new(
+                responses
+                    .iter()
+                    .map(|r| vec![ChatCompletionChunk::Text(r.to_string())])
+                    .collect(),
+            )
+        }
+
+        fn next_response(&self) -> Vec<ChatCompletionChunk> {
             let mut current = self.current_response.lock().unwrap();
-            if *current < self.responses.len() {
-                let response = self.responses[*current].clone();
-                *current += 1;
-                Ok(response)
-            } else {
-                Ok(r#"<final_answer>Final response</final_answer>"#.to_string())
+            match self.responses.get(*current) {
+                Some(response) => {
+                    *current += 1;
+                    response.clone()
+                }
+                None => vec![ChatCompletionChunk::Text(
+                    "<final_answer>Final response</final_answer>".to_string(),
+                )],
             }
         }
+    }
 
-        fn create_streaming_chat_completion(
-            &self,
+    impl ChatCompletionProvider for MockLLMClient {
+        fn create_chat_completion<'a>(
+            &'a self,
             _messages: Vec<Message>,
-            _model: &Model,
-            _custom_key: Option<&str>,
+            _model: &'a Model,
+            _custom_key: Option<&'a str>,
             _response_format: Option<serde_json::Value>,
+        ) -> BoxFuture<'a, BackendResult<String>> {
+            let response = self
+                .next_response()
+                .into_iter()
+                .filter_map(|chunk| match chunk {
+                    ChatCompletionChunk::Text(text) => Some(text),
+                    ChatCompletionChunk::ToolCall(_) => None,
+                })
+                .collect();
+            Box::pin(async move { Ok(response) })
+        }
+
+        fn complete_streaming<'a>(
+            &'a self,
+            request: ChatCompletionRequest,
             _cancellation_token: CancellationToken,
-        ) -> BackendResult<ChatCompletionStream> {
-            // Mock streaming implementation would be needed here
-            unimplemented!("Mock streaming implementation needed for tests")
+        ) -> BoxFuture<'a, BackendResult<ChatCompletionStream>> {
+            let stream = ChatCompletionStream::from_chunks(self.next_response(), request.model);
+            Box::pin(async move { Ok(stream) })
         }
     }
 }
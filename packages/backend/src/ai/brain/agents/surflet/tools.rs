@@ -34,6 +34,7 @@ pub struct SurfletArgs {
     prompt: String,
 }
 
+#[allow(dead_code)]
 #[derive(serde::Deserialize)]
 pub struct SurfletDoneCallbackResult {
     status: String,
@@ -8,6 +8,8 @@ use crate::{
 };
 use serde::{Deserialize, Serialize};
 
+pub use crate::ai::llm::models::{FunctionCall, ToolCall};
+
 #[derive(Debug, thiserror::Error)]
 #[error("Tool error")]
 pub struct ToolError;
@@ -18,6 +20,7 @@ pub trait Tool: Send + Sync {
     fn execution_message(&self) -> Option<&str>;
     fn parameters_schema(&self) -> serde_json::Value;
 
+    #[allow(clippy::too_many_arguments)]
     fn execute(
         &self,
         parameters: serde_json::Value,
@@ -30,18 +33,6 @@ pub trait Tool: Send + Sync {
     ) -> BackendResult<()>;
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct ToolCall {
-    pub r#type: String, // "function"
-    pub function: FunctionCall,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct FunctionCall {
-    pub name: String,
-    pub arguments: String,
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ToolResult {
     pub role: String,
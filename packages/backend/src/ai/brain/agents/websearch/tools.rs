@@ -31,6 +31,7 @@ impl SearchEngineCaller {
     }
 }
 
+#[allow(dead_code)]
 #[derive(serde::Deserialize)]
 pub struct SearchArgs {
     query: String,
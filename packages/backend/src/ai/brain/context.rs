@@ -4,8 +4,9 @@ use crate::{
             agents::context::ContextManager,
             js_tools::{JSToolRegistry, ToolName},
         },
+        citations::verify_quote,
         llm::models::{ContextMessage, Message},
-        youtube::{fetch_transcript, is_youtube_video_url},
+        youtube::{extract_youtube_video_id, fetch_transcript},
     },
     store::{db::Database, models::CompositeResource},
     BackendError, BackendResult,
@@ -16,6 +17,9 @@ use std::sync::Arc;
 #[derive(Clone, Debug)]
 pub struct ContextItem {
     message: ContextMessage,
+    // where in a transcript the content starts, not part of the message sent
+    // to the model
+    timestamp: Option<String>,
     resource_id: Option<String>,
     resource_text_content_id: Option<String>,
 }
@@ -27,7 +31,7 @@ impl ContextItem {
         let mut page = String::new();
         let mut uid = String::new();
         let mut resource_id = String::new();
-        if let Some(ts) = &self.message.timestamp {
+        if let Some(ts) = &self.timestamp {
             timestamp = ts.to_string();
         }
         if let Some(u) = &self.message.source_url {
@@ -42,14 +46,24 @@ impl ContextItem {
         if let Some(rid) = &self.resource_id {
             resource_id = rid.clone();
         }
+        // the cited text has to be in the chunk the context item holds
+        let quote = verify_quote(
+            self.message.content.as_deref().unwrap_or_default(),
+            cited_text,
+        );
+        let start = quote.start.map(|s| s.to_string()).unwrap_or_default();
+        let end = quote.end.map(|e| e.to_string()).unwrap_or_default();
 
-        format!("<citation data-text=\"{}\" data-uid=\"{}\" data-resource-id=\"{}\" data-timestamp=\"{}\" data-url=\"{}\" data-page=\"{}\">{}</citation>", 
+        format!("<citation data-text=\"{}\" data-uid=\"{}\" data-resource-id=\"{}\" data-timestamp=\"{}\" data-url=\"{}\" data-page=\"{}\" data-verified=\"{}\" data-start=\"{}\" data-end=\"{}\">{}</citation>", 
             html_escape::encode_safe(cited_text),
             uid,
             resource_id,
             timestamp,
             html_escape::encode_safe(&url),
             page,
+            quote.verified,
+            start,
+            end,
             self.message.id
         )
     }
@@ -60,7 +74,7 @@ impl ContextItem {
         let mut page = String::new();
         let mut uid = String::new();
         let mut resource_id = String::new();
-        if let Some(ts) = &self.message.timestamp {
+        if let Some(ts) = &self.timestamp {
             timestamp = ts.to_string();
         }
         if let Some(u) = &self.message.source_url {
@@ -123,7 +137,7 @@ impl LLMContext {
         user_lang_preference: Option<String>,
     ) -> BackendResult<Self> {
         let db = Database::new(db_path, false)?;
-        let resources = db.list_resources_metadata_by_ids(&resource_ids)?;
+        let resources = db.list_resources_metadata_by_ids(resource_ids)?;
         let context_items = Self::context_metadata_messages_from_resources(&resources);
 
         let mut llm_context = Self {
@@ -171,7 +185,6 @@ impl LLMContext {
                 source_url: None,
                 author: None,
                 description: None,
-                timestamp: None,
             };
             if let Some(metadata) = &resource.metadata {
                 if !metadata.name.is_empty() {
@@ -188,6 +201,7 @@ impl LLMContext {
                 id,
                 ContextItem {
                     message: msg,
+                    timestamp: None,
                     resource_id: Some(resource.resource.id.clone()),
                     resource_text_content_id: None,
                 },
@@ -217,7 +231,7 @@ impl LLMContext {
                 context_item.message.content = Some(first_text_content.content.clone());
                 context_item.resource_text_content_id = Some(first_text_content.id.clone());
                 if let Some(timestamp) = &first_text_content.metadata.timestamp {
-                    context_item.message.timestamp = Some(timestamp.clone().to_string());
+                    context_item.timestamp = Some(timestamp.clone().to_string());
                 }
                 if let Some(url) = &first_text_content.metadata.url {
                     context_item.message.source_url = Some(url.clone());
@@ -236,9 +250,10 @@ impl LLMContext {
                 new_message.id = new_context_id.clone();
                 new_message.content = Some(text_content.content.clone());
                 new_message.content_type = format!("{} (Part {})", new_message.content_type, i + 2);
-                if let Some(timestamp) = text_content.metadata.timestamp {
-                    new_message.timestamp = Some(timestamp.clone().to_string());
-                }
+                let timestamp = text_content
+                    .metadata
+                    .timestamp
+                    .map(|timestamp| timestamp.to_string());
                 if let Some(url) = &text_content.metadata.url {
                     new_message.source_url = Some(url.clone());
                 }
@@ -248,6 +263,7 @@ impl LLMContext {
 
                 let new_context_item = ContextItem {
                     message: new_message,
+                    timestamp,
                     resource_id: Some(resource_id.clone()),
                     resource_text_content_id: Some(text_content.id.clone()),
                 };
@@ -311,7 +327,8 @@ impl ContextManager for LLMContext {
 
     // TODO: can't really ignore the key in check in the trait impl but it's fine for now
     fn add_url(&mut self, _key: &str, url: &str) -> BackendResult<()> {
-        let (content_type, title, content, _screenshot) = if is_youtube_video_url(url) {
+        let is_youtube_video = extract_youtube_video_id(url).is_some();
+        let (content_type, title, content, _screenshot) = if is_youtube_video {
             let yt_transcript = fetch_transcript(url, self.user_lang_preference.as_deref())?;
             (
                 "Context(YouTube Transcript)",
@@ -341,13 +358,13 @@ impl ContextManager for LLMContext {
             author: None,
             description: None,
             created_at: None,
-            timestamp: None,
         };
 
         self.context_items.insert(
             msg.id.clone(),
             ContextItem {
                 message: msg,
+                timestamp: None,
                 resource_id: None,
                 resource_text_content_id: None,
             },
@@ -1,10 +1,9 @@
 use super::agents::io::AgentIO;
 use neon::prelude::*;
 use neon::{event::Channel, handle::Root, types::JsFunction};
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
-use std::{fs, thread};
 
 use crate::ai::brain::agents::io::StatusMessage;
 use crate::{BackendError, BackendResult};
@@ -102,6 +101,9 @@ impl AgentIO for NoteIO {
     }
 
     fn clear(&self) -> BackendResult<()> {
-        unimplemented!()
+        // the note is owned by the editor, it can only be appended to
+        Err(BackendError::GenericError(
+            "clearing a note is not supported".to_string(),
+        ))
     }
 }
@@ -32,7 +32,7 @@ impl Orchestrator {
         default_model: Model,
         js_tool_registry: Arc<JSToolRegistry>,
     ) -> BackendResult<Self> {
-        let llm_client = LLMClient::new(api_base.clone(), api_key.clone()).map_err(|e| {
+        let llm_client = LLMClient::new().map_err(|e| {
             BackendError::GenericError(
                 format!("failed to create new llm client: {:?}", e).to_string(),
             )
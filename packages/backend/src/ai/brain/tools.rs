@@ -72,7 +72,7 @@ impl Tool for WebSearchAgentTool {
             user_message: args.query,
             execution_id,
             model,
-            custom_key: custom_key,
+            custom_key,
             system_message_preamble: Some(current_time_prompt()),
             allowed_tools: None,
         };
@@ -0,0 +1,537 @@
+use crate::{
+    ai::youtube::extract_youtube_video_id,
+    store::models::{
+        AIChatSessionHistory, AIChatSessionMessage, AIChatSessionMessageSource, ResourceMetadata,
+    },
+    BackendError, BackendResult,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatExportFormat {
+    Markdown,
+    // a single html file with inline styles and no external assets
+    Html,
+    Json,
+}
+
+/// A source cited in an exported chat, numbered in the order it is first cited.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatExportFootnote {
+    pub number: usize,
+    pub resource_id: String,
+    pub title: String,
+    pub source_uri: Option<String>,
+    // the source uri pointing at the cited page or timestamp
+    pub link: Option<String>,
+    pub page: Option<u32>,
+    pub timestamp: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Footnote(usize),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatExportMessage {
+    pub id: String,
+    pub role: String,
+    pub msg_type: String,
+    // the text with citations replaced by `[n]` references to the footnotes
+    pub content: String,
+    pub footnotes: Vec<usize>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(skip)]
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatExport {
+    pub id: String,
+    pub title: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub messages: Vec<ChatExportMessage>,
+    pub footnotes: Vec<ChatExportFootnote>,
+}
+
+impl ChatExport {
+    /// `resources` holds the metadata of the resources cited in `chat`, sources of
+    /// other resources are still listed but without a title.
+    pub fn new(chat: &AIChatSessionHistory, resources: &HashMap<String, ResourceMetadata>) -> Self {
+        let citation_re = regex::Regex::new(r"(?s)<citation[^>]*>(.*?)</citation>").unwrap();
+        let mut footnotes: Vec<ChatExportFootnote> = vec![];
+        let mut messages = vec![];
+
+        for msg in chat.messages.iter() {
+            let content = match msg.role.as_str() {
+                "assistant" => msg.content.replace("<answer>", "").replace("</answer>", ""),
+                _ => msg.content.clone(),
+            };
+            let content = content.trim();
+
+            let mut segments = vec![];
+            let mut last = 0;
+            for captures in citation_re.captures_iter(content) {
+                let whole = captures.get(0).unwrap();
+                if whole.start() > last {
+                    segments.push(Segment::Text(content[last..whole.start()].to_string()));
+                }
+                last = whole.end();
+
+                // image citations and ids of sources that weren't saved are dropped
+                let source = match find_source(msg, captures[1].trim()) {
+                    Some(source) => source,
+                    None => continue,
+                };
+                let number = footnote_number(&mut footnotes, source, resources);
+                if segments.last() != Some(&Segment::Footnote(number)) {
+                    segments.push(Segment::Footnote(number));
+                }
+            }
+            if last < content.len() {
+                segments.push(Segment::Text(content[last..].to_string()));
+            }
+
+            let mut message_footnotes = vec![];
+            let mut text = String::new();
+            for segment in segments.iter() {
+                match segment {
+                    Segment::Text(t) => text.push_str(t),
+                    Segment::Footnote(n) => {
+                        text.push_str(&format!("[{}]", n));
+                        if !message_footnotes.contains(n) {
+                            message_footnotes.push(*n);
+                        }
+                    }
+                }
+            }
+
+            messages.push(ChatExportMessage {
+                id: msg.id.clone(),
+                role: msg.role.clone(),
+                msg_type: msg.msg_type.clone(),
+                content: text,
+                footnotes: message_footnotes,
+                created_at: msg.created_at,
+                segments,
+            });
+        }
+
+        ChatExport {
+            id: chat.id.clone(),
+            title: chat.title.clone(),
+            created_at: chat.created_at,
+            updated_at: chat.updated_at,
+            messages,
+            footnotes,
+        }
+    }
+
+    pub fn render(&self, format: ChatExportFormat) -> BackendResult<String> {
+        match format {
+            ChatExportFormat::Markdown => Ok(self.to_markdown()),
+            ChatExportFormat::Html => Ok(self.to_html()),
+            ChatExportFormat::Json => serde_json::to_string_pretty(self).map_err(|e| {
+                BackendError::GenericError(format!("failed to serialize chat export: {}", e))
+            }),
+        }
+    }
+
+    fn display_title(&self) -> &str {
+        match self.title.trim() {
+            "" => "Untitled chat",
+            title => title,
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut out = format!(
+            "# {}\n\n_{}_\n",
+            self.display_title(),
+            self.created_at.format("%Y-%m-%d %H:%M UTC")
+        );
+        for msg in self.messages.iter() {
+            out.push_str(&format!("\n**{}**\n\n", role_label(&msg.role)));
+            if msg.msg_type == "image" {
+                out.push_str(&format!("![image]({})\n", msg.content));
+                continue;
+            }
+            for segment in msg.segments.iter() {
+                match segment {
+                    Segment::Text(t) => out.push_str(t),
+                    Segment::Footnote(n) => out.push_str(&format!("[^{}]", n)),
+                }
+            }
+            out.push('\n');
+        }
+
+        if !self.footnotes.is_empty() {
+            out.push('\n');
+        }
+        for footnote in self.footnotes.iter() {
+            let title = escape_markdown_link_text(&footnote.title);
+            let source = match &footnote.link {
+                Some(link) => format!("[{}]({})", title, link.replace(' ', "%20")),
+                None => title,
+            };
+            out.push_str(&format!("[^{}]: {}", footnote.number, source));
+            if let Some(location) = location_label(footnote) {
+                out.push_str(&format!(", {}", location));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn to_html(&self) -> String {
+        let title = html_escape::encode_text(self.display_title());
+        let mut out = format!(
+            "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{}</title>
+<style>
+body {{ font-family: -apple-system, BlinkMacSystemFont, \"Segoe UI\", sans-serif; max-width: 760px; margin: 2rem auto; padding: 0 1rem; line-height: 1.5; color: #1f2328; }}
+.date {{ color: #656d76; }}
+.message {{ margin: 1.5rem 0; }}
+.role {{ font-weight: 600; margin-bottom: 0.25rem; }}
+.user .content {{ background: #f6f8fa; border-radius: 8px; padding: 0.5rem 0.75rem; }}
+.content {{ white-space: pre-wrap; }}
+.content img {{ max-width: 100%; }}
+.footnotes {{ border-top: 1px solid #d0d7de; margin-top: 2rem; padding-top: 1rem; font-size: 0.9rem; }}
+</style>
+</head>
+<body>
+<h1>{}</h1>
+<p class=\"date\">{}</p>
+",
+            title,
+            title,
+            self.created_at.format("%Y-%m-%d %H:%M UTC")
+        );
+
+        for msg in self.messages.iter() {
+            out.push_str(&format!(
+                "<section class=\"message {}\">\n<div class=\"role\">{}</div>\n<div class=\"content\">",
+                html_escape::encode_double_quoted_attribute(&msg.role),
+                role_label(&msg.role)
+            ));
+            if msg.msg_type == "image" {
+                out.push_str(&format!(
+                    "<img src=\"{}\" alt=\"image\">",
+                    html_escape::encode_double_quoted_attribute(&msg.content)
+                ));
+                out.push_str("</div>\n</section>\n");
+                continue;
+            }
+            for segment in msg.segments.iter() {
+                match segment {
+                    Segment::Text(t) => out.push_str(&html_escape::encode_text(t)),
+                    Segment::Footnote(n) => {
+                        out.push_str(&format!("<sup><a href=\"#fn-{}\">[{}]</a></sup>", n, n))
+                    }
+                }
+            }
+            out.push_str("</div>\n</section>\n");
+        }
+
+        if !self.footnotes.is_empty() {
+            out.push_str("<ol class=\"footnotes\">\n");
+            for footnote in self.footnotes.iter() {
+                let title = html_escape::encode_text(&footnote.title);
+                let source = match &footnote.link {
+                    Some(link) => format!(
+                        "<a href=\"{}\">{}</a>",
+                        html_escape::encode_double_quoted_attribute(link),
+                        title
+                    ),
+                    None => title.to_string(),
+                };
+                let location = location_label(footnote)
+                    .map(|location| format!(", {}", location))
+                    .unwrap_or_default();
+                out.push_str(&format!(
+                    "<li id=\"fn-{}\">{}{}</li>\n",
+                    footnote.number, source, location
+                ));
+            }
+            out.push_str("</ol>\n");
+        }
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+}
+
+// the model cites the context id of a source, older answers may use its uid
+fn find_source<'a>(
+    msg: &'a AIChatSessionMessage,
+    context_id: &str,
+) -> Option<&'a AIChatSessionMessageSource> {
+    if context_id.is_empty() {
+        return None;
+    }
+    msg.sources.as_ref().and_then(|sources| {
+        sources
+            .iter()
+            .find(|source| source.id == context_id)
+            .or_else(|| sources.iter().find(|source| source.uid == context_id))
+    })
+}
+
+// citations of the same page or timestamp of a resource share a footnote
+fn footnote_number(
+    footnotes: &mut Vec<ChatExportFootnote>,
+    source: &AIChatSessionMessageSource,
+    resources: &HashMap<String, ResourceMetadata>,
+) -> usize {
+    let metadata = source.metadata.as_ref();
+    let page = metadata.and_then(|m| m.page);
+    let timestamp = metadata.and_then(|m| m.timestamp);
+    if let Some(footnote) = footnotes.iter().find(|footnote| {
+        footnote.resource_id == source.resource_id
+            && footnote.page == page
+            && footnote.timestamp == timestamp
+    }) {
+        return footnote.number;
+    }
+
+    let resource = resources.get(&source.resource_id);
+    let source_uri = metadata
+        .and_then(|m| m.url.clone())
+        .or_else(|| resource.map(|r| r.source_uri.clone()))
+        .filter(|uri| !uri.is_empty());
+    let title = resource
+        .map(|r| r.name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .or_else(|| source_uri.clone())
+        .unwrap_or_else(|| source.resource_id.clone());
+
+    let number = footnotes.len() + 1;
+    footnotes.push(ChatExportFootnote {
+        number,
+        resource_id: source.resource_id.clone(),
+        title,
+        link: source_uri
+            .as_deref()
+            .map(|uri| deep_link(uri, page, timestamp)),
+        source_uri,
+        page,
+        timestamp,
+    });
+    number
+}
+
+/// Links to the cited part of a source: `#page=` opens pdfs at the page, youtube
+/// takes a `t` parameter and other media a `#t=` fragment.
+pub fn deep_link(uri: &str, page: Option<u32>, timestamp: Option<f32>) -> String {
+    let base = uri.split('#').next().unwrap_or(uri);
+    if let Some(timestamp) = timestamp {
+        let seconds = timestamp.max(0.0) as u64;
+        if extract_youtube_video_id(base).is_some() {
+            let separator = if base.contains('?') { '&' } else { '?' };
+            return format!("{}{}t={}s", base, separator, seconds);
+        }
+        return format!("{}#t={}", base, seconds);
+    }
+    match page {
+        Some(page) => format!("{}#page={}", base, page),
+        None => uri.to_string(),
+    }
+}
+
+fn location_label(footnote: &ChatExportFootnote) -> Option<String> {
+    if let Some(timestamp) = footnote.timestamp {
+        let seconds = timestamp.max(0.0) as u64;
+        let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+        return Some(match hours {
+            0 => format!("at {}:{:02}", minutes, seconds),
+            _ => format!("at {}:{:02}:{:02}", hours, minutes, seconds),
+        });
+    }
+    footnote.page.map(|page| format!("page {}", page))
+}
+
+fn role_label(role: &str) -> &str {
+    match role {
+        "user" => "You",
+        "assistant" => "Assistant",
+        "system" => "System",
+        _ => role,
+    }
+}
+
+fn escape_markdown_link_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::models::{AIChatSessionConfig, AIChatSessionMessageSourceMetadata};
+
+    fn source(
+        id: &str,
+        resource_id: &str,
+        page: Option<u32>,
+        timestamp: Option<f32>,
+    ) -> AIChatSessionMessageSource {
+        AIChatSessionMessageSource {
+            id: id.to_string(),
+            uid: format!("uid-{}", id),
+            resource_id: resource_id.to_string(),
+            metadata: Some(AIChatSessionMessageSourceMetadata {
+                timestamp,
+                url: None,
+                page,
+                duration: None,
+                heading_path: None,
+            }),
+            quotes: vec![],
+            chunk_uids: vec![],
+        }
+    }
+
+    fn message(
+        role: &str,
+        content: &str,
+        sources: Option<Vec<AIChatSessionMessageSource>>,
+    ) -> AIChatSessionMessage {
+        AIChatSessionMessage {
+            id: format!("{}-{}", role, content.len()),
+            parent_id: None,
+            ai_session_id: "session".to_string(),
+            role: role.to_string(),
+            content: content.to_string(),
+            truncatable: false,
+            is_context: false,
+            msg_type: "text".to_string(),
+            sources,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn metadata(resource_id: &str, name: &str, source_uri: &str) -> (String, ResourceMetadata) {
+        (
+            resource_id.to_string(),
+            ResourceMetadata {
+                id: format!("meta-{}", resource_id),
+                resource_id: resource_id.to_string(),
+                name: name.to_string(),
+                source_uri: source_uri.to_string(),
+                alt: "".to_string(),
+                user_context: "".to_string(),
+            },
+        )
+    }
+
+    fn export() -> ChatExport {
+        let chat = AIChatSessionHistory {
+            id: "session".to_string(),
+            system_prompt: "".to_string(),
+            title: "Pricing research".to_string(),
+            tags: vec![],
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+            summary: "".to_string(),
+            active_message_id: None,
+            config: AIChatSessionConfig::default(),
+            messages: vec![
+                message("user", "what do they charge?", None),
+                message(
+                    "assistant",
+                    "<answer>Plans start at $10 <citation>1</citation><citation>3</citation>. The talk covers discounts <citation>2</citation>, as does the report <citation>uid-3</citation><citation type=\"image\"></citation>.</answer>",
+                    Some(vec![
+                        source("1", "report", Some(3), None),
+                        source("2", "talk", None, Some(3725.0)),
+                        source("3", "report", Some(3), None),
+                    ]),
+                ),
+            ],
+        };
+        let resources = vec![
+            metadata(
+                "report",
+                "Annual [Pricing] Report",
+                "https://example.com/report.pdf",
+            ),
+            metadata("talk", "", "https://www.youtube.com/watch?v=abc"),
+        ]
+        .into_iter()
+        .collect();
+        ChatExport::new(&chat, &resources)
+    }
+
+    #[test]
+    fn test_chat_export_footnotes() {
+        let export = export();
+        // the same page of a resource is a single footnote
+        assert_eq!(export.footnotes.len(), 2);
+        assert_eq!(export.footnotes[0].title, "Annual [Pricing] Report");
+        assert_eq!(
+            export.footnotes[0].link.as_deref(),
+            Some("https://example.com/report.pdf#page=3")
+        );
+        // untitled resources fall back to their url
+        assert_eq!(
+            export.footnotes[1].title,
+            "https://www.youtube.com/watch?v=abc"
+        );
+        assert_eq!(
+            export.footnotes[1].link.as_deref(),
+            Some("https://www.youtube.com/watch?v=abc&t=3725s")
+        );
+
+        let answer = &export.messages[1];
+        assert_eq!(
+            answer.content,
+            "Plans start at $10 [1]. The talk covers discounts [2], as does the report [1]."
+        );
+        assert_eq!(answer.footnotes, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_chat_export_formats() {
+        let export = export();
+        let markdown = export.render(ChatExportFormat::Markdown).unwrap();
+        assert!(markdown.starts_with("# Pricing research\n"));
+        assert!(markdown.contains("Plans start at $10 [^1]. The talk covers discounts [^2]"));
+        assert!(markdown.contains(
+            "[^1]: [Annual \\[Pricing\\] Report](https://example.com/report.pdf#page=3), page 3\n"
+        ));
+        assert!(markdown.contains("[^2]: [https://www.youtube.com/watch?v=abc](https://www.youtube.com/watch?v=abc&t=3725s), at 1:02:05\n"));
+
+        let html = export.render(ChatExportFormat::Html).unwrap();
+        assert!(html.contains("<sup><a href=\"#fn-2\">[2]</a></sup>"));
+        assert!(html.contains("<li id=\"fn-1\"><a href=\"https://example.com/report.pdf#page=3\">"));
+        assert!(!html.contains("<citation"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&export.render(ChatExportFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["footnotes"][1]["timestamp"], 3725.0);
+        assert_eq!(json["messages"][1]["footnotes"], serde_json::json!([1, 2]));
+    }
+
+    #[test]
+    fn test_deep_link() {
+        assert_eq!(
+            deep_link("https://youtu.be/abc", None, Some(61.5)),
+            "https://youtu.be/abc?t=61s"
+        );
+        assert_eq!(
+            deep_link("https://example.com/talk.mp4#intro", None, Some(90.0)),
+            "https://example.com/talk.mp4#t=90"
+        );
+        assert_eq!(
+            deep_link("https://example.com/page#section", None, None),
+            "https://example.com/page#section"
+        );
+    }
+}
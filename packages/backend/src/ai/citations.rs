@@ -0,0 +1,291 @@
+use crate::ai::context_assembler::PASSAGE_CHUNK_SEPARATOR;
+use crate::store::models::{AIChatSessionMessageSource, AIChatSessionMessageSourceQuote};
+use std::collections::HashMap;
+
+// share of the quoted words that have to be found close together in the source
+// for a quote to count as verified
+pub const QUOTE_MATCH_THRESHOLD: f32 = 0.8;
+
+/// Where a quote was found in a text, offsets are in characters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuoteSpan {
+    pub start: usize,
+    pub end: usize,
+    // share of the quoted words found in the span
+    pub score: f32,
+}
+
+#[derive(Debug)]
+struct Word {
+    text: String,
+    start: usize,
+    end: usize,
+}
+
+// lowercased runs of alphanumeric characters with their character offsets,
+// punctuation, casing and whitespace don't matter when matching quotes
+fn words(text: &str) -> Vec<Word> {
+    let mut words = vec![];
+    let mut current: Option<Word> = None;
+    for (i, c) in text.chars().enumerate() {
+        if c.is_alphanumeric() {
+            let word = current.get_or_insert_with(|| Word {
+                text: String::new(),
+                start: i,
+                end: i,
+            });
+            word.text.extend(c.to_lowercase());
+            word.end = i + 1;
+        } else if let Some(word) = current.take() {
+            words.push(word);
+        }
+    }
+    words.extend(current);
+    words
+}
+
+/// Finds the passage of `text` that best matches `quote`.
+///
+/// Every window of `text` as long as the quote is scored by how many of the
+/// quoted words it contains, so a quote with a few words left out, changed or
+/// reordered is still found. Returns `None` if no quoted word is in `text`.
+pub fn locate_quote(text: &str, quote: &str) -> Option<QuoteSpan> {
+    let needle = words(quote);
+    let haystack = words(text);
+    if needle.is_empty() || haystack.is_empty() {
+        return None;
+    }
+    let mut wanted: HashMap<&str, usize> = HashMap::new();
+    for word in needle.iter() {
+        *wanted.entry(word.text.as_str()).or_default() += 1;
+    }
+
+    let width = needle.len().min(haystack.len());
+    let mut best: Option<(usize, &[Word])> = None;
+    for window in haystack.windows(width) {
+        let mut remaining = wanted.clone();
+        let mut matched = 0;
+        for word in window {
+            if let Some(count) = remaining.get_mut(word.text.as_str()) {
+                if *count > 0 {
+                    *count -= 1;
+                    matched += 1;
+                }
+            }
+        }
+        if best.is_none_or(|(best_matched, _)| matched > best_matched) {
+            best = Some((matched, window));
+        }
+    }
+
+    let (matched, window) = best.filter(|(matched, _)| *matched > 0)?;
+    // the window can start or end with words that aren't part of the quote
+    let is_quoted = |word: &&Word| wanted.contains_key(word.text.as_str());
+    let first = window.iter().find(is_quoted)?;
+    let last = window.iter().rev().find(is_quoted)?;
+    Some(QuoteSpan {
+        start: first.start,
+        end: last.end,
+        score: matched as f32 / needle.len() as f32,
+    })
+}
+
+/// Checks that `quote` is in the cited `content`, only verified quotes get offsets.
+pub fn verify_quote(content: &str, quote: &str) -> AIChatSessionMessageSourceQuote {
+    match locate_quote(content, quote).filter(|span| span.score >= QUOTE_MATCH_THRESHOLD) {
+        Some(span) => AIChatSessionMessageSourceQuote {
+            text: quote.to_string(),
+            start: Some(span.start),
+            end: Some(span.end),
+            uid: None,
+            verified: true,
+        },
+        None => AIChatSessionMessageSourceQuote {
+            text: quote.to_string(),
+            start: None,
+            end: None,
+            uid: None,
+            verified: false,
+        },
+    }
+}
+
+/// Checks that `quote` is in the passage made of `chunks`, given as text
+/// content ids and contents in document order.
+///
+/// The quote is looked up in the whole passage, so a quote that runs over
+/// into the next chunk is still found. Its offsets are mapped back to the
+/// chunk it starts in and end with that chunk.
+pub fn verify_passage_quote(
+    chunks: &[(String, String)],
+    quote: &str,
+) -> AIChatSessionMessageSourceQuote {
+    let passage = chunks
+        .iter()
+        .map(|(_, content)| content.as_str())
+        .collect::<Vec<_>>()
+        .join(PASSAGE_CHUNK_SEPARATOR);
+    let mut verified = verify_quote(&passage, quote);
+    let start = match verified.start {
+        Some(start) => start,
+        None => return verified,
+    };
+
+    let separator_len = PASSAGE_CHUNK_SEPARATOR.chars().count();
+    let mut offset = 0;
+    for (uid, content) in chunks {
+        let len = content.chars().count();
+        // quotes start with a word, never in a separator
+        if start < offset + len {
+            verified.start = Some(start - offset);
+            verified.end = verified.end.map(|end| (end - offset).min(len));
+            verified.uid = Some(uid.clone());
+            break;
+        }
+        offset += len + separator_len;
+    }
+    verified
+}
+
+/// The context ids and quotes of the `<citation quote="...">id</citation>` tags
+/// of an answer, citations without a quote are skipped.
+pub fn parse_quoted_citations(answer: &str) -> Vec<(String, String)> {
+    let citation_re =
+        regex::Regex::new(r#"(?s)<citation\b[^>]*?\bquote="([^"]*)"[^>]*>(.*?)</citation>"#)
+            .unwrap();
+    citation_re
+        .captures_iter(answer)
+        .filter_map(|captures| {
+            let quote = html_escape::decode_html_entities(&captures[1])
+                .trim()
+                .to_string();
+            let id = captures[2].trim().to_string();
+            match quote.is_empty() || id.is_empty() {
+                true => None,
+                false => Some((id, quote)),
+            }
+        })
+        .collect()
+}
+
+/// Adds the quotes of `answer` to the sources they cite, `chunks` gives the ids
+/// and contents of the text contents of a source in document order.
+pub fn attach_quotes<F>(answer: &str, sources: &mut [AIChatSessionMessageSource], mut chunks: F)
+where
+    F: FnMut(&AIChatSessionMessageSource) -> Option<Vec<(String, String)>>,
+{
+    let mut contents: HashMap<String, Option<Vec<(String, String)>>> = HashMap::new();
+    for (id, quote) in parse_quoted_citations(answer) {
+        let source = match sources.iter_mut().find(|source| source.id == id) {
+            Some(source) => source,
+            None => continue,
+        };
+        if source.quotes.iter().any(|q| q.text == quote) {
+            continue;
+        }
+        let passage = contents
+            .entry(source.uid.clone())
+            .or_insert_with(|| chunks(source));
+        let verified = match passage {
+            Some(passage) => verify_passage_quote(passage, &quote),
+            None => verify_quote("", &quote),
+        };
+        source.quotes.push(verified);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &str = "Preisübersicht – The Pro plan costs $12 per month, billed annually.\nTeams get a 20% discount on more than ten seats.";
+
+    fn source(id: &str) -> AIChatSessionMessageSource {
+        AIChatSessionMessageSource {
+            id: id.to_string(),
+            uid: format!("uid-{}", id),
+            resource_id: "resource".to_string(),
+            metadata: None,
+            quotes: vec![],
+            chunk_uids: vec![],
+        }
+    }
+
+    #[test]
+    fn test_locate_quote() {
+        let quote = "the Pro plan costs $12 per month";
+        let span = locate_quote(CONTENT, quote).unwrap();
+        assert_eq!(span.score, 1.0);
+        let found = CONTENT
+            .chars()
+            .skip(span.start)
+            .take(span.end - span.start)
+            .collect::<String>();
+        assert_eq!(found, "The Pro plan costs $12 per month");
+
+        // a word left out and different punctuation
+        let span = locate_quote(CONTENT, "teams get 20 % discount on more than ten seats").unwrap();
+        assert!(span.score >= QUOTE_MATCH_THRESHOLD);
+
+        assert_eq!(locate_quote(CONTENT, "..."), None);
+        assert_eq!(locate_quote(CONTENT, "unrelated words only"), None);
+    }
+
+    #[test]
+    fn test_verify_quote() {
+        let quote = verify_quote(CONTENT, "billed annually");
+        assert!(quote.verified);
+        // offsets count characters, not bytes
+        let start = CONTENT[..CONTENT.find("billed").unwrap()].chars().count();
+        assert_eq!(quote.start, Some(start));
+        assert_eq!(quote.end, Some(start + "billed annually".len()));
+
+        // a made up quote that shares a few words with the source
+        let quote = verify_quote(CONTENT, "the Pro plan is free for students");
+        assert!(!quote.verified);
+        assert_eq!(quote.start, None);
+    }
+
+    #[test]
+    fn test_attach_quotes() {
+        let answer = r#"<answer>It costs $12 <citation quote="Pro plan costs $12 per month">1</citation>, teams save half <citation quote="teams get a 50% discount on every seat">1</citation> <citation>2</citation> <citation quote="missing source">7</citation></answer>"#;
+        let mut sources = vec![source("1"), source("2")];
+        let mut lookups = 0;
+        attach_quotes(answer, &mut sources, |source| {
+            lookups += 1;
+            Some(vec![(source.uid.clone(), CONTENT.to_string())])
+        });
+
+        assert_eq!(lookups, 1);
+        assert_eq!(sources[0].quotes.len(), 2);
+        assert!(sources[0].quotes[0].verified);
+        assert_eq!(sources[0].quotes[0].uid.as_deref(), Some("uid-1"));
+        assert!(!sources[0].quotes[1].verified);
+        assert!(sources[1].quotes.is_empty());
+    }
+
+    #[test]
+    fn test_passage_quote_offsets_are_per_chunk() {
+        let chunks = vec![
+            ("first".to_string(), "Pricing of the plans.".to_string()),
+            ("second".to_string(), CONTENT.to_string()),
+            ("third".to_string(), "Cancel at any time.".to_string()),
+        ];
+        let quote = verify_passage_quote(&chunks, "billed annually");
+        assert!(quote.verified);
+        assert_eq!(quote.uid.as_deref(), Some("second"));
+        let start = CONTENT[..CONTENT.find("billed").unwrap()].chars().count();
+        assert_eq!(quote.start, Some(start));
+        assert_eq!(quote.end, Some(start + "billed annually".len()));
+
+        // a quote running over into the next chunk ends with the chunk it starts in
+        let quote = verify_passage_quote(&chunks, "more than ten seats. Cancel at any time");
+        assert!(quote.verified);
+        assert_eq!(quote.uid.as_deref(), Some("second"));
+        assert_eq!(quote.end, Some(CONTENT.chars().count()));
+
+        let quote = verify_passage_quote(&chunks, "the plan is free for students");
+        assert!(!quote.verified);
+        assert_eq!(quote.uid, None);
+    }
+}
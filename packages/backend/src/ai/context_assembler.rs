@@ -0,0 +1,431 @@
+use crate::ai::llm::client::{tokens::Tokenizer, TokenModel};
+use crate::store::models::{CompositeResource, ResourceTextContent};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// share of the model's context window the context documents may take, and the
+// share of that a single resource may take when several are searched
+pub const CONTEXT_TOKEN_SHARE: f32 = 0.4;
+pub const CONTEXT_RESOURCE_SHARE: f32 = 0.4;
+
+// chunks before and after a hit that are added around it
+pub const CONTEXT_NEIGHBOUR_CHUNKS: usize = 1;
+
+// hits of a single resource used at most, so a long document that matches
+// everywhere doesn't crowd out the others
+pub const CONTEXT_MAX_HITS_PER_RESOURCE: usize = 4;
+
+/// Joins the chunks of a passage.
+pub const PASSAGE_CHUNK_SEPARATOR: &str = "\n\n";
+
+#[derive(Debug, Clone)]
+pub struct ContextBudget {
+    pub total_tokens: usize,
+    // ignored if all hits are from the same resource
+    pub resource_tokens: usize,
+    pub neighbour_chunks: usize,
+    pub max_hits_per_resource: usize,
+}
+
+impl ContextBudget {
+    pub fn for_model(model: &impl TokenModel) -> Self {
+        let total_tokens = (model.max_tokens() as f32 * CONTEXT_TOKEN_SHARE) as usize;
+        Self {
+            total_tokens,
+            resource_tokens: (total_tokens as f32 * CONTEXT_RESOURCE_SHARE) as usize,
+            neighbour_chunks: CONTEXT_NEIGHBOUR_CHUNKS,
+            max_hits_per_resource: CONTEXT_MAX_HITS_PER_RESOURCE,
+        }
+    }
+}
+
+/// Adjacent chunks of a resource sent as a single context document.
+#[derive(Debug, Clone)]
+pub struct ContextPassage {
+    // the best hit of the passage, its text content is the hit chunk
+    pub hit: CompositeResource,
+    // ids of the chunks of the passage in document order
+    pub chunk_ids: Vec<String>,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    // the resource used up its share of the budget
+    ResourceBudget,
+    // the context used up the whole budget
+    TotalBudget,
+    // the resource already has the maximum number of hits
+    ResourceQuota,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ResourceBudget => "resource_budget",
+            Self::TotalBudget => "total_budget",
+            Self::ResourceQuota => "resource_quota",
+        }
+    }
+}
+
+/// A search hit left out of the context.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DroppedContext {
+    pub resource_id: String,
+    pub uid: String,
+    pub reason: DropReason,
+}
+
+#[derive(Debug, Default)]
+pub struct AssembledContext {
+    pub passages: Vec<ContextPassage>,
+    pub dropped: Vec<DroppedContext>,
+}
+
+struct ResourceHits {
+    hits: Vec<CompositeResource>,
+    // chunks of the resource, neighbouring chunks have consecutive keys
+    chunks: BTreeMap<usize, ResourceTextContent>,
+    selected: BTreeSet<usize>,
+    // positions of the selected hits with their index in `hits`
+    hit_positions: Vec<(usize, usize)>,
+    tokens: usize,
+}
+
+impl ResourceHits {
+    fn position(&mut self, hit: &ResourceTextContent) -> usize {
+        match self.chunks.iter().find(|(_, chunk)| chunk.id == hit.id) {
+            Some((position, _)) => *position,
+            // a chunk that isn't stored, it goes after a gap so it has no
+            // neighbours
+            None => {
+                let position = self.chunks.keys().last().map_or(0, |last| last + 2);
+                self.chunks.insert(position, hit.clone());
+                position
+            }
+        }
+    }
+}
+
+struct Budget<'a> {
+    resource_tokens: usize,
+    total_tokens: usize,
+    used_tokens: usize,
+    tokenizer: &'a dyn Tokenizer,
+}
+
+impl Budget<'_> {
+    // adds the chunk at `position` to the context if it fits
+    fn select(&mut self, resource: &mut ResourceHits, position: usize) -> Result<(), DropReason> {
+        if resource.selected.contains(&position) {
+            return Ok(());
+        }
+        let cost = self
+            .tokenizer
+            .count_text(&resource.chunks[&position].content);
+        if resource.tokens + cost > self.resource_tokens {
+            return Err(DropReason::ResourceBudget);
+        }
+        if self.used_tokens + cost > self.total_tokens {
+            return Err(DropReason::TotalBudget);
+        }
+        resource.selected.insert(position);
+        resource.tokens += cost;
+        self.used_tokens += cost;
+        Ok(())
+    }
+}
+
+/// Packs search hits into context passages.
+///
+/// Hits are grouped by resource and grown by their neighbouring chunks,
+/// adjacent chunks end up in the same passage. Every resource gets its best
+/// hit in before any gets its next one, within the token budget of the
+/// resource and of the whole context. Passages are ordered by the best hit of
+/// their resource, then by their position in the resource.
+///
+/// `chunks` are the text contents of the hit resources, at least the hits and
+/// their neighbours, keyed so that neighbouring chunks have consecutive keys.
+pub fn assemble_context(
+    hits: Vec<CompositeResource>,
+    chunks: &HashMap<String, BTreeMap<usize, ResourceTextContent>>,
+    budget: &ContextBudget,
+    tokenizer: &dyn Tokenizer,
+) -> AssembledContext {
+    let mut resources: Vec<ResourceHits> = vec![];
+    let mut resource_index: HashMap<String, usize> = HashMap::new();
+    for hit in hits.into_iter().filter(|hit| hit.text_content.is_some()) {
+        let index = *resource_index
+            .entry(hit.resource.id.clone())
+            .or_insert_with(|| {
+                resources.push(ResourceHits {
+                    hits: vec![],
+                    chunks: chunks.get(&hit.resource.id).cloned().unwrap_or_default(),
+                    selected: BTreeSet::new(),
+                    hit_positions: vec![],
+                    tokens: 0,
+                });
+                resources.len() - 1
+            });
+        resources[index].hits.push(hit);
+    }
+
+    let mut tokens = Budget {
+        resource_tokens: match resources.len() {
+            1 => budget.total_tokens,
+            _ => budget.resource_tokens,
+        },
+        total_tokens: budget.total_tokens,
+        used_tokens: 0,
+        tokenizer,
+    };
+    let mut dropped = vec![];
+    let rounds = resources.iter().map(|r| r.hits.len()).max().unwrap_or(0);
+    for round in 0..rounds {
+        for resource in resources.iter_mut() {
+            let (resource_id, hit_chunk) = match resource.hits.get(round) {
+                Some(hit) => (hit.resource.id.clone(), hit.text_content.clone().unwrap()),
+                None => continue,
+            };
+            if round >= budget.max_hits_per_resource {
+                dropped.push(DroppedContext {
+                    resource_id,
+                    uid: hit_chunk.id,
+                    reason: DropReason::ResourceQuota,
+                });
+                continue;
+            }
+
+            let position = resource.position(&hit_chunk);
+            if let Err(reason) = tokens.select(resource, position) {
+                dropped.push(DroppedContext {
+                    resource_id,
+                    uid: hit_chunk.id,
+                    reason,
+                });
+                continue;
+            }
+            resource.hit_positions.push((position, round));
+
+            // grow the passage outwards, a side stops at the first neighbour that
+            // doesn't fit so passages stay contiguous
+            let before: Vec<usize> = (1..=budget.neighbour_chunks)
+                .map_while(|d| position.checked_sub(d))
+                .take_while(|p| resource.chunks.contains_key(p))
+                .collect();
+            let after: Vec<usize> = (1..=budget.neighbour_chunks)
+                .map(|d| position + d)
+                .take_while(|p| resource.chunks.contains_key(p))
+                .collect();
+            for side in [before, after] {
+                for neighbour in side {
+                    if tokens.select(resource, neighbour).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut passages = vec![];
+    for resource in resources.iter() {
+        let mut runs: Vec<Vec<usize>> = vec![];
+        for position in resource.selected.iter() {
+            match runs.last_mut() {
+                Some(run) if run.last() == Some(&(position - 1)) => run.push(*position),
+                _ => runs.push(vec![*position]),
+            }
+        }
+        for run in runs {
+            // hit positions are in rank order, so the first one in the run is
+            // the best hit of the passage
+            let hit = match resource
+                .hit_positions
+                .iter()
+                .find(|(position, _)| run.contains(position))
+            {
+                Some((_, rank)) => resource.hits[*rank].clone(),
+                None => continue,
+            };
+            passages.push(ContextPassage {
+                hit,
+                chunk_ids: run.iter().map(|p| resource.chunks[p].id.clone()).collect(),
+                content: run
+                    .iter()
+                    .map(|p| resource.chunks[p].content.as_str())
+                    .collect::<Vec<_>>()
+                    .join(PASSAGE_CHUNK_SEPARATOR),
+            });
+        }
+    }
+    AssembledContext { passages, dropped }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::models::{Resource, ResourceTextContentMetadata, ResourceTextContentType};
+
+    // a token per word
+    struct WordTokenizer;
+
+    impl Tokenizer for WordTokenizer {
+        fn count_text(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+
+        fn count_image(&self, _dimensions: Option<(u32, u32)>) -> usize {
+            0
+        }
+    }
+
+    fn chunk(resource_id: &str, position: usize) -> ResourceTextContent {
+        ResourceTextContent {
+            id: format!("{}{}", resource_id, position),
+            resource_id: resource_id.to_string(),
+            // five tokens
+            content: format!("{}{} x x x x", resource_id, position),
+            content_type: ResourceTextContentType::Document,
+            metadata: ResourceTextContentMetadata::default(),
+        }
+    }
+
+    fn hit(resource_id: &str, position: usize) -> CompositeResource {
+        CompositeResource {
+            resource: Resource {
+                id: resource_id.to_string(),
+                resource_path: String::new(),
+                resource_type: "application/pdf".to_string(),
+                created_at: chrono::Utc::now(),
+                updated_at: chrono::Utc::now(),
+                deleted: 0,
+            },
+            metadata: None,
+            text_content: Some(chunk(resource_id, position)),
+            resource_tags: None,
+            resource_annotations: None,
+            post_processing_job: None,
+            space_ids: None,
+        }
+    }
+
+    fn chunks(
+        resources: &[(&str, usize)],
+    ) -> HashMap<String, BTreeMap<usize, ResourceTextContent>> {
+        resources
+            .iter()
+            .map(|(id, count)| {
+                (
+                    id.to_string(),
+                    (0..*count).map(|i| (i, chunk(id, i))).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn budget(total_tokens: usize, resource_tokens: usize) -> ContextBudget {
+        ContextBudget {
+            total_tokens,
+            resource_tokens,
+            neighbour_chunks: 1,
+            max_hits_per_resource: 2,
+        }
+    }
+
+    #[test]
+    fn test_assemble_context_merges_neighbours() {
+        let hits = vec![hit("a", 3), hit("b", 1), hit("a", 0)];
+        let context = assemble_context(
+            hits,
+            &chunks(&[("a", 6), ("b", 3)]),
+            &budget(1000, 1000),
+            &WordTokenizer,
+        );
+
+        assert!(context.dropped.is_empty());
+        assert_eq!(context.passages.len(), 2);
+        // the neighbours of both hits touch, the passage is in document order
+        let passage = &context.passages[0];
+        assert_eq!(passage.chunk_ids, vec!["a0", "a1", "a2", "a3", "a4"]);
+        assert_eq!(passage.hit.text_content.as_ref().unwrap().id, "a3");
+        assert!(passage.content.starts_with("a0 x x x x\n\na1"));
+        assert_eq!(context.passages[1].chunk_ids, vec!["b0", "b1", "b2"]);
+    }
+
+    #[test]
+    fn test_assemble_context_budgets() {
+        let hits = vec![
+            hit("a", 3),
+            hit("b", 1),
+            hit("c", 0),
+            hit("a", 0),
+            hit("a", 5),
+        ];
+        let context = assemble_context(
+            hits,
+            &chunks(&[("a", 6), ("b", 3), ("c", 1)]),
+            &budget(30, 15),
+            &WordTokenizer,
+        );
+
+        let passages = context
+            .passages
+            .iter()
+            .map(|p| p.chunk_ids.join(","))
+            .collect::<Vec<_>>();
+        assert_eq!(passages, vec!["a2,a3,a4", "b0,b1,b2"]);
+        let dropped = context
+            .dropped
+            .iter()
+            .map(|d| (d.uid.as_str(), d.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dropped,
+            vec![
+                ("c0", DropReason::TotalBudget),
+                ("a0", DropReason::ResourceBudget),
+                ("a5", DropReason::ResourceQuota),
+            ]
+        );
+
+        // a single resource can use the whole budget
+        let context = assemble_context(
+            vec![hit("a", 0), hit("a", 4)],
+            &chunks(&[("a", 6)]),
+            &budget(30, 5),
+            &WordTokenizer,
+        );
+        assert!(context.dropped.is_empty());
+        assert_eq!(context.passages.len(), 2);
+    }
+
+    #[test]
+    fn test_assemble_context_sparse_chunks() {
+        // only the chunks around the hits are loaded
+        let mut loaded = chunks(&[("a", 10)]);
+        loaded
+            .get_mut("a")
+            .unwrap()
+            .retain(|position, _| matches!(position, 1..=3 | 7..=9));
+        let context = assemble_context(
+            vec![hit("a", 8), hit("a", 2), hit("a", 20)],
+            &loaded,
+            &ContextBudget {
+                max_hits_per_resource: 3,
+                ..budget(1000, 1000)
+            },
+            &WordTokenizer,
+        );
+
+        assert!(context.dropped.is_empty());
+        let passages = context
+            .passages
+            .iter()
+            .map(|p| p.chunk_ids.join(","))
+            .collect::<Vec<_>>();
+        // the hit that isn't stored doesn't borrow the loaded chunks around it
+        assert_eq!(passages, vec!["a1,a2,a3", "a7,a8,a9", "a20"]);
+    }
+}
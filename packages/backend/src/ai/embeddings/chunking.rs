@@ -1,7 +1,9 @@
+use crate::store::models::ChunkMetadata;
 use html_escape::decode_html_entities;
 use unicode_normalization::UnicodeNormalization;
 use unicode_segmentation::UnicodeSegmentation;
 
+#[derive(Debug)]
 pub struct ContentChunker {
     max_chunk_size: usize,
     overlap_sentences: usize,
@@ -50,6 +52,234 @@ impl ContentChunker {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub content: String,
+    pub metadata: ChunkMetadata,
+}
+
+/// Splits a specific kind of input into chunks that keep track of their position
+/// in the source.
+pub trait ChunkingStrategy {
+    type Input: ?Sized;
+
+    fn chunk(&self, input: &Self::Input) -> Vec<Chunk>;
+}
+
+/// Splits markdown on headings, every chunk is prefixed with the path of headings
+/// it is nested under so it keeps its context once embedded on its own.
+///
+/// Text without any headings is chunked exactly like `ContentChunker::chunk`.
+#[derive(Debug)]
+pub struct MarkdownChunker {
+    max_chunk_size: usize,
+    overlap_sentences: usize,
+}
+
+impl MarkdownChunker {
+    pub fn new(max_chunk_size: usize, overlap_sentences: usize) -> Self {
+        MarkdownChunker {
+            max_chunk_size,
+            overlap_sentences,
+        }
+    }
+
+    // ATX headings only (`# Title`), at most 3 spaces of indentation
+    fn parse_heading(line: &str) -> Option<(usize, String)> {
+        let trimmed = line.trim_start_matches(' ');
+        if line.len() - trimmed.len() > 3 {
+            return None;
+        }
+        let level = trimmed.chars().take_while(|&ch| ch == '#').count();
+        if level == 0 || level > 6 {
+            return None;
+        }
+        let rest = &trimmed[level..];
+        if !rest.is_empty() && !rest.starts_with([' ', '\t']) {
+            return None;
+        }
+        let title = rest.trim().trim_end_matches('#').trim();
+        Some((level, ContentChunker::normalize(title)))
+    }
+
+    fn is_code_fence(line: &str) -> bool {
+        let trimmed = line.trim_start();
+        trimmed.starts_with("```") || trimmed.starts_with("~~~")
+    }
+
+    fn chunk_section(&self, headings: &[(usize, String)], text: &str, chunks: &mut Vec<Chunk>) {
+        let heading_path: Vec<String> = headings
+            .iter()
+            .map(|(_, title)| title.clone())
+            .filter(|title| !title.is_empty())
+            .collect();
+        let prefix = heading_path.join(" > ");
+        let max_chunk_size = match prefix.is_empty() {
+            true => self.max_chunk_size,
+            false => self.max_chunk_size.saturating_sub(prefix.len() + 1).max(1),
+        };
+
+        let chunker = ContentChunker::new(max_chunk_size, self.overlap_sentences);
+        for content in chunker.chunk(text) {
+            chunks.push(Chunk {
+                content: match prefix.is_empty() {
+                    true => content,
+                    false => format!("{}\n{}", prefix, content),
+                },
+                metadata: ChunkMetadata {
+                    heading_path: heading_path.clone(),
+                    ..Default::default()
+                },
+            });
+        }
+    }
+}
+
+impl ChunkingStrategy for MarkdownChunker {
+    type Input = str;
+
+    fn chunk(&self, input: &str) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let mut headings: Vec<(usize, String)> = Vec::new();
+        let mut section = String::new();
+        let mut in_code_block = false;
+
+        for line in input.split_inclusive('\n') {
+            if Self::is_code_fence(line) {
+                in_code_block = !in_code_block;
+            }
+            let heading = match in_code_block {
+                true => None,
+                false => Self::parse_heading(line),
+            };
+            match heading {
+                Some((level, title)) => {
+                    self.chunk_section(&headings, &section, &mut chunks);
+                    section.clear();
+                    headings.retain(|(l, _)| *l < level);
+                    headings.push((level, title));
+                }
+                None => section.push_str(line),
+            }
+        }
+        self.chunk_section(&headings, &section, &mut chunks);
+        chunks
+    }
+}
+
+pub struct Page {
+    pub number: u32,
+    pub text: String,
+}
+
+/// Chunks every page on its own so no chunk crosses a page boundary.
+#[derive(Debug)]
+pub struct PageChunker {
+    chunker: ContentChunker,
+}
+
+impl PageChunker {
+    pub fn new(max_chunk_size: usize, overlap_sentences: usize) -> Self {
+        PageChunker {
+            chunker: ContentChunker::new(max_chunk_size, overlap_sentences),
+        }
+    }
+}
+
+impl ChunkingStrategy for PageChunker {
+    type Input = [Page];
+
+    fn chunk(&self, input: &[Page]) -> Vec<Chunk> {
+        input
+            .iter()
+            .flat_map(|page| {
+                self.chunker
+                    .chunk(&page.text)
+                    .into_iter()
+                    .map(move |content| Chunk {
+                        content,
+                        metadata: ChunkMetadata {
+                            page: Some(page.number),
+                            ..Default::default()
+                        },
+                    })
+            })
+            .collect()
+    }
+}
+
+pub struct TranscriptPiece {
+    pub text: String,
+    pub start: f32,
+    pub duration: f32,
+}
+
+/// Groups transcript pieces into time windows of roughly `window` seconds.
+#[derive(Debug)]
+pub struct TranscriptChunker {
+    window: f32,
+    max_chunk_size: usize,
+}
+
+impl TranscriptChunker {
+    // window is the target length of a chunk in seconds, a chunk is closed early
+    // once its text would exceed max_chunk_size characters
+    pub fn new(window: f32, max_chunk_size: usize) -> Self {
+        TranscriptChunker {
+            window,
+            max_chunk_size,
+        }
+    }
+
+    fn window_chunk(text: &str, start: f32, end: f32) -> Chunk {
+        Chunk {
+            content: ContentChunker::normalize(text),
+            metadata: ChunkMetadata {
+                start: Some(start),
+                duration: Some((end - start).max(0.0)),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl ChunkingStrategy for TranscriptChunker {
+    type Input = [TranscriptPiece];
+
+    fn chunk(&self, input: &[TranscriptPiece]) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let mut text = String::new();
+        let mut start = 0.0;
+        let mut end = 0.0;
+
+        for piece in input {
+            let piece_text = piece.text.trim();
+            if piece_text.is_empty() {
+                continue;
+            }
+            if !text.is_empty()
+                && (piece.start - start >= self.window
+                    || text.len() + piece_text.len() + 1 > self.max_chunk_size)
+            {
+                chunks.push(Self::window_chunk(&text, start, end));
+                text.clear();
+            }
+            if text.is_empty() {
+                start = piece.start;
+                end = piece.start;
+            } else {
+                text.push(' ');
+            }
+            text.push_str(piece_text);
+            end = f32::max(end, piece.start + piece.duration);
+        }
+        if !text.is_empty() {
+            chunks.push(Self::window_chunk(&text, start, end));
+        }
+        chunks
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,4 +301,94 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_markdown_chunker_heading_path() {
+        let chunker = MarkdownChunker::new(2000, 1);
+        let content = "Intro text before any heading.\n\
+            # Running\n\
+            Running is fun.\n\
+            ## Endurance\n\
+            Endurance running needs oxygen.\n\
+            ```\n\
+            # not a heading\n\
+            ```\n\
+            # Swimming\n\
+            Swimming is wet.\n";
+
+        let chunks = chunker.chunk(content);
+        let paths: Vec<Vec<String>> = chunks
+            .iter()
+            .map(|chunk| chunk.metadata.heading_path.clone())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                vec![],
+                vec!["Running".to_string()],
+                vec!["Running".to_string(), "Endurance".to_string()],
+                vec!["Swimming".to_string()],
+            ]
+        );
+        assert_eq!(chunks[0].content, "Intro text before any heading.");
+        assert!(chunks[2]
+            .content
+            .starts_with("Running > Endurance\nEndurance running needs oxygen."));
+        assert!(chunks[2].content.contains("# not a heading"));
+    }
+
+    #[test]
+    fn test_markdown_chunker_without_headings_matches_sentence_chunker() {
+        let content = "First sentence here. Second sentence follows. A third one to finish.";
+        let markdown_chunks: Vec<String> = MarkdownChunker::new(30, 1)
+            .chunk(content)
+            .into_iter()
+            .map(|chunk| chunk.content)
+            .collect();
+        assert_eq!(markdown_chunks, ContentChunker::new(30, 1).chunk(content));
+    }
+
+    #[test]
+    fn test_page_chunker_keeps_page_boundaries() {
+        let chunker = PageChunker::new(2000, 1);
+        let pages = vec![
+            Page {
+                number: 1,
+                text: "The first page ends mid".to_string(),
+            },
+            Page {
+                number: 2,
+                text: "sentence on the second page.".to_string(),
+            },
+        ];
+
+        let chunks = chunker.chunk(&pages);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].metadata.page, Some(1));
+        assert_eq!(chunks[1].metadata.page, Some(2));
+        assert_eq!(chunks[1].content, "sentence on the second page.");
+    }
+
+    #[test]
+    fn test_transcript_chunker_time_windows() {
+        let chunker = TranscriptChunker::new(20.0, 2000);
+        let pieces: Vec<TranscriptPiece> = (0..6)
+            .map(|i| TranscriptPiece {
+                text: format!("piece {}", i),
+                start: i as f32 * 8.0,
+                duration: 8.0,
+            })
+            .collect();
+
+        let chunks = chunker.chunk(&pieces);
+        let windows: Vec<(Option<f32>, Option<f32>)> = chunks
+            .iter()
+            .map(|chunk| (chunk.metadata.start, chunk.metadata.duration))
+            .collect();
+        assert_eq!(
+            windows,
+            vec![(Some(0.0), Some(24.0)), (Some(24.0), Some(24.0))]
+        );
+        assert_eq!(chunks[0].content, "piece 0 piece 1 piece 2");
+    }
 }
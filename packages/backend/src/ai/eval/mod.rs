@@ -0,0 +1,302 @@
+//! Offline evaluation of retrieval quality. The fixture corpus of `corpus.json`
+//! is loaded into a temporary database and its golden questions are run through
+//! the vector, keyword and hybrid search. Recall@k and the mean reciprocal rank
+//! of the expected resources are checked against floors, so a retrieval change
+//! that makes results worse fails the tests.
+//!
+//! Embeddings come from the bag of words stub server: the scores measure the
+//! retrieval pipeline (key filtering, ranking, fusion and the joins back to
+//! resources), not the quality of the embedding model.
+use super::local::stub_server::stub_ai;
+use super::retrieval::RetrievalPlan;
+use super::AI;
+use crate::store::db::Database;
+use crate::store::models::{
+    current_time, random_uuid, EmbeddingResource, EmbeddingType, Resource, ResourceMetadata,
+    ResourceTextContent, ResourceTextContentMetadata, ResourceTextContentType,
+};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use tempfile::{tempdir, TempDir};
+
+const CORPUS: &str = include_str!("corpus.json");
+
+const RECALL_K: usize = 3;
+// chunks every search returns before they are grouped by resource
+const NUM_DOCS: usize = 100;
+
+// the scores of the golden set when they were last raised, lower them only
+// when a change knowingly trades retrieval quality for something else
+const VECTOR_FLOOR: Scores = Scores {
+    recall: 0.95,
+    mrr: 0.9,
+};
+const KEYWORD_FLOOR: Scores = Scores {
+    recall: 0.75,
+    mrr: 0.7,
+};
+const HYBRID_FLOOR: Scores = Scores {
+    recall: 0.95,
+    mrr: 0.95,
+};
+
+#[derive(Deserialize)]
+struct Corpus {
+    resources: Vec<FixtureResource>,
+    questions: Vec<GoldenQuestion>,
+}
+
+#[derive(Deserialize)]
+struct FixtureResource {
+    id: String,
+    name: String,
+    source_uri: String,
+    // every chunk is stored as its own text content with one embedding
+    chunks: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct GoldenQuestion {
+    question: String,
+    // what a user would type into the search bar, also the keyword of the
+    // retrieval plan in the hybrid search
+    keywords: String,
+    // ids of the resources that answer the question
+    expected: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Scores {
+    // mean recall@RECALL_K
+    recall: f32,
+    // mean reciprocal rank of the first expected resource
+    mrr: f32,
+}
+
+/// Share of the `expected` ids in the first `k` of `ranked`.
+fn recall_at_k(ranked: &[String], expected: &[String], k: usize) -> f32 {
+    if expected.is_empty() {
+        return 1.0;
+    }
+    let found = expected
+        .iter()
+        .filter(|id| ranked.iter().take(k).any(|ranked_id| ranked_id == *id))
+        .count();
+    found as f32 / expected.len() as f32
+}
+
+/// One over the rank of the first expected id in `ranked`, zero if none is found.
+fn reciprocal_rank(ranked: &[String], expected: &[String]) -> f32 {
+    ranked
+        .iter()
+        .position(|id| expected.contains(id))
+        .map_or(0.0, |position| 1.0 / (position + 1) as f32)
+}
+
+// resource ids in the order of their best ranked chunk
+fn ranked_resource_ids<'a>(ids: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    ids.filter(|id| seen.insert(id.as_str())).cloned().collect()
+}
+
+struct Harness {
+    ai: AI,
+    db: Database,
+    resource_ids: Vec<String>,
+    questions: Vec<GoldenQuestion>,
+    // keeps the database and the socket around for the lifetime of the harness
+    _dir: TempDir,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let dir = tempdir().unwrap();
+        let corpus: Corpus = serde_json::from_str(CORPUS).unwrap();
+        let mut db = Database::new(&dir.path().join("eval.db").to_string_lossy(), true).unwrap();
+
+        let mut texts: HashMap<u64, String> = HashMap::new();
+        let mut tx = db.begin().unwrap();
+        for fixture in corpus.resources.iter() {
+            Database::create_resource_tx(
+                &mut tx,
+                &Resource {
+                    id: fixture.id.clone(),
+                    resource_path: String::new(),
+                    resource_type: "application/vnd.space.article".to_string(),
+                    created_at: current_time(),
+                    updated_at: current_time(),
+                    deleted: 0,
+                },
+            )
+            .unwrap();
+            Database::create_resource_metadata_tx(
+                &mut tx,
+                &ResourceMetadata {
+                    id: random_uuid(),
+                    resource_id: fixture.id.clone(),
+                    name: fixture.name.clone(),
+                    source_uri: fixture.source_uri.clone(),
+                    alt: String::new(),
+                    user_context: String::new(),
+                },
+            )
+            .unwrap();
+            for chunk in fixture.chunks.iter() {
+                Database::create_resource_text_content_tx(
+                    &mut tx,
+                    &ResourceTextContent {
+                        id: random_uuid(),
+                        resource_id: fixture.id.clone(),
+                        content: chunk.clone(),
+                        content_type: ResourceTextContentType::Article,
+                        metadata: ResourceTextContentMetadata::default(),
+                    },
+                )
+                .unwrap();
+                let content_id = tx.last_insert_rowid();
+                let key = Database::create_embedding_resource_tx(
+                    &mut tx,
+                    &EmbeddingResource {
+                        rowid: None,
+                        content_id,
+                        resource_id: fixture.id.clone(),
+                        embedding_type: EmbeddingType::TextContent,
+                    },
+                )
+                .unwrap();
+                texts.insert(key as u64, chunk.clone());
+            }
+        }
+        tx.commit().unwrap();
+
+        let resource_ids: Vec<String> = corpus.resources.iter().map(|r| r.id.clone()).collect();
+        for question in corpus.questions.iter() {
+            for id in question.expected.iter() {
+                assert!(
+                    resource_ids.contains(id),
+                    "golden question {:?} expects unknown resource {}",
+                    question.question,
+                    id
+                );
+            }
+        }
+
+        let ai = stub_ai(dir.path(), &texts);
+        Self {
+            ai,
+            db,
+            resource_ids,
+            questions: corpus.questions,
+            _dir: dir,
+        }
+    }
+
+    fn vector_search(&self, question: &GoldenQuestion) -> Vec<String> {
+        let results = self
+            .ai
+            .vector_search(
+                &self.db,
+                question.question.clone(),
+                NUM_DOCS,
+                Some(self.resource_ids.clone()),
+                false,
+                None,
+            )
+            .unwrap();
+        ranked_resource_ids(results.iter().map(|result| &result.resource.id))
+    }
+
+    fn keyword_search(&self, question: &GoldenQuestion) -> Vec<String> {
+        let results = self
+            .db
+            .search_resources(&question.keywords, &None, false, Some(NUM_DOCS as i64))
+            .unwrap();
+        ranked_resource_ids(results.items.iter().map(|item| &item.resource.resource.id))
+    }
+
+    fn hybrid_search(&self, question: &GoldenQuestion) -> Vec<String> {
+        let plan = RetrievalPlan {
+            standalone_query: question.question.clone(),
+            queries: vec![],
+            keywords: vec![question.keywords.clone()],
+        };
+        let results = self
+            .ai
+            .hybrid_search(&self.db, &plan, &self.resource_ids, NUM_DOCS, None)
+            .unwrap();
+        ranked_resource_ids(results.iter().map(|result| &result.resource.id))
+    }
+
+    /// Runs every golden question through `search`, prints the scores and the
+    /// questions whose first result isn't an expected resource.
+    fn evaluate(
+        &self,
+        name: &str,
+        search: impl Fn(&Self, &GoldenQuestion) -> Vec<String>,
+    ) -> Scores {
+        let mut recall = 0.0;
+        let mut mrr = 0.0;
+        for question in self.questions.iter() {
+            let ranked = search(self, question);
+            let rank = reciprocal_rank(&ranked, &question.expected);
+            recall += recall_at_k(&ranked, &question.expected, RECALL_K);
+            mrr += rank;
+            if rank < 1.0 {
+                println!(
+                    "{}: {:?} expected {:?}, got {:?}",
+                    name,
+                    question.question,
+                    question.expected,
+                    ranked.iter().take(RECALL_K).collect::<Vec<_>>()
+                );
+            }
+        }
+        let scores = Scores {
+            recall: recall / self.questions.len() as f32,
+            mrr: mrr / self.questions.len() as f32,
+        };
+        println!(
+            "{}: recall@{} {:.3}, mrr {:.3}",
+            name, RECALL_K, scores.recall, scores.mrr
+        );
+        scores
+    }
+}
+
+fn assert_above_floor(name: &str, scores: Scores, floor: Scores) {
+    assert!(
+        scores.recall >= floor.recall && scores.mrr >= floor.mrr,
+        "{} search regressed: {:?} is below the floor {:?}",
+        name,
+        scores,
+        floor
+    );
+}
+
+#[test]
+fn test_retrieval_metrics() {
+    let ranked = ["a", "b", "c", "d"].map(String::from).to_vec();
+    let expected = ["c", "e"].map(String::from).to_vec();
+    assert_eq!(recall_at_k(&ranked, &expected, 3), 0.5);
+    assert_eq!(recall_at_k(&ranked, &expected, 2), 0.0);
+    assert_eq!(reciprocal_rank(&ranked, &expected), 1.0 / 3.0);
+    assert_eq!(reciprocal_rank(&ranked, &["e".to_string()]), 0.0);
+
+    let ids = ["a", "b", "a", "c", "b"].map(String::from);
+    assert_eq!(
+        ranked_resource_ids(ids.iter()),
+        ["a", "b", "c"].map(String::from).to_vec()
+    );
+}
+
+#[test]
+fn test_golden_set_retrieval() {
+    let harness = Harness::new();
+    let vector = harness.evaluate("vector", Harness::vector_search);
+    let keyword = harness.evaluate("keyword", Harness::keyword_search);
+    let hybrid = harness.evaluate("hybrid", Harness::hybrid_search);
+
+    assert_above_floor("vector", vector, VECTOR_FLOOR);
+    assert_above_floor("keyword", keyword, KEYWORD_FLOOR);
+    assert_above_floor("hybrid", hybrid, HYBRID_FLOOR);
+}
@@ -0,0 +1,338 @@
+use super::{ChatCompletionRequest, Model, Provider};
+use crate::{
+    ai::llm::models::Message, store::db::setup_connection_settings, BackendError, BackendResult,
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{sync::Mutex, time::Duration};
+
+#[derive(Debug, Clone)]
+pub struct ResponseCacheConfig {
+    /// How long a cached response is served.
+    pub ttl: Duration,
+    /// Upper bound for the size of all cached responses, least recently used
+    /// responses are evicted first.
+    pub max_size_bytes: u64,
+}
+
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(24 * 60 * 60),
+            max_size_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct ResponseCacheStats {
+    pub entries: u64,
+    pub size_bytes: u64,
+    // since the cache was created, shared by every worker using the database
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// everything that determines the answer of a deterministic completion, the
+// same model name may be another model at another provider or behind a proxy
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    model: String,
+    provider: &'a Provider,
+    base_url: Option<&'a str>,
+    messages: &'a [Message],
+    response_format: Option<&'a serde_json::Value>,
+}
+
+/// SQLite backed cache of completion responses for requests marked as cacheable.
+///
+/// Lives in its own database file next to the kv store, losing it only costs a
+/// few repeated requests.
+pub struct ResponseCache {
+    conn: Mutex<Connection>,
+    config: ResponseCacheConfig,
+}
+
+impl ResponseCache {
+    pub fn new(db_path: &str, config: ResponseCacheConfig) -> BackendResult<Self> {
+        let conn = Connection::open(db_path)?;
+        setup_connection_settings(&conn)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS llm_response_cache (
+                key TEXT PRIMARY KEY,
+                model TEXT NOT NULL,
+                content TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                last_used_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS llm_response_cache_last_used_at_index
+                ON llm_response_cache(last_used_at);
+            CREATE TABLE IF NOT EXISTS llm_response_cache_stats (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                hits INTEGER NOT NULL,
+                misses INTEGER NOT NULL
+            );
+            INSERT OR IGNORE INTO llm_response_cache_stats (id, hits, misses) VALUES (0, 0, 0);",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            config,
+        })
+    }
+
+    /// Key of the request sent to `base_url`, the provider's public api if unset.
+    pub(super) fn key(
+        request: &ChatCompletionRequest,
+        base_url: Option<&str>,
+    ) -> BackendResult<String> {
+        let key = serde_json::to_vec(&CacheKey {
+            model: request.model.as_str(),
+            provider: request.model.provider(),
+            base_url,
+            messages: &request.messages,
+            response_format: request.response_format.as_ref(),
+        })
+        .map_err(|e| BackendError::GenericError(format!("failed to build cache key: {e}")))?;
+        Ok(Sha256::digest(&key)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+
+    fn conn(&self) -> BackendResult<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|e| BackendError::GenericError(format!("response cache poisoned: {}", e)))
+    }
+
+    fn expires_before(&self) -> i64 {
+        chrono::Utc::now().timestamp() - self.config.ttl.as_secs() as i64
+    }
+
+    /// The cached content and the model that produced it.
+    pub(super) fn get(&self, key: &str) -> BackendResult<Option<(String, Model)>> {
+        let conn = self.conn()?;
+        let cached = conn
+            .query_row(
+                "SELECT content, model FROM llm_response_cache WHERE key = ?1 AND created_at > ?2",
+                params![key, self.expires_before()],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+
+        let cached = cached.and_then(|(content, model)| {
+            serde_json::from_str::<Model>(&model)
+                .ok()
+                .map(|model| (content, model))
+        });
+        match cached {
+            Some(_) => conn.execute(
+                "UPDATE llm_response_cache SET last_used_at = ?2 WHERE key = ?1",
+                params![key, chrono::Utc::now().timestamp()],
+            )?,
+            None => 0,
+        };
+        // the workers each have their own cache on the same database, the
+        // counters are kept in there so the stats cover all of them
+        conn.execute(
+            "UPDATE llm_response_cache_stats SET hits = hits + ?1, misses = misses + ?2",
+            params![cached.is_some() as i64, cached.is_none() as i64],
+        )?;
+        Ok(cached)
+    }
+
+    pub(super) fn put(&self, key: &str, model: &Model, content: &str) -> BackendResult<()> {
+        let model = serde_json::to_string(model)
+            .map_err(|e| BackendError::GenericError(format!("failed to serialize model: {e}")))?;
+        let now = chrono::Utc::now().timestamp();
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO llm_response_cache (key, model, content, size_bytes, created_at, last_used_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+            params![key, model, content, content.len() as u64, now],
+        )?;
+        self.evict(&conn)
+    }
+
+    // drops expired responses, then the least recently used ones until the cache
+    // fits its size cap
+    fn evict(&self, conn: &Connection) -> BackendResult<()> {
+        conn.execute(
+            "DELETE FROM llm_response_cache WHERE created_at <= ?1",
+            params![self.expires_before()],
+        )?;
+        let size: u64 = conn.query_row(
+            "SELECT COALESCE(SUM(size_bytes), 0) FROM llm_response_cache",
+            [],
+            |row| row.get(0),
+        )?;
+        if size <= self.config.max_size_bytes {
+            return Ok(());
+        }
+
+        let mut excess = size - self.config.max_size_bytes;
+        let mut stmt = conn.prepare(
+            "SELECT key, size_bytes FROM llm_response_cache ORDER BY last_used_at ASC, rowid ASC",
+        )?;
+        let mut rows = stmt.query([])?;
+        let mut evicted = vec![];
+        while let Some(row) = rows.next()? {
+            if excess == 0 {
+                break;
+            }
+            let size: u64 = row.get(1)?;
+            evicted.push(row.get::<_, String>(0)?);
+            excess = excess.saturating_sub(size);
+        }
+        for key in evicted {
+            conn.execute(
+                "DELETE FROM llm_response_cache WHERE key = ?1",
+                params![key],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> BackendResult<ResponseCacheStats> {
+        Ok(self.conn()?.query_row(
+            "SELECT
+                (SELECT COUNT(*) FROM llm_response_cache),
+                (SELECT COALESCE(SUM(size_bytes), 0) FROM llm_response_cache),
+                hits, misses
+            FROM llm_response_cache_stats",
+            [],
+            |row| {
+                Ok(ResponseCacheStats {
+                    entries: row.get(0)?,
+                    size_bytes: row.get(1)?,
+                    hits: row.get(2)?,
+                    misses: row.get(3)?,
+                })
+            },
+        )?)
+    }
+
+    /// Removes expired or, with `expired_only` unset, all responses. Returns how
+    /// many were removed.
+    pub fn purge(&self, expired_only: bool) -> BackendResult<usize> {
+        let conn = self.conn()?;
+        let removed = match expired_only {
+            true => conn.execute(
+                "DELETE FROM llm_response_cache WHERE created_at <= ?1",
+                params![self.expires_before()],
+            )?,
+            false => conn.execute("DELETE FROM llm_response_cache", [])?,
+        };
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::{tempdir, TempDir};
+
+    fn setup_cache(config: ResponseCacheConfig) -> (TempDir, ResponseCache) {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("cache.db");
+        let cache = ResponseCache::new(&db_path.to_string_lossy(), config).unwrap();
+        (dir, cache)
+    }
+
+    fn request(query: &str) -> ChatCompletionRequest {
+        ChatCompletionRequest::new(vec![Message::new_user(query)], &Model::GPT4_1)
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let key = ResponseCache::key(&request("hello"), None).unwrap();
+        assert_eq!(key, ResponseCache::key(&request("hello"), None).unwrap());
+        assert_ne!(key, ResponseCache::key(&request("hello!"), None).unwrap());
+
+        let with_format =
+            request("hello").with_response_format(Some(serde_json::json!({"type": "json_object"})));
+        assert_ne!(key, ResponseCache::key(&with_format, None).unwrap());
+
+        let proxied = ResponseCache::key(&request("hello"), Some("http://proxy")).unwrap();
+        assert_ne!(key, proxied);
+
+        let custom = |url: &str| {
+            let model = Model::Custom {
+                name: "model".to_string(),
+                provider: Provider::Custom(url.to_string()),
+                max_tokens: 8000,
+                vision: false,
+            };
+            let request = ChatCompletionRequest::new(vec![Message::new_user("hello")], &model);
+            ResponseCache::key(&request, None).unwrap()
+        };
+        assert_ne!(custom("http://a"), custom("http://b"));
+    }
+
+    #[test]
+    fn test_cache_hits_and_expiry() {
+        let (_dir, cache) = setup_cache(ResponseCacheConfig::default());
+        assert!(cache.get("key").unwrap().is_none());
+        cache.put("key", &Model::GPT4_1, "answer").unwrap();
+        assert_eq!(
+            cache.get("key").unwrap(),
+            Some(("answer".to_string(), Model::GPT4_1))
+        );
+        assert_eq!(
+            cache.stats().unwrap(),
+            ResponseCacheStats {
+                entries: 1,
+                size_bytes: 6,
+                hits: 1,
+                misses: 1,
+            }
+        );
+
+        let (_dir, cache) = setup_cache(ResponseCacheConfig {
+            ttl: Duration::ZERO,
+            ..Default::default()
+        });
+        cache.put("key", &Model::GPT4_1, "answer").unwrap();
+        assert!(cache.get("key").unwrap().is_none());
+        assert_eq!(cache.purge(true).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cache_stats_are_shared() {
+        let (dir, cache) = setup_cache(ResponseCacheConfig::default());
+        cache.put("key", &Model::GPT4_1, "answer").unwrap();
+        cache.get("key").unwrap();
+
+        // another worker's cache on the same database
+        let db_path = dir.path().join("cache.db");
+        let other = ResponseCache::new(&db_path.to_string_lossy(), Default::default()).unwrap();
+        other.get("key").unwrap();
+        other.get("other").unwrap();
+        for cache in [&cache, &other] {
+            let stats = cache.stats().unwrap();
+            assert_eq!((stats.entries, stats.hits, stats.misses), (1, 2, 1));
+        }
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let (_dir, cache) = setup_cache(ResponseCacheConfig {
+            max_size_bytes: 10,
+            ..Default::default()
+        });
+        cache.put("a", &Model::GPT4_1, "aaaa").unwrap();
+        cache.put("b", &Model::GPT4_1, "bbbb").unwrap();
+        cache.put("c", &Model::GPT4_1, "cccc").unwrap();
+
+        assert!(cache.get("a").unwrap().is_none());
+        assert!(cache.get("b").unwrap().is_some());
+        assert!(cache.get("c").unwrap().is_some());
+        assert_eq!(cache.stats().unwrap().size_bytes, 8);
+
+        assert_eq!(cache.purge(false).unwrap(), 2);
+        assert_eq!(cache.stats().unwrap().entries, 0);
+    }
+}
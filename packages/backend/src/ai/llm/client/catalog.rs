@@ -0,0 +1,301 @@
+//! Context windows, output limits, capabilities and prices of the models.
+//!
+//! The catalog embedded in the app is only the starting point, models discovered
+//! through the providers' listings and user overrides are layered on top so that
+//! supporting a new model doesn't need a release.
+use super::{discovery::ProviderModel, Provider, MAX_OUTPUT_TOKENS};
+use crate::{store::models::LLMModelPrice, BackendError, BackendResult};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::RwLock};
+
+const EMBEDDED_CATALOG: &str = include_str!("catalog.json");
+
+// for models nobody told us anything about
+pub const DEFAULT_CONTEXT_WINDOW: usize = 128_000;
+
+static CATALOG: Lazy<RwLock<ModelCatalog>> = Lazy::new(|| RwLock::new(ModelCatalog::embedded()));
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ModelInfo {
+    /// The name the provider's api knows the model by.
+    pub id: String,
+    pub provider: Option<String>,
+    pub context_window: usize,
+    pub max_output_tokens: usize,
+    pub vision: bool,
+    pub tools: bool,
+    pub json_mode: bool,
+    pub price: Option<LLMModelPrice>,
+}
+
+impl ModelInfo {
+    fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            provider: None,
+            context_window: DEFAULT_CONTEXT_WINDOW,
+            max_output_tokens: MAX_OUTPUT_TOKENS,
+            vision: false,
+            tools: false,
+            json_mode: false,
+            price: None,
+        }
+    }
+}
+
+/// Changes to the catalog entry of a model, unset fields are left as they are.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ModelInfoOverride {
+    pub id: String,
+    pub provider: Option<String>,
+    pub context_window: Option<usize>,
+    pub max_output_tokens: Option<usize>,
+    pub vision: Option<bool>,
+    pub tools: Option<bool>,
+    pub json_mode: Option<bool>,
+    pub price: Option<LLMModelPrice>,
+}
+
+impl ModelInfoOverride {
+    /// What a provider's model listing told about one of its models.
+    pub fn discovered(provider: &Provider, model: ProviderModel) -> Self {
+        Self {
+            id: model.id,
+            provider: Some(provider.name().to_string()),
+            context_window: model.context_window,
+            max_output_tokens: model.max_output_tokens,
+            vision: model.vision,
+            tools: model.tools,
+            json_mode: None,
+            price: model.price,
+        }
+    }
+
+    fn apply(self, info: &mut ModelInfo) {
+        if self.provider.is_some() {
+            info.provider = self.provider;
+        }
+        info.context_window = self.context_window.unwrap_or(info.context_window);
+        info.max_output_tokens = self.max_output_tokens.unwrap_or(info.max_output_tokens);
+        info.vision = self.vision.unwrap_or(info.vision);
+        info.tools = self.tools.unwrap_or(info.tools);
+        info.json_mode = self.json_mode.unwrap_or(info.json_mode);
+        if self.price.is_some() {
+            info.price = self.price;
+        }
+    }
+}
+
+// the same id can name different models at different providers, e.g. an
+// openrouter or ollama model called like an openai one
+type ModelKey = (Option<String>, String);
+
+fn model_key(provider: &Option<String>, id: &str) -> ModelKey {
+    (provider.clone(), id.to_string())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ModelCatalog {
+    models: HashMap<ModelKey, ModelInfo>,
+}
+
+impl ModelCatalog {
+    pub fn from_json(json: &str) -> BackendResult<Self> {
+        let models = serde_json::from_str::<Vec<ModelInfo>>(json).map_err(|e| {
+            BackendError::GenericError(format!("failed to parse model catalog: {e}"))
+        })?;
+        Ok(Self {
+            models: models
+                .into_iter()
+                .map(|info| (model_key(&info.provider, &info.id), info))
+                .collect(),
+        })
+    }
+
+    /// The catalog shipped with the app.
+    pub fn embedded() -> Self {
+        Self::from_json(EMBEDDED_CATALOG).expect("embedded model catalog is invalid")
+    }
+
+    /// Applies `overrides` in order, models that aren't in the catalog yet are
+    /// added with conservative defaults for whatever the override leaves unset.
+    /// An override without a provider changes the model at every provider.
+    pub fn apply(&mut self, overrides: impl IntoIterator<Item = ModelInfoOverride>) {
+        for model in overrides {
+            if model.provider.is_none() {
+                let mut matched = false;
+                for (_, info) in self
+                    .models
+                    .iter_mut()
+                    .filter(|((_, id), _)| *id == model.id)
+                {
+                    model.clone().apply(info);
+                    matched = true;
+                }
+                if matched {
+                    continue;
+                }
+            }
+            let info = self
+                .models
+                .entry(model_key(&model.provider, &model.id))
+                .or_insert_with(|| ModelInfo::new(&model.id));
+            model.apply(info);
+        }
+    }
+
+    /// Entry of the model `provider` knows as `id`, falling back to an entry
+    /// that isn't tied to a provider. Without a provider any entry for `id` is
+    /// returned.
+    pub fn get(&self, id: &str, provider: Option<&str>) -> Option<&ModelInfo> {
+        match provider {
+            Some(provider) => self
+                .models
+                .get(&(Some(provider.to_string()), id.to_string()))
+                .or_else(|| self.models.get(&(None, id.to_string()))),
+            None => self
+                .models
+                .iter()
+                .filter(|((_, model_id), _)| model_id == id)
+                .min_by(|(a, _), (b, _)| a.cmp(b))
+                .map(|(_, info)| info),
+        }
+    }
+
+    pub fn list(&self) -> Vec<ModelInfo> {
+        let mut models = self.models.values().cloned().collect::<Vec<_>>();
+        models.sort_by(|a, b| a.id.cmp(&b.id).then_with(|| a.provider.cmp(&b.provider)));
+        models
+    }
+}
+
+/// Catalog entry of the model `provider` knows as `id`, see `ModelCatalog::get`.
+pub fn model_info(id: &str, provider: Option<&str>) -> Option<ModelInfo> {
+    match CATALOG.read() {
+        Ok(catalog) => catalog.get(id, provider).cloned(),
+        Err(e) => {
+            tracing::error!("model catalog poisoned: {}", e);
+            None
+        }
+    }
+}
+
+/// Replaces the catalog used by all models.
+pub fn set_catalog(catalog: ModelCatalog) {
+    match CATALOG.write() {
+        Ok(mut current) => *current = catalog,
+        Err(e) => tracing::error!("model catalog poisoned: {}", e),
+    }
+}
+
+pub fn list_models() -> Vec<ModelInfo> {
+    CATALOG
+        .read()
+        .map(|catalog| catalog.list())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::llm::client::Model;
+
+    #[test]
+    fn test_embedded_catalog_covers_builtin_models() {
+        let catalog = ModelCatalog::embedded();
+        for model in [
+            Model::GPT5,
+            Model::GPT4_1,
+            Model::Claude35Haiku,
+            Model::Gemini20Flash,
+        ] {
+            let info = catalog
+                .get(&model.as_str(), Some(model.provider().name()))
+                .unwrap();
+            assert!(info.price.is_some());
+            assert!(info.max_output_tokens <= info.context_window);
+        }
+        assert!(
+            catalog
+                .get("claude-3-5-haiku-latest", Some("anthropic"))
+                .unwrap()
+                .vision
+        );
+    }
+
+    #[test]
+    fn test_overrides_are_layered() {
+        let mut catalog = ModelCatalog::embedded();
+        catalog.apply(vec![
+            ModelInfoOverride::discovered(
+                &Provider::OpenRouter,
+                ProviderModel {
+                    id: "qwen/qwen3-coder".to_string(),
+                    vision: Some(false),
+                    context_window: Some(262_144),
+                    tools: Some(true),
+                    ..Default::default()
+                },
+            ),
+            ModelInfoOverride {
+                id: "qwen/qwen3-coder".to_string(),
+                max_output_tokens: Some(32_000),
+                ..Default::default()
+            },
+            ModelInfoOverride {
+                id: "gpt-4.1".to_string(),
+                context_window: Some(1_000_000),
+                ..Default::default()
+            },
+        ]);
+
+        let qwen = catalog.get("qwen/qwen3-coder", Some("openrouter")).unwrap();
+        assert_eq!(qwen.provider.as_deref(), Some("openrouter"));
+        assert_eq!(qwen.context_window, 262_144);
+        assert_eq!(qwen.max_output_tokens, 32_000);
+        assert!(qwen.tools && !qwen.vision && !qwen.json_mode);
+
+        let gpt = catalog.get("gpt-4.1", Some("openai")).unwrap();
+        assert_eq!(gpt.context_window, 1_000_000);
+        // everything else is kept
+        assert_eq!(gpt.max_output_tokens, 32_768);
+        assert!(gpt.vision && gpt.price.is_some());
+    }
+
+    #[test]
+    fn test_lookup_is_scoped_to_the_provider() {
+        let mut catalog = ModelCatalog::embedded();
+        catalog.apply(vec![ModelInfoOverride::discovered(
+            &Provider::Ollama(String::new()),
+            ProviderModel {
+                id: "gpt-4o".to_string(),
+                context_window: Some(8_192),
+                ..Default::default()
+            },
+        )]);
+
+        let local = catalog.get("gpt-4o", Some("ollama")).unwrap();
+        assert_eq!(local.context_window, 8_192);
+        assert!(local.price.is_none());
+        let openai = catalog.get("gpt-4o", Some("openai")).unwrap();
+        assert_eq!(openai.context_window, 128_000);
+        assert!(openai.price.is_some());
+        // the openai entry isn't reused for an openai compatible server
+        assert!(catalog.get("gpt-4o", Some("custom")).is_none());
+
+        // overrides without a provider apply to every provider's entry
+        catalog.apply(vec![ModelInfoOverride {
+            id: "gpt-4o".to_string(),
+            vision: Some(false),
+            ..Default::default()
+        }]);
+        assert!(!catalog.get("gpt-4o", Some("ollama")).unwrap().vision);
+        assert!(!catalog.get("gpt-4o", Some("openai")).unwrap().vision);
+        assert_eq!(
+            catalog.list().len(),
+            ModelCatalog::embedded().list().len() + 1
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use super::{LLMClient, Provider, REQUEST_TIMEOUT};
+use crate::{store::models::LLMModelPrice, BackendError, BackendResult};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A model a provider offers, as listed by its api. Fields are None where the
+/// provider doesn't tell.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ProviderModel {
+    pub id: String,
+    pub vision: Option<bool>,
+    pub context_window: Option<usize>,
+    pub max_output_tokens: Option<usize>,
+    pub tools: Option<bool>,
+    pub price: Option<LLMModelPrice>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Architecture {
+    #[serde(default)]
+    input_modalities: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Capabilities {
+    vision: Option<bool>,
+    function_calling: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopProvider {
+    max_completion_tokens: Option<usize>,
+}
+
+// USD per token as decimal strings
+#[derive(Debug, Deserialize)]
+struct Pricing {
+    prompt: String,
+    completion: String,
+    input_cache_read: Option<String>,
+}
+
+impl Pricing {
+    fn per_mtok(price: &str) -> Option<f64> {
+        // rounded to a millionth of a dollar to undo float noise
+        let per_mtok = price.parse::<f64>().ok()? * 1_000_000.0;
+        Some((per_mtok * 1_000_000.0).round() / 1_000_000.0)
+    }
+
+    fn to_price(&self) -> Option<LLMModelPrice> {
+        let input_per_mtok = Self::per_mtok(&self.prompt)?;
+        Some(LLMModelPrice {
+            input_per_mtok,
+            output_per_mtok: Self::per_mtok(&self.completion)?,
+            cached_input_per_mtok: self
+                .input_cache_read
+                .as_deref()
+                .and_then(Self::per_mtok)
+                .unwrap_or(input_per_mtok),
+        })
+    }
+}
+
+// the openai, openrouter, mistral and anthropic listings share the shape but
+// only openrouter and mistral describe their models
+#[derive(Debug, Deserialize)]
+struct ListedModel {
+    id: String,
+    // openrouter
+    architecture: Option<Architecture>,
+    context_length: Option<usize>,
+    top_provider: Option<TopProvider>,
+    supported_parameters: Option<Vec<String>>,
+    pricing: Option<Pricing>,
+    // mistral
+    capabilities: Option<Capabilities>,
+    max_context_length: Option<usize>,
+}
+
+impl From<ListedModel> for ProviderModel {
+    fn from(model: ListedModel) -> Self {
+        let capabilities = model.capabilities.unwrap_or_default();
+        let vision = match model.architecture {
+            Some(architecture) => Some(
+                architecture
+                    .input_modalities
+                    .iter()
+                    .any(|modality| modality == "image"),
+            ),
+            None => capabilities.vision,
+        };
+        let tools = match model.supported_parameters {
+            Some(parameters) => Some(parameters.iter().any(|p| p == "tools")),
+            None => capabilities.function_calling,
+        };
+        ProviderModel {
+            id: model.id,
+            vision,
+            context_window: model.context_length.or(model.max_context_length),
+            max_output_tokens: model.top_provider.and_then(|p| p.max_completion_tokens),
+            tools,
+            price: model.pricing.as_ref().and_then(Pricing::to_price),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ListedModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaTag {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaTags {
+    models: Vec<OllamaTag>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaShow {
+    // missing before ollama 0.6.4
+    capabilities: Option<Vec<String>>,
+    // keyed by architecture, e.g. `llama.context_length`
+    #[serde(default)]
+    model_info: HashMap<String, serde_json::Value>,
+}
+
+impl OllamaShow {
+    fn into_model(self, id: String) -> ProviderModel {
+        let has = |capability: &str| {
+            self.capabilities
+                .as_ref()
+                .map(|capabilities| capabilities.iter().any(|c| c == capability))
+        };
+        ProviderModel {
+            vision: has("vision"),
+            tools: has("tools"),
+            context_window: self
+                .model_info
+                .iter()
+                .find(|(key, _)| key.ends_with(".context_length"))
+                .and_then(|(_, length)| length.as_u64())
+                .map(|length| length as usize),
+            id,
+            ..Default::default()
+        }
+    }
+}
+
+impl Provider {
+    fn get_models_url(&self, base_url: Option<String>) -> Option<String> {
+        let url = match self {
+            Self::OpenAI => format!(
+                "{}/v1/models",
+                base_url.unwrap_or("https://api.openai.com".to_string())
+            ),
+            Self::Google => format!(
+                "{}/models",
+                base_url.unwrap_or(
+                    "https://generativelanguage.googleapis.com/v1beta/openai".to_string()
+                ),
+            ),
+            Self::Anthropic => format!(
+                "{}/v1/models",
+                base_url.unwrap_or("https://api.anthropic.com".to_string())
+            ),
+            Self::OpenRouter => format!(
+                "{}/models",
+                base_url.unwrap_or("https://openrouter.ai/api/v1".to_string())
+            ),
+            Self::Mistral => format!(
+                "{}/v1/models",
+                base_url.unwrap_or("https://api.mistral.ai".to_string())
+            ),
+            Self::Ollama(url) => format!(
+                "{}/api/tags",
+                base_url.as_deref().unwrap_or(url).trim_end_matches('/')
+            ),
+            // openai compatible servers list their models next to the completions
+            Self::Custom(url) => format!("{}/models", url.strip_suffix("/chat/completions")?),
+            // a deployment is a single model
+            Self::Azure { .. } => return None,
+        };
+        Some(url)
+    }
+}
+
+impl LLMClient {
+    /// Models available to `custom_key` (or without a key for self hosted
+    /// providers) and whether they take images.
+    #[tracing::instrument(level = "trace", skip(self, custom_key))]
+    pub async fn list_models(
+        &self,
+        provider: &Provider,
+        custom_key: Option<String>,
+    ) -> BackendResult<Vec<ProviderModel>> {
+        if let Provider::Azure { deployment, .. } = provider {
+            return Ok(vec![ProviderModel {
+                id: deployment.clone(),
+                ..Default::default()
+            }]);
+        }
+        let url = provider
+            .get_models_url(self.base_url(provider))
+            .ok_or_else(|| {
+                BackendError::GenericError(format!("{} does not list its models", provider.name()))
+            })?;
+        // the key is only required where it is for completions
+        let (_, headers) = provider.get_request_params(custom_key, None)?;
+
+        let body = self.get_json(provider, &url, &headers).await?;
+        match provider {
+            Provider::Ollama(_) => {
+                let tags = serde_json::from_str::<OllamaTags>(&body).map_err(|e| {
+                    BackendError::GenericError(format!("failed to parse ollama models: {e}"))
+                })?;
+                let show_url = url.replace("/api/tags", "/api/show");
+                let mut models = vec![];
+                for tag in tags.models {
+                    let model = match self.show_ollama_model(&show_url, &headers, &tag.name).await {
+                        Ok(show) => show.into_model(tag.name),
+                        Err(e) => {
+                            tracing::warn!("failed to get ollama model {}: {}", tag.name, e);
+                            ProviderModel {
+                                id: tag.name,
+                                ..Default::default()
+                            }
+                        }
+                    };
+                    models.push(model);
+                }
+                Ok(models)
+            }
+            _ => {
+                let list = serde_json::from_str::<ModelList>(&body).map_err(|e| {
+                    BackendError::GenericError(format!(
+                        "failed to parse {} models: {e}",
+                        provider.name()
+                    ))
+                })?;
+                Ok(list.data.into_iter().map(ProviderModel::from).collect())
+            }
+        }
+    }
+
+    async fn show_ollama_model(
+        &self,
+        url: &str,
+        headers: &[(String, String)],
+        name: &str,
+    ) -> BackendResult<OllamaShow> {
+        let mut builder = self
+            .client
+            .post(url)
+            .timeout(REQUEST_TIMEOUT)
+            .body(serde_json::json!({ "model": name }).to_string());
+        for (name, value) in headers.iter() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let response = builder.send().await?.error_for_status()?;
+        serde_json::from_str::<OllamaShow>(&response.text().await?)
+            .map_err(|e| BackendError::GenericError(format!("failed to parse ollama model: {e}")))
+    }
+
+    async fn get_json(
+        &self,
+        provider: &Provider,
+        url: &str,
+        headers: &[(String, String)],
+    ) -> BackendResult<String> {
+        let mut builder = self.client.get(url).timeout(REQUEST_TIMEOUT);
+        for (name, value) in headers.iter() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let response = builder.send().await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if status == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(BackendError::LLMClientErrorUnauthorized);
+        }
+        if !status.is_success() {
+            provider.parse_potential_error(&body)?;
+            return Err(BackendError::GenericError(format!(
+                "failed to list models ({}): {}",
+                status, body
+            )));
+        }
+        Ok(body)
+    }
+}
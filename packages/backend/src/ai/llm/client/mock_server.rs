@@ -0,0 +1,176 @@
+//! Bare bones HTTP server answering with canned responses, enough to check what
+//! the client sends to each provider and how it reads their replies.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    // including the query
+    pub path: String,
+    // lowercased names
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    path_prefix: String,
+    // answered in order, the last one repeats
+    responses: Vec<(u16, String)>,
+    served: Arc<AtomicUsize>,
+}
+
+impl Route {
+    fn next_response(&self) -> (u16, &str) {
+        let served = self.served.fetch_add(1, Ordering::SeqCst);
+        let (status, body) = &self.responses[served.min(self.responses.len() - 1)];
+        (*status, body)
+    }
+}
+
+pub struct MockServer {
+    url: String,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl MockServer {
+    /// Answers every request with `status` and `body`.
+    pub async fn start(status: u16, body: &str) -> Self {
+        Self::with_routes(&[("/", status, body)]).await
+    }
+
+    /// Answers requests with the first route whose prefix matches their path.
+    pub async fn with_routes(routes: &[(&str, u16, &str)]) -> Self {
+        let routes = routes
+            .iter()
+            .map(|(path_prefix, status, body)| Route {
+                path_prefix: path_prefix.to_string(),
+                responses: vec![(*status, body.to_string())],
+                served: Arc::default(),
+            })
+            .collect();
+        Self::serve(routes).await
+    }
+
+    /// Answers the requests with `responses` in order, the last one repeats.
+    pub async fn with_sequence(responses: &[(u16, &str)]) -> Self {
+        Self::serve(vec![Route {
+            path_prefix: "/".to_string(),
+            responses: responses
+                .iter()
+                .map(|(status, body)| (*status, body.to_string()))
+                .collect(),
+            served: Arc::default(),
+        }])
+        .await
+    }
+
+    async fn serve(routes: Vec<Route>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let recorded = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let routes = routes.clone();
+                let recorded = Arc::clone(&recorded);
+                tokio::spawn(async move { handle(socket, &routes, &recorded).await });
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn last_request(&self) -> RecordedRequest {
+        self.requests().pop().expect("no request received")
+    }
+}
+
+async fn handle(
+    mut socket: TcpStream,
+    routes: &[Route],
+    recorded: &Mutex<Vec<RecordedRequest>>,
+) -> Option<()> {
+    let mut data = vec![];
+    let mut buf = [0u8; 4096];
+    let header_end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        let read = socket.read(&mut buf).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buf[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect::<HashMap<_, _>>();
+
+    let content_length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = data[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let read = socket.read(&mut buf).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..read]);
+    }
+
+    let (status, response_body) = routes
+        .iter()
+        .find(|route| path.starts_with(&route.path_prefix))
+        .map(Route::next_response)
+        .unwrap_or((404, ""));
+    // recorded before answering so the client never sees a reply to an unknown request
+    recorded.lock().unwrap().push(RecordedRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    });
+
+    let response = format!(
+        "HTTP/1.1 {} Mock\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        response_body.len(),
+        response_body
+    );
+    socket.write_all(response.as_bytes()).await.ok()?;
+    socket.shutdown().await.ok()
+}
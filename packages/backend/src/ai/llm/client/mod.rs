@@ -1,39 +1,258 @@
+pub mod cache;
+pub mod catalog;
+pub mod discovery;
+#[cfg(test)]
+pub mod mock_server;
+mod ollama;
+pub mod retry;
+pub mod structured;
 pub mod tokens;
 
-use reqwest::{blocking::Response, header};
+use cache::ResponseCache;
+use retry::{parse_retry_after, RequestFailure, RetryPolicy};
+use tokens::Tokenizer;
+
+use futures::{future::BoxFuture, Stream, StreamExt};
+use reqwest::{header, Response};
 use serde::{Deserialize, Serialize};
 use std::{
-    io::{BufRead, BufReader},
+    collections::{BTreeMap, HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
     time::{Duration, Instant},
 };
 
+pub use tokio_util::sync::CancellationToken;
+
 use crate::{
-    ai::llm::models::{Message, MessageContent, MessageRole},
+    ai::llm::{
+        models::{FunctionCall, Message, MessageContent, MessageRole, ToolCall, ToolDefinition},
+        usage::{TokenUsage, UsageRecorder, UsageTag},
+    },
+    store::models::{current_time, LLMUsage},
     BackendError, BackendResult,
 };
 
 pub struct ChatCompletionStream {
-    reader: BufReader<Response>,
-    buffer: String,
+    bytes: Pin<Box<dyn Stream<Item = reqwest::Result<bytes::Bytes>> + Send>>,
+    buffer: Vec<u8>,
     provider: Provider,
-    last_update: Instant,
-    update_interval: Duration,
+    pending: VecDeque<ChatCompletionChunk>,
+    cancellation_token: CancellationToken,
+    done: bool,
+    model: Model,
+    usage: TokenUsage,
+    // tool calls streamed so far, indexes the calls of providers that don't
+    tool_calls: usize,
+    // recorded once the stream is dropped, whether it finished or not
+    usage_recording: Option<UsageRecording>,
+}
+
+// what is needed to record the usage of a completion once it is done
+struct UsageRecording {
+    recorder: UsageRecorder,
+    tag: UsageTag,
+    custom_key: bool,
+    started_at: Instant,
+}
+
+impl UsageRecording {
+    fn record(self, model: &Model, usage: TokenUsage, streamed: bool) {
+        self.recorder.record(LLMUsage {
+            model: model.as_str(),
+            provider: model.provider().name().to_string(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cached_tokens: usage.cached_tokens,
+            latency_ms: self.started_at.elapsed().as_millis() as u64,
+            streamed,
+            custom_key: self.custom_key,
+            session_id: self.tag.session_id,
+            note_id: self.tag.note_id,
+            agent: self.tag.agent,
+            created_at: current_time(),
+        });
+    }
+}
+
+/// A piece of a streamed completion.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatCompletionChunk {
+    Text(String),
+    ToolCall(ToolCallChunk),
+}
+
+/// Part of a natively requested tool call, the id and name are only sent with the
+/// first chunk of a call and the arguments arrive as fragments of a JSON string.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ToolCallChunk {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+/// Assembles streamed tool call chunks into complete tool calls.
+#[derive(Debug, Default)]
+pub struct ToolCallBuilder {
+    calls: BTreeMap<usize, ToolCall>,
+}
+
+impl ToolCallBuilder {
+    pub fn push(&mut self, chunk: ToolCallChunk) {
+        let call = self.calls.entry(chunk.index).or_insert_with(|| ToolCall {
+            id: String::new(),
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name: String::new(),
+                arguments: String::new(),
+            },
+        });
+        if let Some(id) = chunk.id {
+            call.id = id;
+        }
+        if let Some(name) = chunk.name {
+            call.function.name.push_str(&name);
+        }
+        call.function.arguments.push_str(&chunk.arguments);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    pub fn finish(self) -> Vec<ToolCall> {
+        self.calls
+            .into_values()
+            .map(|mut call| {
+                // anthropic sends no arguments at all for tools without parameters
+                if call.function.arguments.trim().is_empty() {
+                    call.function.arguments = "{}".to_string();
+                }
+                call
+            })
+            .collect()
+    }
+}
+
+/// Non streamed completion that may request tool calls instead of or next to its text.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChatCompletionResponse {
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+    // the model that answered, differs from the requested one if a fallback kicked in
+    pub model: Option<Model>,
+    // None if the provider did not report it
+    pub usage: Option<TokenUsage>,
+}
+
+/// Everything needed for a completion, the plain `create_*` methods of the client
+/// cover the common cases.
+#[derive(Debug, Clone)]
+pub struct ChatCompletionRequest {
+    pub messages: Vec<Message>,
+    pub model: Model,
+    pub custom_key: Option<String>,
+    pub response_format: Option<serde_json::Value>,
+    pub tools: Vec<ToolDefinition>,
+    /// Models (with their keys) tried in order when the previous one keeps being rate
+    /// limited, fails with a server error or times out.
+    pub fallbacks: Vec<(Model, Option<String>)>,
+    /// How every model is retried, the client's policy if unset.
+    pub retry_policy: Option<RetryPolicy>,
+    pub usage_tag: UsageTag,
+    /// Whether the same messages always warrant the same answer, so a cached
+    /// response can be served. Ignored for requests with tools.
+    pub cacheable: bool,
+}
+
+impl ChatCompletionRequest {
+    pub fn new(messages: Vec<Message>, model: &Model) -> Self {
+        Self {
+            messages,
+            model: model.clone(),
+            custom_key: None,
+            response_format: None,
+            tools: vec![],
+            fallbacks: vec![],
+            retry_policy: None,
+            usage_tag: UsageTag::default(),
+            cacheable: false,
+        }
+    }
+
+    pub fn with_custom_key(mut self, custom_key: Option<String>) -> Self {
+        self.custom_key = custom_key;
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: Option<serde_json::Value>) -> Self {
+        self.response_format = response_format;
+        self
+    }
+
+    pub fn with_tools(mut self, tools: Vec<ToolDefinition>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    pub fn with_fallback(mut self, model: Model, custom_key: Option<String>) -> Self {
+        self.fallbacks.push((model, custom_key));
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    pub fn with_usage_tag(mut self, usage_tag: UsageTag) -> Self {
+        self.usage_tag = usage_tag;
+        self
+    }
+
+    pub fn cacheable(mut self) -> Self {
+        self.cacheable = true;
+        self
+    }
 }
 
 pub struct LLMClient {
-    client: reqwest::blocking::Client,
+    // shared by all requests so connections to the providers are reused
+    client: reqwest::Client,
+    retry_policy: RetryPolicy,
+    usage_recorder: Option<UsageRecorder>,
+    response_cache: Option<ResponseCache>,
+    // per provider name, replaces the provider's default api url
+    base_urls: HashMap<&'static str, String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum Provider {
     OpenAI,
     Anthropic,
     Google,
     Custom(String),
+    /// Base url of an Ollama server, talked to through its native api.
+    Ollama(String),
+    #[serde(rename = "openrouter")]
+    OpenRouter,
+    Mistral,
+    /// Azure OpenAI serves deployments of a model instead of the model itself.
+    Azure {
+        endpoint: String,
+        deployment: String,
+        #[serde(default = "default_azure_api_version")]
+        api_version: String,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+fn default_azure_api_version() -> String {
+    "2024-10-21".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Model {
     #[serde(rename = "gpt-5")]
     GPT5,
@@ -74,8 +293,38 @@ pub enum Model {
     },
 }
 
+// most tokens asked for in one completion, also the output limit of models
+// without a catalog entry
+const MAX_OUTPUT_TOKENS: usize = 8192;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// streams have no overall timeout, only one between two reads
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Chat completions as used by the agents, lets them run against a mocked client in tests.
+pub trait ChatCompletionProvider: Send + Sync {
+    fn create_chat_completion<'a>(
+        &'a self,
+        messages: Vec<Message>,
+        model: &'a Model,
+        custom_key: Option<&'a str>,
+        response_format: Option<serde_json::Value>,
+    ) -> BoxFuture<'a, BackendResult<String>>;
+
+    fn complete_streaming<'a>(
+        &'a self,
+        request: ChatCompletionRequest,
+        cancellation_token: CancellationToken,
+    ) -> BoxFuture<'a, BackendResult<ChatCompletionStream>>;
+}
+
 pub trait TokenModel {
     fn max_tokens(&self) -> usize;
+
+    fn tokenizer(&self) -> &'static dyn Tokenizer {
+        &tokens::DEFAULT_ESTIMATOR
+    }
 }
 
 mod response_types {
@@ -83,10 +332,43 @@ mod response_types {
 
     #[derive(Debug, Serialize, Deserialize)]
     pub struct ChatCompletionError {
-        pub r#type: String,
+        // azure and openrouter only send a code, openrouter's is the http status
+        pub r#type: Option<String>,
+        pub code: Option<serde_json::Value>,
         pub message: String,
     }
 
+    impl From<ChatCompletionError> for crate::BackendError {
+        fn from(error: ChatCompletionError) -> Self {
+            let ChatCompletionError {
+                r#type,
+                code,
+                message,
+            } = error;
+            let r#type = r#type.or_else(|| match code {
+                Some(serde_json::Value::String(code)) => Some(code),
+                Some(serde_json::Value::Null) | None => None,
+                Some(code) => Some(code.to_string()),
+            });
+            crate::BackendError::LLMClientError {
+                r#type: r#type.unwrap_or_else(|| "error".to_string()),
+                message,
+            }
+        }
+    }
+
+    pub mod mistral {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct ErrorResponse {
+            pub object: String,
+            // a list of validation errors for invalid requests
+            pub message: serde_json::Value,
+            pub r#type: Option<String>,
+        }
+    }
+
     #[derive(Debug, Serialize, Deserialize)]
     pub struct ChatCompletionChunkErrorResponse {
         pub error: ChatCompletionError,
@@ -95,15 +377,36 @@ mod response_types {
     pub mod openai {
         use serde::{Deserialize, Serialize};
 
+        use crate::ai::llm::{models::ToolCall, usage::TokenUsage};
+
+        #[derive(Debug, Serialize, Deserialize, Clone)]
+        pub(crate) struct FunctionCallDelta {
+            pub name: Option<String>,
+            pub arguments: Option<String>,
+        }
+
+        #[derive(Debug, Serialize, Deserialize, Clone)]
+        pub(crate) struct ToolCallDelta {
+            // mistral sends complete calls without an index
+            pub index: Option<usize>,
+            pub id: Option<String>,
+            pub function: Option<FunctionCallDelta>,
+        }
+
         #[derive(Debug, Serialize, Deserialize, Clone)]
         pub(crate) struct ChatCompletionChoiceDelta {
             pub content: Option<String>,
+            #[serde(default)]
+            pub tool_calls: Vec<ToolCallDelta>,
         }
 
         #[derive(Serialize, Deserialize, Debug, Clone)]
         pub struct ChatCompletionMessage {
             pub role: String,
-            pub content: String,
+            // null when the message only requests tool calls
+            pub content: Option<String>,
+            #[serde(default)]
+            pub tool_calls: Vec<ToolCall>,
         }
 
         #[derive(Debug, Serialize, Deserialize)]
@@ -113,23 +416,73 @@ mod response_types {
             pub delta: Option<ChatCompletionChoiceDelta>,
         }
 
+        #[derive(Debug, Serialize, Deserialize, Default)]
+        pub(crate) struct PromptTokensDetails {
+            #[serde(default)]
+            pub cached_tokens: u64,
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub(crate) struct Usage {
+            pub prompt_tokens: u64,
+            pub completion_tokens: u64,
+            pub prompt_tokens_details: Option<PromptTokensDetails>,
+        }
+
+        impl From<Usage> for TokenUsage {
+            fn from(usage: Usage) -> Self {
+                TokenUsage {
+                    prompt_tokens: usage.prompt_tokens,
+                    completion_tokens: usage.completion_tokens,
+                    cached_tokens: usage
+                        .prompt_tokens_details
+                        .unwrap_or_default()
+                        .cached_tokens,
+                }
+            }
+        }
+
         #[derive(Debug, Serialize, Deserialize)]
         pub(crate) struct ChatCompletionChunkResponse {
             pub choices: Vec<ChatCompletionChoice>,
+            // streams only report it in a last chunk without choices, if asked to
+            pub usage: Option<Usage>,
         }
     }
 
     pub mod anthropic {
         use serde::{Deserialize, Serialize};
 
+        use crate::ai::llm::usage::TokenUsage;
+
         #[derive(Debug, Serialize, Deserialize)]
         pub struct ChunkResponseDelta {
             pub text: Option<String>,
+            // argument fragments of a tool_use block
+            pub partial_json: Option<String>,
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct ChunkContentBlock {
+            pub r#type: String,
+            pub id: Option<String>,
+            pub name: Option<String>,
         }
 
         #[derive(Debug, Serialize, Deserialize)]
         pub struct ChunkResponse {
+            pub index: Option<usize>,
             pub delta: Option<ChunkResponseDelta>,
+            // sent with content_block_start events
+            pub content_block: Option<ChunkContentBlock>,
+            // message_start carries the input tokens, message_delta the output tokens
+            pub message: Option<ChunkMessage>,
+            pub usage: Option<Usage>,
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct ChunkMessage {
+            pub usage: Option<Usage>,
         }
 
         #[derive(Debug, Serialize, Deserialize)]
@@ -155,13 +508,34 @@ mod response_types {
         #[derive(Debug, Serialize, Deserialize)]
         pub struct Content {
             pub r#type: String,
+            #[serde(default)]
             pub text: String,
+            // set for tool_use blocks
+            pub id: Option<String>,
+            pub name: Option<String>,
+            pub input: Option<serde_json::Value>,
         }
 
-        #[derive(Debug, Serialize, Deserialize)]
+        #[derive(Debug, Serialize, Deserialize, Default)]
+        #[serde(default)]
         pub struct Usage {
-            pub input_tokens: u32,
-            pub output_tokens: u32,
+            pub input_tokens: u64,
+            pub output_tokens: u64,
+            pub cache_creation_input_tokens: u64,
+            pub cache_read_input_tokens: u64,
+        }
+
+        impl From<&Usage> for TokenUsage {
+            // input_tokens only counts the uncached part of the prompt
+            fn from(usage: &Usage) -> Self {
+                TokenUsage {
+                    prompt_tokens: usage.input_tokens
+                        + usage.cache_creation_input_tokens
+                        + usage.cache_read_input_tokens,
+                    completion_tokens: usage.output_tokens,
+                    cached_tokens: usage.cache_read_input_tokens,
+                }
+            }
         }
 
         #[derive(Debug, Serialize, Deserialize)]
@@ -187,7 +561,7 @@ fn filter_unsupported_content(messages: Vec<Message>, model: &Model) -> Vec<Mess
                 msg.content.retain(|c| matches!(c, MessageContent::Text(_)));
                 msg
             })
-            .filter(|msg| !msg.content.is_empty())
+            .filter(|msg| !msg.content.is_empty() || !msg.tool_calls.is_empty())
             .collect()
     }
 }
@@ -196,38 +570,184 @@ fn truncate_messages(messages: Vec<Message>, model: &Model) -> Vec<Message> {
     if messages.is_empty() {
         return messages;
     }
+    // the first message is always kept, so the rest only gets what it and the
+    // completion leave over
+    let tokenizer = model.tokenizer();
+    let budget = model
+        .max_tokens()
+        .saturating_sub(tokens::estimate_message_token(&messages[0], tokenizer))
+        .saturating_sub(model.completion_tokens().min(model.max_tokens() / 4));
     let mut truncated_messages = vec![messages[0].clone()];
-    let (_, messages) = tokens::truncate_messages(messages[1..].to_vec(), model);
+    let (_, messages) =
+        tokens::truncate_messages_to_budget(messages[1..].to_vec(), tokenizer, budget);
     truncated_messages.extend(messages);
     truncated_messages
 }
 
 impl ChatCompletionStream {
-    fn new(reader: BufReader<Response>, provider: Provider, packets_per_second: u32) -> Self {
+    fn new(response: Response, model: Model, cancellation_token: CancellationToken) -> Self {
+        Self::from_bytes(response.bytes_stream(), model, cancellation_token)
+    }
+
+    fn from_bytes(
+        bytes: impl Stream<Item = reqwest::Result<bytes::Bytes>> + Send + 'static,
+        model: Model,
+        cancellation_token: CancellationToken,
+    ) -> Self {
         Self {
-            reader,
-            buffer: String::new(),
-            provider,
-            last_update: Instant::now(),
-            update_interval: Duration::from_secs_f64(1.0 / packets_per_second as f64),
+            bytes: Box::pin(bytes),
+            buffer: Vec::new(),
+            provider: model.provider().clone(),
+            pending: VecDeque::new(),
+            cancellation_token,
+            done: false,
+            model,
+            usage: TokenUsage::default(),
+            tool_calls: 0,
+            usage_recording: None,
         }
     }
 
-    pub fn set_packets_per_second(&mut self, pps: u32) {
-        self.update_interval = Duration::from_secs_f64(1.0 / pps as f64);
+    /// Stream of chunks that are already parsed, lets tests stand in for a provider.
+    #[cfg(all(test, feature = "wip"))]
+    pub(crate) fn from_chunks(chunks: Vec<ChatCompletionChunk>, model: Model) -> Self {
+        let mut stream =
+            Self::from_bytes(futures::stream::empty(), model, CancellationToken::new());
+        stream.pending.extend(chunks);
+        stream
+    }
+
+    fn with_usage_recording(mut self, usage_recording: Option<UsageRecording>) -> Self {
+        self.usage_recording = usage_recording;
+        self
+    }
+
+    /// The model that is answering, differs from the requested one if a fallback
+    /// kicked in.
+    pub fn model(&self) -> &Model {
+        &self.model
     }
 
-    fn wait_for_next_update(&mut self) {
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.last_update);
-        if elapsed < self.update_interval {
-            std::thread::sleep(self.update_interval - elapsed);
+    /// Tokens reported so far, complete once the stream is exhausted.
+    pub fn usage(&self) -> TokenUsage {
+        self.usage
+    }
+
+    /// Next text or tool call chunk of the stream.
+    ///
+    /// Returns `BackendError::CancelledError` as soon as the stream's cancellation
+    /// token is cancelled, without waiting for the provider to send anything.
+    pub async fn next_chunk(&mut self) -> Option<BackendResult<ChatCompletionChunk>> {
+        loop {
+            if let Some(chunk) = self.pending.pop_front() {
+                return Some(Ok(chunk));
+            }
+            if let Some(line) = self.next_line() {
+                let data = match self.provider.stream_event_data(&line) {
+                    None => continue,
+                    Some("[DONE]") => {
+                        self.done = true;
+                        continue;
+                    }
+                    Some(data) => data.to_string(),
+                };
+                match self
+                    .provider
+                    .parse_stream_chunk(&data, &mut self.usage, &mut self.tool_calls)
+                {
+                    Ok(chunks) => self.pending.extend(chunks),
+                    Err(e) => return Some(Err(e)),
+                }
+                continue;
+            }
+            if self.done {
+                return None;
+            }
+
+            tokio::select! {
+                _ = self.cancellation_token.cancelled() => {
+                    self.done = true;
+                    return Some(Err(BackendError::CancelledError));
+                }
+                next = tokio::time::timeout(STREAM_IDLE_TIMEOUT, self.bytes.next()) => match next {
+                    Err(_) => {
+                        self.done = true;
+                        return Some(Err(BackendError::GenericError(format!(
+                            "no data received from the llm for {}s",
+                            STREAM_IDLE_TIMEOUT.as_secs()
+                        ))));
+                    }
+                    Ok(None) => {
+                        // the last line might not be terminated
+                        self.done = true;
+                        self.buffer.push(b'\n');
+                    }
+                    Ok(Some(Ok(bytes))) => self.buffer.extend_from_slice(&bytes),
+                    Ok(Some(Err(e))) => {
+                        self.done = true;
+                        return Some(Err(e.into()));
+                    }
+                },
+            }
+        }
+    }
+
+    /// Next text chunk of the stream, skipping tool calls.
+    pub async fn next_text(&mut self) -> Option<BackendResult<String>> {
+        loop {
+            match self.next_chunk().await? {
+                Ok(ChatCompletionChunk::Text(text)) => return Some(Ok(text)),
+                Ok(ChatCompletionChunk::ToolCall(_)) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = BackendResult<ChatCompletionChunk>> {
+        futures::stream::unfold(self, |mut stream| async move {
+            stream.next_chunk().await.map(|chunk| (chunk, stream))
+        })
+    }
+
+    fn next_line(&mut self) -> Option<String> {
+        // lines are only split on complete lines so multi byte characters stay intact
+        let end = self.buffer.iter().position(|b| *b == b'\n')?;
+        let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+        Some(String::from_utf8_lossy(&line).into_owned())
+    }
+}
+
+impl Drop for ChatCompletionStream {
+    fn drop(&mut self) {
+        if let Some(usage_recording) = self.usage_recording.take() {
+            usage_recording.record(&self.model, self.usage, true);
         }
-        self.last_update = Instant::now();
     }
 }
 
 impl Provider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::OpenAI => "openai",
+            Self::Anthropic => "anthropic",
+            Self::Google => "google",
+            Self::Custom(_) => "custom",
+            Self::Ollama(_) => "ollama",
+            Self::OpenRouter => "openrouter",
+            Self::Mistral => "mistral",
+            Self::Azure { .. } => "azure",
+        }
+    }
+
+    // ollama streams newline delimited JSON, everyone else server sent events
+    fn stream_event_data<'a>(&self, line: &'a str) -> Option<&'a str> {
+        let line = line.trim();
+        match self {
+            Self::Ollama(_) => Some(line).filter(|line| !line.is_empty()),
+            _ => line.strip_prefix("data: "),
+        }
+    }
+
     fn get_completion_url(&self, base_url: Option<String>) -> String {
         match self {
             Self::OpenAI => format!(
@@ -245,6 +765,31 @@ impl Provider {
                 base_url.unwrap_or("https://api.anthropic.com".to_string())
             ),
             Self::Custom(url) => url.to_string(),
+            Self::Ollama(url) => format!(
+                "{}/api/chat",
+                base_url.as_deref().unwrap_or(url).trim_end_matches('/')
+            ),
+            Self::OpenRouter => format!(
+                "{}/chat/completions",
+                base_url.unwrap_or("https://openrouter.ai/api/v1".to_string())
+            ),
+            Self::Mistral => format!(
+                "{}/v1/chat/completions",
+                base_url.unwrap_or("https://api.mistral.ai".to_string())
+            ),
+            Self::Azure {
+                endpoint,
+                deployment,
+                api_version,
+            } => format!(
+                "{}/openai/deployments/{}/chat/completions?api-version={}",
+                base_url
+                    .as_deref()
+                    .unwrap_or(endpoint)
+                    .trim_end_matches('/'),
+                deployment,
+                api_version
+            ),
         }
     }
 
@@ -253,10 +798,14 @@ impl Provider {
 
         if let Some(api_key) = api_key {
             let auth = match self {
-                Self::OpenAI | Self::Google | Self::Custom(_) => {
-                    ("Authorization".to_string(), format!("Bearer {}", api_key))
-                }
+                Self::OpenAI
+                | Self::Google
+                | Self::Custom(_)
+                | Self::Ollama(_)
+                | Self::OpenRouter
+                | Self::Mistral => ("Authorization".to_string(), format!("Bearer {}", api_key)),
                 Self::Anthropic => ("x-api-key".to_string(), api_key.to_string()),
+                Self::Azure { .. } => ("api-key".to_string(), api_key.to_string()),
             };
             headers.push(auth);
         }
@@ -273,10 +822,14 @@ impl Provider {
     fn get_request_params(
         &self,
         custom_key: Option<String>,
+        base_url: Option<String>,
     ) -> BackendResult<(String, Vec<(String, String)>)> {
         let (completions_url, api_key) = match (self, custom_key) {
-            (Self::Custom(_), api_key) => (self.get_completion_url(None), api_key),
-            (_, Some(api_key)) => (self.get_completion_url(None), Some(api_key)),
+            // self hosted servers usually don't need a key
+            (Self::Custom(_) | Self::Ollama(_), api_key) => {
+                (self.get_completion_url(base_url), api_key)
+            }
+            (_, Some(api_key)) => (self.get_completion_url(base_url), Some(api_key)),
             (_, None) => return Err(BackendError::LLMClientErrorAPIKeyMissing),
         };
 
@@ -290,24 +843,42 @@ impl Provider {
         max_tokens: i32,
         messages: &[Message],
         response_format: Option<&serde_json::Value>,
+        tools: &[ToolDefinition],
     ) -> BackendResult<String> {
         match self {
-            Self::OpenAI | Self::Google => {
-                self.prepare_openai_request(model, stream, messages, response_format)
+            Self::OpenAI | Self::Google | Self::OpenRouter | Self::Mistral | Self::Azure { .. } => {
+                self.prepare_openai_request(model, stream, messages, response_format, tools)
             }
-            Self::Custom(_) => self.prepare_openai_request(
+            Self::Ollama(_) => ollama::prepare_request(
+                self,
                 model,
                 stream,
-                &self.add_response_format_if_needed(messages.to_vec(), response_format),
-                None,
+                max_tokens,
+                messages,
+                response_format,
+                tools,
             ),
-            Self::Anthropic => self.prepare_anthropic_request(
+            Self::Custom(_) => self.prepare_openai_request(
                 model,
                 stream,
-                max_tokens,
                 &self.add_response_format_if_needed(messages.to_vec(), response_format),
-                response_format,
+                None,
+                tools,
             ),
+            Self::Anthropic => {
+                let messages = match self.forced_tool_schema(response_format, tools) {
+                    Some(_) => messages.to_vec(),
+                    None => self.add_response_format_if_needed(messages.to_vec(), response_format),
+                };
+                self.prepare_anthropic_request(
+                    model,
+                    stream,
+                    max_tokens,
+                    &messages,
+                    response_format,
+                    tools,
+                )
+            }
         }
     }
 
@@ -317,7 +888,19 @@ impl Provider {
         stream: bool,
         messages: &[Message],
         response_format: Option<&serde_json::Value>,
+        tools: &[ToolDefinition],
     ) -> BackendResult<String> {
+        let messages = messages
+            .iter()
+            .map(|m| {
+                let mut value = serde_json::json!(m);
+                // assistant messages that only call tools must have a null content
+                if m.content.is_empty() && !m.tool_calls.is_empty() {
+                    value["content"] = serde_json::Value::Null;
+                }
+                value
+            })
+            .collect::<Vec<_>>();
         let mut json_obj = serde_json::json!({
             "model": model,
             "stream": stream,
@@ -326,6 +909,25 @@ impl Provider {
         if let Some(format) = response_format {
             json_obj["response_format"] = serde_json::json!(format);
         }
+        // openai compatible servers don't necessarily know the option
+        if stream && !matches!(self, Self::Custom(_) | Self::Mistral) {
+            json_obj["stream_options"] = serde_json::json!({ "include_usage": true });
+        }
+        if !tools.is_empty() {
+            json_obj["tools"] = tools
+                .iter()
+                .map(|tool| {
+                    serde_json::json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.parameters,
+                        }
+                    })
+                })
+                .collect();
+        }
 
         serde_json::to_string(&json_obj).map_err(|err| {
             BackendError::GenericError(format!(
@@ -340,7 +942,8 @@ impl Provider {
         stream: bool,
         max_tokens: i32,
         messages: &[Message],
-        _response_format: Option<&serde_json::Value>,
+        response_format: Option<&serde_json::Value>,
+        tools: &[ToolDefinition],
     ) -> BackendResult<String> {
         let system_message = messages
             .first()
@@ -348,14 +951,35 @@ impl Provider {
             .map(|m| m.content.clone());
         let transformed_messages = self.transform_messages_for_anthropic(messages);
 
-        serde_json::to_string(&serde_json::json!({
+        let mut json_obj = serde_json::json!({
             "model": model,
             "stream": stream,
             "system": system_message,
             "messages": transformed_messages,
             "max_tokens": max_tokens,
-        }))
-        .map_err(|err| {
+        });
+        if !tools.is_empty() {
+            json_obj["tools"] = tools
+                .iter()
+                .map(|tool| {
+                    serde_json::json!({
+                        "name": tool.name,
+                        "description": tool.description,
+                        "input_schema": tool.parameters,
+                    })
+                })
+                .collect();
+        }
+        if let Some((name, schema)) = self.forced_tool_schema(response_format, tools) {
+            json_obj["tools"] = serde_json::json!([{
+                "name": name,
+                "description": "Respond with the result",
+                "input_schema": schema,
+            }]);
+            json_obj["tool_choice"] = serde_json::json!({ "type": "tool", "name": name });
+        }
+
+        serde_json::to_string(&json_obj).map_err(|err| {
             BackendError::GenericError(format!(
                 "failed to serialize anthropic completion request: {err}"
             ))
@@ -363,41 +987,82 @@ impl Provider {
     }
 
     fn transform_messages_for_anthropic(&self, messages: &[Message]) -> Vec<serde_json::Value> {
-        messages
-            .iter()
-            .filter(|m| m.role != MessageRole::System)
-            .map(|m| {
-                let transformed_content = m
+        let mut transformed: Vec<serde_json::Value> = vec![];
+        for m in messages.iter().filter(|m| m.role != MessageRole::System) {
+            if m.role == MessageRole::Tool {
+                // tool results are user messages with tool_result blocks, results of
+                // parallel calls have to be in the same message
+                let result = m
                     .content
                     .iter()
-                    .map(|content| match content {
-                        MessageContent::Text(text_content) => {
-                            serde_json::json!({
-                                "type": "text",
-                                "text": text_content.text
-                            })
+                    .map(|c| c.get_content())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let block = serde_json::json!({
+                    "type": "tool_result",
+                    "tool_use_id": m.tool_call_id,
+                    "content": result,
+                });
+                match transformed.last_mut() {
+                    Some(last)
+                        if last["role"] == "user"
+                            && last["content"][0]["type"] == "tool_result" =>
+                    {
+                        if let Some(content) = last["content"].as_array_mut() {
+                            content.push(block);
                         }
-                        MessageContent::Image(image_content) => {
-                            let (media_type, base64_data) =
-                                self.extract_image_data(&image_content.image_url.url);
-                            serde_json::json!({
-                                "type": "image",
-                                "source": {
-                                    "type": "base64",
-                                    "media_type": media_type,
-                                    "data": base64_data
-                                }
-                            })
+                    }
+                    _ => transformed.push(serde_json::json!({
+                        "role": "user",
+                        "content": [block],
+                    })),
+                }
+                continue;
+            }
+            transformed.push(self.transform_message_for_anthropic(m));
+        }
+        transformed
+    }
+
+    fn transform_message_for_anthropic(&self, m: &Message) -> serde_json::Value {
+        let mut transformed_content = m
+            .content
+            .iter()
+            .map(|content| match content {
+                MessageContent::Text(text_content) => {
+                    serde_json::json!({
+                        "type": "text",
+                        "text": text_content.text
+                    })
+                }
+                MessageContent::Image(image_content) => {
+                    let (media_type, base64_data) =
+                        self.extract_image_data(&image_content.image_url.url);
+                    serde_json::json!({
+                        "type": "image",
+                        "source": {
+                            "type": "base64",
+                            "media_type": media_type,
+                            "data": base64_data
                         }
                     })
-                    .collect::<Vec<_>>();
-
-                serde_json::json!({
-                    "role": m.role.to_string(),
-                    "content": transformed_content
-                })
+                }
             })
-            .collect()
+            .collect::<Vec<_>>();
+        transformed_content.extend(m.tool_calls.iter().map(|call| {
+            serde_json::json!({
+                "type": "tool_use",
+                "id": call.id,
+                "name": call.function.name,
+                "input": serde_json::from_str::<serde_json::Value>(&call.function.arguments)
+                    .unwrap_or_else(|_| serde_json::json!({})),
+            })
+        }));
+
+        serde_json::json!({
+            "role": m.role.to_string(),
+            "content": transformed_content
+        })
     }
 
     fn extract_image_data<'a>(&self, url: &'a str) -> (&'a str, &'a str) {
@@ -409,6 +1074,19 @@ impl Provider {
         }
     }
 
+    /// Anthropic has no response format, json schemas are sent as a tool the
+    /// model is forced to call instead.
+    fn forced_tool_schema<'a>(
+        &self,
+        response_format: Option<&'a serde_json::Value>,
+        tools: &[ToolDefinition],
+    ) -> Option<(&'a str, &'a serde_json::Value)> {
+        match self {
+            Self::Anthropic if tools.is_empty() => structured::json_schema(response_format),
+            _ => None,
+        }
+    }
+
     fn add_response_format_if_needed(
         &self,
         mut messages: Vec<Message>,
@@ -432,48 +1110,140 @@ impl Provider {
         use response_types::*;
 
         if let Ok(error) = serde_json::from_str::<ChatCompletionChunkErrorResponse>(data) {
-            return Err(BackendError::LLMClientError {
-                r#type: error.error.r#type,
-                message: error.error.message,
-            });
+            return Err(error.error.into());
+        }
+        match self {
+            Self::Mistral => match serde_json::from_str::<mistral::ErrorResponse>(data) {
+                Ok(error) if error.object == "error" => Err(BackendError::LLMClientError {
+                    r#type: error.r#type.unwrap_or_else(|| "error".to_string()),
+                    message: match error.message {
+                        serde_json::Value::String(message) => message,
+                        message => message.to_string(),
+                    },
+                }),
+                _ => Ok(()),
+            },
+            Self::Ollama(_) => ollama::parse_error(data),
+            _ => Ok(()),
         }
-
-        Ok(())
     }
 
-    fn parse_response_chunk(&self, data: &str, delta: bool) -> BackendResult<Option<String>> {
+    fn parse_stream_chunk(
+        &self,
+        data: &str,
+        usage: &mut TokenUsage,
+        tool_calls: &mut usize,
+    ) -> BackendResult<Vec<ChatCompletionChunk>> {
         self.parse_potential_error(data)?;
 
         use response_types::*;
+        let mut chunks = vec![];
         match self {
-            Self::OpenAI | Self::Google | Self::Custom(_) => {
+            Self::OpenAI
+            | Self::Google
+            | Self::Custom(_)
+            | Self::OpenRouter
+            | Self::Mistral
+            | Self::Azure { .. } => {
                 let resp = serde_json::from_str::<openai::ChatCompletionChunkResponse>(data)
                     .map_err(|e| {
                         BackendError::GenericError(format!("failed to parse openai response: {e}"))
                     })?;
 
-                Ok(resp.choices.first().and_then(|choice| {
-                    if delta {
-                        choice.delta.clone().and_then(|d| d.content)
-                    } else {
-                        choice.message.clone().map(|m| m.content)
+                if let Some(chunk_usage) = resp.usage {
+                    usage.merge(chunk_usage.into());
+                }
+                let delta = match resp.choices.into_iter().next().and_then(|c| c.delta) {
+                    Some(delta) => delta,
+                    None => return Ok(chunks),
+                };
+                if let Some(content) = delta.content.filter(|c| !c.is_empty()) {
+                    chunks.push(ChatCompletionChunk::Text(content));
+                }
+                for (i, call) in delta.tool_calls.into_iter().enumerate() {
+                    let function = call.function;
+                    chunks.push(ChatCompletionChunk::ToolCall(ToolCallChunk {
+                        index: call.index.unwrap_or(i),
+                        id: call.id,
+                        name: function.as_ref().and_then(|f| f.name.clone()),
+                        arguments: function.and_then(|f| f.arguments).unwrap_or_default(),
+                    }));
+                }
+            }
+            Self::Ollama(_) => return ollama::parse_stream_chunk(data, usage, tool_calls),
+            Self::Anthropic => {
+                let chunk =
+                    serde_json::from_str::<anthropic::ChunkResponse>(data).map_err(|e| {
+                        BackendError::GenericError(format!(
+                            "failed to parse anthropic response: {e}"
+                        ))
+                    })?;
+                let index = chunk.index.unwrap_or_default();
+
+                let chunk_usage = chunk
+                    .message
+                    .and_then(|message| message.usage)
+                    .or(chunk.usage);
+                if let Some(chunk_usage) = chunk_usage {
+                    usage.merge((&chunk_usage).into());
+                }
+
+                if let Some(block) = chunk.content_block.filter(|b| b.r#type == "tool_use") {
+                    chunks.push(ChatCompletionChunk::ToolCall(ToolCallChunk {
+                        index,
+                        id: block.id,
+                        name: block.name,
+                        arguments: String::new(),
+                    }));
+                }
+                if let Some(delta) = chunk.delta {
+                    if let Some(text) = delta.text {
+                        chunks.push(ChatCompletionChunk::Text(text));
+                    }
+                    if let Some(arguments) = delta.partial_json {
+                        chunks.push(ChatCompletionChunk::ToolCall(ToolCallChunk {
+                            index,
+                            arguments,
+                            ..Default::default()
+                        }));
                     }
-                }))
+                }
             }
-            Self::Anthropic => serde_json::from_str::<anthropic::ChunkResponse>(data)
-                .map_err(|e| {
-                    BackendError::GenericError(format!("failed to parse anthropic response: {e}"))
-                })
-                .map(|chunk| chunk.delta.and_then(|d| d.text)),
         }
+        Ok(chunks)
     }
 
-    fn parse_response(&self, data: &str) -> BackendResult<Option<String>> {
+    fn parse_response(&self, data: &str) -> BackendResult<ChatCompletionResponse> {
         self.parse_potential_error(data)?;
 
         use response_types::*;
         match self {
-            Self::OpenAI | Self::Google | Self::Custom(_) => self.parse_response_chunk(data, false),
+            Self::OpenAI
+            | Self::Google
+            | Self::Custom(_)
+            | Self::OpenRouter
+            | Self::Mistral
+            | Self::Azure { .. } => {
+                let resp = serde_json::from_str::<openai::ChatCompletionChunkResponse>(data)
+                    .map_err(|e| {
+                        BackendError::GenericError(format!("failed to parse openai response: {e}"))
+                    })?;
+
+                let mut response = resp
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.message)
+                    .map(|message| ChatCompletionResponse {
+                        content: message.content.unwrap_or_default(),
+                        tool_calls: message.tool_calls,
+                        ..Default::default()
+                    })
+                    .unwrap_or_default();
+                response.usage = resp.usage.map(TokenUsage::from);
+                Ok(response)
+            }
+            Self::Ollama(_) => ollama::parse_response(data),
             Self::Anthropic => {
                 match serde_json::from_str::<anthropic::Response>(data).map_err(|e| {
                     BackendError::GenericError(format!("failed to parse anthropic response: {e}"))
@@ -482,11 +1252,32 @@ impl Provider {
                         "error response from anthropic: {err:?}"
                     ))),
                     anthropic::Response::Message(message) => {
-                        message.content.first().map(|c| Some(c.text.clone())).ok_or(
-                            BackendError::GenericError(
+                        if message.content.is_empty() {
+                            return Err(BackendError::GenericError(
                                 "no content found in anthropic response".to_owned(),
-                            ),
-                        )
+                            ));
+                        }
+                        let mut response = ChatCompletionResponse {
+                            usage: Some((&message.usage).into()),
+                            ..Default::default()
+                        };
+                        for content in message.content {
+                            match content.r#type.as_str() {
+                                "tool_use" => response.tool_calls.push(ToolCall {
+                                    id: content.id.unwrap_or_default(),
+                                    r#type: "function".to_string(),
+                                    function: FunctionCall {
+                                        name: content.name.unwrap_or_default(),
+                                        arguments: content
+                                            .input
+                                            .unwrap_or_else(|| serde_json::json!({}))
+                                            .to_string(),
+                                    },
+                                }),
+                                _ => response.content.push_str(&content.text),
+                            }
+                        }
+                        Ok(response)
                     }
                 }
             }
@@ -495,13 +1286,49 @@ impl Provider {
 }
 
 impl Model {
+    fn info(&self) -> Option<catalog::ModelInfo> {
+        catalog::model_info(&self.as_str(), Some(self.provider().name()))
+    }
+
+    /// Whether the model can be passed native tool definitions.
+    pub fn supports_tools(&self) -> bool {
+        match (self, self.info()) {
+            (_, Some(info)) => info.tools,
+            // not every openai compatible server or local model implements tools
+            (Self::Custom { provider, .. }, None) => {
+                !matches!(provider, Provider::Custom(_) | Provider::Ollama(_))
+            }
+            (_, None) => true,
+        }
+    }
+
     fn supports_images(&self) -> bool {
         match self {
-            Self::Claude35Haiku => false,
-            // TODO: *some* custom models will support image input
-            // make this configurable from the frontend
+            // configured by the user
             Self::Custom { vision, .. } => *vision,
-            _ => true,
+            _ => self.info().map(|info| info.vision).unwrap_or(true),
+        }
+    }
+
+    /// Tokens asked for in a completion. The catalog's output limit only lowers
+    /// it: the 64000 or more of the newer models would be taken from the
+    /// context for answers that rarely need a few thousand, and a non streamed
+    /// completion that long doesn't finish within `REQUEST_TIMEOUT`.
+    fn completion_tokens(&self) -> usize {
+        self.info()
+            .map(|info| info.max_output_tokens.min(MAX_OUTPUT_TOKENS))
+            .unwrap_or(MAX_OUTPUT_TOKENS)
+    }
+
+    /// The cheapest built-in model of the same provider, for background tasks
+    /// like titling a chat. Custom models are used as they are.
+    pub fn small_model(&self) -> Model {
+        match (self, self.provider()) {
+            (Self::Custom { .. }, _) => self.clone(),
+            (_, Provider::OpenAI) => Self::GPT4_1Mini,
+            (_, Provider::Anthropic) => Self::Claude35Haiku,
+            (_, Provider::Google) => Self::Gemini20Flash,
+            _ => self.clone(),
         }
     }
 
@@ -548,125 +1375,345 @@ impl Model {
 impl TokenModel for Model {
     fn max_tokens(&self) -> usize {
         match self {
-            // NOTE: actual is 1M for gpt4.1
-            Self::GPT5 | Self::GPT5_Mini => 900_000,
-            Self::GPT4_1 => 900_000,
-            Self::GPT4_1Mini => 900_000,
-            Self::GPT4o => 128_000,
-            Self::GPT4oMini => 128_000,
-            Self::O3Mini => 128_000,
-            // TODO: verify if 200k tokens is correct for Claude models
-            Self::Claude45Sonnet
-            | Self::Claude4Sonnet
-            | Self::Claude37Sonnet
-            | Self::Claude35Sonnet
-            | Self::Claude35Haiku => 200_000,
-            // NOTE: actual is 1M for gemini-2.0-flash
-            Self::Gemini20Flash => 900_000,
+            // configured by the user
             Self::Custom { max_tokens, .. } => *max_tokens,
+            _ => self
+                .info()
+                .map(|info| info.context_window)
+                .unwrap_or(catalog::DEFAULT_CONTEXT_WINDOW),
         }
     }
-}
-
-impl Iterator for ChatCompletionStream {
-    type Item = BackendResult<String>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.buffer.clear();
-
-        match self.reader.read_line(&mut self.buffer) {
-            Ok(0) => None,
-            Ok(_) => {
-                self.buffer = self.buffer.trim().to_string();
-                if self.buffer.is_empty() {
-                    return self.next();
-                }
-
-                let data = match self.buffer.strip_prefix("data: ") {
-                    None => return self.next(),
-                    Some("[DONE]") => return None,
-                    Some(data) => data,
-                };
-
-                match self.provider.parse_response_chunk(data, true).transpose() {
-                    Some(Ok(content)) => {
-                        self.wait_for_next_update();
-                        Some(Ok(content))
-                    }
-                    Some(Err(e)) => Some(Err(e)),
-                    None => self.next(),
-                }
-            }
-            Err(e) => Some(Err(BackendError::GenericError(e.to_string()))),
-        }
+    fn tokenizer(&self) -> &'static dyn Tokenizer {
+        tokens::tokenizer_for_provider(self.provider())
     }
 }
 
 impl LLMClient {
     pub fn new() -> BackendResult<Self> {
         Ok(Self {
-            client: reqwest::blocking::Client::builder()
-                .timeout(std::time::Duration::from_secs(300))
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .pool_idle_timeout(Duration::from_secs(90))
+                .tcp_keepalive(Duration::from_secs(60))
                 .build()?,
+            retry_policy: RetryPolicy::default(),
+            usage_recorder: None,
+            response_cache: None,
+            base_urls: HashMap::new(),
         })
     }
 
+    /// Sends requests for `provider` to `base_url` instead of its public api, e.g.
+    /// a proxy. Ignored for custom providers which are configured with a full url.
+    pub fn with_base_url(mut self, provider: &Provider, base_url: &str) -> Self {
+        self.base_urls
+            .insert(provider.name(), base_url.trim_end_matches('/').to_string());
+        self
+    }
+
+    fn base_url(&self, provider: &Provider) -> Option<String> {
+        self.base_urls.get(provider.name()).cloned()
+    }
+
+    /// Policy of the requests that don't bring their own.
+    #[cfg(test)]
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Records the token usage of every completion made with this client.
+    pub fn with_usage_recorder(mut self, usage_recorder: UsageRecorder) -> Self {
+        self.usage_recorder = Some(usage_recorder);
+        self
+    }
+
+    /// Serves requests marked as cacheable from `response_cache` when possible.
+    pub fn with_response_cache(mut self, response_cache: ResponseCache) -> Self {
+        self.response_cache = Some(response_cache);
+        self
+    }
+
+    pub fn response_cache(&self) -> Option<&ResponseCache> {
+        self.response_cache.as_ref()
+    }
+
     #[tracing::instrument(level = "trace", skip(self, messages, response_format))]
-    pub fn create_chat_completion(
+    pub async fn create_chat_completion(
         &self,
         messages: Vec<Message>,
         model: &Model,
         custom_key: Option<String>,
         response_format: Option<serde_json::Value>,
     ) -> BackendResult<String> {
-        let provider = model.provider();
-        let response = self.send_completion_request(
-            messages,
-            model,
-            custom_key,
-            response_format.as_ref(),
-            false,
-        )?;
+        let request = ChatCompletionRequest::new(messages, model)
+            .with_custom_key(custom_key)
+            .with_response_format(response_format);
 
-        self.handle_completion_response(response, provider, response_format.is_some())
+        self.complete(request)
+            .await
+            .map(|response| response.content)
     }
 
-    #[tracing::instrument(level = "trace", skip(self, messages, response_format))]
-    pub fn create_streaming_chat_completion(
+    /// Completion where the model may natively call any of `tools`.
+    #[tracing::instrument(level = "trace", skip(self, messages, tools))]
+    pub async fn create_chat_completion_with_tools(
+        &self,
+        messages: Vec<Message>,
+        model: &Model,
+        custom_key: Option<String>,
+        tools: &[ToolDefinition],
+    ) -> BackendResult<ChatCompletionResponse> {
+        let request = ChatCompletionRequest::new(messages, model)
+            .with_custom_key(custom_key)
+            .with_tools(tools.to_vec());
+
+        self.complete(request).await
+    }
+
+    #[tracing::instrument(
+        level = "trace",
+        skip(self, messages, response_format, cancellation_token)
+    )]
+    pub async fn create_streaming_chat_completion(
         &self,
         messages: Vec<Message>,
         model: &Model,
         custom_key: Option<String>,
         response_format: Option<serde_json::Value>,
+        cancellation_token: CancellationToken,
     ) -> BackendResult<ChatCompletionStream> {
-        let response = self.send_completion_request(
-            messages,
-            model,
+        let request = ChatCompletionRequest::new(messages, model)
+            .with_custom_key(custom_key)
+            .with_response_format(response_format);
+
+        self.complete_streaming(request, cancellation_token).await
+    }
+
+    /// Streaming completion where the model may natively call any of `tools`, read
+    /// the stream with `ChatCompletionStream::next_chunk` to get the calls.
+    #[tracing::instrument(level = "trace", skip(self, messages, tools, cancellation_token))]
+    pub async fn create_streaming_chat_completion_with_tools(
+        &self,
+        messages: Vec<Message>,
+        model: &Model,
+        custom_key: Option<String>,
+        tools: &[ToolDefinition],
+        cancellation_token: CancellationToken,
+    ) -> BackendResult<ChatCompletionStream> {
+        let request = ChatCompletionRequest::new(messages, model)
+            .with_custom_key(custom_key)
+            .with_tools(tools.to_vec());
+
+        self.complete_streaming(request, cancellation_token).await
+    }
+
+    #[tracing::instrument(level = "trace", skip_all, fields(model = ?request.model))]
+    pub async fn complete(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> BackendResult<ChatCompletionResponse> {
+        let cache = self.response_cache_entry(&request)?;
+        if let Some((cache, key)) = cache.as_ref() {
+            match cache.get(key) {
+                Ok(Some((content, model))) => {
+                    return Ok(ChatCompletionResponse {
+                        content,
+                        model: Some(model),
+                        ..Default::default()
+                    })
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("failed to read cached llm response: {}", e),
+            }
+        }
+
+        let started_at = Instant::now();
+        let (response, model, custom_key) = self.send_with_fallbacks(&request, false, None).await?;
+
+        let mut response = self
+            .handle_completion_response(response, model.provider(), &request)
+            .await?;
+        if let Some(usage_recording) = self.usage_recording(&request, custom_key, started_at) {
+            usage_recording.record(&model, response.usage.unwrap_or_default(), false);
+        }
+        if let Some((cache, key)) = cache {
+            if let Err(e) = cache.put(&key, &model, &response.content) {
+                tracing::warn!("failed to cache llm response: {}", e);
+            }
+        }
+        response.model = Some(model);
+        Ok(response)
+    }
+
+    // the cache and the key of the request if its response may be cached
+    fn response_cache_entry(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> BackendResult<Option<(&ResponseCache, String)>> {
+        match self.response_cache.as_ref() {
+            Some(cache) if request.cacheable && request.tools.is_empty() => {
+                let base_url = self.base_url(request.model.provider());
+                Ok(Some((
+                    cache,
+                    ResponseCache::key(request, base_url.as_deref())?,
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Streams the completion, retries and fallbacks only apply until the provider
+    /// starts responding.
+    #[tracing::instrument(level = "trace", skip_all, fields(model = ?request.model))]
+    pub async fn complete_streaming(
+        &self,
+        request: ChatCompletionRequest,
+        cancellation_token: CancellationToken,
+    ) -> BackendResult<ChatCompletionStream> {
+        let started_at = Instant::now();
+        let (response, model, custom_key) = until_cancelled(
+            &cancellation_token,
+            self.send_with_fallbacks(&request, true, Some(&cancellation_token)),
+        )
+        .await?;
+
+        Ok(
+            ChatCompletionStream::new(response, model, cancellation_token)
+                .with_usage_recording(self.usage_recording(&request, custom_key, started_at)),
+        )
+    }
+
+    fn usage_recording(
+        &self,
+        request: &ChatCompletionRequest,
+        custom_key: bool,
+        started_at: Instant,
+    ) -> Option<UsageRecording> {
+        Some(UsageRecording {
+            recorder: self.usage_recorder.clone()?,
+            tag: request.usage_tag.clone(),
             custom_key,
-            response_format.as_ref(),
-            true,
-        )?;
+            started_at,
+        })
+    }
 
-        self.handle_streaming_response(response, model.provider())
+    fn check_tools_supported(model: &Model, tools: &[ToolDefinition]) -> BackendResult<()> {
+        if !tools.is_empty() && !model.supports_tools() {
+            return Err(BackendError::GenericError(format!(
+                "model {} does not support native tool calls",
+                model.as_str()
+            )));
+        }
+        Ok(())
     }
 
-    fn send_completion_request(
+    async fn send_with_fallbacks(
+        &self,
+        request: &ChatCompletionRequest,
+        stream: bool,
+        cancellation_token: Option<&CancellationToken>,
+    ) -> BackendResult<(Response, Model, bool)> {
+        Self::check_tools_supported(&request.model, &request.tools)?;
+
+        let candidates = std::iter::once((&request.model, &request.custom_key))
+            .chain(request.fallbacks.iter().map(|(model, key)| (model, key)));
+        let mut last_error = None;
+        for (model, custom_key) in candidates {
+            if let Err(e) = Self::check_tools_supported(model, &request.tools) {
+                tracing::warn!("skipping fallback model: {}", e);
+                continue;
+            }
+            if let Some(error) = last_error.take() {
+                tracing::warn!("falling back to model {:?} after: {}", model, error);
+            }
+
+            match self
+                .send_with_retries(request, model, custom_key, stream, cancellation_token)
+                .await
+            {
+                // whether the answering model was called with a user supplied key
+                Ok(response) => return Ok((response, model.clone(), custom_key.is_some())),
+                Err(failure) if failure.transient => last_error = Some(failure.error),
+                Err(failure) => return Err(failure.error),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            BackendError::GenericError("no model available for the completion".to_string())
+        }))
+    }
+
+    async fn send_with_retries(
+        &self,
+        request: &ChatCompletionRequest,
+        model: &Model,
+        custom_key: &Option<String>,
+        stream: bool,
+        cancellation_token: Option<&CancellationToken>,
+    ) -> Result<Response, RequestFailure> {
+        let retry_policy = request.retry_policy.as_ref().unwrap_or(&self.retry_policy);
+        let mut attempt = 0;
+        loop {
+            let failure = match self
+                .send_completion_request(
+                    request.messages.clone(),
+                    model,
+                    custom_key.clone(),
+                    request.response_format.as_ref(),
+                    &request.tools,
+                    stream,
+                )
+                .await
+            {
+                Ok(response) => return Ok(response),
+                Err(failure) => failure,
+            };
+            if !failure.transient || attempt >= retry_policy.max_retries {
+                return Err(failure);
+            }
+
+            let delay = retry_policy.delay(attempt, failure.retry_after);
+            tracing::warn!(
+                "completion request failed (attempt {}), retrying in {:?}: {}",
+                attempt + 1,
+                delay,
+                failure.error
+            );
+            match cancellation_token {
+                Some(token) => {
+                    until_cancelled(token, async {
+                        tokio::time::sleep(delay).await;
+                        Ok(())
+                    })
+                    .await?
+                }
+                None => tokio::time::sleep(delay).await,
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn send_completion_request(
         &self,
         messages: Vec<Message>,
         model: &Model,
         custom_key: Option<String>,
         response_format: Option<&serde_json::Value>,
+        tools: &[ToolDefinition],
         stream: bool,
-    ) -> BackendResult<Response> {
+    ) -> Result<Response, RequestFailure> {
         let messages = truncate_messages(filter_unsupported_content(messages, model), model);
         let provider = model.provider();
-        let (url, headers) = provider.get_request_params(custom_key)?;
+        let (url, headers) = provider.get_request_params(custom_key, self.base_url(provider))?;
         let body = provider.prepare_completion_request(
             &model.as_str(),
             stream,
-            8192,
+            model.completion_tokens() as i32,
             &messages,
             response_format,
+            tools,
         )?;
 
         let mut builder = self.client.post(&url);
@@ -677,8 +1724,11 @@ impl LLMClient {
                 }
             }
         }
+        if !stream {
+            builder = builder.timeout(REQUEST_TIMEOUT);
+        }
 
-        let response = builder.body(body).send()?;
+        let response = builder.body(body).send().await?;
         tracing::debug!(
             "completion request - url: {:?}, stream: {}, status: {:?}, model: {:?}",
             url,
@@ -689,54 +1739,787 @@ impl LLMClient {
 
         if let Err(err) = response.error_for_status_ref() {
             if let Some(status) = err.status() {
+                let retry_after = parse_retry_after(response.headers());
                 if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
-                    return Err(BackendError::LLMClientErrorTooManyRequests);
+                    return Err(RequestFailure::transient(
+                        BackendError::LLMClientErrorTooManyRequests,
+                        retry_after,
+                    ));
+                }
+                // overloaded or otherwise broken providers, anthropic uses 529
+                if status.is_server_error() {
+                    return Err(RequestFailure::transient(err.into(), retry_after));
                 }
                 // TODO: are there other cases of bad request
                 if status == reqwest::StatusCode::BAD_REQUEST {
-                    return Err(BackendError::LLMClientErrorBadRequest(response.text()?));
+                    return Err(
+                        BackendError::LLMClientErrorBadRequest(response.text().await?).into(),
+                    );
                 }
                 if status == reqwest::StatusCode::UNAUTHORIZED {
-                    return Err(BackendError::LLMClientErrorUnauthorized);
+                    return Err(BackendError::LLMClientErrorUnauthorized.into());
                 }
                 if status.is_client_error() {
-                    let error_text = response.text()?;
+                    let error_text = response.text().await?;
                     model.provider().parse_potential_error(&error_text)?;
                 }
             }
-            return Err(BackendError::ReqwestError(err));
+            return Err(BackendError::ReqwestError(err).into());
         }
 
         Ok(response)
     }
 
-    fn handle_completion_response(
+    async fn handle_completion_response(
         &self,
         response: Response,
         provider: &Provider,
-        has_response_format: bool,
-    ) -> BackendResult<String> {
-        let resp = provider
-            .parse_response(&response.text()?)
-            .map(|m| m.unwrap_or_default());
-
-        match provider {
-            Provider::Anthropic | Provider::Custom(_) if has_response_format => {
-                resp.map(|r| format!("{{{r}"))
+        request: &ChatCompletionRequest,
+    ) -> BackendResult<ChatCompletionResponse> {
+        let mut resp = provider.parse_response(&response.text().await?)?;
+        let response_format = request.response_format.as_ref();
+
+        if provider
+            .forced_tool_schema(response_format, &request.tools)
+            .is_some()
+        {
+            // the arguments of the forced tool call are the structured reply
+            if !resp.tool_calls.is_empty() {
+                resp.content = resp.tool_calls.remove(0).function.arguments;
+                resp.tool_calls.clear();
             }
-            _ => resp,
+        } else if response_format.is_some()
+            && matches!(provider, Provider::Anthropic | Provider::Custom(_))
+        {
+            // the reply continues the prefilled `{`
+            resp.content = format!("{{{}", resp.content);
         }
+        Ok(resp)
     }
+}
 
-    fn handle_streaming_response(
-        &self,
-        response: Response,
-        provider: &Provider,
-    ) -> BackendResult<ChatCompletionStream> {
-        Ok(ChatCompletionStream::new(
-            BufReader::new(response),
-            provider.clone(),
-            120,
+// races `future` against the token, dropping the future aborts its request
+async fn until_cancelled<T>(
+    cancellation_token: &CancellationToken,
+    future: impl Future<Output = BackendResult<T>>,
+) -> BackendResult<T> {
+    tokio::select! {
+        _ = cancellation_token.cancelled() => Err(BackendError::CancelledError),
+        result = future => result,
+    }
+}
+
+impl ChatCompletionProvider for LLMClient {
+    fn create_chat_completion<'a>(
+        &'a self,
+        messages: Vec<Message>,
+        model: &'a Model,
+        custom_key: Option<&'a str>,
+        response_format: Option<serde_json::Value>,
+    ) -> BoxFuture<'a, BackendResult<String>> {
+        Box::pin(LLMClient::create_chat_completion(
+            self,
+            messages,
+            model,
+            custom_key.map(String::from),
+            response_format,
+        ))
+    }
+
+    fn complete_streaming<'a>(
+        &'a self,
+        request: ChatCompletionRequest,
+        cancellation_token: CancellationToken,
+    ) -> BoxFuture<'a, BackendResult<ChatCompletionStream>> {
+        Box::pin(LLMClient::complete_streaming(
+            self,
+            request,
+            cancellation_token,
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::models::LLMModelPrice;
+    use discovery::ProviderModel;
+    use mock_server::MockServer;
+
+    fn collect_tool_calls(provider: &Provider, events: &[&str]) -> (String, Vec<ToolCall>) {
+        let mut text = String::new();
+        let mut builder = ToolCallBuilder::default();
+        let mut usage = TokenUsage::default();
+        let mut tool_calls = 0;
+        for event in events {
+            for chunk in provider
+                .parse_stream_chunk(event, &mut usage, &mut tool_calls)
+                .unwrap()
+            {
+                match chunk {
+                    ChatCompletionChunk::Text(t) => text.push_str(&t),
+                    ChatCompletionChunk::ToolCall(call) => builder.push(call),
+                }
+            }
+        }
+        (text, builder.finish())
+    }
+
+    #[tokio::test]
+    async fn test_stream_lines_split_across_reads() {
+        let body = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"grüße\"}}]}\n\ndata: [DONE]\n";
+        let bytes = body.as_bytes();
+        // split inside the multi byte ü
+        let split = body.find('ü').unwrap() + 1;
+        let reads = vec![
+            Ok(bytes::Bytes::copy_from_slice(&bytes[..split])),
+            Ok(bytes::Bytes::copy_from_slice(&bytes[split..])),
+        ];
+        let mut stream = ChatCompletionStream::from_bytes(
+            futures::stream::iter(reads),
+            Model::GPT4_1,
+            CancellationToken::new(),
+        );
+
+        assert_eq!(stream.next_text().await.unwrap().unwrap(), "grüße");
+        assert!(stream.next_text().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_cancellation_does_not_wait_for_data() {
+        let token = CancellationToken::new();
+        let mut stream = ChatCompletionStream::from_bytes(
+            futures::stream::pending(),
+            Model::GPT4_1,
+            token.clone(),
+        );
+
+        token.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(1), stream.next_chunk())
+            .await
+            .unwrap();
+        assert!(matches!(result, Some(Err(BackendError::CancelledError))));
+        assert!(stream.next_chunk().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request_stops_emitting() {
+        let body = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"hi\"}}]}\n";
+        let requests = crate::worker::tunnel::RequestCancellations::default();
+        let request = requests.register(Some("request".to_string()));
+        // the provider keeps the connection open after the first chunk
+        let mut stream = ChatCompletionStream::from_bytes(
+            futures::stream::iter(vec![Ok(bytes::Bytes::from_static(body.as_bytes()))])
+                .chain(futures::stream::pending()),
+            Model::GPT4_1,
+            request.token(),
+        );
+
+        assert_eq!(stream.next_text().await.unwrap().unwrap(), "hi");
+        assert!(requests.cancel("request"));
+        let result = tokio::time::timeout(Duration::from_secs(1), stream.next_text())
+            .await
+            .unwrap();
+        assert!(matches!(result, Some(Err(BackendError::CancelledError))));
+        assert!(stream.next_text().await.is_none());
+
+        // the id is free again once the request is done
+        drop(request);
+        assert!(!requests.cancel("request"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_records_usage_when_dropped() {
+        let body = concat!(
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"hi\"}}],\"usage\":null}\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":1200,\"completion_tokens\":3,",
+            "\"prompt_tokens_details\":{\"cached_tokens\":1024}}}\n",
+            "data: [DONE]\n",
+        );
+        let (recorder, usage_rx) = crate::ai::llm::usage::usage_channel();
+        let mut stream = ChatCompletionStream::from_bytes(
+            futures::stream::iter(vec![Ok(bytes::Bytes::from_static(body.as_bytes()))]),
+            Model::GPT4_1,
+            CancellationToken::new(),
+        )
+        .with_usage_recording(Some(UsageRecording {
+            recorder,
+            tag: UsageTag::chat(Some("session".to_string()), None),
+            custom_key: true,
+            started_at: Instant::now(),
+        }));
+
+        assert_eq!(stream.next_text().await.unwrap().unwrap(), "hi");
+        assert!(stream.next_text().await.is_none());
+        assert!(usage_rx.try_recv().is_err());
+        drop(stream);
+
+        let usage = usage_rx.try_recv().unwrap();
+        assert_eq!(usage.model, "gpt-4.1");
+        assert_eq!(usage.provider, "openai");
+        assert_eq!(usage.prompt_tokens, 1200);
+        assert_eq!(usage.completion_tokens, 3);
+        assert_eq!(usage.cached_tokens, 1024);
+        assert!(usage.streamed && usage.custom_key);
+        assert_eq!(usage.session_id.as_deref(), Some("session"));
+        assert!(usage_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_anthropic_stream_usage() {
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"usage":{"input_tokens":10,"cache_creation_input_tokens":0,"cache_read_input_tokens":90,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":25}}"#,
+        ];
+
+        let mut usage = TokenUsage::default();
+        for event in events {
+            Provider::Anthropic
+                .parse_stream_chunk(event, &mut usage, &mut 0)
+                .unwrap();
+        }
+        assert_eq!(
+            usage,
+            TokenUsage {
+                prompt_tokens: 100,
+                completion_tokens: 25,
+                cached_tokens: 90,
+            }
+        );
+    }
+
+    #[test]
+    fn test_openai_streamed_tool_calls() {
+        let events = [
+            r#"{"choices":[{"index":0,"delta":{"content":"Let me look"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"search","arguments":""}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"query\":"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"rust\"}"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":1,"id":"call_2","function":{"name":"noop"}}]}}]}"#,
+        ];
+
+        let (text, calls) = collect_tool_calls(&Provider::OpenAI, &events);
+        assert_eq!(text, "Let me look");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].function.name, "search");
+        assert_eq!(calls[0].function.arguments, r#"{"query":"rust"}"#);
+        assert_eq!(calls[1].function.name, "noop");
+        assert_eq!(calls[1].function.arguments, "{}");
+    }
+
+    #[test]
+    fn test_anthropic_streamed_tool_use() {
+        let events = [
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Searching"}}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"search","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"query\": "}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"\"rust\"}"}}"#,
+            r#"{"type":"message_stop"}"#,
+        ];
+
+        let (text, calls) = collect_tool_calls(&Provider::Anthropic, &events);
+        assert_eq!(text, "Searching");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "toolu_1");
+        assert_eq!(calls[0].function.name, "search");
+        assert_eq!(calls[0].function.arguments, r#"{"query": "rust"}"#);
+    }
+
+    #[test]
+    fn test_anthropic_response_with_tool_use() {
+        let data = r#"{"type":"message","id":"msg_1","model":"claude","role":"assistant","stop_reason":"tool_use","stop_sequence":null,"usage":{"input_tokens":1,"output_tokens":1},"content":[{"type":"text","text":"Calling"},{"type":"tool_use","id":"toolu_1","name":"search","input":{"query":"rust"}}]}"#;
+
+        let response = Provider::Anthropic.parse_response(data).unwrap();
+        assert_eq!(response.content, "Calling");
+        assert_eq!(response.usage.unwrap().prompt_tokens, 1);
+        assert_eq!(response.tool_calls.len(), 1);
+        assert_eq!(response.tool_calls[0].function.name, "search");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&response.tool_calls[0].function.arguments)
+                .unwrap(),
+            serde_json::json!({"query": "rust"})
+        );
+    }
+
+    #[test]
+    fn test_anthropic_json_schema_is_a_forced_tool() {
+        let format = serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {"name": "answer", "schema": {"type": "object"}}
+        });
+        let messages = vec![Message::new_system("system"), Message::new_user("question")];
+
+        let body = Provider::Anthropic
+            .prepare_completion_request("claude", false, 10, &messages, Some(&format), &[])
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["tools"][0]["name"], "answer");
+        assert_eq!(body["tool_choice"]["name"], "answer");
+        // no prefilled assistant message
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+
+        let body = Provider::Anthropic
+            .prepare_completion_request(
+                "claude",
+                false,
+                10,
+                &messages,
+                Some(&serde_json::json!({"type": "json_object"})),
+                &[],
+            )
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body.get("tool_choice").is_none());
+        assert_eq!(body["messages"].as_array().unwrap().len(), 3);
+    }
+
+    fn custom_model(name: &str, provider: Provider) -> Model {
+        Model::Custom {
+            name: name.to_string(),
+            provider,
+            max_tokens: 128_000,
+            vision: false,
+        }
+    }
+
+    fn test_client() -> LLMClient {
+        LLMClient::new()
+            .unwrap()
+            .with_retry_policy(RetryPolicy::none())
+    }
+
+    const OPENAI_RESPONSE: &str = r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"hi"}}],"usage":{"prompt_tokens":5,"completion_tokens":1}}"#;
+
+    #[tokio::test]
+    async fn test_openrouter_and_mistral_requests() {
+        for provider in [Provider::OpenRouter, Provider::Mistral] {
+            let server = MockServer::start(200, OPENAI_RESPONSE).await;
+            let client = test_client().with_base_url(&provider, server.url());
+            let model = custom_model("some/model", provider.clone());
+
+            let response = client
+                .create_chat_completion(
+                    vec![Message::new_user("hello")],
+                    &model,
+                    Some("key".to_string()),
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(response, "hi");
+
+            let request = server.last_request();
+            let path = match provider {
+                Provider::OpenRouter => "/chat/completions",
+                _ => "/v1/chat/completions",
+            };
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, path);
+            assert_eq!(request.headers["authorization"], "Bearer key");
+            assert_eq!(request.json()["model"], "some/model");
+        }
+
+        let model = custom_model("mistral-large-latest", Provider::Mistral);
+        let body = Provider::Mistral
+            .prepare_completion_request(&model.as_str(), true, 10, &[], None, &[])
+            .unwrap();
+        assert!(!body.contains("stream_options"));
+    }
+
+    fn quick_retries(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_responses() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ResponseCache::new(
+            &dir.path().join("cache.db").to_string_lossy(),
+            cache::ResponseCacheConfig::default(),
+        )
+        .unwrap();
+        let server = MockServer::start(200, OPENAI_RESPONSE).await;
+        let client = test_client()
+            .with_base_url(&Provider::OpenRouter, server.url())
+            .with_response_cache(cache);
+        let request = ChatCompletionRequest::new(
+            vec![Message::new_user("hello")],
+            &custom_model("some/model", Provider::OpenRouter),
+        )
+        .with_custom_key(Some("key".to_string()));
+
+        let cached = client.complete(request.clone().cacheable()).await.unwrap();
+        let hit = client.complete(request.clone().cacheable()).await.unwrap();
+        assert_eq!(hit.content, "hi");
+        assert_eq!(hit.model, cached.model);
+        assert_eq!(server.requests().len(), 1);
+        // only requests marked as cacheable are served from the cache
+        client.complete(request.clone()).await.unwrap();
+        assert_eq!(server.requests().len(), 2);
+
+        let stats = client.response_cache().unwrap().stats().unwrap();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 1));
+
+        // another proxy for the same model misses
+        let proxy = MockServer::start(200, OPENAI_RESPONSE).await;
+        let client = client.with_base_url(&Provider::OpenRouter, proxy.url());
+        client.complete(request.cacheable()).await.unwrap();
+        assert_eq!(proxy.requests().len(), 1);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_request_is_retried() {
+        let rate_limited = r#"{"error":{"message":"slow down"}}"#;
+        let server = MockServer::with_sequence(&[
+            (429, rate_limited),
+            (429, rate_limited),
+            (200, OPENAI_RESPONSE),
+        ])
+        .await;
+        let client = test_client().with_base_url(&Provider::OpenRouter, server.url());
+        let request = ChatCompletionRequest::new(
+            vec![Message::new_user("hello")],
+            &custom_model("some/model", Provider::OpenRouter),
+        )
+        .with_custom_key(Some("key".to_string()));
+
+        // the client's policy doesn't retry
+        let err = client.complete(request.clone()).await.unwrap_err();
+        assert!(matches!(err, BackendError::LLMClientErrorTooManyRequests));
+
+        let response = client
+            .complete(request.with_retry_policy(quick_retries(1)))
+            .await
+            .unwrap();
+        assert_eq!(response.content, "hi");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_failing_model_falls_back() {
+        let primary = MockServer::start(500, r#"{"error":{"message":"overloaded"}}"#).await;
+        let fallback = MockServer::start(200, OPENAI_RESPONSE).await;
+        let client = test_client()
+            .with_base_url(&Provider::OpenRouter, primary.url())
+            .with_base_url(&Provider::Mistral, fallback.url());
+        let fallback_model = custom_model("fallback", Provider::Mistral);
+        let request = ChatCompletionRequest::new(
+            vec![Message::new_user("hello")],
+            &custom_model("primary", Provider::OpenRouter),
+        )
+        .with_custom_key(Some("key".to_string()))
+        .with_fallback(fallback_model.clone(), Some("fallback-key".to_string()))
+        .with_retry_policy(quick_retries(1));
+
+        let response = client.complete(request).await.unwrap();
+        assert_eq!(response.content, "hi");
+        assert_eq!(response.model, Some(fallback_model));
+        assert_eq!(primary.requests().len(), 2);
+        let fallback_request = fallback.last_request();
+        assert_eq!(fallback_request.json()["model"], "fallback");
+        assert_eq!(
+            fallback_request.headers["authorization"],
+            "Bearer fallback-key"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fallbacks_are_tried_in_order() {
+        let overloaded = r#"{"error":{"message":"overloaded"}}"#;
+        let primary = MockServer::start(500, overloaded).await;
+        let first = MockServer::start(503, overloaded).await;
+        let second = MockServer::start(200, OPENAI_RESPONSE).await;
+        let client = test_client()
+            .with_base_url(&Provider::OpenRouter, primary.url())
+            .with_base_url(&Provider::Mistral, first.url())
+            .with_base_url(&Provider::OpenAI, second.url());
+        let fallbacks = vec![
+            (
+                custom_model("first", Provider::Mistral),
+                Some("first-key".to_string()),
+            ),
+            (
+                custom_model("second", Provider::OpenAI),
+                Some("second-key".to_string()),
+            ),
+        ];
+        let request = fallbacks.into_iter().fold(
+            ChatCompletionRequest::new(
+                vec![Message::new_user("hello")],
+                &custom_model("primary", Provider::OpenRouter),
+            )
+            .with_custom_key(Some("key".to_string())),
+            |request, (model, custom_key)| request.with_fallback(model, custom_key),
+        );
+
+        let response = client.complete(request).await.unwrap();
+        assert_eq!(response.content, "hi");
+        assert_eq!(
+            response.model,
+            Some(custom_model("second", Provider::OpenAI))
+        );
+        assert_eq!(primary.requests().len(), 1);
+        assert_eq!(first.requests().len(), 1);
+        assert_eq!(first.last_request().json()["model"], "first");
+        let second_request = second.last_request();
+        assert_eq!(second_request.json()["model"], "second");
+        assert_eq!(second_request.headers["authorization"], "Bearer second-key");
+    }
+
+    #[tokio::test]
+    async fn test_azure_deployment_url_and_errors() {
+        let server = MockServer::start(
+            404,
+            r#"{"error":{"code":"DeploymentNotFound","message":"The API deployment for this resource does not exist."}}"#,
+        )
+        .await;
+        let provider = Provider::Azure {
+            endpoint: format!("{}/", server.url()),
+            deployment: "gpt-4o-prod".to_string(),
+            api_version: default_azure_api_version(),
+        };
+
+        let result = test_client()
+            .create_chat_completion(
+                vec![Message::new_user("hello")],
+                &custom_model("gpt-4o", provider),
+                Some("key".to_string()),
+                None,
+            )
+            .await;
+        match result {
+            Err(BackendError::LLMClientError { r#type, .. }) => {
+                assert_eq!(r#type, "DeploymentNotFound")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let request = server.last_request();
+        assert_eq!(
+            request.path,
+            "/openai/deployments/gpt-4o-prod/chat/completions?api-version=2024-10-21"
+        );
+        assert_eq!(request.headers["api-key"], "key");
+        assert!(!request.headers.contains_key("authorization"));
+    }
+
+    #[tokio::test]
+    async fn test_ollama_native_stream() {
+        let body = concat!(
+            "{\"model\":\"llama3.2\",\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n",
+            "{\"model\":\"llama3.2\",\"message\":{\"role\":\"assistant\",\"content\":\"lo\"},\"done\":false}\n",
+            "{\"model\":\"llama3.2\",\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,",
+            "\"prompt_eval_count\":26,\"eval_count\":2}\n",
+        );
+        let server = MockServer::start(200, body).await;
+        let model = custom_model("llama3.2", Provider::Ollama(server.url().to_string()));
+
+        let mut stream = test_client()
+            .create_streaming_chat_completion(
+                vec![Message::new_user("hello")],
+                &model,
+                None,
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let mut text = String::new();
+        while let Some(chunk) = stream.next_text().await {
+            text.push_str(&chunk.unwrap());
+        }
+        assert_eq!(text, "Hello");
+        assert_eq!(
+            stream.usage(),
+            TokenUsage {
+                prompt_tokens: 26,
+                completion_tokens: 2,
+                cached_tokens: 0,
+            }
+        );
+
+        let request = server.last_request();
+        assert_eq!(request.path, "/api/chat");
+        // a local server needs no key
+        assert!(!request.headers.contains_key("authorization"));
+        assert_eq!(request.json()["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn test_ollama_and_mistral_errors() {
+        let server = MockServer::start(
+            404,
+            r#"{"error":"model \"llama9\" not found, try pulling it first"}"#,
+        )
+        .await;
+        let result = test_client()
+            .create_chat_completion(
+                vec![Message::new_user("hello")],
+                &custom_model("llama9", Provider::Ollama(server.url().to_string())),
+                None,
+                None,
+            )
+            .await;
+        match result {
+            Err(BackendError::LLMClientError { message, .. }) => {
+                assert!(message.contains("not found"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let server = MockServer::start(
+            422,
+            r#"{"object":"error","message":"Invalid model: mistral-huge","type":"invalid_model","param":null,"code":"1500"}"#,
+        )
+        .await;
+        let result = test_client()
+            .with_base_url(&Provider::Mistral, server.url())
+            .create_chat_completion(
+                vec![Message::new_user("hello")],
+                &custom_model("mistral-huge", Provider::Mistral),
+                Some("key".to_string()),
+                None,
+            )
+            .await;
+        match result {
+            Err(BackendError::LLMClientError { r#type, message }) => {
+                assert_eq!(r#type, "invalid_model");
+                assert_eq!(message, "Invalid model: mistral-huge");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_openrouter_stream_error_with_numeric_code() {
+        let data = r#"{"error":{"code":502,"message":"Provider returned error"}}"#;
+        let result =
+            Provider::OpenRouter.parse_stream_chunk(data, &mut TokenUsage::default(), &mut 0);
+        match result {
+            Err(BackendError::LLMClientError { r#type, .. }) => assert_eq!(r#type, "502"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_models_with_vision() {
+        let server = MockServer::start(
+            200,
+            r#"{"data":[{"id":"openai/gpt-4o","context_length":128000,"architecture":{"input_modalities":["text","image"]},"top_provider":{"max_completion_tokens":16384},"supported_parameters":["tools"],"pricing":{"prompt":"0.0000025","completion":"0.00001"}},{"id":"deepseek/deepseek-r1","architecture":{"input_modalities":["text"]}}]}"#,
+        )
+        .await;
+        let models = test_client()
+            .with_base_url(&Provider::OpenRouter, server.url())
+            .list_models(&Provider::OpenRouter, Some("key".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            models,
+            vec![
+                ProviderModel {
+                    id: "openai/gpt-4o".to_string(),
+                    vision: Some(true),
+                    context_window: Some(128_000),
+                    max_output_tokens: Some(16_384),
+                    tools: Some(true),
+                    price: Some(LLMModelPrice {
+                        input_per_mtok: 2.5,
+                        output_per_mtok: 10.0,
+                        cached_input_per_mtok: 2.5,
+                    }),
+                },
+                ProviderModel {
+                    id: "deepseek/deepseek-r1".to_string(),
+                    vision: Some(false),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(server.last_request().path, "/models");
+
+        let server = MockServer::with_routes(&[
+            ("/api/tags", 200, r#"{"models":[{"name":"llava:7b"}]}"#),
+            (
+                "/api/show",
+                200,
+                r#"{"capabilities":["completion","vision"],"model_info":{"llama.context_length":131072}}"#,
+            ),
+        ])
+        .await;
+        let models = test_client()
+            .list_models(&Provider::Ollama(server.url().to_string()), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            models,
+            vec![ProviderModel {
+                id: "llava:7b".to_string(),
+                vision: Some(true),
+                tools: Some(false),
+                context_window: Some(131_072),
+                ..Default::default()
+            }]
+        );
+        assert_eq!(server.last_request().json()["model"], "llava:7b");
+    }
+
+    #[test]
+    fn test_anthropic_request_with_tool_results() {
+        let call = |id: &str| ToolCall {
+            id: id.to_string(),
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name: "search".to_string(),
+                arguments: r#"{"query":"rust"}"#.to_string(),
+            },
+        };
+        let messages = vec![
+            Message::new_system("system"),
+            Message::new_user("question"),
+            Message::new_assistant_tool_calls("", vec![call("a"), call("b")]),
+            Message::new_tool_result("a", "first"),
+            Message::new_tool_result("b", "second"),
+        ];
+        let tools = vec![ToolDefinition {
+            name: "search".to_string(),
+            description: "searches".to_string(),
+            parameters: serde_json::json!({"type": "object"}),
+        }];
+
+        let body = Provider::Anthropic
+            .prepare_completion_request("claude", false, 10, &messages, None, &tools)
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"][1]["type"], "tool_use");
+        assert_eq!(messages[1]["content"][1]["input"]["query"], "rust");
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(messages[2]["content"][0]["tool_use_id"], "a");
+        assert_eq!(messages[2]["content"][1]["content"], "second");
+    }
+
+    #[test]
+    fn test_small_model() {
+        assert_eq!(Model::Claude45Sonnet.small_model(), Model::Claude35Haiku);
+        assert_eq!(Model::GPT5.small_model(), Model::GPT4_1Mini);
+        // a custom model of a known provider might be the only one the key can use
+        let model = custom_model(
+            "llama3.1",
+            Provider::Ollama("http://localhost:11434".to_string()),
+        );
+        assert_eq!(model.small_model(), model);
+        let model = custom_model("gpt-4.1", Provider::OpenAI);
+        assert_eq!(model.small_model(), model);
+    }
+}
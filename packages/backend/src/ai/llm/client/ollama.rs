@@ -0,0 +1,364 @@
+//! Ollama's native `/api/chat`, unlike its openai compatible endpoint it takes
+//! json schemas and images directly and streams newline delimited JSON.
+use super::{structured, ChatCompletionChunk, ChatCompletionResponse, Provider, ToolCallChunk};
+use crate::{
+    ai::llm::{
+        models::{FunctionCall, Message, MessageContent, MessageRole, ToolCall, ToolDefinition},
+        usage::TokenUsage,
+    },
+    store::models::random_uuid,
+    BackendError, BackendResult,
+};
+
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct OllamaFunctionCall {
+    name: String,
+    // an object, not a JSON string like openai's
+    #[serde(default)]
+    arguments: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaMessage {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaResponse {
+    message: Option<OllamaMessage>,
+    // only set on the final object of a stream
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+impl OllamaResponse {
+    fn usage(&self) -> Option<TokenUsage> {
+        if self.prompt_eval_count.is_none() && self.eval_count.is_none() {
+            return None;
+        }
+        Some(TokenUsage {
+            prompt_tokens: self.prompt_eval_count.unwrap_or_default(),
+            completion_tokens: self.eval_count.unwrap_or_default(),
+            cached_tokens: 0,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaError {
+    error: String,
+}
+
+// ollama doesn't send ids for tool calls, the results of the calls are matched
+// to them by id so they have to be unique across the turns of a conversation
+fn tool_call_id() -> String {
+    format!("call_{}", random_uuid())
+}
+
+fn arguments_to_string(arguments: serde_json::Value) -> String {
+    match arguments {
+        // some models send the arguments as a JSON string anyway
+        serde_json::Value::String(arguments) => arguments,
+        serde_json::Value::Null => "{}".to_string(),
+        arguments => arguments.to_string(),
+    }
+}
+
+fn transform_messages(provider: &Provider, messages: &[Message]) -> Vec<serde_json::Value> {
+    messages
+        .iter()
+        .map(|m| {
+            let mut images = vec![];
+            let mut text = vec![];
+            for content in m.content.iter() {
+                match content {
+                    MessageContent::Text(text_content) => text.push(text_content.text.as_str()),
+                    MessageContent::Image(image_content) => {
+                        let (_, base64_data) =
+                            provider.extract_image_data(&image_content.image_url.url);
+                        images.push(base64_data);
+                    }
+                }
+            }
+
+            let mut value = serde_json::json!({
+                "role": m.role.to_string(),
+                "content": text.join("\n"),
+            });
+            if !images.is_empty() {
+                value["images"] = serde_json::json!(images);
+            }
+            if !m.tool_calls.is_empty() {
+                value["tool_calls"] = m
+                    .tool_calls
+                    .iter()
+                    .map(|call| {
+                        let arguments =
+                            serde_json::from_str::<serde_json::Value>(&call.function.arguments)
+                                .unwrap_or_else(|_| serde_json::json!({}));
+                        serde_json::json!({
+                            "function": { "name": call.function.name, "arguments": arguments }
+                        })
+                    })
+                    .collect();
+            }
+            if m.role == MessageRole::Tool {
+                // results are matched to calls by the tool's name instead of an id
+                let name = messages
+                    .iter()
+                    .flat_map(|m| m.tool_calls.iter())
+                    .find(|call| Some(&call.id) == m.tool_call_id.as_ref())
+                    .map(|call| call.function.name.as_str());
+                if let Some(name) = name {
+                    value["tool_name"] = serde_json::json!(name);
+                }
+            }
+            value
+        })
+        .collect()
+}
+
+pub(super) fn prepare_request(
+    provider: &Provider,
+    model: &str,
+    stream: bool,
+    max_tokens: i32,
+    messages: &[Message],
+    response_format: Option<&serde_json::Value>,
+    tools: &[ToolDefinition],
+) -> BackendResult<String> {
+    let mut json_obj = serde_json::json!({
+        "model": model,
+        "stream": stream,
+        "messages": transform_messages(provider, messages),
+        "options": { "num_predict": max_tokens },
+    });
+    if let Some(format) = response_format {
+        json_obj["format"] = match structured::json_schema(Some(format)) {
+            Some((_, schema)) => schema.clone(),
+            None => serde_json::json!("json"),
+        };
+    }
+    if !tools.is_empty() {
+        json_obj["tools"] = tools
+            .iter()
+            .map(|tool| {
+                serde_json::json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name,
+                        "description": tool.description,
+                        "parameters": tool.parameters,
+                    }
+                })
+            })
+            .collect();
+    }
+
+    serde_json::to_string(&json_obj).map_err(|err| {
+        BackendError::GenericError(format!(
+            "failed to serialize ollama completion request: {err}"
+        ))
+    })
+}
+
+fn parse(data: &str) -> BackendResult<OllamaResponse> {
+    serde_json::from_str::<OllamaResponse>(data)
+        .map_err(|e| BackendError::GenericError(format!("failed to parse ollama response: {e}")))
+}
+
+/// `tool_calls` counts the calls of the stream so far, they are indexed in the
+/// order they arrive in.
+pub(super) fn parse_stream_chunk(
+    data: &str,
+    usage: &mut TokenUsage,
+    tool_calls: &mut usize,
+) -> BackendResult<Vec<ChatCompletionChunk>> {
+    let resp = parse(data)?;
+    if let Some(chunk_usage) = resp.usage() {
+        usage.merge(chunk_usage);
+    }
+
+    let mut chunks = vec![];
+    let message = match resp.message {
+        Some(message) => message,
+        None => return Ok(chunks),
+    };
+    if !message.content.is_empty() {
+        chunks.push(ChatCompletionChunk::Text(message.content));
+    }
+    // tool calls are sent complete, but may be spread over several objects
+    for call in message.tool_calls {
+        chunks.push(ChatCompletionChunk::ToolCall(ToolCallChunk {
+            index: *tool_calls,
+            id: Some(tool_call_id()),
+            name: Some(call.function.name),
+            arguments: arguments_to_string(call.function.arguments),
+        }));
+        *tool_calls += 1;
+    }
+    Ok(chunks)
+}
+
+pub(super) fn parse_response(data: &str) -> BackendResult<ChatCompletionResponse> {
+    let resp = parse(data)?;
+    let usage = resp.usage();
+    let message = resp.message.ok_or_else(|| {
+        BackendError::GenericError("no message found in ollama response".to_owned())
+    })?;
+
+    Ok(ChatCompletionResponse {
+        content: message.content,
+        tool_calls: message
+            .tool_calls
+            .into_iter()
+            .map(|call| ToolCall {
+                id: tool_call_id(),
+                r#type: "function".to_string(),
+                function: FunctionCall {
+                    name: call.function.name,
+                    arguments: arguments_to_string(call.function.arguments),
+                },
+            })
+            .collect(),
+        usage,
+        ..Default::default()
+    })
+}
+
+pub(super) fn parse_error(data: &str) -> BackendResult<()> {
+    match serde_json::from_str::<OllamaError>(data) {
+        Ok(error) => Err(BackendError::LLMClientError {
+            r#type: "ollama_error".to_string(),
+            message: error.error,
+        }),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_with_images_and_schema() {
+        let format = structured::json_schema_response_format::<bool>();
+        let mut message = Message::new_user("what is this?");
+        message.content.push(MessageContent::new_image(
+            "data:image/png;base64,aGVsbG8=".to_string(),
+        ));
+
+        let provider = Provider::Ollama("http://localhost:11434".to_string());
+        let body = prepare_request(
+            &provider,
+            "llava",
+            true,
+            100,
+            &[message],
+            Some(&format),
+            &[],
+        )
+        .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(body["messages"][0]["content"], "what is this?");
+        assert_eq!(body["messages"][0]["images"][0], "aGVsbG8=");
+        assert_eq!(body["format"]["type"], "boolean");
+        assert_eq!(body["options"]["num_predict"], 100);
+    }
+
+    #[test]
+    fn test_tool_results_carry_the_tool_name() {
+        let messages = vec![
+            Message::new_assistant_tool_calls(
+                "",
+                vec![ToolCall {
+                    id: "call_0".to_string(),
+                    r#type: "function".to_string(),
+                    function: FunctionCall {
+                        name: "search".to_string(),
+                        arguments: r#"{"query":"rust"}"#.to_string(),
+                    },
+                }],
+            ),
+            Message::new_tool_result("call_0", "found"),
+        ];
+
+        let messages =
+            transform_messages(&Provider::Ollama("http://localhost".to_string()), &messages);
+        assert_eq!(
+            messages[0]["tool_calls"][0]["function"]["arguments"]["query"],
+            "rust"
+        );
+        assert_eq!(messages[1]["role"], "tool");
+        assert_eq!(messages[1]["tool_name"], "search");
+    }
+
+    fn tool_call_object(name: &str) -> String {
+        serde_json::json!({
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{"function": {"name": name, "arguments": {"query": name}}}],
+            },
+            "done": false,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_tool_calls_are_indexed_across_objects() {
+        let mut usage = TokenUsage::default();
+        let mut tool_calls = 0;
+        let mut calls = vec![];
+        for name in ["search", "browse"] {
+            for chunk in
+                parse_stream_chunk(&tool_call_object(name), &mut usage, &mut tool_calls).unwrap()
+            {
+                if let ChatCompletionChunk::ToolCall(call) = chunk {
+                    calls.push(call);
+                }
+            }
+        }
+
+        assert_eq!(
+            calls.iter().map(|call| call.index).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(calls[1].name.as_deref(), Some("browse"));
+        assert_ne!(calls[0].id, calls[1].id);
+    }
+
+    #[test]
+    fn test_tool_call_ids_are_unique_across_turns() {
+        let first = parse_response(&tool_call_object("search")).unwrap();
+        let second = parse_response(&tool_call_object("browse")).unwrap();
+        assert_ne!(first.tool_calls[0].id, second.tool_calls[0].id);
+        assert_eq!(
+            first.tool_calls[0].function.arguments,
+            r#"{"query":"search"}"#
+        );
+
+        // every result names the tool of its own turn's call
+        let messages = vec![
+            Message::new_assistant_tool_calls("", first.tool_calls.clone()),
+            Message::new_tool_result(&first.tool_calls[0].id, "first"),
+            Message::new_assistant_tool_calls("", second.tool_calls.clone()),
+            Message::new_tool_result(&second.tool_calls[0].id, "second"),
+        ];
+        let provider = Provider::Ollama("http://localhost".to_string());
+        let transformed = transform_messages(&provider, &messages);
+        assert_eq!(transformed[1]["tool_name"], "search");
+        assert_eq!(transformed[3]["tool_name"], "browse");
+    }
+}
@@ -0,0 +1,258 @@
+use super::{ChatCompletionRequest, LLMClient, Model};
+use crate::{ai::llm::models::Message, BackendError, BackendResult};
+
+use schemars::{gen::SchemaSettings, JsonSchema};
+use serde::de::DeserializeOwned;
+
+// how often a reply that doesn't match the schema is sent back to be fixed
+pub const MAX_STRUCTURED_REPAIRS: usize = 2;
+
+/// `response_format` asking for a JSON object matching the schema of `T`.
+///
+/// Subschemas are inlined since not every provider resolves references.
+pub fn json_schema_response_format<T: JsonSchema>() -> serde_json::Value {
+    let schema = SchemaSettings::draft07()
+        .with(|settings| settings.inline_subschemas = true)
+        .into_generator()
+        .into_root_schema_for::<T>();
+    let mut schema = serde_json::json!(schema);
+    if let Some(schema) = schema.as_object_mut() {
+        schema.remove("$schema");
+        schema.remove("title");
+    }
+
+    // openai only allows a limited set of characters in the name
+    let name: String = T::schema_name()
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    serde_json::json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": name,
+            // strict mode rejects schemas with optional properties
+            "strict": false,
+            "schema": schema,
+        }
+    })
+}
+
+/// The name and schema of a `json_schema` response format.
+pub(super) fn json_schema(
+    response_format: Option<&serde_json::Value>,
+) -> Option<(&str, &serde_json::Value)> {
+    let format = response_format?;
+    if format["type"] != "json_schema" {
+        return None;
+    }
+    let json_schema = &format["json_schema"];
+    Some((json_schema["name"].as_str()?, json_schema.get("schema")?))
+}
+
+// models like to wrap JSON in markdown code fences even when told not to
+fn strip_code_fence(content: &str) -> &str {
+    let content = content.trim();
+    match content.strip_prefix("```") {
+        Some(fenced) => {
+            let fenced = fenced.trim_start_matches("json");
+            fenced.strip_suffix("```").unwrap_or(fenced).trim()
+        }
+        None => content,
+    }
+}
+
+fn parse_structured<T: DeserializeOwned>(content: &str) -> Result<T, String> {
+    serde_json::from_str(strip_code_fence(content)).map_err(|e| e.to_string())
+}
+
+impl LLMClient {
+    /// Completion parsed into `T`, the model is given the JSON schema of `T` and
+    /// replies that don't match it are sent back to be fixed.
+    #[tracing::instrument(level = "trace", skip(self, messages))]
+    pub async fn create_structured_completion<T: DeserializeOwned + JsonSchema>(
+        &self,
+        messages: Vec<Message>,
+        model: &Model,
+        custom_key: Option<String>,
+    ) -> BackendResult<T> {
+        let request = ChatCompletionRequest::new(messages, model).with_custom_key(custom_key);
+        self.complete_structured(request).await
+    }
+
+    /// Like `create_structured_completion` for a full request, its response format
+    /// is replaced by the schema of `T`. Only a reply that matches the schema is
+    /// cached, under the key of the request without the repairs.
+    pub async fn complete_structured<T: DeserializeOwned + JsonSchema>(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> BackendResult<T> {
+        let mut request = request.with_response_format(Some(json_schema_response_format::<T>()));
+
+        let cache = self.response_cache_entry(&request)?;
+        if let Some((cache, key)) = cache.as_ref() {
+            match cache.get(key) {
+                Ok(Some((content, _))) => match parse_structured::<T>(&content) {
+                    Ok(result) => return Ok(result),
+                    Err(e) => tracing::warn!("cached llm reply does not match the schema: {}", e),
+                },
+                Ok(None) => {}
+                Err(e) => tracing::warn!("failed to read cached llm response: {}", e),
+            }
+        }
+        // every attempt goes to the model, its reply may still need a repair
+        request.cacheable = false;
+
+        let mut repairs = 0;
+        loop {
+            let response = self.complete(request.clone()).await?;
+            let content = response.content;
+            let error = match parse_structured::<T>(&content) {
+                Ok(result) => {
+                    if let (Some((cache, key)), Some(model)) = (cache.as_ref(), response.model) {
+                        if let Err(e) = cache.put(key, &model, &content) {
+                            tracing::warn!("failed to cache llm response: {}", e);
+                        }
+                    }
+                    return Ok(result);
+                }
+                Err(error) => error,
+            };
+            if repairs >= MAX_STRUCTURED_REPAIRS {
+                return Err(BackendError::GenericError(format!(
+                    "llm reply does not match the schema of {}: {}",
+                    T::schema_name(),
+                    error
+                )));
+            }
+
+            tracing::warn!(
+                "llm reply does not match the schema (repair {}): {}",
+                repairs + 1,
+                error
+            );
+            request.messages.push(Message::new_assistant(&content));
+            request.messages.push(Message::new_user(&format!(
+                "Your reply is not valid: {error}\n\nReply again with ONLY the corrected JSON object matching the schema."
+            )));
+            repairs += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::llm::client::{
+        cache::{ResponseCache, ResponseCacheConfig},
+        mock_server::MockServer,
+        retry::RetryPolicy,
+        Provider,
+    };
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+    struct Inner {
+        ids: Vec<String>,
+    }
+
+    #[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+    struct Answer {
+        needed: bool,
+        inner: Option<Inner>,
+    }
+
+    #[test]
+    fn test_json_schema_response_format() {
+        let format = json_schema_response_format::<Answer>();
+        let (name, schema) = json_schema(Some(&format)).unwrap();
+        assert_eq!(name, "Answer");
+        assert_eq!(schema["type"], "object");
+        assert!(schema.get("$schema").is_none());
+        // subschemas are inlined
+        assert!(schema.get("definitions").is_none());
+        assert!(schema["properties"]["inner"].to_string().contains("ids"));
+
+        assert!(json_schema(Some(&serde_json::json!({"type": "json_object"}))).is_none());
+        assert!(json_schema(None).is_none());
+    }
+
+    #[test]
+    fn test_parse_structured() {
+        let expected = Answer {
+            needed: true,
+            inner: Some(Inner {
+                ids: vec!["1".to_string()],
+            }),
+        };
+        let json = r#"{"needed": true, "inner": {"ids": ["1"]}}"#;
+        assert_eq!(parse_structured::<Answer>(json).unwrap(), expected);
+        assert_eq!(
+            parse_structured::<Answer>(&format!("```json\n{json}\n```")).unwrap(),
+            expected
+        );
+
+        let error = parse_structured::<Answer>(r#"{"inner": null}"#).unwrap_err();
+        assert!(error.contains("missing field `needed`"));
+    }
+
+    fn openai_reply(content: &str) -> String {
+        serde_json::json!({
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": content}}]
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_invalid_reply_is_repaired_and_not_cached() {
+        let invalid = openai_reply(r#"{"inner": null}"#);
+        let valid = openai_reply(r#"{"needed": false, "inner": null}"#);
+        let server = MockServer::with_sequence(&[(200, &invalid), (200, &valid)]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ResponseCache::new(
+            &dir.path().join("cache.db").to_string_lossy(),
+            ResponseCacheConfig::default(),
+        )
+        .unwrap();
+        let client = LLMClient::new()
+            .unwrap()
+            .with_retry_policy(RetryPolicy::none())
+            .with_base_url(&Provider::OpenRouter, server.url())
+            .with_response_cache(cache);
+        let model = Model::Custom {
+            name: "some/model".to_string(),
+            provider: Provider::OpenRouter,
+            max_tokens: 128_000,
+            vision: false,
+        };
+        let request = ChatCompletionRequest::new(vec![Message::new_user("needed?")], &model)
+            .with_custom_key(Some("key".to_string()))
+            .cacheable();
+        let expected = Answer {
+            needed: false,
+            inner: None,
+        };
+
+        let answer: Answer = client.complete_structured(request.clone()).await.unwrap();
+        assert_eq!(answer, expected);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        // the invalid reply and the error are sent back
+        let repair = requests[1].json();
+        let messages = repair["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"][0]["text"], r#"{"inner": null}"#);
+        assert!(messages[2]["content"][0]["text"]
+            .as_str()
+            .unwrap()
+            .contains("missing field `needed`"));
+
+        // the repaired reply is served for the original request
+        let answer: Answer = client.complete_structured(request).await.unwrap();
+        assert_eq!(answer, expected);
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(client.response_cache().unwrap().stats().unwrap().entries, 1);
+    }
+}
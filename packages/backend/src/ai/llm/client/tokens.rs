@@ -1,48 +1,350 @@
-use super::TokenModel;
-use crate::ai::llm::models::{Message, MessageContent};
+use super::{Provider, TokenModel};
+use crate::ai::llm::models::{Message, MessageContent, MessageRole};
+use once_cell::sync::Lazy;
 use std::collections::HashSet;
 
-// reference: https://help.openai.com/en/articles/4936856-what-are-tokens-and-how-to-count-them
-fn estimate_text_tokens(text: &str) -> usize {
-    // ~4 characters per token
-    text.len().div_ceil(4)
+/// Counts tokens the way a specific model family does.
+pub trait Tokenizer: Send + Sync {
+    fn count_text(&self, text: &str) -> usize;
+
+    // dimensions are None when the image is not an inline data url
+    fn count_image(&self, dimensions: Option<(u32, u32)>) -> usize;
+
+    // fixed cost of every message for the role and separators
+    fn message_overhead(&self) -> usize {
+        0
+    }
 }
 
-// TODO: properly implement this function
-// for now estimate the worst case scenario
 // reference: https://platform.openai.com/docs/guides/vision/calculating-costs#calculating-costs
-fn estimate_image_tokens(_image_url: &str) -> usize {
-    1105
+fn openai_image_tokens(dimensions: Option<(u32, u32)>) -> usize {
+    let (width, height) = match dimensions {
+        Some(dimensions) => dimensions,
+        // worst case for a 2048x768 image
+        None => return 1105,
+    };
+    let (mut width, mut height) = (width as f64, height as f64);
+    // fit within 2048x2048, then shortest side at most 768
+    let scale = (2048.0 / width.max(height)).min(1.0);
+    width *= scale;
+    height *= scale;
+    let scale = (768.0 / width.min(height)).min(1.0);
+    width *= scale;
+    height *= scale;
+
+    let tiles = (width / 512.0).ceil() * (height / 512.0).ceil();
+    85 + 170 * tiles as usize
+}
+
+/// Byte pair encoding with the `o200k_base` vocabulary used by the current OpenAI models.
+pub struct OpenAITokenizer;
+
+static O200K_BASE: Lazy<Option<tiktoken_rs::CoreBPE>> = Lazy::new(|| {
+    tiktoken_rs::o200k_base()
+        .map_err(|e| tracing::error!("failed to load o200k_base tokenizer: {e}"))
+        .ok()
+});
+
+impl Tokenizer for OpenAITokenizer {
+    fn count_text(&self, text: &str) -> usize {
+        match O200K_BASE.as_ref() {
+            Some(bpe) => bpe.encode_ordinary(text).len(),
+            None => OPENAI_ESTIMATOR.count_text(text),
+        }
+    }
+
+    fn count_image(&self, dimensions: Option<(u32, u32)>) -> usize {
+        openai_image_tokens(dimensions)
+    }
+
+    // every message is wrapped as <|start|>{role}<|message|>{content}<|end|>
+    fn message_overhead(&self) -> usize {
+        3
+    }
+}
+
+/// Estimates tokens from the number of characters per script, calibrated against
+/// the tokenizer of a provider.
+///
+/// A flat characters per token ratio is only right for latin text, Hangul and CJK
+/// characters mostly take one or more tokens each.
+pub struct CalibratedEstimator {
+    // latin and other ascii text
+    chars_per_token: f64,
+    // Han ideographs, Hiragana and Katakana
+    cjk_tokens_per_char: f64,
+    hangul_tokens_per_char: f64,
+    // any other script, e.g. Cyrillic or Arabic
+    other_chars_per_token: f64,
+    image_tokens: fn(Option<(u32, u32)>) -> usize,
+}
+
+fn is_cjk(ch: char) -> bool {
+    matches!(ch,
+        '\u{3040}'..='\u{30FF}' // Hiragana, Katakana
+        | '\u{3400}'..='\u{4DBF}' // CJK Extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+        | '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+        | '\u{FF66}'..='\u{FF9F}' // halfwidth Katakana
+        | '\u{20000}'..='\u{2FA1F}' // CJK Extensions B-F
+    )
+}
+
+fn is_hangul(ch: char) -> bool {
+    matches!(ch,
+        '\u{1100}'..='\u{11FF}' // Hangul Jamo
+        | '\u{3130}'..='\u{318F}' // Hangul Compatibility Jamo
+        | '\u{AC00}'..='\u{D7AF}' // Hangul Syllables
+    )
+}
+
+impl Tokenizer for CalibratedEstimator {
+    fn count_text(&self, text: &str) -> usize {
+        let (mut latin, mut cjk, mut hangul, mut other) = (0usize, 0usize, 0usize, 0usize);
+        for ch in text.chars() {
+            if ch <= '\u{024F}' {
+                latin += 1;
+            } else if is_hangul(ch) {
+                hangul += 1;
+            } else if is_cjk(ch) {
+                cjk += 1;
+            } else {
+                other += 1;
+            }
+        }
+
+        let tokens = latin as f64 / self.chars_per_token
+            + cjk as f64 * self.cjk_tokens_per_char
+            + hangul as f64 * self.hangul_tokens_per_char
+            + other as f64 / self.other_chars_per_token;
+        tokens.ceil() as usize
+    }
+
+    fn count_image(&self, dimensions: Option<(u32, u32)>) -> usize {
+        (self.image_tokens)(dimensions)
+    }
+}
+
+// reference: https://docs.anthropic.com/en/docs/build-with-claude/vision#calculate-image-costs
+fn anthropic_image_tokens(dimensions: Option<(u32, u32)>) -> usize {
+    let (width, height) = match dimensions {
+        Some((width, height)) => (width as f64, height as f64),
+        // images are downscaled to at most ~1.15 megapixels
+        None => return 1600,
+    };
+    let scale = (1568.0 / width.max(height)).min(1.0);
+    let tokens = (width * scale) * (height * scale) / 750.0;
+    (tokens.ceil() as usize).min(1600)
+}
+
+// reference: https://ai.google.dev/gemini-api/docs/tokens#multimodal-tokens
+fn google_image_tokens(dimensions: Option<(u32, u32)>) -> usize {
+    match dimensions {
+        Some((width, height)) if width <= 384 && height <= 384 => 258,
+        Some((width, height)) => {
+            (width as usize).div_ceil(768) * (height as usize).div_ceil(768) * 258
+        }
+        None => 258 * 4,
+    }
+}
+
+/// Used for OpenAI models if the vocabulary fails to load.
+pub static OPENAI_ESTIMATOR: CalibratedEstimator = CalibratedEstimator {
+    chars_per_token: 4.0,
+    cjk_tokens_per_char: 1.0,
+    hangul_tokens_per_char: 0.8,
+    other_chars_per_token: 2.5,
+    image_tokens: openai_image_tokens,
+};
+
+pub static ANTHROPIC_ESTIMATOR: CalibratedEstimator = CalibratedEstimator {
+    chars_per_token: 3.5,
+    cjk_tokens_per_char: 1.3,
+    hangul_tokens_per_char: 1.1,
+    other_chars_per_token: 2.0,
+    image_tokens: anthropic_image_tokens,
+};
+
+pub static GOOGLE_ESTIMATOR: CalibratedEstimator = CalibratedEstimator {
+    chars_per_token: 4.0,
+    cjk_tokens_per_char: 0.8,
+    hangul_tokens_per_char: 0.7,
+    other_chars_per_token: 3.0,
+    image_tokens: google_image_tokens,
+};
+
+/// Conservative estimator for models we know nothing about.
+pub static DEFAULT_ESTIMATOR: CalibratedEstimator = CalibratedEstimator {
+    chars_per_token: 4.0,
+    cjk_tokens_per_char: 1.3,
+    hangul_tokens_per_char: 1.1,
+    other_chars_per_token: 2.0,
+    image_tokens: openai_image_tokens,
+};
+
+static OPENAI_TOKENIZER: OpenAITokenizer = OpenAITokenizer;
+
+pub fn tokenizer_for_provider(provider: &Provider) -> &'static dyn Tokenizer {
+    match provider {
+        Provider::OpenAI | Provider::Azure { .. } => &OPENAI_TOKENIZER,
+        Provider::Anthropic => &ANTHROPIC_ESTIMATOR,
+        Provider::Google => &GOOGLE_ESTIMATOR,
+        Provider::Custom(_) | Provider::Ollama(_) | Provider::OpenRouter | Provider::Mistral => {
+            &DEFAULT_ESTIMATOR
+        }
+    }
+}
+
+fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            b'\r' | b'\n' | b' ' => continue,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+fn read_u16_be(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_be_bytes([*bytes.get(at)?, *bytes.get(at + 1)?]) as u32)
+}
+
+fn read_u16_le(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_le_bytes([*bytes.get(at)?, *bytes.get(at + 1)?]) as u32)
+}
+
+fn read_u24_le(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(read_u16_le(bytes, at)? | ((*bytes.get(at + 2)? as u32) << 16))
+}
+
+fn read_u32_be(bytes: &[u8], at: usize) -> Option<u32> {
+    Some((read_u16_be(bytes, at)? << 16) | read_u16_be(bytes, at + 2)?)
 }
 
-pub fn estimate_message_content_tokens(content: &MessageContent) -> usize {
+// reads width and height from the header of PNG, JPEG, GIF and WebP images
+fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((read_u32_be(bytes, 16)?, read_u32_be(bytes, 20)?));
+    }
+    if bytes.starts_with(b"GIF8") {
+        return Some((read_u16_le(bytes, 6)?, read_u16_le(bytes, 8)?));
+    }
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        return match bytes.get(12..16)? {
+            b"VP8 " => Some((
+                read_u16_le(bytes, 26)? & 0x3fff,
+                read_u16_le(bytes, 28)? & 0x3fff,
+            )),
+            b"VP8L" => {
+                let bits = read_u16_le(bytes, 21)? | (read_u16_le(bytes, 23)? << 16);
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((read_u24_le(bytes, 24)? + 1, read_u24_le(bytes, 27)? + 1)),
+            _ => None,
+        };
+    }
+    if bytes.starts_with(&[0xff, 0xd8]) {
+        let mut i = 2;
+        while i + 9 < bytes.len() {
+            if bytes[i] != 0xff {
+                return None;
+            }
+            let marker = bytes[i + 1];
+            if marker == 0xff {
+                i += 1;
+                continue;
+            }
+            // start of frame markers, except DHT, JPG and DAC
+            if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+                return Some((read_u16_be(bytes, i + 7)?, read_u16_be(bytes, i + 5)?));
+            }
+            i += 2 + read_u16_be(bytes, i + 2)? as usize;
+        }
+    }
+    None
+}
+
+fn data_url_image_dimensions(url: &str) -> Option<(u32, u32)> {
+    let (_, data) = url.strip_prefix("data:")?.split_once(";base64,")?;
+    image_dimensions(&decode_base64(data)?)
+}
+
+pub fn estimate_message_content_tokens(
+    content: &MessageContent,
+    tokenizer: &dyn Tokenizer,
+) -> usize {
     match content {
-        MessageContent::Text(text) => estimate_text_tokens(&text.text),
-        MessageContent::Image(image) => estimate_image_tokens(&image.image_url.url),
+        MessageContent::Text(text) => tokenizer.count_text(&text.text),
+        MessageContent::Image(image) => {
+            tokenizer.count_image(data_url_image_dimensions(&image.image_url.url))
+        }
     }
 }
 
-pub fn estimate_message_token(message: &Message) -> usize {
-    message
-        .content
+pub fn estimate_message_token(message: &Message, tokenizer: &dyn Tokenizer) -> usize {
+    tokenizer.message_overhead()
+        + message
+            .content
+            .iter()
+            .map(|content| estimate_message_content_tokens(content, tokenizer))
+            .sum::<usize>()
+}
+
+pub fn estimate_messages_tokens(messages: &[Message], tokenizer: &dyn Tokenizer) -> usize {
+    messages
         .iter()
-        .map(estimate_message_content_tokens)
+        .map(|message| estimate_message_token(message, tokenizer))
         .sum()
 }
 
-pub fn estimate_messages_tokens(messages: &[Message]) -> usize {
-    messages.iter().map(estimate_message_token).sum()
+// number of leading messages to fold into a summary once the messages take more
+// than `max_tokens`, the last `keep_recent` are always kept and only whole turns
+// (ending with the assistant's answer) are summarised
+pub fn messages_to_summarize(
+    messages: &[Message],
+    tokenizer: &dyn Tokenizer,
+    max_tokens: usize,
+    keep_recent: usize,
+) -> usize {
+    if estimate_messages_tokens(messages, tokenizer) <= max_tokens {
+        return 0;
+    }
+    let mut count = messages.len().saturating_sub(keep_recent);
+    while count > 0 && messages[count - 1].role != MessageRole::Assistant {
+        count -= 1;
+    }
+    count
 }
 
 // truncate messages to fit the max tokens
 //
 // the following function gives priority to non-truncatable messages
 // and includes as many as possible truncatable messages until tokens are exhausted
-pub fn truncate_messages(
+pub fn truncate_messages(messages: Vec<Message>, model: &impl TokenModel) -> (bool, Vec<Message>) {
+    truncate_messages_to_budget(messages, model.tokenizer(), model.max_tokens())
+}
+
+pub fn truncate_messages_to_budget(
     mut messages: Vec<Message>,
-    model: &impl TokenModel,
+    tokenizer: &dyn Tokenizer,
+    max_tokens: usize,
 ) -> (bool, Vec<Message>) {
-    let max_tokens = model.max_tokens();
     let mut truncated_messages: Vec<Message> = Vec::new();
     let mut truncated = false;
 
@@ -51,7 +353,7 @@ pub fn truncate_messages(
     let mut required_tokens = 0;
     for message in messages.iter() {
         if !message.truncatable {
-            required_tokens += estimate_message_token(message);
+            required_tokens += estimate_message_token(message, tokenizer);
         }
     }
 
@@ -64,7 +366,7 @@ pub fn truncate_messages(
         // include non-truncatable messages until we run out of tokens
         for message in messages.iter() {
             if !message.truncatable {
-                let message_tokens = estimate_message_token(message);
+                let message_tokens = estimate_message_token(message, tokenizer);
                 if message_tokens <= remaining_tokens {
                     remaining_tokens = remaining_tokens.saturating_sub(message_tokens);
                     truncated_messages.push(message.clone());
@@ -86,7 +388,7 @@ pub fn truncate_messages(
     for message in messages.iter() {
         if !message.truncatable {
             // non-truncatable messages are always included as we confirmed enough space above
-            let message_tokens = estimate_message_token(message);
+            let message_tokens = estimate_message_token(message, tokenizer);
             remaining_tokens -= message_tokens;
             tokens_reserved -= message_tokens;
             truncated_messages.push(message.clone());
@@ -106,7 +408,7 @@ pub fn truncate_messages(
                 truncated = true;
                 continue;
             }
-            let content_tokens = estimate_message_content_tokens(content);
+            let content_tokens = estimate_message_content_tokens(content, tokenizer);
             if total_content_tokens + content_tokens > available_tokens {
                 truncated = true;
                 continue;
@@ -120,6 +422,8 @@ pub fn truncate_messages(
                 content: message.content.clone(),
                 truncatable: message.truncatable,
                 is_context: message.is_context,
+                tool_calls: message.tool_calls.clone(),
+                tool_call_id: message.tool_call_id.clone(),
             });
         }
     }
@@ -143,6 +447,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_messages_to_summarize() {
+        let mut messages = vec![];
+        for _ in 0..4 {
+            messages.push(Message::new_user(&"q".repeat(400)));
+            messages.push(Message::new_assistant(&"a".repeat(400)));
+        }
+
+        assert_eq!(
+            messages_to_summarize(&messages, &DEFAULT_ESTIMATOR, 10_000, 3),
+            0
+        );
+        // the cut is moved back to the end of the second turn
+        assert_eq!(
+            messages_to_summarize(&messages, &DEFAULT_ESTIMATOR, 100, 3),
+            4
+        );
+        assert_eq!(
+            messages_to_summarize(&messages, &DEFAULT_ESTIMATOR, 100, 8),
+            0
+        );
+    }
+
     #[test]
     fn test_empty_messages() {
         let messages = vec![];
@@ -378,4 +705,59 @@ mod tests {
         assert_eq!(truncated_messages[1], third);
         assert_eq!(truncated_messages[2], fourth);
     }
+
+    const PNG_1024X768: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAABAAAAAMACAIAAAAAAAAA";
+    const PNG_64X64: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAEAAAABACAIAAAAAAAAA";
+    const JPEG_800X600: &str =
+        "data:image/jpeg;base64,/9j/4AAQSkZJRgABAQAAAQABAAD/wAALCAJYAyABAREA/9k=";
+
+    #[test]
+    fn test_data_url_image_dimensions() {
+        assert_eq!(data_url_image_dimensions(PNG_1024X768), Some((1024, 768)));
+        assert_eq!(data_url_image_dimensions(PNG_64X64), Some((64, 64)));
+        assert_eq!(data_url_image_dimensions(JPEG_800X600), Some((800, 600)));
+        assert_eq!(
+            data_url_image_dimensions("https://example.com/image.jpg"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_image_tokens_by_provider() {
+        let openai = tokenizer_for_provider(&Provider::OpenAI);
+        let anthropic = tokenizer_for_provider(&Provider::Anthropic);
+        let google = tokenizer_for_provider(&Provider::Google);
+
+        let tokens = |tokenizer: &dyn Tokenizer, url: &str| {
+            estimate_message_content_tokens(&Message::new_image(url).content[0], tokenizer)
+        };
+        assert_eq!(tokens(openai, PNG_1024X768), 765);
+        assert_eq!(tokens(openai, PNG_64X64), 255);
+        assert_eq!(tokens(openai, "https://example.com/image.jpg"), 1105);
+        assert_eq!(tokens(anthropic, PNG_1024X768), 1049);
+        assert_eq!(tokens(anthropic, PNG_64X64), 6);
+        assert_eq!(tokens(google, PNG_64X64), 258);
+        assert_eq!(tokens(google, JPEG_800X600), 516);
+    }
+
+    #[test]
+    fn test_openai_tokenizer_uses_bpe() {
+        let openai = tokenizer_for_provider(&Provider::OpenAI);
+        assert_eq!(openai.count_text("hello world"), 2);
+        assert_eq!(
+            estimate_message_token(&Message::new_user("hello world"), openai),
+            5
+        );
+    }
+
+    #[test]
+    fn test_cjk_estimates() {
+        // four characters per token would count these as 4 and 3 tokens
+        let korean = "안녕하세요";
+        let chinese = "你好世界";
+        assert_eq!(DEFAULT_ESTIMATOR.count_text(korean), 6);
+        assert_eq!(DEFAULT_ESTIMATOR.count_text(chinese), 6);
+        assert_eq!(GOOGLE_ESTIMATOR.count_text(korean), 4);
+        assert_eq!(DEFAULT_ESTIMATOR.count_text("hello world, 안녕"), 6);
+    }
 }
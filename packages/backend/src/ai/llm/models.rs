@@ -69,6 +69,28 @@ pub struct ContextMessage {
     pub created_at: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    // JSON encoded arguments, as generated by the model
+    pub arguments: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub r#type: String, // "function"
+    pub function: FunctionCall,
+}
+
+/// A tool the model can call natively, `parameters` is the JSON schema of its arguments.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Message {
     pub role: MessageRole,
@@ -77,6 +99,12 @@ pub struct Message {
     pub truncatable: bool,
     #[serde(skip)]
     pub is_context: bool,
+    // tool calls requested by an assistant message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    // the call a tool message is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
 impl Message {
@@ -91,6 +119,8 @@ impl Message {
             content: vec![MessageContent::new_text(msg.to_string())],
             truncatable: false,
             is_context: false,
+            tool_calls: vec![],
+            tool_call_id: None,
         }
     }
 
@@ -100,6 +130,8 @@ impl Message {
             content: vec![MessageContent::new_text(msg.to_string())],
             truncatable: false,
             is_context: false,
+            tool_calls: vec![],
+            tool_call_id: None,
         }
     }
 
@@ -109,6 +141,33 @@ impl Message {
             content: vec![MessageContent::new_text(msg.to_string())],
             truncatable: false,
             is_context: false,
+            tool_calls: vec![],
+            tool_call_id: None,
+        }
+    }
+
+    pub fn new_assistant_tool_calls(msg: &str, tool_calls: Vec<ToolCall>) -> Message {
+        Message {
+            role: MessageRole::Assistant,
+            content: match msg.is_empty() {
+                true => vec![],
+                false => vec![MessageContent::new_text(msg.to_string())],
+            },
+            truncatable: false,
+            is_context: false,
+            tool_calls,
+            tool_call_id: None,
+        }
+    }
+
+    pub fn new_tool_result(tool_call_id: &str, result: &str) -> Message {
+        Message {
+            role: MessageRole::Tool,
+            content: vec![MessageContent::new_text(result.to_string())],
+            truncatable: false,
+            is_context: false,
+            tool_calls: vec![],
+            tool_call_id: Some(tool_call_id.to_string()),
         }
     }
 
@@ -122,6 +181,8 @@ impl Message {
             content: vec![MessageContent::new_text(context_message_str)],
             truncatable: true,
             is_context: true,
+            tool_calls: vec![],
+            tool_call_id: None,
         })
     }
 
@@ -132,6 +193,8 @@ impl Message {
             content: vec![MessageContent::new_image(url.to_string())],
             truncatable: true,
             is_context: true,
+            tool_calls: vec![],
+            tool_call_id: None,
         }
     }
 
@@ -142,6 +205,21 @@ impl Message {
             content: vec![MessageContent::new_text(content_str)],
             truncatable: false,
             is_context: false,
+            tool_calls: vec![],
+            tool_call_id: None,
+        }
+    }
+
+    // sent in place of the turns of a chat session the summary covers
+    pub fn new_summary(summary: &str) -> Message {
+        let content_str = format!("summary of the earlier conversation:\n{}", summary);
+        Message {
+            role: MessageRole::User,
+            content: vec![MessageContent::new_text(content_str)],
+            truncatable: false,
+            is_context: false,
+            tool_calls: vec![],
+            tool_call_id: None,
         }
     }
 }
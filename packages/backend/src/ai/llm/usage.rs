@@ -0,0 +1,125 @@
+use crate::{
+    ai::llm::client::catalog,
+    store::models::{LLMModelPrice, LLMUsage},
+};
+
+use crossbeam_channel as crossbeam;
+
+/// Token counts reported by the provider for a single completion.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TokenUsage {
+    // includes the cached tokens
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cached_tokens: u64,
+}
+
+impl TokenUsage {
+    pub fn is_empty(&self) -> bool {
+        self.prompt_tokens == 0 && self.completion_tokens == 0
+    }
+
+    /// Merges a partial report, providers send prompt and completion counts in
+    /// different stream events so only non zero counts overwrite.
+    pub fn merge(&mut self, other: TokenUsage) {
+        if other.prompt_tokens > 0 {
+            self.prompt_tokens = other.prompt_tokens;
+        }
+        if other.completion_tokens > 0 {
+            self.completion_tokens = other.completion_tokens;
+        }
+        if other.cached_tokens > 0 {
+            self.cached_tokens = other.cached_tokens;
+        }
+    }
+}
+
+/// Who a completion was made for, stored next to its usage.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageTag {
+    pub session_id: Option<String>,
+    pub note_id: Option<String>,
+    pub agent: Option<String>,
+}
+
+impl UsageTag {
+    pub fn chat(session_id: Option<String>, note_id: Option<String>) -> Self {
+        Self {
+            session_id,
+            note_id,
+            agent: None,
+        }
+    }
+
+    pub fn agent(agent: &str) -> Self {
+        Self {
+            agent: Some(agent.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Hands finished usage records to whoever persists them.
+///
+/// Completions run on the worker's async runtime and streams are dropped
+/// wherever their reader is done, so records are sent over a channel instead of
+/// writing to the database right away.
+#[derive(Debug, Clone)]
+pub struct UsageRecorder {
+    tx: crossbeam::Sender<LLMUsage>,
+}
+
+impl UsageRecorder {
+    pub fn record(&self, usage: LLMUsage) {
+        if let Err(e) = self.tx.send(usage) {
+            tracing::warn!("failed to record llm usage: {}", e);
+        }
+    }
+}
+
+pub fn usage_channel() -> (UsageRecorder, crossbeam::Receiver<LLMUsage>) {
+    let (tx, rx) = crossbeam::unbounded();
+    (UsageRecorder { tx }, rx)
+}
+
+/// List price in USD per million tokens from the model catalog, overridable per
+/// model through the `llm_model_prices` table.
+pub fn default_model_price(model: &str) -> Option<LLMModelPrice> {
+    catalog::model_info(model, None).and_then(|info| info.price)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_cost_with_cached_tokens() {
+        let price = default_model_price("gpt-4.1").unwrap();
+        // 1M prompt tokens of which half are cached, 100k completion tokens
+        let cost = price.estimate_cost(1_000_000, 100_000, 500_000);
+        assert!((cost - (1.0 + 0.25 + 0.8)).abs() < 1e-9);
+        assert!(default_model_price("my-local-model").is_none());
+    }
+
+    #[test]
+    fn test_merge_partial_usage() {
+        let mut usage = TokenUsage::default();
+        usage.merge(TokenUsage {
+            prompt_tokens: 120,
+            cached_tokens: 100,
+            ..Default::default()
+        });
+        usage.merge(TokenUsage {
+            completion_tokens: 42,
+            ..Default::default()
+        });
+        assert_eq!(
+            usage,
+            TokenUsage {
+                prompt_tokens: 120,
+                completion_tokens: 42,
+                cached_tokens: 100,
+            }
+        );
+    }
+}
@@ -36,6 +36,36 @@ pub struct FilteredSearchRequest {
     pub threshold: Option<f32>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RerankRequest {
+    pub query: String,
+    pub docs: Vec<String>,
+    pub top_k: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RerankScore {
+    pub index: usize,
+    pub score: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpsertImageEmbeddingsRequest {
+    pub old_keys: Vec<i64>,
+    pub new_keys: Vec<i64>,
+    pub image_paths: Vec<String>,
+}
+
+/// Either `query` or `image_path` must be set, `image_path` wins if both are.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageSearchRequest {
+    pub query: Option<String>,
+    pub image_path: Option<String>,
+    pub num_docs: usize,
+    pub keys: Vec<u64>,
+    pub threshold: Option<f32>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpsertEmbeddingsRequest {
     pub old_keys: Vec<i64>,
@@ -148,6 +178,11 @@ impl LocalAIClient {
         (message.contains("error:"), message.to_string())
     }
 
+    // the server rejects requests with a fixed message when its encode queue is full
+    fn is_busy(message: &str) -> bool {
+        message.trim() == "error: busy"
+    }
+
     fn send_api_request_preamble(stream: &mut UnixStream, api_request: &str) -> BackendResult<()> {
         Self::send_message(stream, api_request)?;
         let response = Self::read_message(stream)?;
@@ -178,6 +213,9 @@ impl LocalAIClient {
             let message = Self::read_message(&mut stream)?;
             let (is_err, message) = Self::is_error(&message);
             if is_err {
+                if Self::is_busy(&message) {
+                    return Err(BackendError::LocalAIServerBusy);
+                }
                 eprintln!("failed to get docs similarity: {:#?}", message);
                 return Err(BackendError::GenericError(format!(
                     "failed to get docs similarity: {:#?}",
@@ -212,6 +250,9 @@ impl LocalAIClient {
             let message = Self::read_message(&mut stream)?;
             let (is_err, message) = Self::is_error(&message);
             if is_err {
+                if Self::is_busy(&message) {
+                    return Err(BackendError::LocalAIServerBusy);
+                }
                 eprintln!("failed to encode sentences: {:#?}", message);
                 return Err(BackendError::GenericError(format!(
                     "failed to encode sentences: {:#?}",
@@ -246,6 +287,9 @@ impl LocalAIClient {
             let message = Self::read_message(&mut stream)?;
             let (is_err, message) = Self::is_error(&message);
             if is_err {
+                if Self::is_busy(&message) {
+                    return Err(BackendError::LocalAIServerBusy);
+                }
                 eprintln!("failed to do filtered search: {:#?}", message);
                 return Err(BackendError::GenericError(format!(
                     "failed to do filtered search: {:#?}",
@@ -279,6 +323,9 @@ impl LocalAIClient {
             let message = Self::read_message(&mut stream)?;
             let (is_err, message) = Self::is_error(&message);
             if is_err {
+                if Self::is_busy(&message) {
+                    return Err(BackendError::LocalAIServerBusy);
+                }
                 eprintln!("failed to upsert embeddings: {:#?}", message);
                 return Err(BackendError::GenericError(format!(
                     "failed to upsert embeddings: {:#?}",
@@ -300,6 +347,118 @@ impl LocalAIClient {
         Ok(())
     }
 
+    pub fn upsert_image_embeddings(&self, req: UpsertImageEmbeddingsRequest) -> BackendResult<()> {
+        let message = serde_json::to_string(&req).map_err(|e| {
+            BackendError::GenericError(format!("failed to serialize request: {:#?}", e))
+        })?;
+
+        let mut stream = UnixStream::connect(&self.socket_path)?;
+
+        Self::send_api_request_preamble(&mut stream, "upsert_image_embeddings")?;
+        Self::send_message(&mut stream, &message)?;
+        Self::send_done(&mut stream)?;
+        let mut server_message_buffer = String::new();
+        loop {
+            let message = Self::read_message(&mut stream)?;
+            let (is_err, message) = Self::is_error(&message);
+            if is_err {
+                if Self::is_busy(&message) {
+                    return Err(BackendError::LocalAIServerBusy);
+                }
+                eprintln!("failed to upsert image embeddings: {:#?}", message);
+                return Err(BackendError::GenericError(format!(
+                    "failed to upsert image embeddings: {:#?}",
+                    message
+                )));
+            }
+            let (is_done, message) = Self::is_done(&message);
+            server_message_buffer.push_str(&message);
+            if is_done {
+                break;
+            }
+        }
+        if server_message_buffer != "ok" {
+            return Err(BackendError::GenericError(format!(
+                "failed to upsert image embeddings: {:#?}",
+                server_message_buffer
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn image_search(&self, req: ImageSearchRequest) -> BackendResult<Vec<i64>> {
+        let message = serde_json::to_string(&req).map_err(|e| {
+            BackendError::GenericError(format!("failed to serialize request: {:#?}", e))
+        })?;
+
+        let mut stream = UnixStream::connect(&self.socket_path)?;
+
+        Self::send_api_request_preamble(&mut stream, "image_search")?;
+        Self::send_message(&mut stream, &message)?;
+        Self::send_done(&mut stream)?;
+        let mut server_message_buffer = String::new();
+        loop {
+            let message = Self::read_message(&mut stream)?;
+            let (is_err, message) = Self::is_error(&message);
+            if is_err {
+                if Self::is_busy(&message) {
+                    return Err(BackendError::LocalAIServerBusy);
+                }
+                eprintln!("failed to do image search: {:#?}", message);
+                return Err(BackendError::GenericError(format!(
+                    "failed to do image search: {:#?}",
+                    message
+                )));
+            }
+            let (is_done, message) = Self::is_done(&message);
+            server_message_buffer.push_str(&message);
+            if is_done {
+                break;
+            }
+        }
+        let results = serde_json::from_str::<Vec<i64>>(&server_message_buffer).map_err(|e| {
+            BackendError::GenericError(format!("failed to parse response: {:#?}", e))
+        })?;
+        Ok(results)
+    }
+
+    pub fn rerank(&self, req: RerankRequest) -> BackendResult<Vec<RerankScore>> {
+        let message = serde_json::to_string(&req).map_err(|e| {
+            BackendError::GenericError(format!("failed to serialize request: {:#?}", e))
+        })?;
+
+        let mut stream = UnixStream::connect(&self.socket_path)?;
+
+        Self::send_api_request_preamble(&mut stream, "rerank")?;
+        Self::send_message(&mut stream, &message)?;
+        Self::send_done(&mut stream)?;
+        let mut server_message_buffer = String::new();
+        loop {
+            let message = Self::read_message(&mut stream)?;
+            let (is_err, message) = Self::is_error(&message);
+            if is_err {
+                if Self::is_busy(&message) {
+                    return Err(BackendError::LocalAIServerBusy);
+                }
+                eprintln!("failed to rerank: {:#?}", message);
+                return Err(BackendError::GenericError(format!(
+                    "failed to rerank: {:#?}",
+                    message
+                )));
+            }
+            let (is_done, message) = Self::is_done(&message);
+            server_message_buffer.push_str(&message);
+            if is_done {
+                break;
+            }
+        }
+        let scores =
+            serde_json::from_str::<Vec<RerankScore>>(&server_message_buffer).map_err(|e| {
+                BackendError::GenericError(format!("failed to parse response: {:#?}", e))
+            })?;
+        Ok(scores)
+    }
+
     #[allow(dead_code)]
     pub async fn create_chat_completion(
         &self,
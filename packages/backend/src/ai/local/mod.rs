@@ -1 +1,3 @@
 pub mod client;
+#[cfg(all(test, not(target_os = "windows")))]
+pub mod stub_server;
@@ -0,0 +1,206 @@
+//! In-process stand-in for the local ai server that answers `filtered_search`,
+//! `image_search` and `rerank` requests over a unix socket. Texts are embedded
+//! as bags of words, so results are deterministic and only depend on the words
+//! a query shares with the indexed chunks or the reranked documents.
+use super::client::{FilteredSearchRequest, ImageSearchRequest, RerankRequest, RerankScore};
+use crate::ai::llm::client::cache::{ResponseCache, ResponseCacheConfig};
+use crate::ai::llm::usage::usage_channel;
+use crate::ai::AI;
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::Arc,
+};
+
+// carry no meaning on their own, matching on them only adds noise
+const STOP_WORDS: &[&str] = &[
+    "all", "also", "and", "any", "are", "but", "can", "does", "for", "from", "had", "has", "have",
+    "her", "his", "how", "into", "its", "not", "our", "out", "per", "should", "than", "that",
+    "the", "their", "then", "there", "this", "too", "was", "were", "what", "when", "which", "why",
+    "with", "you", "your",
+];
+
+const SUFFIXES: &[&str] = &["ing", "ed", "es", "s"];
+
+type Embedding = HashMap<String, f32>;
+
+/// Lowercased words of `text` without stop words and common suffixes, with the
+/// number of times they occur.
+pub fn embed(text: &str) -> Embedding {
+    let mut embedding = Embedding::new();
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.to_lowercase())
+        .filter(|word| word.chars().count() >= 3 && !STOP_WORDS.contains(&word.as_str()))
+    {
+        let stem = SUFFIXES
+            .iter()
+            .find_map(|suffix| {
+                word.strip_suffix(suffix)
+                    .filter(|stem| stem.chars().count() >= 3)
+            })
+            .unwrap_or(word.as_str());
+        *embedding.entry(stem.to_string()).or_default() += 1.0;
+    }
+    embedding
+}
+
+pub fn cosine_similarity(a: &Embedding, b: &Embedding) -> f32 {
+    let dot: f32 = a
+        .iter()
+        .filter_map(|(word, weight)| b.get(word).map(|other| weight * other))
+        .sum();
+    let norm = |embedding: &Embedding| embedding.values().map(|w| w * w).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms > 0.0 {
+        dot / norms
+    } else {
+        0.0
+    }
+}
+
+pub struct StubServer {
+    embeddings: HashMap<u64, Embedding>,
+}
+
+impl StubServer {
+    /// Indexes the text of every embedding key.
+    pub fn new(texts: &HashMap<u64, String>) -> Self {
+        Self {
+            embeddings: texts
+                .iter()
+                .map(|(key, text)| (*key, embed(text)))
+                .collect(),
+        }
+    }
+
+    /// Listens on `socket_path` until the test process exits.
+    pub fn serve(self, socket_path: &Path) {
+        let listener = UnixListener::bind(socket_path).unwrap();
+        let server = Arc::new(self);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = Arc::clone(&server);
+                std::thread::spawn(move || server.handle(stream));
+            }
+        });
+    }
+
+    /// Keys that share words with the query, most similar first. Ties go to the
+    /// lower key, the threshold is ignored as bag of words similarities aren't
+    /// comparable to the ones of the real model.
+    pub fn filtered_search(&self, req: &FilteredSearchRequest) -> Vec<i64> {
+        self.search(&req.query, &req.keys, req.num_docs)
+    }
+
+    /// Like `filtered_search`, the texts of image keys describe the image. A
+    /// reference image is matched by the words of its path.
+    pub fn image_search(&self, req: &ImageSearchRequest) -> Result<Vec<i64>, String> {
+        match (&req.image_path, &req.query) {
+            (Some(query), _) | (None, Some(query)) => {
+                Ok(self.search(query, &req.keys, req.num_docs))
+            }
+            (None, None) => Err("image search needs either a query or an image_path".to_string()),
+        }
+    }
+
+    fn search(&self, query: &str, keys: &[u64], num_docs: usize) -> Vec<i64> {
+        let query = embed(query);
+        let mut scored: Vec<(f32, u64)> = keys
+            .iter()
+            .filter_map(|key| {
+                let embedding = self.embeddings.get(key)?;
+                let similarity = cosine_similarity(&query, embedding);
+                (similarity > 0.0).then_some((similarity, *key))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        scored
+            .into_iter()
+            .take(num_docs)
+            .map(|(_, key)| key as i64)
+            .collect()
+    }
+
+    /// Scores every document by its similarity to the query, best first.
+    pub fn rerank(&self, req: &RerankRequest) -> Vec<RerankScore> {
+        let query = embed(&req.query);
+        let mut scores: Vec<RerankScore> = req
+            .docs
+            .iter()
+            .enumerate()
+            .map(|(index, doc)| RerankScore {
+                index,
+                score: cosine_similarity(&query, &embed(doc)),
+            })
+            .collect();
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.index.cmp(&b.index)));
+        scores.truncate(req.top_k.unwrap_or(scores.len()));
+        scores
+    }
+
+    // same framing as the real server: the api name, an ack, then the json
+    // request and the response both terminated by `[done]`
+    fn handle(&self, mut stream: UnixStream) -> Option<()> {
+        let api = read_until(&mut stream, |data| !data.is_empty())?;
+        stream.write_all(b"[ack]").ok()?;
+        let request = read_until(&mut stream, |data| data.trim_end().ends_with("[done]"))?;
+        let request = request.trim_end().strip_suffix("[done]")?;
+
+        let response = match api.trim() {
+            "filtered_search" => match serde_json::from_str(request) {
+                Ok(req) => serde_json::to_string(&self.filtered_search(&req)).ok()?,
+                Err(e) => format!("error: invalid request: {}", e),
+            },
+            "image_search" => {
+                match serde_json::from_str(request).map(|req| self.image_search(&req)) {
+                    Ok(Ok(keys)) => serde_json::to_string(&keys).ok()?,
+                    Ok(Err(e)) => format!("error: {}", e),
+                    Err(e) => format!("error: invalid request: {}", e),
+                }
+            }
+            "rerank" => match serde_json::from_str(request) {
+                Ok(req) => serde_json::to_string(&self.rerank(&req)).ok()?,
+                Err(e) => format!("error: invalid request: {}", e),
+            },
+            api => format!("error: unsupported api request: {}", api),
+        };
+        stream
+            .write_all(format!("{}[done]\n", response).as_bytes())
+            .ok()?;
+        stream.flush().ok()
+    }
+}
+
+/// Starts a stub server for `texts` in `dir` and returns an `AI` that talks to it.
+pub fn stub_ai(dir: &Path, texts: &HashMap<u64, String>) -> AI {
+    let socket_path = dir.join("local-ai.sock");
+    StubServer::new(texts).serve(&socket_path);
+    let (usage_recorder, _) = usage_channel();
+    let response_cache = ResponseCache::new(
+        &dir.join("response-cache.db").to_string_lossy(),
+        ResponseCacheConfig::default(),
+    )
+    .unwrap();
+    AI::new(
+        socket_path.to_string_lossy().to_string(),
+        usage_recorder,
+        Some(response_cache),
+    )
+    .unwrap()
+}
+
+fn read_until(stream: &mut UnixStream, complete: impl Fn(&str) -> bool) -> Option<String> {
+    let mut data = String::new();
+    let mut buf = [0u8; 1024];
+    while !complete(&data) {
+        let read = stream.read(&mut buf).ok()?;
+        if read == 0 {
+            return None;
+        }
+        data.push_str(&String::from_utf8_lossy(&buf[..read]));
+    }
+    Some(data)
+}
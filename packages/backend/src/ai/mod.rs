@@ -1,8 +1,15 @@
+pub mod chat_export;
+pub mod citations;
+pub mod context_assembler;
 pub mod embeddings;
+#[cfg(all(test, not(target_os = "windows")))]
+mod eval;
 pub mod llm;
+pub mod retrieval;
 pub mod youtube;
 
 #[cfg(feature = "wip")]
+#[allow(clippy::module_inception)]
 pub mod brain;
 
 mod local;
@@ -11,23 +18,65 @@ mod prompts;
 pub const _MODULE_PREFIX: &str = "ai";
 pub const _AI_API_ENDPOINT: &str = "v1/deta-os-ai";
 
+// older turns of a chat session are summarised once its history takes more than
+// this fraction of the model's context window, the most recent messages are
+// always sent as they are
+const HISTORY_SUMMARY_THRESHOLD: f32 = 0.5;
+const HISTORY_KEEP_RECENT_MESSAGES: usize = 6;
+
+// how many more candidates than requested are retrieved when reranking
+pub const RERANK_CANDIDATES_FACTOR: usize = 3;
+
+// generated chat tags beyond this are dropped
+pub const MAX_CHAT_TAGS: usize = 3;
+
+// CLIP text/image similarities are a lot lower than text/text ones, so the image
+// index needs a looser threshold than the text embeddings
+pub const IMAGE_SEARCH_DISTANCE_THRESHOLD: f32 = 0.8;
+
+// max chunk size in characters and number of sentences overlapping between chunks
+pub const CHUNK_MAX_SIZE: usize = 2000;
+pub const CHUNK_OVERLAP_SENTENCES: usize = 1;
+
+const UPSERT_BUSY_RETRIES: u32 = 5;
+const UPSERT_BUSY_INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_millis(200);
+
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
 
-use crate::ai::embeddings::chunking::ContentChunker;
+use crate::ai::context_assembler::{
+    AssembledContext, ContextBudget, ContextPassage, DroppedContext,
+};
+use crate::ai::embeddings::chunking::{
+    ChunkingStrategy, ContentChunker, MarkdownChunker, Page, PageChunker,
+};
 use crate::ai::llm::client;
-use crate::ai::llm::client::{ChatCompletionStream, Model};
+use crate::ai::llm::client::{
+    cache::ResponseCache, retry::RetryPolicy, CancellationToken, ChatCompletionRequest,
+    ChatCompletionStream, Model, TokenModel,
+};
 use crate::ai::llm::models::{ContextMessage, Message, MessageContent, MessageRole};
+use crate::ai::llm::usage::{UsageRecorder, UsageTag};
 use crate::ai::local::client::{
-    DocsSimilarityRequest, FilteredSearchRequest, LocalAIClient, UpsertEmbeddingsRequest,
+    DocsSimilarityRequest, FilteredSearchRequest, ImageSearchRequest, LocalAIClient, RerankRequest,
+    UpsertEmbeddingsRequest, UpsertImageEmbeddingsRequest,
 };
+use crate::ai::retrieval::{fuse_rankings, RetrievalPlan, PLANNER_HISTORY_MESSAGES};
+use crate::store::ai_sessions::active_branch;
 use crate::store::db::Database;
-use crate::store::models::{AIChatSessionMessage, AIChatSessionMessageSource, CompositeResource};
+use crate::store::models::{
+    AIChatSessionMessage, AIChatSessionMessageHit, AIChatSessionMessageSource, CompositeResource,
+    EmbeddingType, ResourceTextContentMetadata, ResourceTextContentType,
+};
 use crate::{BackendError, BackendResult};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use prompts::{
-    chat_prompt, create_app_prompt, general_chat_prompt, note_prompt, should_narrow_search_prompt,
-    should_narrow_search_prompt_simple, sql_query_generator_prompt,
+    chat_prompt, chat_title_prompt, create_app_prompt, general_chat_prompt, note_prompt,
+    retrieval_plan_prompt, should_narrow_search_prompt, should_narrow_search_prompt_simple,
+    sql_query_generator_prompt, summarize_history_prompt,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -66,28 +115,111 @@ pub struct ChatInput {
     pub general: bool,
     pub websearch: bool,
     pub surflet: bool,
+    pub rerank: bool,
+    // instructions of the chat session, on top of the built-in prompt
+    pub system_prompt: Option<String>,
+    // threshold of the embedding search, 0.5 if unset
+    pub similarity_threshold: Option<f32>,
+    // rewrite the query with the chat history and search for paraphrases and
+    // keywords too, see `AI::plan_retrieval`
+    pub plan_retrieval: bool,
+    // models (with their keys) answering in order in place of `model` while
+    // the ones before keep failing
+    pub fallbacks: Vec<(Model, Option<String>)>,
+    // retries of a failed answer before falling back, the client's default if unset
+    pub max_retries: Option<u32>,
 }
 
 // TODO: fix sources vs messages
 pub struct ChatResult {
     pub messages: Vec<Message>,
     pub sources: Vec<AIChatSessionMessageSource>,
+    // also lists the search results left out of the context, for the UI
     pub sources_xml: String,
     pub stream: ChatCompletionStream,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct ShouldClusterResult {
     pub embeddings_search_needed: bool,
     pub relevant_context_ids: Option<Vec<String>>,
 }
 
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ChatTitle {
+    pub title: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SqlQueryResult {
+    pub sql_query: String,
+    pub embedding_search_query: Option<String>,
+}
+
 pub struct AI {
-    pub client: client::LLMClient,
+    // shared with the completions that outlive a worker message
+    pub client: Arc<client::LLMClient>,
     pub chunker: ContentChunker,
     local_ai_client: LocalAIClient,
 }
 
+// the local ai server rejects bulk requests while its encode queue is full,
+// imports back off and retry so they yield to interactive queries
+fn retry_while_busy<T>(mut request: impl FnMut() -> BackendResult<T>) -> BackendResult<T> {
+    let mut backoff = UPSERT_BUSY_INITIAL_BACKOFF;
+    for _ in 0..UPSERT_BUSY_RETRIES {
+        match request() {
+            Err(BackendError::LocalAIServerBusy) => {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    request()
+}
+
+/// Title and topic tags of a chat from its first question and answer. Takes
+/// the client only, so that it can run on its own on the async runtime.
+pub async fn generate_chat_title(
+    client: &client::LLMClient,
+    query: &str,
+    answer: &str,
+    model: &Model,
+    custom_key: Option<String>,
+    usage_tag: UsageTag,
+) -> BackendResult<ChatTitle> {
+    let input = format!(
+        "<question>\n{}\n</question>\n\n<answer>\n{}\n</answer>",
+        query, answer
+    );
+    let messages = vec![
+        Message::new_system(&chat_title_prompt()),
+        Message::new_user(&input),
+    ];
+    let request = ChatCompletionRequest::new(messages, model)
+        .with_custom_key(custom_key)
+        .with_usage_tag(UsageTag {
+            agent: Some("chat_title".to_string()),
+            ..usage_tag
+        });
+    let mut result: ChatTitle = client.complete_structured(request).await?;
+    result.title = result
+        .title
+        .trim()
+        .trim_matches(|c| c == '"' || c == '.')
+        .to_string();
+    result.tags = result
+        .tags
+        .iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .take(MAX_CHAT_TAGS)
+        .collect();
+    Ok(result)
+}
+
 fn human_readable_current_time() -> String {
     // 2023-09-13 21:00:00 Tuesday
     chrono::Utc::now()
@@ -96,20 +228,88 @@ fn human_readable_current_time() -> String {
 }
 
 impl AI {
-    pub fn new(local_ai_socket_path: String) -> BackendResult<Self> {
+    pub fn new(
+        local_ai_socket_path: String,
+        usage_recorder: UsageRecorder,
+        response_cache: Option<ResponseCache>,
+    ) -> BackendResult<Self> {
+        let mut client = client::LLMClient::new()?.with_usage_recorder(usage_recorder);
+        if let Some(response_cache) = response_cache {
+            client = client.with_response_cache(response_cache);
+        }
         Ok(Self {
-            client: client::LLMClient::new()?,
-            chunker: ContentChunker::new(2000, 1),
+            client: Arc::new(client),
+            chunker: ContentChunker::new(CHUNK_MAX_SIZE, CHUNK_OVERLAP_SENTENCES),
             local_ai_client: LocalAIClient::new(local_ai_socket_path),
         })
     }
 
+    /// Chunks text content for embedding with the strategy matching its type, the
+    /// position of every chunk is merged into the metadata of the content it came from.
+    pub fn chunk_text_content(
+        &self,
+        content_type: &ResourceTextContentType,
+        content: &[String],
+        metadata: &[ResourceTextContentMetadata],
+    ) -> (Vec<String>, Vec<ResourceTextContentMetadata>) {
+        let mut chunks: Vec<String> = vec![];
+        let mut metadatas: Vec<ResourceTextContentMetadata> = vec![];
+
+        match content_type {
+            // every content is a single page
+            ResourceTextContentType::PDF => {
+                let pages: Vec<Page> = content
+                    .iter()
+                    .zip(metadata.iter())
+                    .enumerate()
+                    .map(|(i, (c, m))| Page {
+                        number: m.page.unwrap_or(i as u32 + 1),
+                        text: c.clone(),
+                    })
+                    .collect();
+                let chunker = PageChunker::new(CHUNK_MAX_SIZE, CHUNK_OVERLAP_SENTENCES);
+                let base_metadata = metadata.first().cloned().unwrap_or_default();
+                for chunk in chunker.chunk(&pages) {
+                    metadatas.push(base_metadata.with_chunk_metadata(&chunk.metadata));
+                    chunks.push(chunk.content);
+                }
+            }
+            ResourceTextContentType::Note
+            | ResourceTextContentType::Article
+            | ResourceTextContentType::Post
+            | ResourceTextContentType::Link
+            | ResourceTextContentType::Document
+            | ResourceTextContentType::GenericText => {
+                let chunker = MarkdownChunker::new(CHUNK_MAX_SIZE, CHUNK_OVERLAP_SENTENCES);
+                for (c, m) in content.iter().zip(metadata.iter()) {
+                    for chunk in chunker.chunk(c.as_str()) {
+                        metadatas.push(m.with_chunk_metadata(&chunk.metadata));
+                        chunks.push(chunk.content);
+                    }
+                }
+            }
+            // transcripts already come in time windows with their start and duration
+            _ => {
+                for (c, m) in content.iter().zip(metadata.iter()) {
+                    let embedding_chunks = self.chunker.chunk(c);
+                    // same metadata for each chunk
+                    metadatas.extend(std::iter::repeat_n(m.clone(), embedding_chunks.len()));
+                    chunks.extend(embedding_chunks);
+                }
+            }
+        }
+        (chunks, metadatas)
+    }
+
+    /// Messages of the branch of the session's `history` that ends with the
+    /// newest message after `active_message_id`, see [`active_branch`].
     pub fn parse_chat_history(
         &self,
         history: Vec<AIChatSessionMessage>,
+        active_message_id: Option<&str>,
     ) -> BackendResult<Vec<Message>> {
         let mut messages = Vec::new();
-        for msg in history {
+        for msg in active_branch(history, active_message_id) {
             let content = match msg.msg_type.as_ref() {
                 "text" => MessageContent::new_text(msg.content),
                 "image" => MessageContent::new_image(msg.content),
@@ -128,6 +328,8 @@ impl AI {
                 content: vec![content],
                 truncatable: msg.truncatable,
                 is_context: msg.is_context,
+                tool_calls: vec![],
+                tool_call_id: None,
             });
         }
         Ok(messages)
@@ -155,43 +357,21 @@ impl AI {
             })
     }
 
-    pub fn should_cluster(
+    pub async fn should_cluster(
         &self,
         query: &str,
         model: &Model,
         custom_key: Option<String>,
         context: Vec<ContextMessage>,
+        usage_tag: UsageTag,
     ) -> BackendResult<ShouldClusterResult> {
         // TODO(@nullptropy): temporary measure to make local model UX better
-        let (prompt, response_format) = match model {
-            Model::Custom { .. } => (should_narrow_search_prompt_simple(), None),
-            _ => (
-                should_narrow_search_prompt(&human_readable_current_time()),
-                Some(serde_json::json!({
-                    "type": "json_schema",
-                    "json_schema": {
-                        "name": "should_cluster_response",
-                        "strict": true,
-                        "schema": {
-                            "type": "object",
-                            "properties": {
-                                "embeddings_search_needed": {
-                                    "type": "boolean"
-                                },
-                                "relevant_context_ids": {
-                                    "type": "array",
-                                    "items": {
-                                        "type": "string"
-                                    }
-                                }
-                            },
-                            "required": ["embeddings_search_needed", "relevant_context_ids"],
-                            "additionalProperties": false
-                        }
-                    }
-                })),
-            ),
+        let prompt = match model {
+            Model::Custom { .. } => should_narrow_search_prompt_simple(),
+            _ => should_narrow_search_prompt(&human_readable_current_time()),
         };
+        // the answer of the prompt with the current time can't be reused
+        let cacheable = matches!(model, Model::Custom { .. });
 
         let mut messages = vec![Message::new_system(&prompt)];
         for msg in context {
@@ -202,23 +382,21 @@ impl AI {
             prompt, query
         )));
 
-        let answer =
-            self.client
-                .create_chat_completion(messages, model, custom_key, response_format)?;
+        let mut request = ChatCompletionRequest::new(messages, model)
+            .with_custom_key(custom_key)
+            .with_usage_tag(usage_tag);
+        if cacheable {
+            request = request.cacheable();
+        }
 
         if let Model::Custom { .. } = model {
+            let answer = self.client.complete(request).await?.content;
             Ok(ShouldClusterResult {
                 embeddings_search_needed: answer.trim().to_lowercase() == "true",
                 relevant_context_ids: Some(vec![]),
             })
         } else {
-            let response: ShouldClusterResult = serde_json::from_str(&answer).map_err(|e| {
-                BackendError::GenericError(format!(
-                    "failed to parse should_cluster response: {}",
-                    e
-                ))
-            })?;
-            Ok(response)
+            self.client.complete_structured(request).await
         }
     }
 
@@ -228,12 +406,30 @@ impl AI {
         new_keys: Vec<i64>,
         chunks: Vec<String>,
     ) -> BackendResult<()> {
-        self.local_ai_client
-            .upsert_embeddings(UpsertEmbeddingsRequest {
-                old_keys,
-                new_keys,
-                chunks,
-            })
+        retry_while_busy(|| {
+            self.local_ai_client
+                .upsert_embeddings(UpsertEmbeddingsRequest {
+                    old_keys: old_keys.clone(),
+                    new_keys: new_keys.clone(),
+                    chunks: chunks.clone(),
+                })
+        })
+    }
+
+    pub fn upsert_image_embeddings(
+        &mut self,
+        old_keys: Vec<i64>,
+        new_keys: Vec<i64>,
+        image_paths: Vec<String>,
+    ) -> BackendResult<()> {
+        retry_while_busy(|| {
+            self.local_ai_client
+                .upsert_image_embeddings(UpsertImageEmbeddingsRequest {
+                    old_keys: old_keys.clone(),
+                    new_keys: new_keys.clone(),
+                    image_paths: image_paths.clone(),
+                })
+        })
     }
 
     pub fn encode_sentences(&self, sentences: &Vec<String>) -> BackendResult<Vec<Vec<f32>>> {
@@ -276,6 +472,102 @@ impl AI {
         Ok(resources)
     }
 
+    /// Searches the embedded assistant answers of chat sessions, returns the
+    /// matching messages, best first.
+    pub fn chat_message_vector_search(
+        &self,
+        contents_store: &Database,
+        query: String,
+        num_docs: usize,
+        distance_threshold: Option<f32>,
+    ) -> BackendResult<Vec<AIChatSessionMessageHit>> {
+        let keys = contents_store.list_ai_session_message_embedding_keys(None)?;
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let keys: Vec<u64> = keys.iter().map(|id| *id as u64).collect();
+
+        let search_results = self
+            .local_ai_client
+            .filtered_search(FilteredSearchRequest {
+                query,
+                num_docs,
+                keys,
+                threshold: distance_threshold,
+            })?;
+        contents_store.list_ai_session_message_hits_by_embedding_keys(search_results)
+    }
+
+    /// Searches the image index with either a text `query` or a reference image at
+    /// `image_path`, returns one entry per matching resource.
+    pub fn image_search(
+        &self,
+        contents_store: &Database,
+        query: Option<String>,
+        image_path: Option<String>,
+        num_docs: usize,
+        resource_ids: Option<Vec<String>>,
+        distance_threshold: Option<f32>,
+    ) -> BackendResult<Vec<CompositeResource>> {
+        let keys: Vec<i64> = match resource_ids {
+            Some(resource_ids) => contents_store
+                .list_embedding_ids_by_type_resource_ids(EmbeddingType::Image, resource_ids)?,
+            None => contents_store.list_non_deleted_embedding_ids_by_type(EmbeddingType::Image)?,
+        };
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let keys: Vec<u64> = keys.iter().map(|id| *id as u64).collect();
+
+        let search_results = self.local_ai_client.image_search(ImageSearchRequest {
+            query,
+            image_path,
+            num_docs,
+            keys,
+            threshold: distance_threshold,
+        })?;
+        contents_store.list_unique_resources_only_by_embedding_row_ids(search_results)
+    }
+
+    /// Reorders `resources` by cross-encoder relevance to `query` and keeps the best `top_k`.
+    ///
+    /// Only resources with text content can be scored, the rest keep their original
+    /// order after the scored ones.
+    pub fn rerank(
+        &self,
+        query: &str,
+        resources: Vec<CompositeResource>,
+        top_k: usize,
+    ) -> BackendResult<Vec<CompositeResource>> {
+        let (scorable, mut unscored): (Vec<_>, Vec<_>) = resources
+            .into_iter()
+            .partition(|resource| resource.text_content.is_some());
+        if scorable.is_empty() {
+            unscored.truncate(top_k);
+            return Ok(unscored);
+        }
+
+        let docs = scorable
+            .iter()
+            .filter_map(|resource| resource.text_content.as_ref())
+            .map(|text_content| text_content.content.clone())
+            .collect();
+        let scores = self.local_ai_client.rerank(RerankRequest {
+            query: query.to_string(),
+            docs,
+            top_k: Some(top_k),
+        })?;
+
+        let mut scorable: Vec<Option<CompositeResource>> = scorable.into_iter().map(Some).collect();
+        let mut reranked: Vec<CompositeResource> = scores
+            .iter()
+            .filter_map(|score| scorable.get_mut(score.index).and_then(Option::take))
+            .collect();
+        reranked.extend(unscored);
+        reranked.truncate(top_k);
+        Ok(reranked)
+    }
+
     pub fn llm_metadata_messages_from_sources(
         &self,
         resources: &[CompositeResource],
@@ -312,20 +604,59 @@ impl AI {
         messages
     }
 
+    /// Packs search results into context passages with their neighbouring chunks,
+    /// see `context_assembler::assemble_context`.
+    pub fn assemble_context(
+        &self,
+        contents_store: &Database,
+        results: Vec<CompositeResource>,
+        model: &Model,
+    ) -> BackendResult<AssembledContext> {
+        let budget = ContextBudget::for_model(model);
+        // only the hits that can make it into the context, in rank order
+        let mut hit_ids: HashMap<&str, Vec<String>> = HashMap::new();
+        for result in results.iter() {
+            let ids = hit_ids.entry(result.resource.id.as_str()).or_default();
+            if let Some(text_content) = result.text_content.as_ref() {
+                if ids.len() < budget.max_hits_per_resource {
+                    ids.push(text_content.id.clone());
+                }
+            }
+        }
+        let mut chunks = HashMap::new();
+        for (resource_id, ids) in hit_ids {
+            chunks.insert(
+                resource_id.to_string(),
+                contents_store.list_resource_text_content_around(
+                    resource_id,
+                    &ids,
+                    budget.neighbour_chunks,
+                )?,
+            );
+        }
+        Ok(context_assembler::assemble_context(
+            results,
+            &chunks,
+            &budget,
+            model.tokenizer(),
+        ))
+    }
+
     pub fn llm_context_messages_from_sources(
         &self,
-        resources: &[CompositeResource],
+        passages: &[ContextPassage],
     ) -> Vec<ContextMessage> {
         let mut messages = Vec::new();
 
-        for (i, resource) in resources.iter().enumerate() {
-            if resource.text_content.is_none() {
-                continue;
-            }
-            let text_content = resource.text_content.as_ref().unwrap();
+        for (i, passage) in passages.iter().enumerate() {
+            let resource = &passage.hit;
+            let text_content = match resource.text_content.as_ref() {
+                Some(text_content) => text_content,
+                None => continue,
+            };
             let mut msg = ContextMessage {
                 id: (i + 1).to_string(),
-                content: Some(text_content.content.clone()),
+                content: Some(passage.content.clone()),
                 content_type: resource.resource.get_human_readable_type().clone(),
                 created_at: Some(resource.resource.created_at.to_string()),
                 page: text_content.metadata.page,
@@ -351,33 +682,52 @@ impl AI {
         messages
     }
 
+    // the ids of the sources match the ids of the context messages of the passages,
+    // dropped search results are listed after the sources
     pub fn get_sources_xml(
         &self,
-        resources: Vec<CompositeResource>,
+        passages: &[ContextPassage],
+        dropped: &[DroppedContext],
     ) -> (Vec<AIChatSessionMessageSource>, String) {
         let mut sources_xml = "<sources>\n".to_string();
-        let mut index = 1;
         let mut sources = Vec::new();
-        for resource in resources {
-            let source = AIChatSessionMessageSource::from_resource_index(&resource, index);
-            if source.is_none() {
-                continue;
+        for (i, passage) in passages.iter().enumerate() {
+            let mut source =
+                match AIChatSessionMessageSource::from_resource_index(&passage.hit, i + 1) {
+                    Some(source) => source,
+                    None => continue,
+                };
+            if passage.chunk_ids.len() > 1 {
+                source.chunk_uids = passage.chunk_ids.clone();
             }
-            let source = source.unwrap();
             sources_xml.push_str(&source.to_xml());
             sources.push(source);
-            index += 1;
+        }
+        for context in dropped {
+            sources_xml.push_str(&format!(
+                "
+<dropped>
+    <uid>{}</uid>
+    <resource_id>{}</resource_id>
+    <reason>{}</reason>
+</dropped>\n",
+                context.uid,
+                context.resource_id,
+                context.reason.as_str()
+            ));
         }
         sources_xml.push_str("</sources>");
         (sources, sources_xml)
     }
 
-    pub fn chat(
+    pub async fn chat(
         &self,
         contents_store: &Database,
         input: ChatInput,
         history: Vec<Message>,
         should_cluster: bool,
+        usage_tag: UsageTag,
+        cancellation_token: CancellationToken,
     ) -> BackendResult<ChatResult> {
         if input.resource_ids.is_empty() && !input.general {
             return Err(BackendError::GenericError(
@@ -385,17 +735,43 @@ impl AI {
             ));
         }
 
+        let number_documents = input.number_documents as usize;
         let mut rag_results = match should_cluster {
-            true => self.vector_search(
-                contents_store,
-                input.query.clone(),
-                input.number_documents as usize,
-                Some(input.resource_ids.clone()),
-                false,
-                // this is intentionally set a bit lax to allow for more results
-                // ultimately the llm will decide what to do with the results
-                Some(0.5),
-            )?,
+            true => {
+                let num_candidates = match input.rerank {
+                    true => number_documents * RERANK_CANDIDATES_FACTOR,
+                    false => number_documents,
+                };
+                let (search_query, candidates) = match input.plan_retrieval {
+                    true => {
+                        self.planned_search(
+                            contents_store,
+                            &input,
+                            &history,
+                            num_candidates,
+                            usage_tag.clone(),
+                        )
+                        .await?
+                    }
+                    false => (
+                        input.query.clone(),
+                        self.vector_search(
+                            contents_store,
+                            input.query.clone(),
+                            num_candidates,
+                            Some(input.resource_ids.clone()),
+                            false,
+                            // this is intentionally set a bit lax to allow for more results
+                            // ultimately the llm will decide what to do with the results
+                            Some(input.similarity_threshold.unwrap_or(0.5)),
+                        )?,
+                    ),
+                };
+                match input.rerank {
+                    true => self.rerank(&search_query, candidates, number_documents)?,
+                    false => candidates,
+                }
+            }
             false => contents_store.list_resources_by_ids(input.resource_ids.clone())?,
         };
         if rag_results.is_empty() && !input.general {
@@ -412,8 +788,9 @@ impl AI {
             }
         }
 
-        let contexts = self.llm_context_messages_from_sources(&rag_results);
-        let (sources, sources_xml) = self.get_sources_xml(rag_results);
+        let assembled = self.assemble_context(contents_store, rag_results, &input.model)?;
+        let contexts = self.llm_context_messages_from_sources(&assembled.passages);
+        let (sources, sources_xml) = self.get_sources_xml(&assembled.passages, &assembled.dropped);
 
         // system message
         let current_time = human_readable_current_time();
@@ -425,6 +802,19 @@ impl AI {
             },
         };
 
+        // the built-in prompt is kept as the answer format and citations depend on it
+        let system_message_prompt = match input
+            .system_prompt
+            .as_deref()
+            .filter(|prompt| !prompt.trim().is_empty())
+        {
+            Some(instructions) => format!(
+                "{}\n\n## Instructions for this conversation\n\n{}",
+                system_message_prompt, instructions
+            ),
+            None => system_message_prompt,
+        };
+
         let mut messages = vec![Message::new_system(&system_message_prompt)];
 
         let history_len = history.len();
@@ -456,12 +846,22 @@ impl AI {
 
         messages.push(Message::new_user(&input.query));
         let messages_slice = messages[history_len + 1..].to_vec().clone();
-        let stream = self.client.create_streaming_chat_completion(
-            messages,
-            &input.model,
-            input.custom_key,
-            None,
-        )?;
+        let mut request = input.fallbacks.into_iter().fold(
+            ChatCompletionRequest::new(messages, &input.model)
+                .with_custom_key(input.custom_key)
+                .with_usage_tag(usage_tag),
+            |request, (model, custom_key)| request.with_fallback(model, custom_key),
+        );
+        if let Some(max_retries) = input.max_retries {
+            request = request.with_retry_policy(RetryPolicy {
+                max_retries,
+                ..Default::default()
+            });
+        }
+        let stream = self
+            .client
+            .complete_streaming(request, cancellation_token)
+            .await?;
 
         Ok(ChatResult {
             messages: messages_slice,
@@ -471,27 +871,223 @@ impl AI {
         })
     }
 
+    /// Folds the oldest turns of `history` into `summary` once the history takes
+    /// more than `HISTORY_SUMMARY_THRESHOLD` of the model's context window.
+    /// Returns the new summary and the number of leading messages it covers,
+    /// None if the history still fits.
+    pub async fn fold_history(
+        &self,
+        summary: &str,
+        history: &[Message],
+        model: &Model,
+        custom_key: Option<String>,
+        usage_tag: UsageTag,
+    ) -> BackendResult<Option<(String, usize)>> {
+        let budget = (model.max_tokens() as f32 * HISTORY_SUMMARY_THRESHOLD) as usize;
+        let count = client::tokens::messages_to_summarize(
+            history,
+            model.tokenizer(),
+            budget,
+            HISTORY_KEEP_RECENT_MESSAGES,
+        );
+        if count == 0 {
+            return Ok(None);
+        }
+        let summary = self
+            .summarize_history(summary, &history[..count], model, custom_key, usage_tag)
+            .await?;
+        Ok(Some((summary, count)))
+    }
+
+    /// Folds `messages` into `previous_summary`, the new summary is sent in
+    /// place of them in the session's later requests.
+    pub async fn summarize_history(
+        &self,
+        previous_summary: &str,
+        messages: &[Message],
+        model: &Model,
+        custom_key: Option<String>,
+        usage_tag: UsageTag,
+    ) -> BackendResult<String> {
+        // context documents and images are left out, they are sent again when
+        // they're relevant to a later query
+        let mut transcript = String::new();
+        for message in messages.iter().filter(|m| !m.is_context) {
+            for content in message.content.iter() {
+                if let MessageContent::Text(text) = content {
+                    transcript.push_str(&format!("{}: {}\n\n", message.role, text.text));
+                }
+            }
+        }
+
+        let mut input = String::new();
+        if !previous_summary.is_empty() {
+            input.push_str(&format!(
+                "<previous_summary>\n{}\n</previous_summary>\n\n",
+                previous_summary
+            ));
+        }
+        input.push_str(&format!("<messages>\n{}</messages>", transcript));
+
+        let messages = vec![
+            Message::new_system(&summarize_history_prompt()),
+            Message::new_user(&input),
+        ];
+        let request = ChatCompletionRequest::new(messages, model)
+            .with_custom_key(custom_key)
+            .with_usage_tag(UsageTag {
+                agent: Some("history_summary".to_string()),
+                ..usage_tag
+            });
+        let summary = self.client.complete(request).await?.content;
+        Ok(summary.trim().to_string())
+    }
+
+    /// Rewrites a chat query into a standalone query, paraphrases and keywords
+    /// to search for, the most recent messages of the chat resolve what a
+    /// follow-up question refers to.
+    pub async fn plan_retrieval(
+        &self,
+        query: &str,
+        history: &[Message],
+        model: &Model,
+        custom_key: Option<String>,
+        usage_tag: UsageTag,
+    ) -> BackendResult<RetrievalPlan> {
+        let recent = history
+            .iter()
+            .filter(|m| !m.is_context)
+            .rev()
+            .take(PLANNER_HISTORY_MESSAGES)
+            .collect::<Vec<_>>();
+        let mut transcript = String::new();
+        for message in recent.into_iter().rev() {
+            for content in message.content.iter() {
+                if let MessageContent::Text(text) = content {
+                    transcript.push_str(&format!("{}: {}\n\n", message.role, text.text));
+                }
+            }
+        }
+
+        let input = format!(
+            "<messages>\n{}</messages>\n\n<query>\n{}\n</query>",
+            transcript, query
+        );
+        let messages = vec![
+            Message::new_system(&retrieval_plan_prompt()),
+            Message::new_user(&input),
+        ];
+        let request = ChatCompletionRequest::new(messages, model)
+            .with_custom_key(custom_key)
+            .with_usage_tag(UsageTag {
+                agent: Some("retrieval_plan".to_string()),
+                ..usage_tag
+            });
+        self.client.complete_structured(request).await
+    }
+
+    // plans the searches for the chat query and runs them, returns the
+    // standalone query with the results
+    async fn planned_search(
+        &self,
+        contents_store: &Database,
+        input: &ChatInput,
+        history: &[Message],
+        num_docs: usize,
+        usage_tag: UsageTag,
+    ) -> BackendResult<(String, Vec<CompositeResource>)> {
+        let plan = match self
+            .plan_retrieval(
+                &input.query,
+                history,
+                &input.model,
+                input.custom_key.clone(),
+                usage_tag,
+            )
+            .await
+        {
+            Ok(plan) => plan,
+            Err(e) => {
+                tracing::warn!("failed to plan retrieval, searching for the query: {}", e);
+                RetrievalPlan::from_query(&input.query)
+            }
+        };
+
+        let resources = self.hybrid_search(
+            contents_store,
+            &plan,
+            &input.resource_ids,
+            num_docs,
+            Some(input.similarity_threshold.unwrap_or(0.5)),
+        )?;
+        Ok((plan.standalone_query, resources))
+    }
+
+    /// Runs a vector search for every query and a keyword search for every
+    /// keyword of `plan` within `resource_ids` and fuses the rankings, returns
+    /// one entry per matching chunk, best first.
+    pub fn hybrid_search(
+        &self,
+        contents_store: &Database,
+        plan: &RetrievalPlan,
+        resource_ids: &[String],
+        num_docs: usize,
+        distance_threshold: Option<f32>,
+    ) -> BackendResult<Vec<CompositeResource>> {
+        let keys: Vec<u64> = contents_store
+            .list_embedding_ids_by_resource_ids(resource_ids.to_vec())?
+            .iter()
+            .map(|id| *id as u64)
+            .collect();
+        let mut rankings = vec![];
+        for query in plan.search_queries() {
+            rankings.push(
+                self.local_ai_client
+                    .filtered_search(FilteredSearchRequest {
+                        query: query.to_string(),
+                        num_docs,
+                        keys: keys.clone(),
+                        threshold: distance_threshold,
+                    })?,
+            );
+        }
+        for keyword in plan.search_keywords() {
+            rankings.push(contents_store.keyword_search_text_content_embedding_ids(
+                keyword,
+                resource_ids,
+                num_docs,
+            )?);
+        }
+
+        let row_ids = fuse_rankings(&rankings, num_docs);
+        contents_store.list_resources_by_embedding_row_ids(row_ids)
+    }
+
     // TODO: migrate
-    pub fn get_sql_query(
+    pub async fn get_sql_query(
         &self,
         prompt: String,
         model: &Model,
         custom_key: Option<String>,
-    ) -> BackendResult<String> {
+    ) -> BackendResult<SqlQueryResult> {
         let messages = vec![
             Message::new_system(&sql_query_generator_prompt()),
             Message::new_user(&prompt),
         ];
-        self.client
-            .create_chat_completion(messages, model, custom_key, None)
+        let request = ChatCompletionRequest::new(messages, model)
+            .with_custom_key(custom_key)
+            .with_usage_tag(UsageTag::agent("sql_query"))
+            .cacheable();
+        self.client.complete_structured(request).await
     }
 
-    pub fn create_app(
+    pub async fn create_app(
         &self,
         query: String,
         model: &Model,
         custom_key: Option<String>,
         inline_images: Option<Vec<String>>,
+        cancellation_token: CancellationToken,
     ) -> BackendResult<ChatCompletionStream> {
         let mut messages = vec![
             Message::new_system(&create_app_prompt(&human_readable_current_time())),
@@ -502,7 +1098,253 @@ impl AI {
                 messages.push(Message::new_image(&image));
             }
         }
+        let request = ChatCompletionRequest::new(messages, model)
+            .with_custom_key(custom_key)
+            .with_usage_tag(UsageTag::agent("create_app"));
         self.client
-            .create_streaming_chat_completion(messages, model, custom_key, None)
+            .complete_streaming(request, cancellation_token)
+            .await
+    }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::local::stub_server::stub_ai;
+    use super::HISTORY_KEEP_RECENT_MESSAGES;
+    use crate::ai::llm::client::{mock_server::MockServer, tokens, Model, Provider};
+    use crate::ai::llm::models::{Message, MessageContent};
+    use crate::ai::llm::usage::UsageTag;
+    use crate::store::db::Database;
+    use crate::store::models::{
+        current_time, random_uuid, CompositeResource, EmbeddingResource, EmbeddingType, Resource,
+        ResourceMetadata, ResourceTextContent, ResourceTextContentMetadata,
+        ResourceTextContentType,
+    };
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    fn resource(id: &str, content: Option<&str>) -> CompositeResource {
+        CompositeResource {
+            resource: Resource {
+                id: id.to_string(),
+                resource_path: String::new(),
+                resource_type: "application/vnd.space.article".to_string(),
+                created_at: current_time(),
+                updated_at: current_time(),
+                deleted: 0,
+            },
+            metadata: None,
+            text_content: content.map(|content| ResourceTextContent {
+                id: format!("{}-content", id),
+                resource_id: id.to_string(),
+                content: content.to_string(),
+                content_type: ResourceTextContentType::Article,
+                metadata: ResourceTextContentMetadata::default(),
+            }),
+            resource_tags: None,
+            resource_annotations: None,
+            post_processing_job: None,
+            space_ids: None,
+        }
+    }
+
+    fn ids(resources: &[CompositeResource]) -> Vec<&str> {
+        resources.iter().map(|r| r.resource.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_rerank_maps_scores_to_resources() {
+        let dir = tempdir().unwrap();
+        let ai = stub_ai(dir.path(), &HashMap::new());
+        let resources = vec![
+            resource("pricing", Some("The Pro plan costs 12 dollars per seat")),
+            resource("image", None),
+            resource("espresso", Some("Grind finer when the espresso runs fast")),
+            resource(
+                "discount",
+                Some("Teams get a discount on the Pro plan per seat"),
+            ),
+        ];
+
+        // scored resources in the order of their scores, then the ones without text
+        let reranked = ai
+            .rerank("Pro plan discount per seat", resources.clone(), 4)
+            .unwrap();
+        assert_eq!(
+            ids(&reranked),
+            vec!["discount", "pricing", "espresso", "image"]
+        );
+
+        let reranked = ai.rerank("espresso", resources.clone(), 1).unwrap();
+        assert_eq!(ids(&reranked), vec!["espresso"]);
+
+        let unscored = vec![resource("a", None), resource("b", None)];
+        let reranked = ai.rerank("anything", unscored, 1).unwrap();
+        assert_eq!(ids(&reranked), vec!["a"]);
+    }
+
+    #[test]
+    fn test_image_search() {
+        let dir = tempdir().unwrap();
+        let mut db = Database::new(&dir.path().join("test.db").to_string_lossy(), true).unwrap();
+
+        let mut texts = HashMap::new();
+        let mut tx = db.begin().unwrap();
+        for (id, description) in [
+            ("sunset", "sunset over the beach"),
+            ("receipt", "scanned grocery receipt"),
+            ("note", "handwritten note"),
+        ] {
+            Database::create_resource_tx(
+                &mut tx,
+                &Resource {
+                    id: id.to_string(),
+                    resource_path: format!("/images/{}.png", description.replace(' ', "-")),
+                    resource_type: "image/png".to_string(),
+                    created_at: current_time(),
+                    updated_at: current_time(),
+                    deleted: 0,
+                },
+            )
+            .unwrap();
+            let content_id = tx.last_insert_rowid();
+            Database::create_resource_metadata_tx(
+                &mut tx,
+                &ResourceMetadata {
+                    id: random_uuid(),
+                    resource_id: id.to_string(),
+                    name: id.to_string(),
+                    source_uri: String::new(),
+                    alt: String::new(),
+                    user_context: String::new(),
+                },
+            )
+            .unwrap();
+            // the note was never embedded
+            if id == "note" {
+                continue;
+            }
+            let key = Database::create_embedding_resource_tx(
+                &mut tx,
+                &EmbeddingResource {
+                    rowid: None,
+                    content_id,
+                    resource_id: id.to_string(),
+                    embedding_type: EmbeddingType::Image,
+                },
+            )
+            .unwrap();
+            texts.insert(key as u64, description.to_string());
+        }
+        tx.commit().unwrap();
+        assert!(db.get_resource_rowid("sunset").unwrap().is_some());
+        assert_eq!(db.get_resource_rowid("missing").unwrap(), None);
+
+        let ai = stub_ai(dir.path(), &texts);
+        let results = ai
+            .image_search(
+                &db,
+                Some("beach at sunset".to_string()),
+                None,
+                5,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(ids(&results), vec!["sunset"]);
+
+        let results = ai
+            .image_search(
+                &db,
+                None,
+                Some("/images/scanned-grocery-receipt.png".to_string()),
+                5,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(ids(&results), vec!["receipt"]);
+
+        // nothing to search, the server isn't asked
+        let results = ai
+            .image_search(
+                &db,
+                Some("handwritten note".to_string()),
+                None,
+                5,
+                Some(vec!["note".to_string()]),
+                None,
+            )
+            .unwrap();
+        assert!(results.is_empty());
+
+        // errors of the server are returned, not swallowed
+        assert!(ai.image_search(&db, None, None, 5, None, None).is_err());
+    }
+
+    fn text(message: &Message) -> &str {
+        match &message.content[0] {
+            MessageContent::Text(text) => &text.text,
+            MessageContent::Image(_) => panic!("unexpected image"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_long_history_is_summarized_then_truncated() {
+        let server = MockServer::start(
+            200,
+            r#"{"choices":[{"index":0,"message":{"role":"assistant","content":" earlier turns \n"}}]}"#,
+        )
+        .await;
+        let dir = tempdir().unwrap();
+        let ai = stub_ai(dir.path(), &HashMap::new());
+        let model = Model::Custom {
+            name: "local".to_string(),
+            provider: Provider::Custom(server.url().to_string()),
+            max_tokens: 4_000,
+            vision: false,
+        };
+        // ~210 tokens per turn, the summary is due beyond 2000
+        let mut history = vec![];
+        for turn in 0..12 {
+            history.push(Message::new_user(&format!(
+                "question {} {}",
+                turn,
+                "q".repeat(400)
+            )));
+            history.push(Message::new_assistant(&format!(
+                "answer {} {}",
+                turn,
+                "a".repeat(400)
+            )));
+        }
+
+        let folded = ai
+            .fold_history("", &history[..16], &model, None, UsageTag::default())
+            .await
+            .unwrap();
+        assert!(folded.is_none());
+        assert!(server.requests().is_empty());
+
+        let (summary, count) = ai
+            .fold_history("first turn", &history, &model, None, UsageTag::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary, "earlier turns");
+        assert_eq!(count, history.len() - HISTORY_KEEP_RECENT_MESSAGES);
+        // only the summarized turns and the previous summary are sent
+        let request = server.last_request().body;
+        assert!(request.contains("first turn"));
+        assert!(request.contains("answer 8") && !request.contains("question 9"));
+
+        // the summary is sent with the recent turns
+        let mut folded = vec![Message::new_summary(&summary)];
+        folded.extend_from_slice(&history[count..]);
+        let (truncated, sent) = tokens::truncate_messages(folded, &model);
+        assert!(!truncated);
+        assert_eq!(sent.len(), HISTORY_KEEP_RECENT_MESSAGES + 1);
+        assert!(text(&sent[0]).ends_with("earlier turns"));
+        assert!(text(&sent[1]).starts_with("question 9"));
     }
 }
@@ -323,7 +323,8 @@ In cases where you need both a websearch and a surflet, do not use both in the s
 
 - Multiple documents may be provided as JSON context
 - Try to root answers in provided context with proper citations when context is available, when not enough information is provided, you can use your own knowledge to answer the question.{}
-- Citation format: `<citation>context_id</citation>` immediately after supported statements, use separate tags for each context ID
+- Citation format: `<citation quote=\"passage\">context_id</citation>` immediately after supported statements, use separate tags for each context ID
+- The quote is a short passage of the context copied word for word that supports the statement, without double quotes or angle brackets
 - For images: `<citation type=\"image\"></citation>`
 - Each factual statement needs its own citation - never group multiple context IDs
 - Place citations outside punctuation but inside paragraphs/lists{}
@@ -355,7 +356,8 @@ pub fn chat_prompt(current_time: &str) -> String {
 - Never group multiple context ids within a single citation tag.
 
 - Citation format:
-  - Basic citation: `<citation>context_id</citation>`
+  - Basic citation: `<citation quote=\"passage\">context_id</citation>`
+  - The quote is a short passage of the cited context copied word for word that supports the statement, without double quotes or angle brackets. Never make up or paraphrase a quote.
   - Image citation: `<citation type=\"image\"></citation>`
   - Place citations outside of punctuation marks but inside list items or paragraphs
 
@@ -396,7 +398,7 @@ Quality Control Steps:
 
 Example of Correct Citation Usage:
 ```markdown
-The temperature reached 32°C yesterday <citation>1</citation> while humidity remained at 45% <citation>2</citation>.
+The temperature reached 32°C yesterday <citation quote=\"the temperature peaked at 32°C on Monday\">1</citation> while humidity remained at 45% <citation quote=\"relative humidity held steady at 45%\">2</citation>.
 ```
 
 Example of Incorrect Citation Usage:
@@ -667,3 +669,41 @@ When it makes sense, combine approaches for queries with both specific terms and
 Note: Always ensure that your SQL queries only return resources where deleted = 0, unless the query explicitly includes deleted resources.
 "#.to_string()
 }
+
+pub fn summarize_history_prompt() -> String {
+    r#"You maintain the running summary of a long conversation between a user and an AI assistant. The summary replaces the summarised messages in future requests, so anything it leaves out is forgotten.
+
+You are given the previous summary (if any) and the messages that follow it. Write a new summary that merges both:
+
+- Keep every decision, conclusion, preference, constraint and open question the user stated or agreed to, with the reasoning behind it where it was given.
+- Keep names, numbers, dates, code identifiers and resource titles exactly as written.
+- Note which topics or documents were discussed, but not the contents of documents that were only provided as context.
+- Drop greetings, repetition and anything that was later corrected.
+- Write in the third person ("The user decided ..."), as concise bullet points grouped by topic.
+
+Respond only with the new summary."#
+        .to_string()
+}
+
+pub fn chat_title_prompt() -> String {
+    r#"You name conversations between a user and an AI assistant so the user can find them again in a long list.
+
+You are given the first question of a conversation and the answer to it. Respond with:
+
+- title: a concise title of at most six words that names the subject, not the form of the question ("Sourdough starter feeding schedule", not "Question about bread"). Use the language of the question, no quotes and no trailing punctuation.
+- tags: one to three short lowercase topic tags, most specific first, e.g. "baking"."#
+        .to_string()
+}
+
+pub fn retrieval_plan_prompt() -> String {
+    r#"You plan the document searches for a question asked in a conversation with an AI assistant. The documents are searched by meaning and by keywords, the search doesn't see the conversation.
+
+You are given the most recent messages of the conversation and the new query. Respond with:
+
+- standalone_query: the query rewritten so it can be understood without the conversation. Replace pronouns and references like "the second one" or "that" with what they refer to. Keep the query as it is if it already stands on its own.
+- queries: up to three paraphrases of the standalone query, or the sub-questions it is made of if it asks for several things. Leave it empty for simple queries.
+- keywords: up to four names, technical terms or short phrases that are likely to appear word for word in relevant documents. No generic words.
+
+Use the language of the query."#
+        .to_string()
+}
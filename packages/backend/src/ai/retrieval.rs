@@ -0,0 +1,130 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+// searches run per planned query on top of the standalone query
+pub const MAX_PLANNED_QUERIES: usize = 3;
+pub const MAX_PLANNED_KEYWORDS: usize = 4;
+
+// most recent chat messages the planner sees to resolve follow-up questions
+pub const PLANNER_HISTORY_MESSAGES: usize = 6;
+
+// dampens the weight of the top ranks in reciprocal rank fusion, 60 is the
+// value from the original paper and works well without tuning
+const RRF_K: f32 = 60.0;
+
+// the keyword index is a trigram index, shorter keywords match nothing
+const MIN_KEYWORD_CHARS: usize = 3;
+
+/// Searches to run for a chat query, generated from the query and the chat history.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RetrievalPlan {
+    /// The query rewritten to be understood without the chat history.
+    pub standalone_query: String,
+    /// Paraphrases of the standalone query or sub-questions it is made of.
+    pub queries: Vec<String>,
+    /// Names, terms and short phrases likely to appear word for word in relevant documents.
+    pub keywords: Vec<String>,
+}
+
+impl RetrievalPlan {
+    /// A plan that only searches for `query` as it is.
+    pub fn from_query(query: &str) -> Self {
+        Self {
+            standalone_query: query.to_string(),
+            queries: vec![],
+            keywords: vec![],
+        }
+    }
+
+    /// The standalone query followed by the distinct planned queries.
+    pub fn search_queries(&self) -> Vec<&str> {
+        let mut seen = HashSet::new();
+        std::iter::once(&self.standalone_query)
+            .chain(self.queries.iter())
+            .map(|query| query.trim())
+            .filter(|query| !query.is_empty() && seen.insert(query.to_lowercase()))
+            .take(MAX_PLANNED_QUERIES + 1)
+            .collect()
+    }
+
+    pub fn search_keywords(&self) -> Vec<&str> {
+        let mut seen = HashSet::new();
+        self.keywords
+            .iter()
+            .map(|keyword| keyword.trim())
+            .filter(|keyword| keyword.chars().count() >= MIN_KEYWORD_CHARS)
+            .filter(|keyword| seen.insert(keyword.to_lowercase()))
+            .take(MAX_PLANNED_KEYWORDS)
+            .collect()
+    }
+}
+
+/// Merges ranked lists of ids with reciprocal rank fusion, ids ranked high in
+/// many lists come first. Returns at most `limit` ids.
+pub fn fuse_rankings(rankings: &[Vec<i64>], limit: usize) -> Vec<i64> {
+    let mut scores: HashMap<i64, f32> = HashMap::new();
+    // ties keep the order in which the ids were first seen
+    let mut order = vec![];
+    for ranking in rankings {
+        for (rank, id) in ranking.iter().enumerate() {
+            let score = scores.entry(*id).or_insert_with(|| {
+                order.push(*id);
+                0.0
+            });
+            *score += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    order.sort_by(|a, b| scores[b].total_cmp(&scores[a]));
+    order.truncate(limit);
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuse_rankings() {
+        let rankings = vec![vec![1, 2, 3], vec![3, 4], vec![3, 2]];
+        assert_eq!(fuse_rankings(&rankings, 10), vec![3, 2, 1, 4]);
+        assert_eq!(fuse_rankings(&rankings, 2), vec![3, 2]);
+        assert_eq!(fuse_rankings(&[vec![5, 6], vec![6, 5]], 10), vec![5, 6]);
+        assert!(fuse_rankings(&[], 10).is_empty());
+    }
+
+    #[test]
+    fn test_retrieval_plan_searches() {
+        let plan = RetrievalPlan {
+            standalone_query: "What does the second pricing tier cost?".to_string(),
+            queries: vec![
+                "what does the second pricing tier cost?".to_string(),
+                " ".to_string(),
+                "Pro plan price".to_string(),
+                "Pro plan seat discount".to_string(),
+                "Pro plan billing".to_string(),
+                "Pro plan refunds".to_string(),
+            ],
+            keywords: vec![
+                "Pro plan".to_string(),
+                "pro plan".to_string(),
+                "$".to_string(),
+                "annual billing".to_string(),
+            ],
+        };
+        assert_eq!(
+            plan.search_queries(),
+            vec![
+                "What does the second pricing tier cost?",
+                "Pro plan price",
+                "Pro plan seat discount",
+                "Pro plan billing",
+            ]
+        );
+        assert_eq!(plan.search_keywords(), vec!["Pro plan", "annual billing"]);
+        assert_eq!(
+            RetrievalPlan::from_query("pricing").search_queries(),
+            vec!["pricing"]
+        );
+    }
+}
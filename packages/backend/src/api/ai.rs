@@ -1,6 +1,10 @@
 use crate::{
-    ai::llm::{client::Model, models::Message},
+    ai::llm::{
+        client::{catalog::ModelInfoOverride, Model, Provider},
+        models::Message,
+    },
     api::message::*,
+    store::models::{AIChatSessionConfig, LLMModelPrice, LLMUsagePeriod},
     worker::tunnel::WorkerTunnel,
 };
 use neon::prelude::*;
@@ -11,11 +15,26 @@ pub fn register_exported_functions(cx: &mut ModuleContext) -> NeonResult<()> {
     cx.export_function("js__ai_send_chat_message", js_send_chat_message)?;
     cx.export_function("js__ai_send_note_message", js_send_note_message)?;
     cx.export_function("js__ai_create_app", js_create_app)?;
+    cx.export_function("js__ai_cancel_request", js_cancel_request)?;
     cx.export_function("js__ai_query_sffs_resources", js_query_sffs_resources)?;
     cx.export_function("js__ai_get_chat_data_source", js_get_ai_chat_data_source)?;
     cx.export_function("js__ai_get_docs_similarity", js_get_ai_docs_similarity)?;
     cx.export_function("js__ai_get_youtube_transcript", js_get_youtube_transcript)?;
     cx.export_function("js__ai_search_chat_resources", js_search_chat_resources)?;
+    cx.export_function("js__ai_get_llm_usage", js_get_llm_usage)?;
+    cx.export_function("js__ai_set_llm_model_price", js_set_llm_model_price)?;
+    cx.export_function("js__ai_get_llm_cache_stats", js_get_llm_cache_stats)?;
+    cx.export_function("js__ai_purge_llm_cache", js_purge_llm_cache)?;
+    cx.export_function("js__ai_get_model_catalog", js_get_model_catalog)?;
+    cx.export_function(
+        "js__ai_set_model_catalog_override",
+        js_set_model_catalog_override,
+    )?;
+    cx.export_function(
+        "js__ai_remove_model_catalog_override",
+        js_remove_model_catalog_override,
+    )?;
+    cx.export_function("js__ai_refresh_model_catalog", js_refresh_model_catalog)?;
     Ok(())
 }
 
@@ -119,6 +138,7 @@ fn js_create_app(mut cx: FunctionContext) -> JsResult<JsPromise> {
         pub model: Model,
         pub custom_key: Option<String>,
         pub inline_images: Option<Vec<String>>,
+        pub request_id: Option<String>,
     }
 
     let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
@@ -142,12 +162,26 @@ fn js_create_app(mut cx: FunctionContext) -> JsResult<JsPromise> {
             model: opts.model,
             custom_key: opts.custom_key,
             inline_images: opts.inline_images,
+            request_id: opts.request_id,
         }),
         deferred,
     );
     Ok(promise)
 }
 
+fn js_cancel_request(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let request_id = cx.argument::<JsString>(1)?.value(&mut cx);
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::CancelAIRequest(request_id)),
+        deferred,
+    );
+
+    Ok(promise)
+}
+
 fn js_create_chat_completion(mut cx: FunctionContext) -> JsResult<JsPromise> {
     #[derive(Serialize, Deserialize, Debug)]
     struct Options {
@@ -179,6 +213,26 @@ fn js_create_chat_completion(mut cx: FunctionContext) -> JsResult<JsPromise> {
     Ok(promise)
 }
 
+// a model answering in place of the query's model while the ones before it
+// keep failing
+#[derive(Serialize, Deserialize, Debug)]
+struct FallbackModel {
+    model: Model,
+    custom_key: Option<String>,
+}
+
+fn fallback_chain(fallbacks: Vec<FallbackModel>) -> Vec<(Model, Option<String>)> {
+    fallbacks
+        .into_iter()
+        .map(|fallback| {
+            (
+                fallback.model,
+                fallback.custom_key.filter(|k| !k.is_empty()),
+            )
+        })
+        .collect()
+}
+
 fn js_send_note_message(mut cx: FunctionContext) -> JsResult<JsPromise> {
     fn default_limit() -> i32 {
         20
@@ -200,6 +254,12 @@ fn js_send_note_message(mut cx: FunctionContext) -> JsResult<JsPromise> {
         pub websearch: bool,
         #[serde(default)]
         pub surflet: bool,
+        #[serde(default)]
+        pub rerank: bool,
+        pub request_id: Option<String>,
+        #[serde(default)]
+        pub fallbacks: Vec<FallbackModel>,
+        pub max_retries: Option<u32>,
     }
 
     let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
@@ -226,6 +286,10 @@ fn js_send_note_message(mut cx: FunctionContext) -> JsResult<JsPromise> {
             general: opts.general,
             websearch: opts.websearch,
             surflet: opts.surflet,
+            rerank: opts.rerank,
+            request_id: opts.request_id,
+            fallbacks: fallback_chain(opts.fallbacks),
+            max_retries: opts.max_retries,
         }),
         deferred,
     );
@@ -234,26 +298,47 @@ fn js_send_note_message(mut cx: FunctionContext) -> JsResult<JsPromise> {
 }
 
 fn js_send_chat_message(mut cx: FunctionContext) -> JsResult<JsPromise> {
-    fn default_limit() -> i32 {
-        20
-    }
     // TODO: why separate struct from ChatInput?
     #[derive(Serialize, Deserialize, Debug)]
     struct ChatMessageOptions {
         pub query: String,
         pub chat_id: String,
-        pub model: Model,
+        // the model, sources and limits fall back to the chat's configuration
+        pub model: Option<Model>,
         pub custom_key: Option<String>,
         pub resource_ids: Option<Vec<String>>,
+        pub space_ids: Option<Vec<String>>,
         pub inline_images: Option<Vec<String>>,
-        #[serde(default = "default_limit")]
-        pub limit: i32,
+        pub limit: Option<i32>,
+        pub similarity_threshold: Option<f32>,
         #[serde(default)]
         pub rag_only: bool,
         #[serde(default)]
         pub general: bool,
         #[serde(default)]
         pub app_creation: bool,
+        #[serde(default)]
+        pub rerank: bool,
+        // id of a message of an earlier turn, the query is answered as a new
+        // version of that turn (an edit, or a regeneration with the same query)
+        // instead of continuing the active branch
+        pub branch_from: Option<String>,
+        #[serde(default)]
+        pub embed_answer: bool,
+        // generate a title and tags for an untitled chat, sent over the event bus
+        #[serde(default)]
+        pub generate_title: bool,
+        // rewrite follow-up questions with the chat history and search for
+        // paraphrases and keywords too
+        #[serde(default)]
+        pub plan_retrieval: bool,
+        // id the generation can be stopped with through `js__ai_cancel_request`
+        pub request_id: Option<String>,
+        // the user's fallback models in order, used while `model` keeps failing
+        #[serde(default)]
+        pub fallbacks: Vec<FallbackModel>,
+        // how often a failed answer is retried before falling back
+        pub max_retries: Option<u32>,
     }
 
     let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
@@ -272,14 +357,26 @@ fn js_send_chat_message(mut cx: FunctionContext) -> JsResult<JsPromise> {
             callback,
             query: opts.query,
             session_id: opts.chat_id,
-            model: opts.model,
+            config: AIChatSessionConfig {
+                model: opts.model,
+                resource_ids: opts.resource_ids.unwrap_or_default(),
+                space_ids: opts.space_ids.unwrap_or_default(),
+                number_documents: opts.limit,
+                similarity_threshold: opts.similarity_threshold,
+            },
             custom_key: opts.custom_key,
-            resource_ids: opts.resource_ids.unwrap_or_default(),
             inline_images: opts.inline_images,
-            number_documents: opts.limit,
             search_only: opts.rag_only,
             general: opts.general,
             app_creation: opts.app_creation,
+            rerank: opts.rerank,
+            branch_from: opts.branch_from,
+            embed_answer: opts.embed_answer,
+            generate_title: opts.generate_title,
+            plan_retrieval: opts.plan_retrieval,
+            request_id: opts.request_id,
+            fallbacks: fallback_chain(opts.fallbacks),
+            max_retries: opts.max_retries,
         }),
         deferred,
     );
@@ -324,3 +421,144 @@ fn js_search_chat_resources(mut cx: FunctionContext) -> JsResult<JsPromise> {
 
     Ok(promise)
 }
+
+fn js_get_llm_usage(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    #[derive(Serialize, Deserialize, Debug)]
+    struct LLMUsageOptions {
+        pub period: LLMUsagePeriod,
+        pub from: Option<chrono::DateTime<chrono::Utc>>,
+        pub to: Option<chrono::DateTime<chrono::Utc>>,
+        #[serde(default)]
+        pub custom_key_only: bool,
+    }
+
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let json_opt = cx.argument::<JsString>(1)?.value(&mut cx);
+    let opts: LLMUsageOptions = match serde_json::from_str(&json_opt) {
+        Ok(opts) => opts,
+        Err(err) => return cx.throw_error(format!("failed to parse options: {err}")),
+    };
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::GetLLMUsage {
+            period: opts.period,
+            from: opts.from,
+            to: opts.to,
+            custom_key_only: opts.custom_key_only,
+        }),
+        deferred,
+    );
+
+    Ok(promise)
+}
+
+fn js_set_llm_model_price(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let model = cx.argument::<JsString>(1)?.value(&mut cx);
+    let price = cx.argument::<JsString>(2)?.value(&mut cx);
+    let price: LLMModelPrice = match serde_json::from_str(&price) {
+        Ok(price) => price,
+        Err(err) => return cx.throw_error(format!("failed to parse price: {err}")),
+    };
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::SetLLMModelPrice { model, price }),
+        deferred,
+    );
+
+    Ok(promise)
+}
+
+fn js_get_llm_cache_stats(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::GetLLMCacheStats),
+        deferred,
+    );
+
+    Ok(promise)
+}
+
+fn js_purge_llm_cache(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let expired_only = cx.argument::<JsBoolean>(1)?.value(&mut cx);
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::PurgeLLMCache { expired_only }),
+        deferred,
+    );
+
+    Ok(promise)
+}
+
+fn js_get_model_catalog(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::GetModelCatalog),
+        deferred,
+    );
+
+    Ok(promise)
+}
+
+fn js_set_model_catalog_override(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let model = cx.argument::<JsString>(1)?.value(&mut cx);
+    let model: ModelInfoOverride = match serde_json::from_str(&model) {
+        Ok(model) => model,
+        Err(err) => return cx.throw_error(format!("failed to parse model override: {err}")),
+    };
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::SetModelCatalogOverride(model)),
+        deferred,
+    );
+
+    Ok(promise)
+}
+
+fn js_remove_model_catalog_override(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let id = cx.argument::<JsString>(1)?.value(&mut cx);
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::RemoveModelCatalogOverride(id)),
+        deferred,
+    );
+
+    Ok(promise)
+}
+
+fn js_refresh_model_catalog(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let provider = cx.argument::<JsString>(1)?.value(&mut cx);
+    let provider: Provider = match serde_json::from_str(&provider) {
+        Ok(provider) => provider,
+        Err(err) => return cx.throw_error(format!("failed to parse provider: {err}")),
+    };
+    let custom_key = cx.argument_opt(2).and_then(|arg| {
+        arg.downcast::<JsString, FunctionContext>(&mut cx)
+            .ok()
+            .map(|js_string| js_string.value(&mut cx))
+    });
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::RefreshModelCatalog {
+            provider,
+            custom_key,
+        }),
+        deferred,
+    );
+
+    Ok(promise)
+}
@@ -1,5 +1,11 @@
 use crate::{
-    ai::llm::{client::Model, models::Message},
+    ai::{
+        chat_export::ChatExportFormat,
+        llm::{
+            client::{catalog::ModelInfoOverride, Model, Provider},
+            models::Message,
+        },
+    },
     store::models::*,
     BackendResult,
 };
@@ -126,6 +132,11 @@ pub enum ResourceMessage {
         content: Vec<String>,
         metadata: Vec<ResourceTextContentMetadata>,
     },
+    UpsertImageEmbedding(String),
+    SearchSimilarImages {
+        resource_id: String,
+        limit: usize,
+    },
     UpsertResourceHash {
         resource_id: String,
         hash: String,
@@ -163,16 +174,30 @@ pub enum MiscMessage {
     },
     ChatQuery {
         callback: Root<JsFunction>,
-        number_documents: i32,
         query: String,
-        model: Model,
+        // model, sources and retrieval settings of the query, the session's
+        // configuration fills in what is unset
+        config: AIChatSessionConfig,
         custom_key: Option<String>,
         session_id: String,
         search_only: bool,
-        resource_ids: Vec<String>,
         inline_images: Option<Vec<String>>,
         general: bool,
         app_creation: bool,
+        rerank: bool,
+        // a message of an earlier turn the query replaces, see `ChatMessageOptions`
+        branch_from: Option<String>,
+        // add the answer to the vector index for searching chat history
+        embed_answer: bool,
+        // name an untitled chat after its first answer, see `title_ai_chat`
+        generate_title: bool,
+        // see `ChatInput::plan_retrieval`
+        plan_retrieval: bool,
+        // lets `CancelAIRequest` stop the answer
+        request_id: Option<String>,
+        // see `ChatInput::fallbacks` and `ChatInput::max_retries`
+        fallbacks: Vec<(Model, Option<String>)>,
+        max_retries: Option<u32>,
     },
     NoteQuery {
         callback: Root<JsFunction>,
@@ -186,6 +211,10 @@ pub enum MiscMessage {
         general: bool,
         websearch: bool,
         surflet: bool,
+        rerank: bool,
+        request_id: Option<String>,
+        fallbacks: Vec<(Model, Option<String>)>,
+        max_retries: Option<u32>,
     },
     CreateAppQuery {
         chunk_callback: Root<JsFunction>,
@@ -194,12 +223,32 @@ pub enum MiscMessage {
         model: Model,
         custom_key: Option<String>,
         inline_images: Option<Vec<String>>,
+        request_id: Option<String>,
     },
+    // stops the streamed response of the query with the request id
+    CancelAIRequest(String),
     Print(String),
     CreateAIChatMessage(String, String),
     UpdateAIChatMessage(String, String),
+    UpdateAIChatSummary(String, String),
+    ExportAIChat {
+        session_id: String,
+        format: ChatExportFormat,
+    },
+    UpdateAIChatConfig {
+        session_id: String,
+        system_prompt: String,
+        config: AIChatSessionConfig,
+    },
+    ListAIChatBranches(String),
+    SwitchAIChatBranch(String, String),
     ListAIChats(Option<i64>),
     SearchAIChats(String, Option<i64>),
+    SearchAIChatMessages {
+        search: String,
+        limit: Option<i64>,
+        semantic: bool,
+    },
     GetAIChatMessage(String),
     DeleteAIChatMessage(String),
     QuerySFFSResources(
@@ -227,6 +276,27 @@ pub enum MiscMessage {
         number_documents: i32,
         resource_ids: Option<Vec<String>>,
     },
+    GetLLMUsage {
+        period: LLMUsagePeriod,
+        from: Option<chrono::DateTime<chrono::Utc>>,
+        to: Option<chrono::DateTime<chrono::Utc>>,
+        custom_key_only: bool,
+    },
+    SetLLMModelPrice {
+        model: String,
+        price: LLMModelPrice,
+    },
+    GetLLMCacheStats,
+    PurgeLLMCache {
+        expired_only: bool,
+    },
+    GetModelCatalog,
+    SetModelCatalogOverride(ModelInfoOverride),
+    RemoveModelCatalogOverride(String),
+    RefreshModelCatalog {
+        provider: Provider,
+        custom_key: Option<String>,
+    },
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -236,6 +306,11 @@ pub enum EventBusMessage {
         resource_id: String,
         status: ResourceProcessingState,
     },
+    AIChatTitleMessage {
+        session_id: String,
+        title: String,
+        tags: Vec<String>,
+    },
 }
 
 #[derive(Debug, serde::Serialize)]
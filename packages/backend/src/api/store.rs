@@ -14,6 +14,7 @@ pub fn register_exported_functions(cx: &mut ModuleContext) -> NeonResult<()> {
     )?;
     cx.export_function("js__store_recover_resource", js_recover_resource)?;
     cx.export_function("js__store_search_resources", js_search_resources)?;
+    cx.export_function("js__store_search_similar_images", js_search_similar_images)?;
     cx.export_function(
         "js__store_list_resources_by_tags",
         js_list_resources_by_tags,
@@ -81,9 +82,21 @@ pub fn register_exported_functions(cx: &mut ModuleContext) -> NeonResult<()> {
 
     cx.export_function("js__store_create_ai_chat", js_create_ai_chat)?;
     cx.export_function("js__store_update_ai_chat", js_update_ai_chat)?;
+    cx.export_function(
+        "js__store_update_ai_chat_summary",
+        js_update_ai_chat_summary,
+    )?;
+    cx.export_function("js__store_update_ai_chat_config", js_update_ai_chat_config)?;
+    cx.export_function("js__store_list_ai_chat_branches", js_list_ai_chat_branches)?;
+    cx.export_function("js__store_switch_ai_chat_branch", js_switch_ai_chat_branch)?;
     cx.export_function("js__store_list_ai_chats", js_list_ai_chats)?;
     cx.export_function("js__store_search_ai_chats", js_search_ai_chats)?;
+    cx.export_function(
+        "js__store_search_ai_chat_messages",
+        js_search_ai_chat_messages,
+    )?;
     cx.export_function("js__store_get_ai_chat", js_get_ai_chat)?;
+    cx.export_function("js__store_export_ai_chat", js_export_ai_chat)?;
     cx.export_function("js__store_remove_ai_chat", js_remove_ai_chat)?;
 
     cx.export_function("js__store_create_space", js_create_space)?;
@@ -589,6 +602,18 @@ fn js_search_resources(mut cx: FunctionContext) -> JsResult<JsPromise> {
             .map(|js_number| js_number.value(&mut cx) as i64)
     });
 
+    let rerank = cx.argument_opt(9).and_then(|arg| {
+        arg.downcast::<JsBoolean, FunctionContext>(&mut cx)
+            .ok()
+            .map(|js_boolean| js_boolean.value(&mut cx))
+    });
+
+    let image_search_enabled = cx.argument_opt(10).and_then(|arg| {
+        arg.downcast::<JsBoolean, FunctionContext>(&mut cx)
+            .ok()
+            .map(|js_boolean| js_boolean.value(&mut cx))
+    });
+
     let (deferred, promise) = cx.promise();
     tunnel.worker_send_js(
         WorkerMessage::ResourceMessage(ResourceMessage::SearchResources(SearchResourcesParams {
@@ -600,6 +625,8 @@ fn js_search_resources(mut cx: FunctionContext) -> JsResult<JsPromise> {
             include_annotations,
             space_id,
             keyword_limit,
+            rerank,
+            image_search_enabled,
         })),
         deferred,
     );
@@ -927,6 +954,66 @@ fn js_update_ai_chat(mut cx: FunctionContext) -> JsResult<JsPromise> {
     Ok(promise)
 }
 
+fn js_update_ai_chat_summary(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let session_id = cx.argument::<JsString>(1)?.value(&mut cx);
+    let summary = cx.argument::<JsString>(2)?.value(&mut cx);
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::UpdateAIChatSummary(session_id, summary)),
+        deferred,
+    );
+    Ok(promise)
+}
+
+fn js_update_ai_chat_config(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let session_id = cx.argument::<JsString>(1)?.value(&mut cx);
+    let system_prompt = cx.argument::<JsString>(2)?.value(&mut cx);
+    let config_json = cx.argument::<JsString>(3)?.value(&mut cx);
+    let config: models::AIChatSessionConfig = match serde_json::from_str(&config_json) {
+        Ok(config) => config,
+        Err(err) => return cx.throw_error(format!("failed to parse chat config: {err}")),
+    };
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::UpdateAIChatConfig {
+            session_id,
+            system_prompt,
+            config,
+        }),
+        deferred,
+    );
+    Ok(promise)
+}
+
+fn js_list_ai_chat_branches(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let session_id = cx.argument::<JsString>(1)?.value(&mut cx);
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::ListAIChatBranches(session_id)),
+        deferred,
+    );
+    Ok(promise)
+}
+
+fn js_switch_ai_chat_branch(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let session_id = cx.argument::<JsString>(1)?.value(&mut cx);
+    let message_id = cx.argument::<JsString>(2)?.value(&mut cx);
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::SwitchAIChatBranch(session_id, message_id)),
+        deferred,
+    );
+    Ok(promise)
+}
+
 fn js_list_ai_chats(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
     let limit = cx.argument_opt(1).and_then(|arg| {
@@ -960,6 +1047,32 @@ fn js_search_ai_chats(mut cx: FunctionContext) -> JsResult<JsPromise> {
     Ok(promise)
 }
 
+fn js_search_ai_chat_messages(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let search = cx.argument::<JsString>(1)?.value(&mut cx);
+    let limit = cx.argument_opt(2).and_then(|arg| {
+        arg.downcast::<JsNumber, FunctionContext>(&mut cx)
+            .ok()
+            .map(|js_number| js_number.value(&mut cx) as i64)
+    });
+    let semantic = cx
+        .argument_opt(3)
+        .and_then(|arg| arg.downcast::<JsBoolean, FunctionContext>(&mut cx).ok())
+        .map(|js_bool| js_bool.value(&mut cx))
+        .unwrap_or(false);
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::SearchAIChatMessages {
+            search,
+            limit,
+            semantic,
+        }),
+        deferred,
+    );
+    Ok(promise)
+}
+
 fn js_remove_ai_chat(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
     let session_id = cx.argument::<JsString>(1)?.value(&mut cx);
@@ -984,6 +1097,23 @@ fn js_get_ai_chat(mut cx: FunctionContext) -> JsResult<JsPromise> {
     Ok(promise)
 }
 
+fn js_export_ai_chat(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let session_id = cx.argument::<JsString>(1)?.value(&mut cx);
+    let format = cx.argument::<JsString>(2)?.value(&mut cx);
+    let format = match serde_json::from_value(serde_json::Value::String(format)) {
+        Ok(format) => format,
+        Err(err) => return cx.throw_error(format!("failed to parse export format: {err}")),
+    };
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::MiscMessage(MiscMessage::ExportAIChat { session_id, format }),
+        deferred,
+    );
+    Ok(promise)
+}
+
 fn js_upsert_resource_hash(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
     let resource_id = cx.argument::<JsString>(1)?.value(&mut cx);
@@ -1011,6 +1141,27 @@ fn js_get_resource_hash(mut cx: FunctionContext) -> JsResult<JsPromise> {
     Ok(promise)
 }
 
+fn js_search_similar_images(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
+    let resource_id = cx.argument::<JsString>(1)?.value(&mut cx);
+    let limit = cx
+        .argument_opt(2)
+        .and_then(|arg| {
+            arg.downcast::<JsNumber, FunctionContext>(&mut cx)
+                .ok()
+                .map(|n| n.value(&mut cx) as usize)
+        })
+        .unwrap_or(20);
+
+    let (deferred, promise) = cx.promise();
+    tunnel.worker_send_js(
+        WorkerMessage::ResourceMessage(ResourceMessage::SearchSimilarImages { resource_id, limit }),
+        deferred,
+    );
+
+    Ok(promise)
+}
+
 fn js_delete_resource_hash(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let tunnel = cx.argument::<JsBox<WorkerTunnel>>(0)?;
     let resource_id = cx.argument::<JsString>(1)?.value(&mut cx);
@@ -33,6 +33,10 @@ pub enum BackendError {
     // TODO: fix this monstrosity
     #[error("LLM Quota Depleted error: {quotas}")]
     LLMClientErrorQuotasDepleted { quotas: serde_json::Value },
+    #[error("Cancelled")]
+    CancelledError,
+    #[error("Local AI server busy")]
+    LocalAIServerBusy,
     #[error("RAG Empty Context error: {0}")]
     RAGEmptyContextError(String),
     #[error("Generic error: {0}")]
@@ -1,5 +1,18 @@
 use super::models::*;
-use crate::{store::db::Database, BackendResult};
+use crate::{
+    store::{db::Database, search::escape_fts_query},
+    BackendError, BackendResult,
+};
+
+use std::collections::{HashMap, HashSet};
+
+// matching messages returned per session by `search_ai_session_messages`
+const MAX_SEARCH_HITS_PER_SESSION: usize = 5;
+
+// tags are stored as a JSON array
+fn parse_tags(raw: String) -> Vec<String> {
+    serde_json::from_str(&raw).unwrap_or_default()
+}
 
 impl Database {
     pub fn create_ai_session_tx(
@@ -7,13 +20,15 @@ impl Database {
         session: &AIChatSession,
     ) -> BackendResult<()> {
         tx.execute(
-            "INSERT INTO ai_sessions (id, system_prompt, title, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            "INSERT INTO ai_sessions (id, system_prompt, title, created_at, updated_at, config, tags) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
             rusqlite::params![
             session.id,
             session.system_prompt,
             session.title,
             session.created_at,
-            session.updated_at
+            session.updated_at,
+            session.config,
+            serde_json::to_string(&session.tags).unwrap_or_else(|_| "[]".to_string())
             ],
         )?;
         Ok(())
@@ -21,7 +36,7 @@ impl Database {
 
     pub fn get_ai_session(&self, id: &str) -> BackendResult<Option<AIChatSession>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, system_prompt, title, created_at, updated_at 
+            "SELECT id, system_prompt, title, created_at, updated_at, active_message_id, config, tags 
             FROM ai_sessions 
             WHERE id = ?1",
         )?;
@@ -35,6 +50,9 @@ impl Database {
                 title: row.get(2)?,
                 created_at: row.get(3).unwrap_or_else(|_| chrono::Utc::now()),
                 updated_at: row.get(4).unwrap_or_else(|_| chrono::Utc::now()),
+                active_message_id: row.get(5)?,
+                config: row.get(6)?,
+                tags: parse_tags(row.get(7)?),
             }))
         } else {
             Ok(None)
@@ -54,24 +72,146 @@ impl Database {
         Ok(())
     }
 
+    /// Marks the title of an untitled session as being generated, returns false
+    /// if the session has a title or its title was attempted before.
+    pub fn claim_ai_session_title_generation_tx(
+        tx: &mut rusqlite::Transaction,
+        id: &str,
+    ) -> BackendResult<bool> {
+        let claimed = tx.execute(
+            "UPDATE ai_sessions SET title_generation = 'pending'
+            WHERE id = ?1 AND title_generation = '' AND trim(title) = ''",
+            rusqlite::params![id],
+        )?;
+        Ok(claimed == 1)
+    }
+
+    /// Records that the title of a session couldn't be generated, it isn't
+    /// attempted again.
+    pub fn fail_ai_session_title_generation_tx(
+        tx: &mut rusqlite::Transaction,
+        id: &str,
+    ) -> BackendResult<()> {
+        tx.execute(
+            "UPDATE ai_sessions SET title_generation = 'failed' WHERE id = ?1",
+            rusqlite::params![id],
+        )?;
+        Ok(())
+    }
+
+    /// Sets the generated title of a session unless it was given one meanwhile,
+    /// the tags are replaced either way.
+    pub fn update_ai_session_generated_title_tx(
+        tx: &mut rusqlite::Transaction,
+        id: &str,
+        title: &str,
+        tags: &[String],
+    ) -> BackendResult<()> {
+        tx.execute(
+            "UPDATE ai_sessions SET title = CASE WHEN trim(title) = '' THEN ?1 ELSE title END, tags = ?2,
+            title_generation = 'done' WHERE id = ?3",
+            rusqlite::params![
+                title,
+                serde_json::to_string(tags).unwrap_or_else(|_| "[]".to_string()),
+                id
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn upsert_ai_session_summary_tx(
+        tx: &mut rusqlite::Transaction,
+        session_id: &str,
+        summary: &AIChatSessionSummary,
+    ) -> BackendResult<()> {
+        tx.execute(
+            "INSERT INTO ai_session_summaries (ai_session_id, message_id, summary, created_at) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (ai_session_id, message_id) DO UPDATE SET summary = excluded.summary",
+            rusqlite::params![
+                session_id,
+                summary.message_id,
+                summary.summary,
+                chrono::Utc::now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_ai_session_summaries(
+        &self,
+        session_id: &str,
+    ) -> BackendResult<Vec<AIChatSessionSummary>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT message_id, summary FROM ai_session_summaries WHERE ai_session_id = ?1",
+        )?;
+        let summaries = stmt.query_map(rusqlite::params![session_id], |row| {
+            Ok(AIChatSessionSummary {
+                message_id: row.get(0)?,
+                summary: row.get(1)?,
+            })
+        })?;
+        let mut result = Vec::new();
+        for summary in summaries {
+            result.push(summary?);
+        }
+        Ok(result)
+    }
+
+    pub fn update_ai_session_config_tx(
+        tx: &mut rusqlite::Transaction,
+        id: &str,
+        system_prompt: &str,
+        config: &AIChatSessionConfig,
+    ) -> BackendResult<()> {
+        tx.execute(
+            "UPDATE ai_sessions SET system_prompt = ?1, config = ?2 WHERE id = ?3",
+            rusqlite::params![system_prompt, config, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_ai_session_active_message_tx(
+        tx: &mut rusqlite::Transaction,
+        id: &str,
+        message_id: &str,
+    ) -> BackendResult<()> {
+        tx.execute(
+            "UPDATE ai_sessions SET active_message_id = ?1 WHERE id = ?2",
+            rusqlite::params![message_id, id],
+        )?;
+        Ok(())
+    }
+
     pub fn delete_ai_session_tx(tx: &mut rusqlite::Transaction, id: &str) -> BackendResult<()> {
         tx.execute(
             "DELETE FROM ai_sessions WHERE id = ?1",
             rusqlite::params![id],
         )?;
+        tx.execute(
+            "DELETE FROM ai_session_messages_fts WHERE ai_session_id = ?1",
+            rusqlite::params![id],
+        )?;
+        tx.execute(
+            "DELETE FROM ai_session_summaries WHERE ai_session_id = ?1",
+            rusqlite::params![id],
+        )?;
+        tx.execute(
+            "DELETE FROM ai_session_message_embeddings WHERE ai_session_id = ?1",
+            rusqlite::params![id],
+        )?;
         Ok(())
     }
 
     pub fn list_ai_sessions(&self, limit: Option<i64>) -> BackendResult<Vec<AIChatSession>> {
         let sql = match limit {
             Some(_) => {
-                "SELECT id, system_prompt, title, created_at, updated_at 
+                "SELECT id, system_prompt, title, created_at, updated_at, active_message_id, config, tags 
                        FROM ai_sessions 
                        ORDER BY updated_at DESC 
                        LIMIT ?1"
             }
             None => {
-                "SELECT id, system_prompt, title, created_at, updated_at 
+                "SELECT id, system_prompt, title, created_at, updated_at, active_message_id, config, tags 
                     FROM ai_sessions 
                     ORDER BY updated_at DESC"
             }
@@ -86,6 +226,9 @@ impl Database {
                 title: row.get(2)?,
                 created_at: row.get(3).unwrap_or_else(|_| chrono::Utc::now()),
                 updated_at: row.get(4).unwrap_or_else(|_| chrono::Utc::now()),
+                active_message_id: row.get(5)?,
+                config: row.get(6)?,
+                tags: parse_tags(row.get(7)?),
             })
         };
 
@@ -108,14 +251,14 @@ impl Database {
     ) -> BackendResult<Vec<AIChatSession>> {
         let sql = match limit {
             Some(_) => {
-                "SELECT id, system_prompt, title, created_at, updated_at 
+                "SELECT id, system_prompt, title, created_at, updated_at, active_message_id, config, tags 
                        FROM ai_sessions 
                        WHERE title LIKE ?1 
                        ORDER BY updated_at DESC 
                        LIMIT ?2"
             }
             None => {
-                "SELECT id, system_prompt, title, created_at, updated_at 
+                "SELECT id, system_prompt, title, created_at, updated_at, active_message_id, config, tags 
                     FROM ai_sessions 
                     WHERE title LIKE ?1 
                     ORDER BY updated_at DESC"
@@ -131,6 +274,9 @@ impl Database {
                 title: row.get(2)?,
                 created_at: row.get(3).unwrap_or_else(|_| chrono::Utc::now()),
                 updated_at: row.get(4).unwrap_or_else(|_| chrono::Utc::now()),
+                active_message_id: row.get(5)?,
+                config: row.get(6)?,
+                tags: parse_tags(row.get(7)?),
             })
         };
 
@@ -146,6 +292,132 @@ impl Database {
         Ok(result)
     }
 
+    /// Messages containing all words of `search`, best matches first. The index is
+    /// trigram based, words shorter than three characters match nothing.
+    pub fn keyword_search_ai_session_messages(
+        &self,
+        search: &str,
+        limit: Option<i64>,
+    ) -> BackendResult<Vec<AIChatSessionMessageHit>> {
+        if search.trim().is_empty() {
+            return Ok(vec![]);
+        }
+
+        let limit_clause = limit.map_or(String::new(), |l| format!("LIMIT {}", l));
+        let query = format!(
+            "SELECT F.ai_session_id, F.message_id, M.role,
+                snippet(ai_session_messages_fts, 2, '<mark>', '</mark>', '…', 64), M.created_at
+            FROM ai_session_messages_fts F
+            INNER JOIN ai_session_messages M ON F.message_id = M.id
+            WHERE ai_session_messages_fts MATCH ?1
+            ORDER BY rank {}",
+            limit_clause
+        );
+        let mut stmt = self.conn.prepare(&query)?;
+        let hits = stmt.query_map(rusqlite::params![escape_fts_query(search)], |row| {
+            Ok(AIChatSessionMessageHit {
+                ai_session_id: row.get(0)?,
+                message_id: row.get(1)?,
+                role: row.get(2)?,
+                snippet: row.get(3)?,
+                semantic: false,
+                created_at: row.get(4)?,
+            })
+        })?;
+        let mut result = Vec::new();
+        for hit in hits {
+            result.push(hit?);
+        }
+        Ok(result)
+    }
+
+    /// Sessions whose messages or title match `search`, ordered by their best
+    /// matching message. `semantic_hits` rank after all keyword matches, sessions
+    /// only matching by title come last.
+    pub fn search_ai_session_messages(
+        &self,
+        search: &str,
+        semantic_hits: Vec<AIChatSessionMessageHit>,
+        limit: Option<i64>,
+    ) -> BackendResult<Vec<AIChatSearchResult>> {
+        let mut hits = self.keyword_search_ai_session_messages(
+            search,
+            limit.map(|l| l * MAX_SEARCH_HITS_PER_SESSION as i64),
+        )?;
+        hits.extend(semantic_hits);
+
+        let mut results: Vec<AIChatSearchResult> = vec![];
+        let mut seen_messages = HashSet::new();
+        // messages of deleted sessions are left in the table
+        let mut deleted_sessions = HashSet::new();
+        for hit in hits {
+            if !seen_messages.insert(hit.message_id.clone())
+                || deleted_sessions.contains(&hit.ai_session_id)
+            {
+                continue;
+            }
+            match results
+                .iter_mut()
+                .find(|result| result.session.id == hit.ai_session_id)
+            {
+                Some(result) => {
+                    if result.hits.len() < MAX_SEARCH_HITS_PER_SESSION {
+                        result.hits.push(hit);
+                    }
+                }
+                None => match self.get_ai_session(&hit.ai_session_id)? {
+                    Some(session) => results.push(AIChatSearchResult {
+                        session,
+                        hits: vec![hit],
+                    }),
+                    None => {
+                        deleted_sessions.insert(hit.ai_session_id);
+                    }
+                },
+            }
+        }
+
+        for session in self.search_ai_sessions(search, limit)? {
+            if !results.iter().any(|result| result.session.id == session.id) {
+                results.push(AIChatSearchResult {
+                    session,
+                    hits: vec![],
+                });
+            }
+        }
+        if let Some(limit) = limit {
+            results.truncate(limit as usize);
+        }
+        Ok(results)
+    }
+
+    /// Ids and text of the assistant answers of a session that aren't in the
+    /// vector index yet.
+    pub fn list_unembedded_ai_session_answers(
+        &self,
+        session_id: &str,
+    ) -> BackendResult<(Vec<String>, Vec<String>)> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, content FROM ai_session_messages
+            WHERE ai_session_id = ?1 AND role = 'assistant' AND is_context = 0 AND msg_type = 'text'
+            AND id NOT IN (
+                SELECT message_id FROM ai_session_message_embeddings WHERE ai_session_id = ?1
+            )",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![session_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut ids = Vec::new();
+        let mut contents = Vec::new();
+        for row_result in rows {
+            let (id, content) = row_result?;
+            ids.push(id);
+            contents.push(content);
+        }
+        Ok((ids, contents))
+    }
+
     pub fn create_ai_session_message_tx(
         tx: &mut rusqlite::Transaction,
         msg: &AIChatSessionMessage,
@@ -159,9 +431,11 @@ impl Database {
             None => "".to_string(),
         };
         tx.execute(
-            "INSERT INTO ai_session_messages (ai_session_id, role, content, truncatable, is_context, msg_type, sources, created_at) 
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            "INSERT INTO ai_session_messages (id, parent_id, ai_session_id, role, content, truncatable, is_context, msg_type, sources, created_at) 
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
             rusqlite::params![
+                msg.id,
+                msg.parent_id,
                 msg.ai_session_id,
                 msg.role,
                 msg.content,
@@ -172,10 +446,17 @@ impl Database {
                 msg.created_at
             ],
         )?;
+        if !msg.is_context && msg.msg_type == "text" {
+            tx.execute(
+                "INSERT INTO ai_session_messages_fts (message_id, ai_session_id, content) VALUES (?1, ?2, ?3)",
+                rusqlite::params![msg.id, msg.ai_session_id, msg.content],
+            )?;
+        }
 
+        // a new message is always the last one of the branch it's added to
         tx.execute(
-            "UPDATE ai_sessions SET updated_at = ?1 WHERE id = ?2",
-            rusqlite::params![msg.created_at, msg.ai_session_id],
+            "UPDATE ai_sessions SET updated_at = ?1, active_message_id = ?2 WHERE id = ?3",
+            rusqlite::params![msg.created_at, msg.id, msg.ai_session_id],
         )?;
 
         Ok(())
@@ -186,20 +467,22 @@ impl Database {
         session_id: &str,
     ) -> BackendResult<Vec<AIChatSessionMessage>> {
         let mut stmt = self.conn.prepare(
-            "SELECT ai_session_id, role, content, truncatable, is_context, msg_type, created_at
+            "SELECT id, parent_id, ai_session_id, role, content, truncatable, is_context, msg_type, created_at
             FROM ai_session_messages
             WHERE ai_session_id = ?1
             ORDER BY created_at ASC",
         )?;
         let messages = stmt.query_map(rusqlite::params![session_id], |row| {
             Ok(AIChatSessionMessage {
-                ai_session_id: row.get(0)?,
-                role: row.get(1)?,
-                content: row.get(2)?,
-                truncatable: row.get(3)?,
-                is_context: row.get(4)?,
-                msg_type: row.get(5)?,
-                created_at: row.get(6)?,
+                id: row.get(0)?,
+                parent_id: row.get(1)?,
+                ai_session_id: row.get(2)?,
+                role: row.get(3)?,
+                content: row.get(4)?,
+                truncatable: row.get(5)?,
+                is_context: row.get(6)?,
+                msg_type: row.get(7)?,
+                created_at: row.get(8)?,
                 sources: None,
             })
         })?;
@@ -215,26 +498,28 @@ impl Database {
         session_id: &str,
     ) -> BackendResult<Vec<AIChatSessionMessage>> {
         let mut stmt = self.conn.prepare(
-            "SELECT ai_session_id, role, content, truncatable, is_context, msg_type, sources, created_at
+            "SELECT id, parent_id, ai_session_id, role, content, truncatable, is_context, msg_type, sources, created_at
             FROM ai_session_messages
             WHERE ai_session_id = ?1
             AND is_context = 0
             ORDER BY created_at ASC",
         )?;
         let messages = stmt.query_map(rusqlite::params![session_id], |row| {
-            let sources_raw: String = row.get(6)?;
+            let sources_raw: String = row.get(8)?;
             let parsed_sources: Option<Vec<AIChatSessionMessageSource>> =
                 serde_json::from_str(&sources_raw).ok();
 
             Ok(AIChatSessionMessage {
-                ai_session_id: row.get(0)?,
-                role: row.get(1)?,
-                content: row.get(2)?,
-                truncatable: row.get(3)?,
-                is_context: row.get(4)?,
-                msg_type: row.get(5)?,
+                id: row.get(0)?,
+                parent_id: row.get(1)?,
+                ai_session_id: row.get(2)?,
+                role: row.get(3)?,
+                content: row.get(4)?,
+                truncatable: row.get(5)?,
+                is_context: row.get(6)?,
+                msg_type: row.get(7)?,
                 sources: parsed_sources,
-                created_at: row.get(7)?,
+                created_at: row.get(9)?,
             })
         })?;
         let mut result = Vec::new();
@@ -244,3 +529,747 @@ impl Database {
         Ok(result)
     }
 }
+
+// Messages of a session form a tree: editing a query or regenerating an answer
+// adds a sibling of the turn instead of replacing it. The functions below take
+// all messages of a session, ordered by created_at.
+
+/// The newest message nothing follows yet among `message_id` and the messages
+/// after it, among all messages if `message_id` is None or unknown.
+pub fn newest_leaf(messages: &[AIChatSessionMessage], message_id: Option<&str>) -> Option<String> {
+    let parents = messages
+        .iter()
+        .filter_map(|msg| msg.parent_id.as_deref())
+        .collect::<HashSet<_>>();
+    let by_id = messages
+        .iter()
+        .map(|msg| (msg.id.as_str(), msg))
+        .collect::<HashMap<_, _>>();
+    let root = message_id.filter(|id| by_id.contains_key(id));
+
+    messages
+        .iter()
+        .rev()
+        .filter(|msg| !parents.contains(msg.id.as_str()))
+        .find(|msg| match root {
+            Some(root) => {
+                let mut current = Some(*msg);
+                while let Some(msg) = current {
+                    if msg.id == root {
+                        return true;
+                    }
+                    current = msg
+                        .parent_id
+                        .as_deref()
+                        .and_then(|id| by_id.get(id).copied());
+                }
+                false
+            }
+            None => true,
+        })
+        .map(|msg| msg.id.clone())
+}
+
+/// The messages leading up to and including `last_id`, oldest first.
+pub fn message_path(
+    messages: Vec<AIChatSessionMessage>,
+    last_id: Option<&str>,
+) -> Vec<AIChatSessionMessage> {
+    let mut by_id = messages
+        .into_iter()
+        .map(|msg| (msg.id.clone(), msg))
+        .collect::<HashMap<_, _>>();
+    let mut path = vec![];
+    let mut current = last_id.map(|id| id.to_string());
+    while let Some(msg) = current.and_then(|id| by_id.remove(&id)) {
+        current = msg.parent_id.clone();
+        path.push(msg);
+    }
+    path.reverse();
+    path
+}
+
+/// The messages of the branch ending with the newest leaf after `active_message_id`.
+pub fn active_branch(
+    messages: Vec<AIChatSessionMessage>,
+    active_message_id: Option<&str>,
+) -> Vec<AIChatSessionMessage> {
+    let leaf = newest_leaf(&messages, active_message_id);
+    message_path(messages, leaf.as_deref())
+}
+
+/// The summary of `path` and the messages of the path it doesn't cover. Every
+/// summary includes the ones before it, so the one ending furthest along the
+/// path is used, summaries of other branches don't apply.
+pub fn unsummarized_path(
+    summaries: &[AIChatSessionSummary],
+    mut path: Vec<AIChatSessionMessage>,
+) -> (String, Vec<AIChatSessionMessage>) {
+    let by_message_id = summaries
+        .iter()
+        .map(|summary| (summary.message_id.as_str(), summary.summary.as_str()))
+        .collect::<HashMap<_, _>>();
+    match path
+        .iter()
+        .rposition(|msg| by_message_id.contains_key(msg.id.as_str()))
+    {
+        Some(end) => {
+            let summary = by_message_id[path[end].id.as_str()].to_string();
+            (summary, path.split_off(end + 1))
+        }
+        None => (String::new(), path),
+    }
+}
+
+/// The message a new turn follows, the summary of the turns leading up to it
+/// and the messages the summary doesn't cover. The new turn continues the
+/// active branch or replaces the turn of `branch_from`.
+pub fn turn_history(
+    messages: Vec<AIChatSessionMessage>,
+    summaries: &[AIChatSessionSummary],
+    active_message_id: Option<&str>,
+    branch_from: Option<&str>,
+) -> BackendResult<(Option<String>, String, Vec<AIChatSessionMessage>)> {
+    let parent_id = match branch_from {
+        Some(message_id) => turn_parent_id(&messages, message_id)?,
+        None => newest_leaf(&messages, active_message_id),
+    };
+    let (summary, path) =
+        unsummarized_path(summaries, message_path(messages, parent_id.as_deref()));
+    Ok((parent_id, summary, path))
+}
+
+/// The message a new version of the turn `message_id` belongs to follows: the
+/// answer of the turn before, None for the first turn of the session.
+pub fn turn_parent_id(
+    messages: &[AIChatSessionMessage],
+    message_id: &str,
+) -> BackendResult<Option<String>> {
+    let by_id = messages
+        .iter()
+        .map(|msg| (msg.id.as_str(), msg))
+        .collect::<HashMap<_, _>>();
+    let msg = by_id.get(message_id).ok_or_else(|| {
+        BackendError::GenericError(format!("AI chat message not found: {}", message_id))
+    })?;
+
+    // the context, images and query of a turn come before its answer
+    let mut current = msg.parent_id.as_deref().and_then(|id| by_id.get(id));
+    while let Some(msg) = current {
+        if msg.role == "assistant" {
+            return Ok(Some(msg.id.clone()));
+        }
+        current = msg.parent_id.as_deref().and_then(|id| by_id.get(id));
+    }
+    Ok(None)
+}
+
+pub fn list_branches(
+    messages: &[AIChatSessionMessage],
+    active_message_id: Option<&str>,
+) -> Vec<AIChatSessionBranch> {
+    let parents = messages
+        .iter()
+        .filter_map(|msg| msg.parent_id.as_deref())
+        .collect::<HashSet<_>>();
+    let by_id = messages
+        .iter()
+        .map(|msg| (msg.id.as_str(), msg))
+        .collect::<HashMap<_, _>>();
+    let parent = |msg: &AIChatSessionMessage| {
+        msg.parent_id
+            .as_deref()
+            .and_then(|id| by_id.get(id).copied())
+    };
+
+    let active_leaf = newest_leaf(messages, active_message_id);
+    let mut active_ids = HashSet::new();
+    let mut current = active_leaf.as_deref().and_then(|id| by_id.get(id).copied());
+    while let Some(msg) = current {
+        active_ids.insert(msg.id.as_str());
+        current = parent(msg);
+    }
+
+    let mut branches = vec![];
+    for leaf in messages
+        .iter()
+        .filter(|msg| !parents.contains(msg.id.as_str()))
+    {
+        let active = active_leaf.as_deref() == Some(leaf.id.as_str());
+        // the whole active branch, only the messages after the fork of the
+        // others, newest first
+        let mut own = vec![];
+        let mut current = Some(leaf);
+        while let Some(msg) = current {
+            if !active && active_ids.contains(msg.id.as_str()) {
+                break;
+            }
+            own.push(msg);
+            current = parent(msg);
+        }
+        let first = own.last().copied().unwrap_or(leaf);
+        let preview = own
+            .iter()
+            .rev()
+            .find(|msg| msg.role == "user" && !msg.is_context && msg.msg_type == "text")
+            .map(|msg| msg.content.clone())
+            .unwrap_or_default();
+        branches.push(AIChatSessionBranch {
+            leaf_message_id: leaf.id.clone(),
+            first_message_id: first.id.clone(),
+            fork_parent_id: first.parent_id.clone(),
+            preview,
+            active,
+            updated_at: leaf.created_at,
+        });
+    }
+    branches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::llm::client::Model;
+    use tempfile::{tempdir, TempDir};
+
+    fn setup_test_db() -> (TempDir, Database) {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db = Database::new(&db_path.to_string_lossy(), true).unwrap();
+        (dir, db)
+    }
+
+    #[test]
+    fn test_ai_session_summary() {
+        let (_dir, mut db) = setup_test_db();
+        let session = AIChatSession {
+            id: random_uuid(),
+            system_prompt: "".to_string(),
+            title: "research".to_string(),
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+            active_message_id: None,
+            config: AIChatSessionConfig::default(),
+            tags: vec![],
+        };
+        let mut tx = db.begin().unwrap();
+        Database::create_ai_session_tx(&mut tx, &session).unwrap();
+        tx.commit().unwrap();
+
+        assert!(db
+            .list_ai_session_summaries(&session.id)
+            .unwrap()
+            .is_empty());
+
+        let mut tx = db.begin().unwrap();
+        for (message_id, summary) in [
+            ("a1", "we compared sqlite and postgres"),
+            ("a2", "we picked postgres"),
+            // corrected by the user
+            ("a2", "we picked sqlite over postgres"),
+        ] {
+            Database::upsert_ai_session_summary_tx(
+                &mut tx,
+                &session.id,
+                &AIChatSessionSummary {
+                    message_id: message_id.to_string(),
+                    summary: summary.to_string(),
+                },
+            )
+            .unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut summaries = db.list_ai_session_summaries(&session.id).unwrap();
+        summaries.sort_by(|a, b| a.message_id.cmp(&b.message_id));
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|s| (s.message_id.as_str(), s.summary.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("a1", "we compared sqlite and postgres"),
+                ("a2", "we picked sqlite over postgres")
+            ]
+        );
+
+        let config = AIChatSessionConfig {
+            model: Some(Model::Claude35Haiku),
+            space_ids: vec!["space".to_string()],
+            number_documents: Some(5),
+            ..Default::default()
+        };
+        let mut tx = db.begin().unwrap();
+        Database::update_ai_session_config_tx(&mut tx, &session.id, "answer in german", &config)
+            .unwrap();
+        tx.commit().unwrap();
+
+        let stored = db.get_ai_session(&session.id).unwrap().unwrap();
+        assert_eq!(stored.system_prompt, "answer in german");
+        assert_eq!(stored.config, config);
+        // the summaries are left alone
+        assert_eq!(db.list_ai_session_summaries(&session.id).unwrap().len(), 2);
+
+        let mut tx = db.begin().unwrap();
+        Database::delete_ai_session_tx(&mut tx, &session.id).unwrap();
+        tx.commit().unwrap();
+        assert!(db
+            .list_ai_session_summaries(&session.id)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_ai_session_config_fallback() {
+        let session = AIChatSessionConfig {
+            model: Some(Model::GPT4_1),
+            resource_ids: vec!["pinned".to_string()],
+            space_ids: vec!["project".to_string()],
+            number_documents: Some(10),
+            similarity_threshold: Some(0.3),
+        };
+
+        let query = AIChatSessionConfig {
+            number_documents: Some(3),
+            ..Default::default()
+        };
+        let config = query.or(session.clone());
+        assert_eq!(config.model, Some(Model::GPT4_1));
+        assert_eq!(config.resource_ids, vec!["pinned".to_string()]);
+        assert_eq!(config.space_ids, vec!["project".to_string()]);
+        assert_eq!(config.number_documents, Some(3));
+        assert_eq!(config.similarity_threshold, Some(0.3));
+
+        // sources passed with the query replace all pinned ones
+        let query = AIChatSessionConfig {
+            resource_ids: vec!["attached".to_string()],
+            ..Default::default()
+        };
+        let config = query.or(session);
+        assert_eq!(config.resource_ids, vec!["attached".to_string()]);
+        assert!(config.space_ids.is_empty());
+    }
+
+    fn session(title: &str) -> AIChatSession {
+        AIChatSession {
+            id: random_uuid(),
+            system_prompt: "".to_string(),
+            title: title.to_string(),
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+            active_message_id: None,
+            config: AIChatSessionConfig::default(),
+            tags: vec![],
+        }
+    }
+
+    #[test]
+    fn test_ai_session_generated_title() {
+        let (_dir, mut db) = setup_test_db();
+        let untitled = session("");
+        let named = session("q3 planning");
+        let mut tx = db.begin().unwrap();
+        Database::create_ai_session_tx(&mut tx, &untitled).unwrap();
+        Database::create_ai_session_tx(&mut tx, &named).unwrap();
+        let tags = vec!["databases".to_string(), "sqlite".to_string()];
+        for id in [&untitled.id, &named.id] {
+            Database::update_ai_session_generated_title_tx(
+                &mut tx,
+                id,
+                "Choosing a database",
+                &tags,
+            )
+            .unwrap();
+        }
+        tx.commit().unwrap();
+
+        let stored = db.get_ai_session(&untitled.id).unwrap().unwrap();
+        assert_eq!(stored.title, "Choosing a database");
+        assert_eq!(stored.tags, tags);
+        // titles set by the user are kept
+        let stored = db.get_ai_session(&named.id).unwrap().unwrap();
+        assert_eq!(stored.title, "q3 planning");
+        assert_eq!(stored.tags, tags);
+    }
+
+    #[test]
+    fn test_ai_session_title_generation() {
+        let (_dir, mut db) = setup_test_db();
+        let titled = session("");
+        let failed = session("");
+        let named = session("q3 planning");
+        let mut tx = db.begin().unwrap();
+        for session in [&titled, &failed, &named] {
+            Database::create_ai_session_tx(&mut tx, session).unwrap();
+        }
+
+        // a title is generated once, while none is pending
+        assert!(Database::claim_ai_session_title_generation_tx(&mut tx, &titled.id).unwrap());
+        assert!(!Database::claim_ai_session_title_generation_tx(&mut tx, &titled.id).unwrap());
+        Database::update_ai_session_generated_title_tx(
+            &mut tx,
+            &titled.id,
+            "Choosing a database",
+            &[],
+        )
+        .unwrap();
+        assert!(!Database::claim_ai_session_title_generation_tx(&mut tx, &titled.id).unwrap());
+
+        // failures aren't retried
+        assert!(Database::claim_ai_session_title_generation_tx(&mut tx, &failed.id).unwrap());
+        Database::fail_ai_session_title_generation_tx(&mut tx, &failed.id).unwrap();
+        assert!(!Database::claim_ai_session_title_generation_tx(&mut tx, &failed.id).unwrap());
+
+        assert!(!Database::claim_ai_session_title_generation_tx(&mut tx, &named.id).unwrap());
+        tx.commit().unwrap();
+
+        let stored = db.get_ai_session(&failed.id).unwrap().unwrap();
+        assert_eq!(stored.title, "");
+    }
+
+    #[test]
+    fn test_search_ai_session_messages() {
+        let (_dir, mut db) = setup_test_db();
+        let databases = session("databases");
+        let travel = session("travel");
+        let lisbon = session("lisbon restaurants");
+
+        let mut context = message("c1", None, "user", "<document>lisbon and porto</document>");
+        context.is_context = true;
+        let mut messages = [
+            (&databases, context),
+            (
+                &databases,
+                message("u1", Some("c1"), "user", "sqlite or postgres?"),
+            ),
+            (
+                &databases,
+                message("a1", Some("u1"), "assistant", "sqlite, it's embedded"),
+            ),
+            (
+                &travel,
+                message("u2", None, "user", "trains from lisbon to porto"),
+            ),
+            (
+                &travel,
+                message(
+                    "a2",
+                    Some("u2"),
+                    "assistant",
+                    "the train to porto takes three hours",
+                ),
+            ),
+        ];
+        let mut tx = db.begin().unwrap();
+        for session in [&databases, &travel, &lisbon] {
+            Database::create_ai_session_tx(&mut tx, session).unwrap();
+        }
+        for (session, msg) in messages.iter_mut() {
+            msg.ai_session_id = session.id.clone();
+            Database::create_ai_session_message_tx(&mut tx, msg).unwrap();
+        }
+        tx.commit().unwrap();
+
+        // context messages aren't searched
+        let results = db
+            .search_ai_session_messages("lisbon", vec![], None)
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].session.id, travel.id);
+        assert_eq!(results[0].hits.len(), 1);
+        assert_eq!(results[0].hits[0].message_id, "u2");
+        assert!(results[0].hits[0].snippet.contains("<mark>lisbon</mark>"));
+        // matching by title only
+        assert_eq!(results[1].session.id, lisbon.id);
+        assert!(results[1].hits.is_empty());
+
+        let results = db
+            .search_ai_session_messages("porto", vec![], None)
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(ids_of_hits(&results[0].hits), vec!["a2", "u2"]);
+
+        let semantic_hit = AIChatSessionMessageHit {
+            ai_session_id: databases.id.clone(),
+            message_id: "a1".to_string(),
+            role: "assistant".to_string(),
+            snippet: "sqlite, it's embedded".to_string(),
+            semantic: true,
+            created_at: chrono::Utc::now(),
+        };
+        let results = db
+            .search_ai_session_messages("porto", vec![semantic_hit], Some(5))
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].session.id, databases.id);
+        assert!(results[1].hits[0].semantic);
+
+        let mut tx = db.begin().unwrap();
+        Database::delete_ai_session_tx(&mut tx, &travel.id).unwrap();
+        tx.commit().unwrap();
+        assert!(db
+            .search_ai_session_messages("porto", vec![], None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_ai_session_message_embeddings() {
+        let (_dir, mut db) = setup_test_db();
+        let databases = session("databases");
+        let travel = session("travel");
+        let mut tx = db.begin().unwrap();
+        for session in [&databases, &travel] {
+            Database::create_ai_session_tx(&mut tx, session).unwrap();
+        }
+        for (session, mut msg) in [
+            (
+                &databases,
+                message("u1", None, "user", "sqlite or postgres?"),
+            ),
+            (
+                &databases,
+                message("a1", Some("u1"), "assistant", "sqlite, it's embedded"),
+            ),
+            (&travel, message("u2", None, "user", "trains to porto")),
+            (
+                &travel,
+                message("a2", Some("u2"), "assistant", "three hours"),
+            ),
+        ] {
+            msg.ai_session_id = session.id.clone();
+            Database::create_ai_session_message_tx(&mut tx, &msg).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let (ids, contents) = db
+            .list_unembedded_ai_session_answers(&databases.id)
+            .unwrap();
+        assert_eq!(ids, vec!["a1"]);
+        assert_eq!(contents, vec!["sqlite, it's embedded"]);
+
+        let mut tx = db.begin().unwrap();
+        let keys = [
+            Database::create_ai_session_message_embedding_tx(&mut tx, &databases.id, "a1").unwrap(),
+            Database::create_ai_session_message_embedding_tx(&mut tx, &databases.id, "a1").unwrap(),
+            Database::create_ai_session_message_embedding_tx(&mut tx, &travel.id, "a2").unwrap(),
+        ];
+        tx.commit().unwrap();
+        // keys never collide with the rowids of resource embeddings
+        let start = crate::store::embedding_resources::AI_SESSION_MESSAGE_EMBEDDING_KEYS_START;
+        assert_eq!(keys, [start, start + 1, start + 2]);
+        assert!(db
+            .list_unembedded_ai_session_answers(&databases.id)
+            .unwrap()
+            .0
+            .is_empty());
+        assert_eq!(
+            db.list_ai_session_message_embedding_keys(Some(&databases.id))
+                .unwrap(),
+            keys[..2]
+        );
+
+        let hits = db
+            .list_ai_session_message_hits_by_embedding_keys(vec![keys[2], keys[0]])
+            .unwrap();
+        assert_eq!(
+            hits.iter()
+                .map(|hit| hit.message_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a2", "a1"]
+        );
+        assert!(hits.iter().all(|hit| hit.semantic));
+
+        let mut tx = db.begin().unwrap();
+        Database::delete_ai_session_tx(&mut tx, &databases.id).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(
+            db.list_ai_session_message_embedding_keys(None).unwrap(),
+            vec![keys[2]]
+        );
+
+        // the keys of deleted chunks aren't handed out again, their vectors may
+        // still be in the index
+        let mut tx = db.begin().unwrap();
+        Database::remove_ai_session_message_embeddings_tx(&mut tx, &keys[2..]).unwrap();
+        let key =
+            Database::create_ai_session_message_embedding_tx(&mut tx, &travel.id, "a2").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(key, start + 3);
+    }
+
+    fn ids_of_hits(hits: &[AIChatSessionMessageHit]) -> Vec<&str> {
+        let mut ids: Vec<&str> = hits.iter().map(|hit| hit.message_id.as_str()).collect();
+        ids.sort();
+        ids
+    }
+
+    fn message(
+        id: &str,
+        parent_id: Option<&str>,
+        role: &str,
+        content: &str,
+    ) -> AIChatSessionMessage {
+        AIChatSessionMessage {
+            id: id.to_string(),
+            parent_id: parent_id.map(|id| id.to_string()),
+            ai_session_id: "session".to_string(),
+            role: role.to_string(),
+            content: content.to_string(),
+            truncatable: false,
+            is_context: false,
+            msg_type: "text".to_string(),
+            sources: None,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    // u1 -> a1 -> c2 -> u2 -> a2, then u2 edited into u3 -> a3 and a3 regenerated as a4
+    fn edited_session() -> Vec<AIChatSessionMessage> {
+        let mut context = message("c2", Some("a1"), "user", "<document>");
+        context.is_context = true;
+        vec![
+            message("u1", None, "user", "first"),
+            message("a1", Some("u1"), "assistant", "first answer"),
+            context,
+            message("u2", Some("c2"), "user", "second"),
+            message("a2", Some("u2"), "assistant", "second answer"),
+            message("u3", Some("a1"), "user", "second, edited"),
+            message("a3", Some("u3"), "assistant", "edited answer"),
+            message("u4", Some("a1"), "user", "second, edited"),
+            message("a4", Some("u4"), "assistant", "regenerated answer"),
+        ]
+    }
+
+    fn ids(messages: &[AIChatSessionMessage]) -> Vec<&str> {
+        messages.iter().map(|msg| msg.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_turn_parent_id() {
+        let messages = edited_session();
+        // the context of the turn is replaced as well
+        assert_eq!(
+            turn_parent_id(&messages, "u2").unwrap().as_deref(),
+            Some("a1")
+        );
+        assert_eq!(
+            turn_parent_id(&messages, "a3").unwrap().as_deref(),
+            Some("a1")
+        );
+        assert_eq!(turn_parent_id(&messages, "a1").unwrap(), None);
+        assert!(turn_parent_id(&messages, "missing").is_err());
+    }
+
+    #[test]
+    fn test_active_branch() {
+        let messages = edited_session();
+        assert_eq!(
+            ids(&active_branch(messages.clone(), Some("a4"))),
+            vec!["u1", "a1", "u4", "a4"]
+        );
+        // switching to a turn continues with its newest answer
+        assert_eq!(
+            ids(&active_branch(messages.clone(), Some("u2"))),
+            vec!["u1", "a1", "c2", "u2", "a2"]
+        );
+        assert_eq!(
+            ids(&active_branch(messages.clone(), Some("a1"))),
+            vec!["u1", "a1", "u4", "a4"]
+        );
+        assert_eq!(
+            ids(&active_branch(messages, None)),
+            vec!["u1", "a1", "u4", "a4"]
+        );
+    }
+
+    fn summary(message_id: &str, summary: &str) -> AIChatSessionSummary {
+        AIChatSessionSummary {
+            message_id: message_id.to_string(),
+            summary: summary.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_unsummarized_path() {
+        let messages = edited_session();
+        let path = message_path(messages.clone(), Some("a2"));
+
+        let (summary_text, kept) = unsummarized_path(&[], path.clone());
+        assert!(summary_text.is_empty());
+        assert_eq!(ids(&kept), vec!["u1", "a1", "c2", "u2", "a2"]);
+
+        // the message the summary ends with is covered, the ones after it aren't
+        let first = summary("a1", "first question answered");
+        let (summary_text, kept) = unsummarized_path(std::slice::from_ref(&first), path.clone());
+        assert_eq!(summary_text, "first question answered");
+        assert_eq!(ids(&kept), vec!["c2", "u2", "a2"]);
+
+        let second = summary("a2", "both questions answered");
+        let edited = summary("a3", "edited question answered");
+        let summaries = vec![second, first, edited];
+        let (summary_text, kept) = unsummarized_path(&summaries, path);
+        assert_eq!(summary_text, "both questions answered");
+        assert!(kept.is_empty());
+
+        // the edited branch forks off before the newest summary of the other
+        // and keeps the one of the turn they share
+        let path = message_path(messages, Some("a4"));
+        let (summary_text, kept) = unsummarized_path(&summaries, path);
+        assert_eq!(summary_text, "first question answered");
+        assert_eq!(ids(&kept), vec!["u4", "a4"]);
+    }
+
+    #[test]
+    fn test_turn_history() {
+        let messages = edited_session();
+        let summaries = vec![
+            summary("a1", "first question answered"),
+            summary("a2", "both questions answered"),
+        ];
+
+        let (parent_id, summary_text, kept) =
+            turn_history(messages.clone(), &summaries, Some("a2"), None).unwrap();
+        assert_eq!(parent_id.as_deref(), Some("a2"));
+        assert_eq!(summary_text, "both questions answered");
+        assert!(kept.is_empty());
+
+        // editing the second question replaces its turn, context included
+        let (parent_id, summary_text, kept) =
+            turn_history(messages.clone(), &summaries, Some("a2"), Some("u2")).unwrap();
+        assert_eq!(parent_id.as_deref(), Some("a1"));
+        assert_eq!(summary_text, "first question answered");
+        assert!(kept.is_empty());
+
+        // regenerating the first answer starts over without a summary
+        let (parent_id, summary_text, kept) =
+            turn_history(messages.clone(), &summaries, Some("a4"), Some("a1")).unwrap();
+        assert_eq!(parent_id, None);
+        assert!(summary_text.is_empty() && kept.is_empty());
+
+        let (parent_id, summary_text, kept) =
+            turn_history(messages.clone(), &summaries, Some("a4"), None).unwrap();
+        assert_eq!(parent_id.as_deref(), Some("a4"));
+        assert_eq!(summary_text, "first question answered");
+        assert_eq!(ids(&kept), vec!["u4", "a4"]);
+
+        assert!(turn_history(messages, &summaries, None, Some("missing")).is_err());
+    }
+
+    #[test]
+    fn test_list_branches() {
+        let messages = edited_session();
+        let branches = list_branches(&messages, Some("a2"));
+        assert_eq!(branches.len(), 3);
+
+        let active = branches.iter().find(|b| b.active).unwrap();
+        assert_eq!(active.leaf_message_id, "a2");
+        assert_eq!(active.first_message_id, "u1");
+        assert_eq!(active.preview, "first");
+
+        let edited = branches.iter().find(|b| b.leaf_message_id == "a3").unwrap();
+        assert_eq!(edited.first_message_id, "u3");
+        assert_eq!(edited.fork_parent_id.as_deref(), Some("a1"));
+        assert_eq!(edited.preview, "second, edited");
+    }
+}
@@ -1,6 +1,11 @@
 use super::models::*;
 use crate::{store::db::Database, BackendResult};
 
+/// First vector index key of chat message embeddings, well above any rowid of
+/// `embedding_resources` as both share the vector index. The key sequence of
+/// `ai_session_message_embeddings` starts here, see migration 7.
+pub const AI_SESSION_MESSAGE_EMBEDDING_KEYS_START: i64 = 1 << 40;
+
 fn get_order_by_clause_for_embedding_row_ids(column_name: &str, row_ids: &[i64]) -> String {
     let mut order_by_clause = format!("CASE {} ", column_name);
     for (i, row_id) in row_ids.iter().enumerate() {
@@ -105,6 +110,23 @@ impl Database {
         Ok(results)
     }
 
+    pub fn list_non_deleted_embedding_ids_by_type(
+        &self,
+        embedding_type: EmbeddingType,
+    ) -> BackendResult<Vec<i64>> {
+        let query = "SELECT E.rowid FROM embedding_resources E LEFT JOIN resources R ON E.resource_id = R.id WHERE R.deleted = 0 AND E.embedding_type = ?1";
+        let mut stmt = self.conn.prepare(query)?;
+        let mut results = vec![];
+        let results_iter = stmt.query_map(rusqlite::params![embedding_type], |row| {
+            let content_id: i64 = row.get(0)?;
+            Ok(content_id)
+        })?;
+        for result in results_iter {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+
     pub fn list_embedding_ids_by_type_resource_id(
         &self,
         embedding_type: EmbeddingType,
@@ -142,6 +164,11 @@ impl Database {
                     "SELECT rowid FROM embedding_resources WHERE embedding_type = 'metadata' AND resource_id IN ({})",
                     placeholders
                 ),
+            EmbeddingType::Image =>
+                format!(
+                    "SELECT rowid FROM embedding_resources WHERE embedding_type = 'image' AND resource_id IN ({})",
+                    placeholders
+                ),
             };
         let mut stmt = self.conn.prepare(&query)?;
         let mut results = vec![];
@@ -291,6 +318,143 @@ impl Database {
         Ok(results)
     }
 
+    /// Adds the vector index key of a chunk of a chat message and returns it.
+    /// Keys count up from `AI_SESSION_MESSAGE_EMBEDDING_KEYS_START` and are never
+    /// reused, even after the newest ones are deleted.
+    pub fn create_ai_session_message_embedding_tx(
+        tx: &mut rusqlite::Transaction,
+        session_id: &str,
+        message_id: &str,
+    ) -> BackendResult<i64> {
+        tx.execute(
+            "INSERT INTO ai_session_message_embeddings (message_id, ai_session_id) VALUES (?1, ?2)",
+            rusqlite::params![message_id, session_id],
+        )?;
+        Ok(tx.last_insert_rowid())
+    }
+
+    pub fn remove_ai_session_message_embeddings_tx(
+        tx: &mut rusqlite::Transaction,
+        keys: &[i64],
+    ) -> BackendResult<()> {
+        for key in keys {
+            tx.execute(
+                "DELETE FROM ai_session_message_embeddings WHERE key = ?1",
+                rusqlite::params![key],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Chat message embedding keys of the session, or of all sessions if
+    /// `session_id` is `None`.
+    pub fn list_ai_session_message_embedding_keys(
+        &self,
+        session_id: Option<&str>,
+    ) -> BackendResult<Vec<i64>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key FROM ai_session_message_embeddings
+            WHERE ?1 IS NULL OR ai_session_id = ?1 ORDER BY key",
+        )?;
+        let mut results = vec![];
+        let results_iter = stmt.query_map(rusqlite::params![session_id], |row| {
+            let key: i64 = row.get(0)?;
+            Ok(key)
+        })?;
+        for result in results_iter {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+
+    pub fn list_ai_session_message_hits_by_embedding_keys(
+        &self,
+        keys: Vec<i64>,
+    ) -> BackendResult<Vec<AIChatSessionMessageHit>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let placeholders = vec!["?"; keys.len()].join(",");
+        let query = format!(
+            "SELECT M.ai_session_id, M.id, M.role, substr(M.content, 1, 200), M.created_at
+            FROM ai_session_message_embeddings E
+            INNER JOIN ai_session_messages M ON E.message_id = M.id
+            WHERE E.key IN ({})
+            ORDER BY {}",
+            placeholders,
+            get_order_by_clause_for_embedding_row_ids("E.key", &keys)
+        );
+        let mut stmt = self.conn.prepare(&query)?;
+        let mut results = vec![];
+        let results_iter = stmt.query_map(rusqlite::params_from_iter(keys.iter()), |row| {
+            Ok(AIChatSessionMessageHit {
+                ai_session_id: row.get(0)?,
+                message_id: row.get(1)?,
+                role: row.get(2)?,
+                snippet: row.get(3)?,
+                semantic: true,
+                created_at: row.get(4)?,
+            })
+        })?;
+        for result in results_iter {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+
+    /// Embedding row ids of the text content chunks containing `keyword`, best
+    /// match first. Searches all resources that aren't deleted if `resource_ids`
+    /// is empty.
+    pub fn keyword_search_text_content_embedding_ids(
+        &self,
+        keyword: &str,
+        resource_ids: &[String],
+        limit: usize,
+    ) -> BackendResult<Vec<i64>> {
+        let resource_filter = match resource_ids.is_empty() {
+            true => String::new(),
+            false => format!(
+                "AND resource_id IN ({})",
+                vec!["?"; resource_ids.len()].join(",")
+            ),
+        };
+        // a chunk can have more than one embedding, any of them finds the chunk
+        let query = format!(
+            "SELECT (
+                SELECT MIN(E.rowid) FROM embedding_resources E
+                WHERE E.content_id = T.rowid AND E.embedding_type = 'text_content'
+            )
+            FROM (
+                SELECT rowid, resource_id, rank
+                FROM resource_text_content
+                WHERE resource_text_content MATCH ?1 {}
+                ORDER BY rank
+                LIMIT {}
+            ) T
+            INNER JOIN resources R ON T.resource_id = R.id
+            WHERE R.deleted = 0
+            ORDER BY T.rank",
+            resource_filter, limit
+        );
+        let mut params = vec![format!("content: \"{}\"", keyword.replace('"', "\"\""))];
+        params.extend(resource_ids.iter().cloned());
+
+        let mut stmt = self.conn.prepare(&query)?;
+        let mut results = vec![];
+        let results_iter = stmt.query_map(rusqlite::params_from_iter(params.iter()), |row| {
+            let row_id: Option<i64> = row.get(0)?;
+            Ok(row_id)
+        })?;
+        for result in results_iter {
+            // chunks that aren't embedded yet
+            if let Some(row_id) = result? {
+                results.push(row_id);
+            }
+        }
+        Ok(results)
+    }
+
     pub fn remove_embedding_resource_by_row_id_tx(
         tx: &mut rusqlite::Transaction,
         row_id: &i64,
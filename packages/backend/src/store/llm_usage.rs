@@ -0,0 +1,210 @@
+use super::models::{LLMModelPrice, LLMUsage, LLMUsagePeriod, LLMUsageTotal};
+use crate::{store::db::Database, BackendResult};
+use std::collections::HashMap;
+
+impl Database {
+    pub fn create_llm_usage_tx(
+        tx: &mut rusqlite::Transaction,
+        usage: &LLMUsage,
+    ) -> BackendResult<()> {
+        tx.execute(
+            "INSERT INTO llm_usage (model, provider, prompt_tokens, completion_tokens, cached_tokens, latency_ms, streamed, custom_key, session_id, note_id, agent, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            rusqlite::params![
+                usage.model,
+                usage.provider,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.cached_tokens,
+                usage.latency_ms,
+                usage.streamed,
+                usage.custom_key,
+                usage.session_id,
+                usage.note_id,
+                usage.agent,
+                usage.created_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Token totals per period and model between `from` and `to`, estimated costs
+    /// are left to the caller.
+    pub fn list_llm_usage_totals(
+        &self,
+        period: LLMUsagePeriod,
+        from: Option<chrono::DateTime<chrono::Utc>>,
+        to: Option<chrono::DateTime<chrono::Utc>>,
+        custom_key_only: bool,
+    ) -> BackendResult<Vec<LLMUsageTotal>> {
+        // created_at is stored as an ISO 8601 UTC timestamp, its prefix is the period
+        let period_length = match period {
+            LLMUsagePeriod::Daily => 10,
+            LLMUsagePeriod::Monthly => 7,
+        };
+        let mut stmt = self.conn.prepare(
+            "SELECT substr(created_at, 1, ?1) AS period, model, COUNT(*),
+                SUM(prompt_tokens), SUM(completion_tokens), SUM(cached_tokens)
+            FROM llm_usage
+            WHERE (?2 IS NULL OR created_at >= ?2)
+                AND (?3 IS NULL OR created_at < ?3)
+                AND (?4 = 0 OR custom_key = 1)
+            GROUP BY period, model
+            ORDER BY period DESC, model",
+        )?;
+
+        let totals = stmt.query_map(
+            rusqlite::params![period_length, from, to, custom_key_only],
+            |row| {
+                Ok(LLMUsageTotal {
+                    period: row.get(0)?,
+                    model: row.get(1)?,
+                    requests: row.get(2)?,
+                    prompt_tokens: row.get(3)?,
+                    completion_tokens: row.get(4)?,
+                    cached_tokens: row.get(5)?,
+                    estimated_cost: None,
+                })
+            },
+        )?;
+        let mut result = Vec::new();
+        for total in totals {
+            result.push(total?);
+        }
+        Ok(result)
+    }
+
+    pub fn upsert_llm_model_price(&self, model: &str, price: &LLMModelPrice) -> BackendResult<()> {
+        self.conn.execute(
+            "INSERT INTO llm_model_prices (model, input_per_mtok, output_per_mtok, cached_input_per_mtok)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(model) DO UPDATE SET
+                input_per_mtok = excluded.input_per_mtok,
+                output_per_mtok = excluded.output_per_mtok,
+                cached_input_per_mtok = excluded.cached_input_per_mtok",
+            rusqlite::params![
+                model,
+                price.input_per_mtok,
+                price.output_per_mtok,
+                price.cached_input_per_mtok
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_llm_model_prices(&self) -> BackendResult<HashMap<String, LLMModelPrice>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT model, input_per_mtok, output_per_mtok, cached_input_per_mtok FROM llm_model_prices",
+        )?;
+        let prices = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                LLMModelPrice {
+                    input_per_mtok: row.get(1)?,
+                    output_per_mtok: row.get(2)?,
+                    cached_input_per_mtok: row.get(3)?,
+                },
+            ))
+        })?;
+        let mut result = HashMap::new();
+        for price in prices {
+            let (model, price) = price?;
+            result.insert(model, price);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::db::Database;
+    use crate::store::models::{LLMModelPrice, LLMUsage, LLMUsagePeriod};
+    use chrono::TimeZone;
+    use tempfile::{tempdir, TempDir};
+
+    fn setup_test_db() -> (TempDir, Database) {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db = Database::new(&db_path.to_string_lossy(), true).unwrap();
+        (dir, db)
+    }
+
+    fn usage(model: &str, custom_key: bool, day: u32) -> LLMUsage {
+        LLMUsage {
+            model: model.to_string(),
+            provider: "openai".to_string(),
+            prompt_tokens: 1000,
+            completion_tokens: 100,
+            cached_tokens: 200,
+            latency_ms: 1200,
+            streamed: true,
+            custom_key,
+            session_id: Some("session".to_string()),
+            note_id: None,
+            agent: None,
+            created_at: chrono::Utc
+                .with_ymd_and_hms(2025, 3, day, 12, 0, 0)
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_llm_usage_totals() {
+        let (_dir, mut db) = setup_test_db();
+        let mut tx = db.begin().unwrap();
+        for usage in [
+            usage("gpt-4.1", false, 1),
+            usage("gpt-4.1", true, 1),
+            usage("gpt-4.1", true, 2),
+            usage("claude-sonnet-4-20250514", true, 2),
+        ] {
+            Database::create_llm_usage_tx(&mut tx, &usage).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let daily = db
+            .list_llm_usage_totals(LLMUsagePeriod::Daily, None, None, false)
+            .unwrap();
+        assert_eq!(daily.len(), 3);
+        assert_eq!(daily[2].period, "2025-03-01");
+        assert_eq!(daily[2].requests, 2);
+        assert_eq!(daily[2].prompt_tokens, 2000);
+
+        let monthly = db
+            .list_llm_usage_totals(LLMUsagePeriod::Monthly, None, None, true)
+            .unwrap();
+        assert_eq!(monthly.len(), 2);
+        assert_eq!(monthly[1].period, "2025-03");
+        assert_eq!(monthly[1].model, "gpt-4.1");
+        assert_eq!(monthly[1].requests, 2);
+
+        let from = chrono::Utc.with_ymd_and_hms(2025, 3, 2, 0, 0, 0).unwrap();
+        let since = db
+            .list_llm_usage_totals(LLMUsagePeriod::Monthly, Some(from), None, false)
+            .unwrap();
+        assert_eq!(since.iter().map(|t| t.requests).sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn test_llm_model_price_overrides() {
+        let (_dir, db) = setup_test_db();
+        let price = LLMModelPrice {
+            input_per_mtok: 1.0,
+            output_per_mtok: 2.0,
+            cached_input_per_mtok: 0.5,
+        };
+        db.upsert_llm_model_price("custom-model", &price).unwrap();
+        db.upsert_llm_model_price(
+            "custom-model",
+            &LLMModelPrice {
+                input_per_mtok: 3.0,
+                ..price
+            },
+        )
+        .unwrap();
+
+        let prices = db.list_llm_model_prices().unwrap();
+        assert_eq!(prices.len(), 1);
+        assert_eq!(prices["custom-model"].input_per_mtok, 3.0);
+    }
+}
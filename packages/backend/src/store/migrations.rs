@@ -183,4 +183,55 @@ mod tests {
             .unwrap();
         assert_eq!(name, "test_value");
     }
+
+    #[test]
+    fn test_ai_session_message_branches_migration() {
+        let (mut conn, _temp_dir) = setup_test_db();
+        let mut tx = conn.transaction().unwrap();
+        for migration_file in parse_migration_filenames().unwrap().iter().take(4) {
+            run_migration(&mut tx, migration_file).unwrap();
+        }
+        update_db_version(&tx, 4).unwrap();
+        tx.execute_batch(
+            "INSERT INTO ai_sessions (id) VALUES ('chat'), ('empty');
+            -- the context and the query of a turn share their timestamp
+            INSERT INTO ai_session_messages (ai_session_id, role, content, sources, created_at) VALUES
+                ('chat', 'user', '<document>', '', '2024-05-01 10:00:00+00:00'),
+                ('chat', 'user', 'which database?', '', '2024-05-01 10:00:00+00:00'),
+                ('chat', 'assistant', 'sqlite', '', '2024-05-01 10:00:01+00:00'),
+                ('chat', 'user', 'why?', '', '2024-05-01 10:00:02+00:00'),
+                ('chat', 'assistant', 'it is embedded', '', '2024-05-01 10:00:03+00:00');",
+        )
+        .unwrap();
+        tx.commit().unwrap();
+
+        migrate(&mut conn, "").unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT id, parent_id FROM ai_session_messages ORDER BY rowid")
+            .unwrap();
+        let messages = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        // a single branch in the order the messages were saved
+        assert_eq!(messages[0].1, None);
+        for pair in messages.windows(2) {
+            assert_eq!(pair[1].1.as_ref(), Some(&pair[0].0));
+        }
+
+        let active = |id: &str| -> Option<String> {
+            conn.query_row(
+                "SELECT active_message_id FROM ai_sessions WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(active("chat").as_ref(), Some(&messages[4].0));
+        assert_eq!(active("empty"), None);
+    }
 }
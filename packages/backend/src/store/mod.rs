@@ -4,6 +4,7 @@ pub mod db;
 pub mod embedding_resources;
 pub mod history_entries;
 pub mod kv;
+pub mod llm_usage;
 pub mod models;
 pub mod post_processing_jobs;
 pub mod resource_content_hash;
@@ -1,3 +1,4 @@
+use crate::ai::llm::client::Model;
 use rusqlite::types::FromSql;
 use rusqlite::ToSql;
 use serde::{Deserialize, Serialize};
@@ -45,6 +46,9 @@ pub trait EmbeddableContent {
 pub enum EmbeddingType {
     Metadata,
     TextContent,
+    // the embedded image file has no text content, the content id is the rowid
+    // of the resource
+    Image,
 }
 
 impl Display for EmbeddingType {
@@ -52,6 +56,7 @@ impl Display for EmbeddingType {
         match self {
             EmbeddingType::Metadata => write!(f, "metadata"),
             EmbeddingType::TextContent => write!(f, "text_content"),
+            EmbeddingType::Image => write!(f, "image"),
         }
     }
 }
@@ -495,11 +500,47 @@ impl ResourceMetadata {
     }
 }
 
+/// Where in its source a chunk came from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkMetadata {
+    /// Headings the chunk is nested under, outermost first.
+    pub heading_path: Vec<String>,
+    pub page: Option<u32>,
+    /// Start of the chunk in seconds, for time based content.
+    pub start: Option<f32>,
+    /// Duration of the chunk in seconds, for time based content.
+    pub duration: Option<f32>,
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct ResourceTextContentMetadata {
     pub timestamp: Option<f32>,
     pub url: Option<String>,
     pub page: Option<u32>,
+    #[serde(default)]
+    pub duration: Option<f32>,
+    #[serde(default)]
+    pub heading_path: Option<Vec<String>>,
+}
+
+impl ResourceTextContentMetadata {
+    /// Returns a copy with the position of a chunk within this content filled in.
+    pub fn with_chunk_metadata(&self, chunk: &ChunkMetadata) -> Self {
+        let mut metadata = self.clone();
+        if !chunk.heading_path.is_empty() {
+            metadata.heading_path = Some(chunk.heading_path.clone());
+        }
+        if chunk.page.is_some() {
+            metadata.page = chunk.page;
+        }
+        if chunk.start.is_some() {
+            metadata.timestamp = chunk.start;
+        }
+        if chunk.duration.is_some() {
+            metadata.duration = chunk.duration;
+        }
+        metadata
+    }
 }
 
 impl ToSql for ResourceTextContentMetadata {
@@ -706,6 +747,62 @@ pub struct AIChatSession {
     pub created_at: chrono::DateTime<chrono::Utc>,
     #[serde(default = "current_time")]
     pub updated_at: chrono::DateTime<chrono::Utc>,
+    // last message of the branch that is shown and continued
+    #[serde(default)]
+    pub active_message_id: Option<String>,
+    #[serde(default)]
+    pub config: AIChatSessionConfig,
+    // topics of the session, generated with the title
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Defaults for the queries of a chat session, used where a query leaves them
+/// unset.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AIChatSessionConfig {
+    pub model: Option<Model>,
+    // pinned sources, the resources of the spaces are resolved at query time
+    #[serde(default)]
+    pub resource_ids: Vec<String>,
+    #[serde(default)]
+    pub space_ids: Vec<String>,
+    pub number_documents: Option<i32>,
+    // threshold of the embedding search
+    pub similarity_threshold: Option<f32>,
+}
+
+impl AIChatSessionConfig {
+    /// Takes whatever `self` leaves unset from `defaults`.
+    pub fn or(self, defaults: AIChatSessionConfig) -> AIChatSessionConfig {
+        // pinned resources and spaces are a single set of sources
+        let (resource_ids, space_ids) =
+            match self.resource_ids.is_empty() && self.space_ids.is_empty() {
+                true => (defaults.resource_ids, defaults.space_ids),
+                false => (self.resource_ids, self.space_ids),
+            };
+        AIChatSessionConfig {
+            model: self.model.or(defaults.model),
+            resource_ids,
+            space_ids,
+            number_documents: self.number_documents.or(defaults.number_documents),
+            similarity_threshold: self.similarity_threshold.or(defaults.similarity_threshold),
+        }
+    }
+}
+
+impl ToSql for AIChatSessionConfig {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        let json = serde_json::to_string(self).unwrap();
+        Ok(rusqlite::types::ToSqlOutput::from(json))
+    }
+}
+
+impl FromSql for AIChatSessionConfig {
+    fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        let json = String::column_result(value)?;
+        serde_json::from_str(&json).map_err(|_| rusqlite::types::FromSqlError::InvalidType)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -718,6 +815,60 @@ pub struct AIChatSessionHistory {
     pub created_at: chrono::DateTime<chrono::Utc>,
     #[serde(default = "current_time")]
     pub updated_at: chrono::DateTime<chrono::Utc>,
+    // summary of the older turns of the active branch
+    #[serde(default)]
+    pub summary: String,
+    #[serde(default)]
+    pub active_message_id: Option<String>,
+    #[serde(default)]
+    pub config: AIChatSessionConfig,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Summary of the turns of a chat session up to and including `message_id`,
+/// sent in their place on every branch that goes through that message.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AIChatSessionSummary {
+    pub message_id: String,
+    pub summary: String,
+}
+
+/// A path through the message tree of a chat session, from its first message to
+/// a message nothing follows yet.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AIChatSessionBranch {
+    pub leaf_message_id: String,
+    // first message of the branch that isn't on the active branch, the first
+    // message of the session for the active branch itself
+    pub first_message_id: String,
+    // the message the branch forks off after, None if it forks at the start
+    pub fork_parent_id: Option<String>,
+    // the first user query from where the branch forks
+    pub preview: String,
+    pub active: bool,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A message of a chat session matching a search.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AIChatSessionMessageHit {
+    pub ai_session_id: String,
+    pub message_id: String,
+    pub role: String,
+    // the matching part of the message with the matches wrapped in <mark></mark>,
+    // the start of the message for semantic matches
+    pub snippet: String,
+    pub semantic: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A chat session matching a search by its title or messages, the best
+/// matching messages first.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AIChatSearchResult {
+    pub session: AIChatSession,
+    pub hits: Vec<AIChatSessionMessageHit>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -725,6 +876,23 @@ pub struct AIChatSessionMessageSourceMetadata {
     pub timestamp: Option<f32>,
     pub url: Option<String>,
     pub page: Option<u32>,
+    #[serde(default)]
+    pub duration: Option<f32>,
+    #[serde(default)]
+    pub heading_path: Option<Vec<String>>,
+}
+
+/// A passage an answer quotes from a source, checked against the source's text content.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AIChatSessionMessageSourceQuote {
+    pub text: String,
+    // character offsets of the passage in the text content `uid`, the chunk of
+    // the source the passage starts in, unverified quotes have none
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+    #[serde(default)]
+    pub uid: Option<String>,
+    pub verified: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -733,6 +901,12 @@ pub struct AIChatSessionMessageSource {
     pub uid: String,
     pub resource_id: String,
     pub metadata: Option<AIChatSessionMessageSourceMetadata>,
+    #[serde(default)]
+    pub quotes: Vec<AIChatSessionMessageSourceQuote>,
+    // text contents of the passage in document order if `uid` was sent along
+    // with its neighbouring chunks, `uid` is the one that matched the query
+    #[serde(default)]
+    pub chunk_uids: Vec<String>,
 }
 
 impl AIChatSessionMessageSource {
@@ -754,7 +928,11 @@ impl AIChatSessionMessageSource {
                 timestamp: text_content.metadata.timestamp,
                 url: Some(metadata.source_uri.clone()),
                 page: text_content.metadata.page,
+                duration: text_content.metadata.duration,
+                heading_path: text_content.metadata.heading_path.clone(),
             }),
+            quotes: vec![],
+            chunk_uids: vec![],
         })
     }
 
@@ -792,6 +970,10 @@ impl AIChatSessionMessageSource {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AIChatSessionMessage {
+    pub id: String,
+    // the message this one follows in its branch
+    #[serde(default)]
+    pub parent_id: Option<String>,
     pub ai_session_id: String,
     pub role: String,
     pub content: String,
@@ -804,6 +986,69 @@ pub struct AIChatSessionMessage {
     pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
+/// Tokens and latency of a single LLM completion.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LLMUsage {
+    pub model: String,
+    pub provider: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // part of the prompt tokens that were served from the provider's prompt cache
+    pub cached_tokens: u64,
+    pub latency_ms: u64,
+    pub streamed: bool,
+    // whether the user's own api key was used
+    pub custom_key: bool,
+    pub session_id: Option<String>,
+    pub note_id: Option<String>,
+    pub agent: Option<String>,
+    #[serde(default = "current_time")]
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LLMUsagePeriod {
+    Daily,
+    Monthly,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LLMUsageTotal {
+    // YYYY-MM-DD for daily totals, YYYY-MM for monthly ones
+    pub period: String,
+    pub model: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cached_tokens: u64,
+    // None if there is no price for the model
+    pub estimated_cost: Option<f64>,
+}
+
+/// Price of a model in USD per million tokens.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct LLMModelPrice {
+    pub input_per_mtok: f64,
+    pub output_per_mtok: f64,
+    pub cached_input_per_mtok: f64,
+}
+
+impl LLMModelPrice {
+    pub fn estimate_cost(
+        &self,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+        cached_tokens: u64,
+    ) -> f64 {
+        let cached_tokens = cached_tokens.min(prompt_tokens);
+        ((prompt_tokens - cached_tokens) as f64 * self.input_per_mtok
+            + cached_tokens as f64 * self.cached_input_per_mtok
+            + completion_tokens as f64 * self.output_per_mtok)
+            / 1_000_000.0
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Space {
     #[serde(default = "random_uuid")]
@@ -954,6 +1199,8 @@ pub struct SearchResourcesParams {
     pub include_annotations: Option<bool>,
     pub space_id: Option<String>,
     pub keyword_limit: Option<i64>,
+    pub rerank: Option<bool>,
+    pub image_search_enabled: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
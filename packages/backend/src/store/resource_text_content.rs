@@ -1,6 +1,7 @@
 use super::models::*;
 use crate::{store::db::Database, BackendResult};
 use rusqlite::OptionalExtension;
+use std::collections::{BTreeMap, BTreeSet};
 
 impl Database {
     pub fn create_resource_text_content(
@@ -61,7 +62,7 @@ impl Database {
         id: &str,
     ) -> BackendResult<Vec<ResourceTextContent>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, resource_id, content, content_type, metadata FROM resource_text_content WHERE resource_id = ?1",
+            "SELECT id, resource_id, content, content_type, metadata FROM resource_text_content WHERE resource_id = ?1 ORDER BY rowid",
         )?;
         let rows = stmt.query_map(rusqlite::params![id], |row| {
             Ok(ResourceTextContent {
@@ -79,6 +80,80 @@ impl Database {
         Ok(contents)
     }
 
+    /// Chunks of a resource at most `radius` chunks away from one of the embedded
+    /// chunks `ids`, keyed by their rowid. The chunks of a text content are
+    /// inserted in one go, so neighbouring chunks have consecutive rowids.
+    ///
+    /// Only rowids are looked up, filtering the text content on its other
+    /// columns would scan the whole table.
+    pub fn list_resource_text_content_around(
+        &self,
+        resource_id: &str,
+        ids: &[String],
+        radius: usize,
+    ) -> BackendResult<BTreeMap<usize, ResourceTextContent>> {
+        if ids.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+
+        let placeholders = (2..ids.len() + 2)
+            .map(|i| format!("?{}", i))
+            .collect::<Vec<_>>()
+            .join(",");
+        let query = format!(
+            "SELECT C.rowid FROM embedding_resources E
+            INNER JOIN resource_text_content C ON C.rowid = E.content_id
+            WHERE E.resource_id = ?1 AND E.embedding_type = 'text_content' AND C.id IN ({})",
+            placeholders
+        );
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![&resource_id];
+        for id in ids {
+            params.push(id);
+        }
+        let mut stmt = self.conn.prepare(&query)?;
+        let hit_rowids = stmt
+            .query_map(params.as_slice(), |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let radius = radius as i64;
+        let rowids: BTreeSet<i64> = hit_rowids
+            .iter()
+            .flat_map(|rowid| (rowid - radius)..=(rowid + radius))
+            .filter(|rowid| *rowid > 0)
+            .collect();
+        let mut contents = BTreeMap::new();
+        if rowids.is_empty() {
+            return Ok(contents);
+        }
+        let placeholders = vec!["?"; rowids.len()].join(",");
+        let query = format!(
+            "SELECT rowid, id, resource_id, content, content_type, metadata
+            FROM resource_text_content WHERE rowid IN ({})",
+            placeholders
+        );
+        let mut stmt = self.conn.prepare(&query)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(rowids.iter()), |row| {
+            Ok((
+                row.get::<_, i64>(0)? as usize,
+                ResourceTextContent {
+                    id: row.get(1)?,
+                    resource_id: row.get(2)?,
+                    content: row.get(3)?,
+                    content_type: row.get(4)?,
+                    metadata: row.get(5)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (rowid, content) = row?;
+            // the chunks around the first and last chunk can be of other resources
+            if content.resource_id == resource_id {
+                contents.insert(rowid, content);
+            }
+        }
+        Ok(contents)
+    }
+
     pub fn list_resource_text_content_rowids_and_content_by_resource_id(
         &self,
         id: &str,
@@ -184,3 +259,83 @@ impl Database {
         Ok(count)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_list_resource_text_content_around() {
+        let dir = tempdir().unwrap();
+        let mut db = Database::new(&dir.path().join("test.db").to_string_lossy(), true).unwrap();
+        let mut tx = db.begin().unwrap();
+        // rowids 1 to 3 are chunks of b, 4 to 13 of a
+        for (resource_id, count) in [("b", 3), ("a", 10)] {
+            Database::create_resource_tx(
+                &mut tx,
+                &Resource {
+                    id: resource_id.to_string(),
+                    resource_path: String::new(),
+                    resource_type: "application/pdf".to_string(),
+                    created_at: current_time(),
+                    updated_at: current_time(),
+                    deleted: 0,
+                },
+            )
+            .unwrap();
+            for position in 0..count {
+                Database::create_resource_text_content_tx(
+                    &mut tx,
+                    &ResourceTextContent {
+                        id: format!("{}{}", resource_id, position),
+                        resource_id: resource_id.to_string(),
+                        content: format!("chunk {}", position),
+                        content_type: ResourceTextContentType::Document,
+                        metadata: ResourceTextContentMetadata::default(),
+                    },
+                )
+                .unwrap();
+                let content_id = tx.last_insert_rowid();
+                Database::create_embedding_resource_tx(
+                    &mut tx,
+                    &EmbeddingResource {
+                        rowid: None,
+                        content_id,
+                        resource_id: resource_id.to_string(),
+                        embedding_type: EmbeddingType::TextContent,
+                    },
+                )
+                .unwrap();
+            }
+        }
+        tx.commit().unwrap();
+
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let around = db
+            .list_resource_text_content_around("a", &ids(&["a2", "a8"]), 1)
+            .unwrap();
+        assert_eq!(
+            around.values().map(|c| c.id.as_str()).collect::<Vec<_>>(),
+            vec!["a1", "a2", "a3", "a7", "a8", "a9"]
+        );
+        assert_eq!(
+            around.keys().copied().collect::<Vec<_>>(),
+            vec![5, 6, 7, 11, 12, 13]
+        );
+        assert_eq!(around[&11].content, "chunk 7");
+
+        // chunks of other resources are neither hits nor neighbours
+        let around = db
+            .list_resource_text_content_around("a", &ids(&["b1", "a0"]), 2)
+            .unwrap();
+        assert_eq!(
+            around.values().map(|c| c.id.as_str()).collect::<Vec<_>>(),
+            vec!["a0", "a1", "a2"]
+        );
+        assert!(db
+            .list_resource_text_content_around("a", &[], 2)
+            .unwrap()
+            .is_empty());
+    }
+}
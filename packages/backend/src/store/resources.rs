@@ -88,6 +88,15 @@ impl Database {
             .optional()?)
     }
 
+    pub fn get_resource_rowid(&self, id: &str) -> BackendResult<Option<i64>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT rowid FROM resources WHERE id = ?1")?;
+        Ok(stmt
+            .query_row(rusqlite::params![id], |row| row.get(0))
+            .optional()?)
+    }
+
     pub fn remove_resources_tx(
         tx: &mut rusqlite::Transaction,
         ids: &[String],
@@ -4,7 +4,7 @@ use crate::{
     BackendResult,
 };
 
-fn escape_fts_query(keyword: &str) -> String {
+pub fn escape_fts_query(keyword: &str) -> String {
     let escaped_quotes = keyword.replace(r#"""#, r#"""""#);
     let tokens: Vec<&str> = escaped_quotes.split_whitespace().collect();
 
@@ -1,26 +1,83 @@
 use crate::{
     ai::{
+        chat_export::{ChatExport, ChatExportFormat},
+        citations::attach_quotes,
         llm::{
-            client::Model,
+            client::{
+                cache::ResponseCacheStats,
+                catalog::{self, ModelCatalog, ModelInfo, ModelInfoOverride},
+                CancellationToken, Model, Provider,
+            },
             models::{Message, MessageContent},
+            usage::{default_model_price, UsageTag},
         },
         youtube::YoutubeTranscript,
-        {ChatInput, ChatResult, DocsSimilarity},
+        {
+            generate_chat_title, ChatInput, ChatResult, ChatTitle, DocsSimilarity, SqlQueryResult,
+            RERANK_CANDIDATES_FACTOR,
+        },
     },
-    api::message::{MiscMessage, TunnelOneshot},
+    api::message::{EventBusMessage, MiscMessage, TunnelOneshot},
     store::{
+        ai_sessions::{self, newest_leaf, unsummarized_path},
         db::Database,
         models::{
-            random_uuid, AIChatSession, AIChatSessionHistory, AIChatSessionMessage,
-            AIChatSessionMessageSource, CompositeResource, EmbeddingType, InternalResourceTagNames,
+            random_uuid, AIChatSearchResult, AIChatSession, AIChatSessionBranch,
+            AIChatSessionConfig, AIChatSessionHistory, AIChatSessionMessage,
+            AIChatSessionMessageSource, AIChatSessionSummary, CompositeResource, EmbeddingType,
+            InternalResourceTagNames, LLMModelPrice, LLMUsagePeriod, LLMUsageTotal,
             ResourceTextContent,
         },
     },
-    worker::{send_worker_response, Worker},
+    worker::{send_event_bus_message, send_worker_response, Worker},
     BackendError, BackendResult,
 };
 use neon::prelude::*;
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+// used if neither the query nor the chat session set it
+const DEFAULT_NUMBER_DOCUMENTS: i32 = 20;
+
+// embedded answers considered when searching chat history semantically
+const CHAT_SEARCH_NUM_DOCS: usize = 20;
+const CHAT_SEARCH_DISTANCE_THRESHOLD: f32 = 0.4;
+
+// kv tables layered over the embedded model catalog, keyed by model id
+const MODEL_CATALOG_DISCOVERED_TABLE: &str = "llm_model_catalog_discovered";
+const MODEL_CATALOG_OVERRIDES_TABLE: &str = "llm_model_catalog_overrides";
+
+// stores the outcome of a chat title with a connection of its own, the worker's
+// is busy with later messages by then. Returns the session as stored, without
+// the generated title if the user named it meanwhile, or `None` if it's deleted
+fn store_ai_chat_title(
+    db_path: &str,
+    session_id: &str,
+    generated: BackendResult<ChatTitle>,
+) -> BackendResult<Option<AIChatSession>> {
+    let mut db = Database::new(db_path, false)?;
+    let generated = generated.and_then(|generated| match generated.title.is_empty() {
+        true => Err(BackendError::GenericError(
+            "the model returned an empty title".to_string(),
+        )),
+        false => Ok(generated),
+    });
+    let mut tx = db.begin()?;
+    match &generated {
+        Ok(generated) => Database::update_ai_session_generated_title_tx(
+            &mut tx,
+            session_id,
+            &generated.title,
+            &generated.tags,
+        )?,
+        Err(_) => Database::fail_ai_session_title_generation_tx(&mut tx, session_id)?,
+    }
+    tx.commit()?;
+    generated?;
+    db.get_ai_session(session_id)
+}
 
 impl Worker {
     pub fn print(&mut self, content: String) -> BackendResult<String> {
@@ -33,17 +90,59 @@ impl Worker {
             .db
             .get_ai_session(&id)?
             .ok_or_else(|| BackendError::GenericError("AI chat session not found".to_string()))?;
-        let messages = self.db.list_non_context_ai_session_messages(&id)?;
+        let branch = ai_sessions::active_branch(
+            self.db.list_ai_session_messages_skip_sources(&id)?,
+            session.active_message_id.as_deref(),
+        );
+        let branch_ids = branch
+            .iter()
+            .map(|msg| msg.id.as_str())
+            .collect::<HashSet<_>>();
+        let mut messages = self.db.list_non_context_ai_session_messages(&id)?;
+        messages.retain(|msg| branch_ids.contains(msg.id.as_str()));
+        let (summary, _) =
+            unsummarized_path(&self.db.list_ai_session_summaries(&id)?, branch.clone());
         Ok(AIChatSessionHistory {
             id: session.id,
             system_prompt: session.system_prompt,
             title: session.title,
+            tags: session.tags,
             created_at: session.created_at,
             updated_at: session.updated_at,
+            summary,
+            active_message_id: branch.last().map(|msg| msg.id.clone()),
+            config: session.config,
             messages,
         })
     }
 
+    /// Renders the active branch of a chat with its citations as footnotes.
+    pub fn export_ai_chat(
+        &mut self,
+        session_id: String,
+        format: ChatExportFormat,
+    ) -> BackendResult<String> {
+        let chat = self.get_ai_chat_message(session_id)?;
+        let mut resources = HashMap::new();
+        for source in chat
+            .messages
+            .iter()
+            .filter_map(|msg| msg.sources.as_ref())
+            .flatten()
+        {
+            if resources.contains_key(&source.resource_id) {
+                continue;
+            }
+            if let Some(metadata) = self
+                .db
+                .get_resource_metadata_by_resource_id(&source.resource_id)?
+            {
+                resources.insert(source.resource_id.clone(), metadata);
+            }
+        }
+        ChatExport::new(&chat, &resources).render(format)
+    }
+
     pub fn create_ai_chat_message(
         &mut self,
         system_prompt: String,
@@ -53,8 +152,11 @@ impl Worker {
             id: random_uuid(),
             system_prompt,
             title,
+            tags: vec![],
             updated_at: chrono::Utc::now(),
             created_at: chrono::Utc::now(),
+            active_message_id: None,
+            config: AIChatSessionConfig::default(),
         };
         let mut tx = self.db.begin()?;
         Database::create_ai_session_tx(&mut tx, &new_chat)?;
@@ -69,6 +171,111 @@ impl Worker {
         Ok(())
     }
 
+    /// Replaces the summary of the older turns of the session's active branch,
+    /// e.g. after the user corrected it, the turns it covers stay the same.
+    pub fn update_ai_chat_summary(&mut self, id: String, summary: String) -> BackendResult<()> {
+        let session = self
+            .db
+            .get_ai_session(&id)?
+            .ok_or_else(|| BackendError::GenericError("AI chat session not found".to_string()))?;
+        let summaries = self.db.list_ai_session_summaries(&id)?;
+        let branch = ai_sessions::active_branch(
+            self.db.list_ai_session_messages_skip_sources(&id)?,
+            session.active_message_id.as_deref(),
+        );
+        let message_id = branch
+            .iter()
+            .rev()
+            .find(|msg| summaries.iter().any(|s| s.message_id == msg.id))
+            .map(|msg| msg.id.clone())
+            .ok_or_else(|| {
+                BackendError::GenericError("AI chat session has no summary yet".to_string())
+            })?;
+        let mut tx = self.db.begin()?;
+        Database::upsert_ai_session_summary_tx(
+            &mut tx,
+            &id,
+            &AIChatSessionSummary {
+                message_id,
+                summary,
+            },
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn update_ai_chat_config(
+        &mut self,
+        id: String,
+        system_prompt: String,
+        config: AIChatSessionConfig,
+    ) -> BackendResult<()> {
+        let mut tx = self.db.begin()?;
+        Database::update_ai_session_config_tx(&mut tx, &id, &system_prompt, &config)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The session's instructions and what a query runs with: its own settings,
+    /// then the session's, with pinned spaces resolved to their resources.
+    pub fn ai_chat_query_config(
+        &mut self,
+        session_id: &str,
+        query_config: AIChatSessionConfig,
+    ) -> BackendResult<(String, AIChatSessionConfig)> {
+        let session = self
+            .db
+            .get_ai_session(session_id)?
+            .ok_or_else(|| BackendError::GenericError("AI chat session not found".to_string()))?;
+        let mut config = query_config.or(session.config);
+
+        let mut seen = config.resource_ids.iter().cloned().collect::<HashSet<_>>();
+        for space_id in std::mem::take(&mut config.space_ids) {
+            for resource_id in self.db.list_resource_ids_by_space_id(&space_id)? {
+                if seen.insert(resource_id.clone()) {
+                    config.resource_ids.push(resource_id);
+                }
+            }
+        }
+        Ok((session.system_prompt, config))
+    }
+
+    pub fn list_ai_chat_branches(
+        &mut self,
+        session_id: String,
+    ) -> BackendResult<Vec<AIChatSessionBranch>> {
+        let session = self
+            .db
+            .get_ai_session(&session_id)?
+            .ok_or_else(|| BackendError::GenericError("AI chat session not found".to_string()))?;
+        let messages = self.db.list_ai_session_messages_skip_sources(&session_id)?;
+        Ok(ai_sessions::list_branches(
+            &messages,
+            session.active_message_id.as_deref(),
+        ))
+    }
+
+    /// Makes the newest branch going through `message_id` the active one.
+    pub fn switch_ai_chat_branch(
+        &mut self,
+        session_id: String,
+        message_id: String,
+    ) -> BackendResult<AIChatSessionHistory> {
+        let messages = self.db.list_ai_session_messages_skip_sources(&session_id)?;
+        if !messages.iter().any(|msg| msg.id == message_id) {
+            return Err(BackendError::GenericError(format!(
+                "AI chat message not found: {}",
+                message_id
+            )));
+        }
+        if let Some(leaf) = newest_leaf(&messages, Some(&message_id)) {
+            let mut tx = self.db.begin()?;
+            Database::set_ai_session_active_message_tx(&mut tx, &session_id, &leaf)?;
+            tx.commit()?;
+        }
+        self.get_ai_chat_message(session_id)
+    }
+
     pub fn list_ai_chats(&mut self, limit: Option<i64>) -> BackendResult<Vec<AIChatSession>> {
         self.db.list_ai_sessions(limit)
     }
@@ -81,10 +288,140 @@ impl Worker {
         self.db.search_ai_sessions(search, limit)
     }
 
+    pub fn search_ai_chat_messages(
+        &mut self,
+        search: String,
+        limit: Option<i64>,
+        semantic: bool,
+    ) -> BackendResult<Vec<AIChatSearchResult>> {
+        let semantic_hits = match semantic {
+            true => self.ai.chat_message_vector_search(
+                &self.db,
+                search.clone(),
+                CHAT_SEARCH_NUM_DOCS,
+                Some(CHAT_SEARCH_DISTANCE_THRESHOLD),
+            )?,
+            false => vec![],
+        };
+        self.db
+            .search_ai_session_messages(&search, semantic_hits, limit)
+    }
+
+    // adds the answers of the session that aren't in the vector index yet
+    fn embed_ai_chat_answers(&mut self, session_id: &str) -> BackendResult<()> {
+        let (message_ids, answers) = self.db.list_unembedded_ai_session_answers(session_id)?;
+        let mut keys = vec![];
+        let mut chunks = vec![];
+        let mut tx = self.db.begin()?;
+        for (message_id, answer) in message_ids.iter().zip(answers) {
+            for chunk in self.ai.chunker.chunk(&answer) {
+                keys.push(Database::create_ai_session_message_embedding_tx(
+                    &mut tx, session_id, message_id,
+                )?);
+                chunks.push(chunk);
+            }
+        }
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        tx.commit()?;
+
+        if let Err(e) = self.ai.upsert_embeddings(vec![], keys.clone(), chunks) {
+            // the answers are embedded again with the next query
+            let mut tx = self.db.begin()?;
+            Database::remove_ai_session_message_embeddings_tx(&mut tx, &keys)?;
+            tx.commit()?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // names an untitled chat from its first question and answer with the small
+    // model of the provider. The completion runs on the async runtime, its title
+    // is stored and sent over the event bus when it's done, a title set in the
+    // meantime is kept
+    fn title_ai_chat(
+        &mut self,
+        session_id: &str,
+        model: &Model,
+        custom_key: Option<String>,
+    ) -> BackendResult<()> {
+        let session = match self.db.get_ai_session(session_id)? {
+            Some(session) if session.title.trim().is_empty() => session,
+            _ => return Ok(()),
+        };
+        let branch = ai_sessions::active_branch(
+            self.db.list_ai_session_messages_skip_sources(session_id)?,
+            session.active_message_id.as_deref(),
+        );
+        let mut turn = branch
+            .iter()
+            .filter(|msg| !msg.is_context && msg.msg_type == "text");
+        let query = turn.find(|msg| msg.role == "user");
+        let answer = turn.find(|msg| msg.role == "assistant");
+        let (query, answer) = match (query, answer) {
+            (Some(query), Some(answer)) => (query, answer),
+            _ => return Ok(()),
+        };
+        let mut tx = self.db.begin()?;
+        let claimed = Database::claim_ai_session_title_generation_tx(&mut tx, session_id)?;
+        tx.commit()?;
+        if !claimed {
+            return Ok(());
+        }
+
+        let client = Arc::clone(&self.ai.client);
+        let db_path = self.db_path.clone();
+        let channel = self.channel.clone();
+        let event_bus_rx = Arc::clone(&self.event_bus_rx);
+        let (query, answer) = (query.content.clone(), answer.content.clone());
+        let model = model.small_model();
+        let session_id = session_id.to_string();
+        self.async_runtime.spawn(async move {
+            let generated = generate_chat_title(
+                &client,
+                &query,
+                &answer,
+                &model,
+                custom_key,
+                UsageTag::chat(Some(session_id.clone()), None),
+            )
+            .await;
+            match store_ai_chat_title(&db_path, &session_id, generated) {
+                Ok(Some(session)) => send_event_bus_message(
+                    &channel,
+                    &event_bus_rx,
+                    EventBusMessage::AIChatTitleMessage {
+                        session_id,
+                        title: session.title,
+                        tags: session.tags,
+                    },
+                ),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("failed to title chat {}: {}", session_id, e),
+            }
+        });
+        Ok(())
+    }
+
     pub fn delete_ai_chat_message(&mut self, session_id: String) -> BackendResult<()> {
+        let embedding_keys = self
+            .db
+            .list_ai_session_message_embedding_keys(Some(&session_id))?;
         let mut tx = self.db.begin()?;
         Database::delete_ai_session_tx(&mut tx, &session_id)?;
         tx.commit()?;
+        // the keys are gone with the session, vectors left behind are never
+        // searched again
+        if !embedding_keys.is_empty() {
+            if let Err(e) = self.ai.upsert_embeddings(embedding_keys, vec![], vec![]) {
+                tracing::warn!(
+                    "failed to remove the embeddings of chat {}: {}",
+                    session_id,
+                    e
+                );
+            }
+        }
         Ok(())
     }
 
@@ -97,6 +434,7 @@ impl Worker {
         self.ai.get_docs_similarity(query, docs, threshold)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_app_query(
         &mut self,
         mut chunk_callback: Root<JsFunction>,
@@ -105,6 +443,7 @@ impl Worker {
         model: &Model,
         custom_key: Option<String>,
         inline_images: Option<Vec<String>>,
+        request_id: Option<String>,
     ) -> BackendResult<()> {
         // frontend sends a query with a trailing <p></p> for some reason
         let query = match query.strip_suffix("<p></p>") {
@@ -112,20 +451,23 @@ impl Worker {
             None => query,
         };
 
-        let mut stream = self
-            .ai
-            .create_app(query, model, custom_key, inline_images)?;
+        let request = self.request_cancellations.register(request_id);
+        self.async_runtime.block_on(async {
+            let mut stream = self
+                .ai
+                .create_app(query, model, custom_key, inline_images, request.token())
+                .await?;
 
-        for chunk in stream.by_ref() {
-            match chunk {
-                Ok(data) => {
-                    chunk_callback = self.send_callback(chunk_callback, data)?;
-                }
-                Err(err) => return Err(err),
+            while let Some(chunk) = stream.next_text().await {
+                let data = match chunk {
+                    Err(BackendError::CancelledError) => break,
+                    chunk => chunk?,
+                };
+                chunk_callback = self.send_callback(chunk_callback, data)?;
             }
-        }
-        self.send_done_callback(done_callback)?;
-        Ok(())
+            self.send_done_callback(done_callback)?;
+            Ok(())
+        })
     }
 
     pub fn create_chat_completion(
@@ -135,9 +477,165 @@ impl Worker {
         custom_key: Option<String>,
         _response_format: Option<&str>,
     ) -> BackendResult<String> {
-        self.ai
-            .client
-            .create_chat_completion(messages, &model, custom_key, None)
+        self.async_runtime.block_on(
+            self.ai
+                .client
+                .create_chat_completion(messages, &model, custom_key, None),
+        )
+    }
+
+    /// Writes the usage of all completions finished since the last call.
+    pub fn flush_llm_usage(&mut self) {
+        let usages: Vec<_> = self.llm_usage_rx.try_iter().collect();
+        if usages.is_empty() {
+            return;
+        }
+        let result = self.db.begin().and_then(|mut tx| {
+            for usage in usages.iter() {
+                Database::create_llm_usage_tx(&mut tx, usage)?;
+            }
+            tx.commit()?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            tracing::error!("failed to store {} llm usage records: {}", usages.len(), e);
+        }
+    }
+
+    pub fn get_llm_usage_totals(
+        &mut self,
+        period: LLMUsagePeriod,
+        from: Option<chrono::DateTime<chrono::Utc>>,
+        to: Option<chrono::DateTime<chrono::Utc>>,
+        custom_key_only: bool,
+    ) -> BackendResult<Vec<LLMUsageTotal>> {
+        // include whatever the current message produced so far
+        self.flush_llm_usage();
+
+        let prices = self.db.list_llm_model_prices()?;
+        let mut totals = self
+            .db
+            .list_llm_usage_totals(period, from, to, custom_key_only)?;
+        for total in totals.iter_mut() {
+            let price = prices
+                .get(&total.model)
+                .copied()
+                .or_else(|| default_model_price(&total.model));
+            total.estimated_cost = price.map(|price| {
+                price.estimate_cost(
+                    total.prompt_tokens,
+                    total.completion_tokens,
+                    total.cached_tokens,
+                )
+            });
+        }
+        Ok(totals)
+    }
+
+    pub fn get_llm_cache_stats(&mut self) -> BackendResult<ResponseCacheStats> {
+        match self.ai.client.response_cache() {
+            Some(cache) => cache.stats(),
+            None => Ok(ResponseCacheStats::default()),
+        }
+    }
+
+    pub fn purge_llm_cache(&mut self, expired_only: bool) -> BackendResult<usize> {
+        match self.ai.client.response_cache() {
+            Some(cache) => cache.purge(expired_only),
+            None => Ok(0),
+        }
+    }
+
+    pub fn set_llm_model_price(
+        &mut self,
+        model: String,
+        price: LLMModelPrice,
+    ) -> BackendResult<()> {
+        self.db.upsert_llm_model_price(&model, &price)
+    }
+
+    /// Rebuilds the model catalog from the embedded one, what was discovered
+    /// through the providers and the user's overrides, in that order.
+    pub fn reload_model_catalog(&mut self) -> BackendResult<()> {
+        let mut model_catalog = ModelCatalog::embedded();
+        for table in [
+            MODEL_CATALOG_DISCOVERED_TABLE,
+            MODEL_CATALOG_OVERRIDES_TABLE,
+        ] {
+            self.kv.new_table(table)?;
+            let overrides = self
+                .kv
+                .list(table)?
+                .into_iter()
+                .filter_map(
+                    |data| match serde_json::from_str::<ModelInfoOverride>(&data) {
+                        Ok(model) => Some(model),
+                        Err(e) => {
+                            tracing::warn!("skipping invalid model catalog entry: {}", e);
+                            None
+                        }
+                    },
+                )
+                .collect::<Vec<_>>();
+            model_catalog.apply(overrides);
+        }
+        catalog::set_catalog(model_catalog);
+        Ok(())
+    }
+
+    pub fn get_model_catalog(&mut self) -> BackendResult<Vec<ModelInfo>> {
+        Ok(catalog::list_models())
+    }
+
+    fn put_model_catalog_entry(
+        &mut self,
+        table: &str,
+        key: &str,
+        model: &ModelInfoOverride,
+    ) -> BackendResult<()> {
+        let data = serde_json::to_string(model).map_err(|e| {
+            BackendError::GenericError(format!("failed to serialize model catalog entry: {e}"))
+        })?;
+        self.kv.put(table, key, &data)
+    }
+
+    pub fn set_model_catalog_override(&mut self, model: ModelInfoOverride) -> BackendResult<()> {
+        self.put_model_catalog_entry(MODEL_CATALOG_OVERRIDES_TABLE, &model.id, &model)?;
+        self.reload_model_catalog()
+    }
+
+    pub fn remove_model_catalog_override(&mut self, id: String) -> BackendResult<()> {
+        self.kv.delete(MODEL_CATALOG_OVERRIDES_TABLE, &id)?;
+        self.reload_model_catalog()
+    }
+
+    /// Updates the catalog with what `provider` lists and returns the listed models'
+    /// entries.
+    pub fn refresh_model_catalog(
+        &mut self,
+        provider: Provider,
+        custom_key: Option<String>,
+    ) -> BackendResult<Vec<ModelInfo>> {
+        let models = self
+            .async_runtime
+            .block_on(self.ai.client.list_models(&provider, custom_key))?;
+
+        let mut ids = HashSet::new();
+        for model in models {
+            let model = ModelInfoOverride::discovered(&provider, model);
+            // providers can list models under the same id
+            let key = format!("{}:{}", provider.name(), model.id);
+            self.put_model_catalog_entry(MODEL_CATALOG_DISCOVERED_TABLE, &key, &model)?;
+            ids.insert(model.id);
+        }
+        self.reload_model_catalog()?;
+
+        Ok(catalog::list_models()
+            .into_iter()
+            .filter(|info| {
+                info.provider.as_deref() == Some(provider.name()) && ids.contains(&info.id)
+            })
+            .collect())
     }
 
     pub fn send_chat_query(
@@ -146,6 +644,8 @@ impl Worker {
         callback: Root<JsFunction>,
         search_only: bool,
         chat_input: ChatInput,
+        branch_from: Option<String>,
+        request_id: Option<String>,
     ) -> BackendResult<()> {
         // frontend sends a query with a trailing <p></p> sometimes for some reason
         let query = match chat_input.query.strip_suffix("<p></p>") {
@@ -158,10 +658,11 @@ impl Worker {
                 query,
                 chat_input.number_documents,
                 Some(chat_input.resource_ids),
+                chat_input.rerank,
                 callback,
             );
         }
-        self.handle_full_chat_query(session_id, callback, chat_input)
+        self.handle_full_chat_query(session_id, callback, chat_input, branch_from, request_id)
     }
 
     // TODO: store history
@@ -170,16 +671,25 @@ impl Worker {
         query: String,
         number_documents: i32,
         resource_ids: Option<Vec<String>>,
+        rerank: bool,
         callback: Root<JsFunction>,
     ) -> BackendResult<()> {
-        let results = self.ai.vector_search(
+        let number_documents = number_documents as usize;
+        let num_candidates = match rerank {
+            true => number_documents * RERANK_CANDIDATES_FACTOR,
+            false => number_documents,
+        };
+        let mut results = self.ai.vector_search(
             &self.db,
-            query,
-            number_documents as usize,
+            query.clone(),
+            num_candidates,
             resource_ids,
             false,
             None,
         )?;
+        if rerank {
+            results = self.ai.rerank(&query, results, number_documents)?;
+        }
 
         let sources_str = self.process_search_results(&results)?;
         self.send_callback(callback, sources_str)?;
@@ -243,13 +753,15 @@ impl Worker {
         session_id: Option<String>,
         callback: Root<JsFunction>,
         mut chat_input: ChatInput,
+        branch_from: Option<String>,
+        request_id: Option<String>,
     ) -> BackendResult<()> {
         let mut history: Vec<Message> = vec![];
+        let mut parent_id: Option<String> = None;
+        let usage_tag = UsageTag::chat(session_id.clone(), chat_input.note_resource_id.clone());
 
         if let Some(ref session_id) = session_id {
-            history = self
-                .ai
-                .parse_chat_history(self.db.list_ai_session_messages_skip_sources(session_id)?)?;
+            (parent_id, history) = self.load_chat_history(session_id, branch_from.as_deref())?;
         }
 
         let mut should_cluster = false;
@@ -260,12 +772,15 @@ impl Worker {
             let composite_resources = self
                 .db
                 .list_resources_metadata_by_ids(&chat_input.resource_ids)?;
-            let should_cluster_result = self.ai.should_cluster(
-                &chat_input.query,
-                &chat_input.model,
-                chat_input.custom_key.clone(),
-                self.ai
-                    .llm_metadata_messages_from_sources(&composite_resources),
+            let should_cluster_result = self.async_runtime.block_on(
+                self.ai.should_cluster(
+                    &chat_input.query,
+                    &chat_input.model,
+                    chat_input.custom_key.clone(),
+                    self.ai
+                        .llm_metadata_messages_from_sources(&composite_resources),
+                    usage_tag.clone(),
+                ),
             )?;
             should_cluster = should_cluster_result.embeddings_search_needed;
             // we are already narrowing down the search space
@@ -291,11 +806,88 @@ impl Worker {
 
         self.handle_lazy_embeddings(&chat_input.resource_ids)?;
 
-        let (assistant_message, chat_result) =
-            self.process_chat_stream(callback, chat_input, history, should_cluster)?;
+        let request = self.request_cancellations.register(request_id);
+        let (assistant_message, chat_result) = self.process_chat_stream(
+            callback,
+            chat_input,
+            history,
+            should_cluster,
+            usage_tag,
+            request.token(),
+        )?;
 
         if let Some(session_id) = session_id {
-            self.save_messages(session_id, assistant_message, chat_result)?;
+            self.save_messages(session_id, parent_id, assistant_message, chat_result)?;
+        }
+        Ok(())
+    }
+
+    // the message the new turn follows and the history leading up to it, the
+    // new turn continues the active branch or replaces the turn of `branch_from`
+    //
+    // the turns the session's summary covers are replaced by the summary
+    fn load_chat_history(
+        &mut self,
+        session_id: &str,
+        branch_from: Option<&str>,
+    ) -> BackendResult<(Option<String>, Vec<Message>)> {
+        let session = self
+            .db
+            .get_ai_session(session_id)?
+            .ok_or_else(|| BackendError::GenericError("AI chat session not found".to_string()))?;
+        let (parent_id, summary, stored) = ai_sessions::turn_history(
+            self.db.list_ai_session_messages_skip_sources(session_id)?,
+            &self.db.list_ai_session_summaries(session_id)?,
+            session.active_message_id.as_deref(),
+            branch_from,
+        )?;
+        let mut history = self.ai.parse_chat_history(stored, parent_id.as_deref())?;
+        if !summary.is_empty() {
+            history.insert(0, Message::new_summary(&summary));
+        }
+        Ok((parent_id, history))
+    }
+
+    // folds the older turns of the chat's active branch into the session's
+    // summary once they take too much of the model's context window, after the
+    // answer so that it doesn't hold up the first token
+    fn summarize_ai_chat(
+        &mut self,
+        session_id: &str,
+        model: &Model,
+        custom_key: Option<String>,
+    ) -> BackendResult<()> {
+        let session = match self.db.get_ai_session(session_id)? {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let (leaf_id, summary, stored) = ai_sessions::turn_history(
+            self.db.list_ai_session_messages_skip_sources(session_id)?,
+            &self.db.list_ai_session_summaries(session_id)?,
+            session.active_message_id.as_deref(),
+            None,
+        )?;
+        let message_ids = stored.iter().map(|msg| msg.id.clone()).collect::<Vec<_>>();
+        let history = self.ai.parse_chat_history(stored, leaf_id.as_deref())?;
+
+        let folded = self.async_runtime.block_on(self.ai.fold_history(
+            &summary,
+            &history,
+            model,
+            custom_key,
+            UsageTag::chat(Some(session_id.to_string()), None),
+        ))?;
+        if let Some((summary, count)) = folded {
+            let mut tx = self.db.begin()?;
+            Database::upsert_ai_session_summary_tx(
+                &mut tx,
+                session_id,
+                &AIChatSessionSummary {
+                    message_id: message_ids[count - 1].clone(),
+                    summary,
+                },
+            )?;
+            tx.commit()?;
         }
         Ok(())
     }
@@ -364,25 +956,37 @@ impl Worker {
         chat_input: ChatInput,
         history: Vec<Message>,
         should_cluster: bool,
+        usage_tag: UsageTag,
+        cancellation_token: CancellationToken,
     ) -> BackendResult<(String, ChatResult)> {
-        let mut chat_result = self
-            .ai
-            .chat(&self.db, chat_input, history, should_cluster)?;
-
-        callback = self.send_callback(callback, chat_result.sources_xml.clone())?;
-
-        let mut assistant_message = String::new();
-        for chunk in chat_result.stream.by_ref() {
-            match chunk {
-                Ok(data) => {
-                    assistant_message.push_str(&data);
-                    callback = self.send_callback(callback, data)?;
-                }
-                Err(err) => return Err(err),
+        self.async_runtime.block_on(async {
+            let mut chat_result = self
+                .ai
+                .chat(
+                    &self.db,
+                    chat_input,
+                    history,
+                    should_cluster,
+                    usage_tag,
+                    cancellation_token,
+                )
+                .await?;
+
+            callback = self.send_callback(callback, chat_result.sources_xml.clone())?;
+
+            // a cancelled answer is kept as far as it got
+            let mut assistant_message = String::new();
+            while let Some(chunk) = chat_result.stream.next_text().await {
+                let data = match chunk {
+                    Err(BackendError::CancelledError) => break,
+                    chunk => chunk?,
+                };
+                assistant_message.push_str(&data);
+                callback = self.send_callback(callback, data)?;
             }
-        }
 
-        Ok((assistant_message, chat_result))
+            Ok((assistant_message, chat_result))
+        })
     }
 
     fn send_callback(
@@ -420,9 +1024,30 @@ impl Worker {
     fn save_messages(
         &mut self,
         session_id: String,
+        mut parent_id: Option<String>,
         assistant_message: String,
         chat_result: ChatResult,
     ) -> BackendResult<()> {
+        let mut sources = chat_result.sources;
+        attach_quotes(&assistant_message, &mut sources, |source| {
+            let uids = match source.chunk_uids.is_empty() {
+                true => std::slice::from_ref(&source.uid),
+                false => source.chunk_uids.as_slice(),
+            };
+            let mut chunks = vec![];
+            for uid in uids {
+                match self.db.get_resource_text_content(uid) {
+                    Ok(Some(content)) => chunks.push((content.id, content.content)),
+                    Ok(None) => return None,
+                    Err(e) => {
+                        tracing::warn!("failed to get text content {}: {}", uid, e);
+                        return None;
+                    }
+                }
+            }
+            Some(chunks)
+        });
+
         let mut tx = self.db.begin()?;
         for msg in chat_result.messages.iter() {
             if msg.content.len() != 1 {
@@ -434,6 +1059,8 @@ impl Worker {
             };
 
             let message = AIChatSessionMessage {
+                id: random_uuid(),
+                parent_id: parent_id.take(),
                 ai_session_id: session_id.clone(),
                 role: msg.role.to_string(),
                 content,
@@ -444,10 +1071,13 @@ impl Worker {
                 sources: None,
             };
             Database::create_ai_session_message_tx(&mut tx, &message)?;
+            parent_id = Some(message.id);
         }
         Database::create_ai_session_message_tx(
             &mut tx,
             &AIChatSessionMessage {
+                id: random_uuid(),
+                parent_id,
                 ai_session_id: session_id.clone(),
                 role: "assistant".to_owned(),
                 content: assistant_message,
@@ -455,7 +1085,7 @@ impl Worker {
                 is_context: false,
                 msg_type: "text".to_owned(),
                 created_at: chrono::Utc::now(),
-                sources: Some(chat_result.sources),
+                sources: Some(sources),
             },
         )?;
 
@@ -478,11 +1108,6 @@ impl Worker {
         embedding_query: Option<String>,
         embedding_distance_threshold: Option<f32>,
     ) -> BackendResult<String> {
-        #[derive(serde::Deserialize, Debug)]
-        struct JsonResult {
-            sql_query: String,
-            embedding_search_query: Option<String>,
-        }
         #[derive(serde::Serialize, Debug)]
         struct FunctionResult {
             sql_query: String,
@@ -492,18 +1117,13 @@ impl Worker {
         }
 
         let result = match sql_query {
-            Some(string) => JsonResult {
+            Some(string) => SqlQueryResult {
                 sql_query: string,
                 embedding_search_query: embedding_query,
             },
-            None => serde_json::from_str::<JsonResult>(
-                self.ai
-                    .get_sql_query(prompt, model, custom_key)?
-                    .replace("```json", "")
-                    .replace("```", "")
-                    .as_str(),
-            )
-            .map_err(|e| BackendError::GenericError(e.to_string()))?,
+            None => self
+                .async_runtime
+                .block_on(self.ai.get_sql_query(prompt, model, custom_key))?,
         };
         let mut resource_ids_first: HashSet<String> = HashSet::new();
         let mut resource_ids_stmt = self.db.read_only_conn.prepare(result.sql_query.as_str())?;
@@ -593,12 +1213,15 @@ impl Worker {
 
             if send_cluster_query {
                 let composite_resources = self.db.list_resources_metadata_by_ids(&ids)?;
-                let should_cluster_result = self.ai.should_cluster(
-                    &query,
-                    &model,
-                    custom_key,
-                    self.ai
-                        .llm_metadata_messages_from_sources(&composite_resources),
+                let should_cluster_result = self.async_runtime.block_on(
+                    self.ai.should_cluster(
+                        &query,
+                        &model,
+                        custom_key,
+                        self.ai
+                            .llm_metadata_messages_from_sources(&composite_resources),
+                        UsageTag::agent("search_chat_resources"),
+                    ),
                 )?;
                 should_cluster = should_cluster_result.embeddings_search_needed;
 
@@ -708,6 +1331,30 @@ pub fn handle_misc_message(
             let result = worker.update_ai_chat_message(id, title);
             send_worker_response(&mut worker.channel, oneshot, result)
         }
+        MiscMessage::ExportAIChat { session_id, format } => {
+            let result = worker.export_ai_chat(session_id, format);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::UpdateAIChatSummary(id, summary) => {
+            let result = worker.update_ai_chat_summary(id, summary);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::UpdateAIChatConfig {
+            session_id,
+            system_prompt,
+            config,
+        } => {
+            let result = worker.update_ai_chat_config(session_id, system_prompt, config);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::ListAIChatBranches(session_id) => {
+            let result = worker.list_ai_chat_branches(session_id);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::SwitchAIChatBranch(session_id, message_id) => {
+            let result = worker.switch_ai_chat_branch(session_id, message_id);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
         MiscMessage::ListAIChats(limit) => {
             let result = worker.list_ai_chats(limit);
             send_worker_response(&mut worker.channel, oneshot, result)
@@ -716,6 +1363,14 @@ pub fn handle_misc_message(
             let result = worker.search_ai_chats(&search, limit);
             send_worker_response(&mut worker.channel, oneshot, result)
         }
+        MiscMessage::SearchAIChatMessages {
+            search,
+            limit,
+            semantic,
+        } => {
+            let result = worker.search_ai_chat_messages(search, limit, semantic);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
         MiscMessage::CreateChatCompletion {
             messages,
             model,
@@ -733,32 +1388,88 @@ pub fn handle_misc_message(
         // TODO: use chat input
         MiscMessage::ChatQuery {
             query,
-            model,
+            config,
             custom_key,
             search_only,
             session_id,
-            number_documents,
             callback,
-            resource_ids,
             inline_images,
             general,
             app_creation,
+            rerank,
+            branch_from,
+            embed_answer,
+            generate_title,
+            plan_retrieval,
+            request_id,
+            fallbacks,
+            max_retries,
         } => {
-            let input = ChatInput {
-                query,
-                model,
-                custom_key,
-                number_documents,
-                resource_ids,
-                inline_images,
-                general: general || app_creation, // general is true for app creation
-                note_resource_id: None,
-                websearch: false,
-                surflet: false,
-            };
-            let result = worker.send_chat_query(Some(session_id), callback, search_only, input);
-
-            send_worker_response(&mut worker.channel, oneshot, result)
+            let chat_id = session_id.clone();
+            let title_key = custom_key.clone();
+            let result = worker.ai_chat_query_config(&session_id, config).and_then(
+                |(system_prompt, config)| {
+                    let model = config.model.ok_or_else(|| {
+                        BackendError::GenericError(
+                            "no model set for the query or the chat".to_string(),
+                        )
+                    })?;
+                    let input = ChatInput {
+                        query,
+                        model: model.clone(),
+                        custom_key,
+                        number_documents: config
+                            .number_documents
+                            .unwrap_or(DEFAULT_NUMBER_DOCUMENTS),
+                        // general is true for app creation, a chat with pinned
+                        // sources is answered from them
+                        general: (general && config.resource_ids.is_empty()) || app_creation,
+                        resource_ids: config.resource_ids,
+                        inline_images,
+                        note_resource_id: None,
+                        websearch: false,
+                        surflet: false,
+                        rerank,
+                        system_prompt: Some(system_prompt),
+                        similarity_threshold: config.similarity_threshold,
+                        plan_retrieval,
+                        fallbacks,
+                        max_retries,
+                    };
+                    worker
+                        .send_chat_query(
+                            Some(session_id),
+                            callback,
+                            search_only,
+                            input,
+                            branch_from,
+                            request_id,
+                        )
+                        .map(|_| model)
+                },
+            );
+            let model = result.as_ref().ok().cloned();
+            send_worker_response(&mut worker.channel, oneshot, result.map(|_| ()));
+
+            // the answer is already streamed and resolved, these only hold up
+            // this worker thread while the title is generated in the background
+            if let (Some(model), false) = (model, search_only) {
+                if embed_answer {
+                    // on failure the answers stay searchable by keyword
+                    if let Err(e) = worker.embed_ai_chat_answers(&chat_id) {
+                        tracing::warn!("failed to embed answers of chat {}: {}", chat_id, e);
+                    }
+                }
+                // the full history is sent and truncated until a summary succeeds
+                if let Err(e) = worker.summarize_ai_chat(&chat_id, &model, title_key.clone()) {
+                    tracing::warn!("failed to summarize chat {}: {}", chat_id, e);
+                }
+                if generate_title {
+                    if let Err(e) = worker.title_ai_chat(&chat_id, &model, title_key) {
+                        tracing::warn!("failed to title chat {}: {}", chat_id, e);
+                    }
+                }
+            }
         }
         MiscMessage::NoteQuery {
             query,
@@ -772,6 +1483,10 @@ pub fn handle_misc_message(
             general,
             surflet,
             websearch,
+            rerank,
+            request_id,
+            fallbacks,
+            max_retries,
         } => {
             let input = ChatInput {
                 query,
@@ -784,9 +1499,15 @@ pub fn handle_misc_message(
                 note_resource_id: Some(note_resource_id),
                 websearch,
                 surflet,
+                rerank,
+                system_prompt: None,
+                similarity_threshold: None,
+                plan_retrieval: false,
+                fallbacks,
+                max_retries,
             };
 
-            let result = worker.send_chat_query(None, callback, false, input);
+            let result = worker.send_chat_query(None, callback, false, input, None, request_id);
             send_worker_response(&mut worker.channel, oneshot, result)
         }
         MiscMessage::CreateAppQuery {
@@ -796,6 +1517,7 @@ pub fn handle_misc_message(
             chunk_callback,
             done_callback,
             inline_images,
+            request_id,
         } => {
             let result = worker.create_app_query(
                 chunk_callback,
@@ -804,9 +1526,16 @@ pub fn handle_misc_message(
                 &model,
                 custom_key,
                 inline_images,
+                request_id,
             );
             send_worker_response(&mut worker.channel, oneshot, result)
         }
+        MiscMessage::CancelAIRequest(request_id) => {
+            if !worker.request_cancellations.cancel(&request_id) {
+                tracing::debug!("no running request {} to cancel", request_id);
+            }
+            send_worker_response(&mut worker.channel, oneshot, Ok(()));
+        }
         MiscMessage::QuerySFFSResources(
             prompt,
             model,
@@ -870,5 +1599,45 @@ pub fn handle_misc_message(
             );
             send_worker_response(&mut worker.channel, oneshot, result)
         }
+        MiscMessage::GetLLMUsage {
+            period,
+            from,
+            to,
+            custom_key_only,
+        } => {
+            let result = worker.get_llm_usage_totals(period, from, to, custom_key_only);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::SetLLMModelPrice { model, price } => {
+            let result = worker.set_llm_model_price(model, price);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::GetLLMCacheStats => {
+            let result = worker.get_llm_cache_stats();
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::PurgeLLMCache { expired_only } => {
+            let result = worker.purge_llm_cache(expired_only);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::GetModelCatalog => {
+            let result = worker.get_model_catalog();
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::SetModelCatalogOverride(model) => {
+            let result = worker.set_model_catalog_override(model);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::RemoveModelCatalogOverride(id) => {
+            let result = worker.remove_model_catalog_override(id);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
+        MiscMessage::RefreshModelCatalog {
+            provider,
+            custom_key,
+        } => {
+            let result = worker.refresh_model_catalog(provider, custom_key);
+            send_worker_response(&mut worker.channel, oneshot, result)
+        }
     }
 }
@@ -3,6 +3,7 @@ use std::collections::HashSet;
 use tracing::{debug, instrument};
 
 use crate::{
+    ai::{IMAGE_SEARCH_DISTANCE_THRESHOLD, RERANK_CANDIDATES_FACTOR},
     api::message::{ProcessorMessage, ResourceMessage, ResourceTagMessage, TunnelOneshot},
     store::{
         db::Database,
@@ -175,6 +176,7 @@ impl Worker {
         }
         let mut resources_to_remove = Vec::new();
         let mut all_embedding_keys = Vec::new();
+        let mut all_image_embedding_keys = Vec::new();
 
         for id in &ids {
             if let Some(resource) = self.db.get_resource(id)? {
@@ -184,6 +186,10 @@ impl Worker {
                     .db
                     .list_embedding_ids_by_type_resource_id(EmbeddingType::TextContent, id)?;
                 all_embedding_keys.extend(embedding_keys);
+                let image_embedding_keys = self
+                    .db
+                    .list_embedding_ids_by_type_resource_id(EmbeddingType::Image, id)?;
+                all_image_embedding_keys.extend(image_embedding_keys);
             }
         }
 
@@ -195,6 +201,10 @@ impl Worker {
         Database::remove_resources_tx(&mut tx, &ids)?;
         self.ai
             .upsert_embeddings(all_embedding_keys, vec![], vec![])?;
+        if !all_image_embedding_keys.is_empty() {
+            self.ai
+                .upsert_image_embeddings(all_image_embedding_keys, vec![], vec![])?;
+        }
         for resource in resources_to_remove {
             match std::fs::remove_file(&resource.resource_path) {
                 Ok(_) => {}
@@ -297,6 +307,8 @@ impl Worker {
 
         let embeddings_distance_threshold = params.embeddings_distance_threshold.unwrap_or(0.4);
         let embeddings_limit = params.embeddings_limit.unwrap_or(100);
+        let rerank = params.rerank.unwrap_or_default();
+        let image_search_enabled = params.image_search_enabled.unwrap_or_default();
 
         let mut seen_keys: HashSet<String> = HashSet::new();
         let mut results: Vec<SearchResultItem> = vec![];
@@ -323,15 +335,46 @@ impl Worker {
         }
 
         if semantic_search_enabled {
-            let vector_search_results = self.ai.vector_search(
-                &self.db,
-                params.query.clone(),
-                embeddings_limit as usize,
-                filtered_resource_ids,
-                true,
-                Some(embeddings_distance_threshold),
-            )?;
-            for result in vector_search_results {
+            // reranking needs the matched chunks, so the results are only
+            // deduplicated by resource after scoring
+            let vector_search_results = match rerank {
+                true => {
+                    let candidates = self.ai.vector_search(
+                        &self.db,
+                        params.query.clone(),
+                        embeddings_limit as usize * RERANK_CANDIDATES_FACTOR,
+                        filtered_resource_ids.clone(),
+                        false,
+                        Some(embeddings_distance_threshold),
+                    )?;
+                    self.ai
+                        .rerank(&params.query, candidates, embeddings_limit as usize)?
+                }
+                false => self.ai.vector_search(
+                    &self.db,
+                    params.query.clone(),
+                    embeddings_limit as usize,
+                    filtered_resource_ids.clone(),
+                    true,
+                    Some(embeddings_distance_threshold),
+                )?,
+            };
+            // images without any OCR-able text are only reachable through the image index
+            let image_search_results = match image_search_enabled {
+                true => self.ai.image_search(
+                    &self.db,
+                    Some(params.query.clone()),
+                    None,
+                    embeddings_limit as usize,
+                    filtered_resource_ids,
+                    Some(IMAGE_SEARCH_DISTANCE_THRESHOLD),
+                )?,
+                false => vec![],
+            };
+            for result in vector_search_results
+                .into_iter()
+                .chain(image_search_results)
+            {
                 if result.resource.resource_type.ends_with(".ignore") {
                     continue;
                 }
@@ -528,15 +571,9 @@ impl Worker {
             ));
         }
 
-        let mut chunks: Vec<String> = vec![];
-        let mut metadatas: Vec<ResourceTextContentMetadata> = vec![];
-
-        for (c, m) in content.iter().zip(metadata.iter()) {
-            let embedding_chunks = self.ai.chunker.chunk(c);
-            // same metadata for each chunk
-            metadatas.extend(std::iter::repeat_n(m.clone(), embedding_chunks.len()));
-            chunks.extend(embedding_chunks);
-        }
+        let (chunks, metadatas) = self
+            .ai
+            .chunk_text_content(&content_type, &content, &metadata);
         let old_keys = self
             .db
             .list_embedding_ids_by_type_resource_id(EmbeddingType::TextContent, &resource_id)?;
@@ -619,10 +656,17 @@ impl Worker {
         // commit transaction already to not hold the table lock
         tx.commit()?;
 
-        match self
-            .ai
-            .upsert_embeddings(old_keys.clone(), new_row_ids.clone(), chunks)
-        {
+        // for image embeddings the chunks are the paths of the image files
+        let upsert_result = match embedding_type {
+            EmbeddingType::Image => {
+                self.ai
+                    .upsert_image_embeddings(old_keys.clone(), new_row_ids.clone(), chunks)
+            }
+            _ => self
+                .ai
+                .upsert_embeddings(old_keys.clone(), new_row_ids.clone(), chunks),
+        };
+        match upsert_result {
             Ok(_) => {}
             Err(e) => {
                 let mut errors = Vec::new();
@@ -666,6 +710,53 @@ impl Worker {
         Ok(())
     }
 
+    /// Embeds the image file of the resource into the image index, replacing any
+    /// previous image embedding of it.
+    #[instrument(level = "trace", skip(self))]
+    pub fn upsert_image_embedding(&mut self, resource_id: String) -> BackendResult<()> {
+        let resource = self.db.get_resource(&resource_id)?.ok_or_else(|| {
+            BackendError::GenericError(format!("resource not found: {}", resource_id))
+        })?;
+        let content_id = self.db.get_resource_rowid(&resource_id)?.ok_or_else(|| {
+            BackendError::GenericError(format!("resource not found: {}", resource_id))
+        })?;
+        let old_keys = self
+            .db
+            .list_embedding_ids_by_type_resource_id(EmbeddingType::Image, &resource_id)?;
+
+        self.upsert_embeddings(
+            resource_id,
+            EmbeddingType::Image,
+            old_keys,
+            vec![content_id],
+            vec![resource.resource_path],
+        )
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub fn search_similar_images(
+        &mut self,
+        resource_id: String,
+        limit: usize,
+    ) -> BackendResult<Vec<CompositeResource>> {
+        let resource = self.db.get_resource(&resource_id)?.ok_or_else(|| {
+            BackendError::GenericError(format!("resource not found: {}", resource_id))
+        })?;
+
+        // the resource itself is always the closest match
+        let mut results = self.ai.image_search(
+            &self.db,
+            None,
+            Some(resource.resource_path),
+            limit + 1,
+            None,
+            Some(IMAGE_SEARCH_DISTANCE_THRESHOLD),
+        )?;
+        results.retain(|result| result.resource.id != resource_id);
+        results.truncate(limit);
+        Ok(results)
+    }
+
     #[instrument(level = "trace", skip(self))]
     pub fn upsert_resource_hash(&mut self, resource_id: String, hash: String) -> BackendResult<()> {
         let mut tx = self.db.begin()?;
@@ -795,6 +886,14 @@ pub fn handle_resource_message(
             );
             send_worker_response(&mut worker.channel, oneshot, result);
         }
+        ResourceMessage::UpsertImageEmbedding(resource_id) => {
+            let result = worker.upsert_image_embedding(resource_id);
+            send_worker_response(&mut worker.channel, oneshot, result);
+        }
+        ResourceMessage::SearchSimilarImages { resource_id, limit } => {
+            let result = worker.search_similar_images(resource_id, limit);
+            send_worker_response(&mut worker.channel, oneshot, result);
+        }
         ResourceMessage::UpsertResourceHash { resource_id, hash } => {
             let result = worker.upsert_resource_hash(resource_id, hash);
             send_worker_response(&mut worker.channel, oneshot, result);
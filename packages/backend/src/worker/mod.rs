@@ -5,15 +5,25 @@ pub mod tunnel;
 const _MODULE_PREFIX: &str = "backend";
 
 use crate::{
-    ai::AI,
+    ai::{
+        llm::{
+            client::cache::{ResponseCache, ResponseCacheConfig},
+            usage::usage_channel,
+        },
+        AI,
+    },
     api::message::{
         AIMessage, EventBusMessage, ProcessorMessage, TunnelMessage, TunnelOneshot, WorkerMessage,
     },
-    store::{db::Database, kv::KeyValueStore, models::current_time},
+    store::{
+        db::Database,
+        kv::KeyValueStore,
+        models::{current_time, LLMUsage},
+    },
     BackendError, BackendResult,
 };
 use handlers::*;
-use tunnel::SurfBackendHealth;
+use tunnel::{RequestCancellations, SurfBackendHealth};
 
 use chrono::{DateTime, Utc};
 use crossbeam_channel as crossbeam;
@@ -58,6 +68,14 @@ impl PathConfig {
             .to_string()
     }
 
+    pub fn llm_cache_db_path(&self) -> String {
+        Path::new(&self.backend_root_path)
+            .join("llm-cache-0-01.sqlite")
+            .as_os_str()
+            .to_string_lossy()
+            .to_string()
+    }
+
     pub fn local_ai_socket_path(&self) -> String {
         Path::new(&self.backend_root_path)
             .join("sffs-ai.sock")
@@ -91,6 +109,7 @@ pub struct WorkerConfig {
     pub language_setting: String,
     pub run_migrations: bool,
     pub surf_backend_health: SurfBackendHealth,
+    pub request_cancellations: RequestCancellations,
 }
 
 pub struct Worker {
@@ -104,10 +123,15 @@ pub struct Worker {
     pub app_path: String,
     pub backend_root_path: String,
     pub resources_path: String,
+    // for connections of tasks that outlive a worker message
+    pub db_path: String,
     pub language_setting: String,
     pub async_runtime: tokio::runtime::Runtime,
     pub surf_backend_health: SurfBackendHealth,
+    pub request_cancellations: RequestCancellations,
     pub created_at: DateTime<Utc>,
+    // usage of completions made by `ai`, written to the db after every message
+    pub llm_usage_rx: crossbeam::Receiver<LLMUsage>,
 }
 
 impl Worker {
@@ -116,11 +140,19 @@ impl Worker {
         let kv_db_path = config.path_config.kv_db_path();
         let resources_path = config.path_config.resources_path();
         let local_ai_socket_path = config.path_config.local_ai_socket_path();
+        let (usage_recorder, llm_usage_rx) = usage_channel();
+        // the cache only saves repeated requests, go without it if it can't be opened
+        let response_cache = ResponseCache::new(
+            &config.path_config.llm_cache_db_path(),
+            ResponseCacheConfig::default(),
+        )
+        .map_err(|e| tracing::error!("failed to open the llm response cache: {}", e))
+        .ok();
 
-        Ok(Self {
+        let mut worker = Self {
             db: Database::new(&db_path, config.run_migrations)?,
             kv: KeyValueStore::new(&kv_db_path)?,
-            ai: AI::new(local_ai_socket_path)?,
+            ai: AI::new(local_ai_socket_path, usage_recorder, response_cache)?,
             channel: config.channel_config.channel,
             event_bus_rx: config.channel_config.event_bus_rx,
             tqueue_tx: config.channel_config.tqueue_tx,
@@ -128,34 +160,52 @@ impl Worker {
             app_path: config.path_config.app_path.clone(),
             backend_root_path: config.path_config.backend_root_path.clone(),
             resources_path,
+            db_path: db_path.clone(),
             language_setting: config.language_setting,
             async_runtime: tokio::runtime::Runtime::new()?,
             surf_backend_health: config.surf_backend_health,
+            request_cancellations: config.request_cancellations,
             created_at: current_time(),
-        })
+            llm_usage_rx,
+        };
+        // the embedded catalog is still used if the stored entries can't be read
+        if let Err(e) = worker.reload_model_catalog() {
+            tracing::error!("failed to load the model catalog: {}", e);
+        }
+        Ok(worker)
     }
 
     pub fn send_event_bus_message(&mut self, message: EventBusMessage) {
-        let message = match serde_json::to_string(&message) {
-            Ok(result) => result,
-            Err(e) => {
-                tracing::debug!("serde to json string failed: {e:?}");
-                return;
-            }
-        };
-        let event_bus_rx = self.event_bus_rx.clone();
+        send_event_bus_message(&self.channel, &self.event_bus_rx, message)
+    }
+}
 
-        self.channel.send(move |mut cx| {
-            let this = cx.undefined();
-            let event_bus_rx = event_bus_rx.to_inner(&mut cx);
-            let string = cx.string(message).as_value(&mut cx);
-            if let Err(e) = event_bus_rx.call(&mut cx, this, [string]) {
-                tracing::debug!("event bus callback failed: {e:?}");
-            }
+/// Passes `message` to the event bus callback of the JS side, also from tasks
+/// that run on the async runtime after the worker moved on.
+pub fn send_event_bus_message(
+    channel: &Channel,
+    event_bus_rx: &Arc<Root<JsFunction>>,
+    message: EventBusMessage,
+) {
+    let message = match serde_json::to_string(&message) {
+        Ok(result) => result,
+        Err(e) => {
+            tracing::debug!("serde to json string failed: {e:?}");
+            return;
+        }
+    };
+    let event_bus_rx = event_bus_rx.clone();
 
-            Ok(())
-        });
-    }
+    channel.send(move |mut cx| {
+        let this = cx.undefined();
+        let event_bus_rx = event_bus_rx.to_inner(&mut cx);
+        let string = cx.string(message).as_value(&mut cx);
+        if let Err(e) = event_bus_rx.call(&mut cx, this, [string]) {
+            tracing::debug!("event bus callback failed: {e:?}");
+        }
+
+        Ok(())
+    });
 }
 
 pub fn worker_thread_entry_point(
@@ -186,6 +236,7 @@ pub fn worker_thread_entry_point(
             }
             WorkerMessage::AppMessage(message) => handle_app_message(&mut worker, oneshot, message),
         }
+        worker.flush_llm_usage();
     }
 }
 
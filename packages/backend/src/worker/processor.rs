@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use super::tunnel::WorkerTunnel;
 use crate::{
-    ai::embeddings::chunking::ContentChunker,
+    ai::embeddings::chunking::{ChunkingStrategy, TranscriptChunker, TranscriptPiece},
     api::message::*,
     store::models::{
         CompositeResource, ResourceProcessingState, ResourceTextContentMetadata,
@@ -193,6 +193,22 @@ impl Processor {
             }
         }
 
+        // the image embedding is best effort, the OCR text is still useful without it
+        if resource.resource.resource_type.starts_with("image/") {
+            let (tx, rx) = crossbeam_channel::bounded(1);
+            self.tunnel.worker_send_rust(
+                WorkerMessage::ResourceMessage(ResourceMessage::UpsertImageEmbedding(
+                    resource.resource.id.clone(),
+                )),
+                Some(tx),
+            );
+            match rx.recv() {
+                Ok(Err(e)) => tracing::error!("failed to upsert image embedding: {e}"),
+                Err(_) => tracing::error!("failed to receive oneshot response"),
+                Ok(Ok(_)) => {}
+            }
+        }
+
         tracing::debug!("content types to be batch upserted: {}", result.len());
         for (content_type, (content, metadata)) in result {
             if !content.is_empty() {
@@ -262,9 +278,8 @@ fn create_ocr_engine(app_path: &str) -> Result<OcrEngine, Box<dyn std::error::Er
 
 fn create_metadata_from_resource(resource: &CompositeResource) -> ResourceTextContentMetadata {
     ResourceTextContentMetadata {
-        timestamp: None,
-        page: None,
         url: resource.metadata.as_ref().map(|m| m.source_uri.clone()),
+        ..Default::default()
     }
 }
 
@@ -317,26 +332,30 @@ pub fn get_youtube_contents_metadatas(
     language: Option<String>,
 ) -> BackendResult<(Vec<String>, Vec<ResourceTextContentMetadata>)> {
     let transcript = crate::ai::youtube::fetch_transcript(source_uri, language.as_deref())?;
-    let mut contents: Vec<String> = vec![];
-    let mut metadatas: Vec<ResourceTextContentMetadata> = vec![];
-    let mut prev_offset = 0.0;
-    let mut transcript_chunk = String::new();
-    // min 20 second chunks
-    for (i, piece) in transcript.metadata.transcript_pieces.iter().enumerate() {
-        transcript_chunk.push_str(&format!(" {}", piece.text));
-        if piece.start - prev_offset > 20.0 || i == transcript.metadata.transcript_pieces.len() - 1
-        {
-            contents.push(ContentChunker::normalize(&transcript_chunk));
-            metadatas.push(ResourceTextContentMetadata {
-                timestamp: Some(prev_offset as f32),
-                url: Some(source_uri.to_string()),
-                page: None,
-            });
-            prev_offset = piece.start;
-            transcript_chunk = String::new();
-        }
-    }
-    Ok((contents, metadatas))
+    let pieces: Vec<TranscriptPiece> = transcript
+        .metadata
+        .transcript_pieces
+        .into_iter()
+        .map(|piece| TranscriptPiece {
+            text: piece.text,
+            start: piece.start as f32,
+            duration: piece.duration as f32,
+        })
+        .collect();
+
+    // 20 second windows
+    let base_metadata = ResourceTextContentMetadata {
+        url: Some(source_uri.to_string()),
+        ..Default::default()
+    };
+    Ok(TranscriptChunker::new(20.0, crate::ai::CHUNK_MAX_SIZE)
+        .chunk(&pieces)
+        .into_iter()
+        .map(|chunk| {
+            let metadata = base_metadata.with_chunk_metadata(&chunk.metadata);
+            (chunk.content, metadata)
+        })
+        .unzip())
 }
 
 fn process_resource_data(
@@ -3,6 +3,7 @@ use super::{
     PathConfig, WorkerConfig,
 };
 use crate::{
+    ai::llm::client::CancellationToken,
     api::message::{
         AIMessage, ProcessorMessage, ResourceMessage, TunnelMessage, TunnelOneshot, WorkerMessage,
     },
@@ -14,6 +15,7 @@ use neon::{
     prelude::Context,
     types::{Deferred, Finalize, JsFunction},
 };
+use std::collections::HashMap;
 use std::panic;
 
 const NUM_WORKER_THREADS: usize = 12;
@@ -27,6 +29,7 @@ pub struct WorkerTunnel {
     pub aiqueue_rx: crossbeam::Receiver<AIMessage>,
     pub event_bus_rx_callback: Arc<Root<JsFunction>>,
     pub surf_backend_health: SurfBackendHealth,
+    pub request_cancellations: RequestCancellations,
 }
 
 pub struct SurfBackendHealth(Arc<(Mutex<bool>, Condvar)>);
@@ -68,6 +71,58 @@ impl Clone for SurfBackendHealth {
     }
 }
 
+// the worker streaming a response is blocked until it is done, so the cancel
+// message is handled by another worker thread and the tokens are shared
+#[derive(Clone, Default)]
+pub struct RequestCancellations(Arc<Mutex<HashMap<String, CancellationToken>>>);
+
+impl RequestCancellations {
+    /// Token of a new request that `cancel` with the same id cancels until the
+    /// returned registration is dropped. Requests without an id can't be cancelled.
+    pub fn register(&self, request_id: Option<String>) -> RegisteredRequest {
+        let token = CancellationToken::new();
+        if let Some(ref id) = request_id {
+            self.0.lock().unwrap().insert(id.clone(), token.clone());
+        }
+        RegisteredRequest {
+            cancellations: self.clone(),
+            request_id,
+            token,
+        }
+    }
+
+    /// Returns false if no request with the id is running.
+    pub fn cancel(&self, request_id: &str) -> bool {
+        match self.0.lock().unwrap().remove(request_id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct RegisteredRequest {
+    cancellations: RequestCancellations,
+    request_id: Option<String>,
+    token: CancellationToken,
+}
+
+impl RegisteredRequest {
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+}
+
+impl Drop for RegisteredRequest {
+    fn drop(&mut self) {
+        if let Some(ref id) = self.request_id {
+            self.cancellations.0.lock().unwrap().remove(id);
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct TunnelConfig {
     pub backend_root_path: String,
@@ -93,6 +148,7 @@ impl WorkerTunnel {
         let (tqueue_tx, tqueue_rx) = crossbeam::unbounded();
         let (aiqueue_tx, aiqueue_rx) = crossbeam::unbounded();
         let surf_backend_health = SurfBackendHealth::new(Some(false));
+        let request_cancellations = RequestCancellations::default();
         let event_bus_rx_callback = Arc::new(event_bus_rx_callback);
         let tunnel = Self {
             worker_tx,
@@ -100,6 +156,7 @@ impl WorkerTunnel {
             aiqueue_rx,
             event_bus_rx_callback: event_bus_rx_callback.clone(),
             surf_backend_health: surf_backend_health.clone(),
+            request_cancellations,
         };
 
         Self::spawn_threads(cx, config, worker_rx, tqueue_tx, aiqueue_tx, &tunnel);
@@ -126,10 +183,12 @@ impl WorkerTunnel {
             aiqueue_tx,
             Arc::clone(&tunnel.event_bus_rx_callback),
             tunnel.surf_backend_health.clone(),
+            tunnel.request_cancellations.clone(),
         );
         Self::spawn_processor_threads(tunnel, &config);
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn spawn_worker_threads<'a, C>(
         cx: &mut C,
         config: &TunnelConfig,
@@ -138,6 +197,7 @@ impl WorkerTunnel {
         aiqueue_tx: crossbeam::Sender<AIMessage>,
         event_bus_rx_callback: Arc<Root<JsFunction>>,
         surf_backend_health: SurfBackendHealth,
+        request_cancellations: RequestCancellations,
     ) where
         C: Context<'a>,
     {
@@ -151,6 +211,7 @@ impl WorkerTunnel {
             let aiqueue_tx = aiqueue_tx.clone();
             let callback = Arc::clone(&event_bus_rx_callback);
             let surf_backend_health = surf_backend_health.clone();
+            let request_cancellations = request_cancellations.clone();
             let libuv_ch = libuv_ch.clone();
             let thread_name = format!("W{n}");
 
@@ -189,6 +250,7 @@ impl WorkerTunnel {
                         language_setting: language_setting.clone(),
                         run_migrations: _run_migrations,
                         surf_backend_health: surf_backend_health.clone(),
+                        request_cancellations: request_cancellations.clone(),
                     };
 
                     worker_thread_entry_point(worker_rx.clone(), worker_config)
@@ -1,4 +1,11 @@
-export type Provider = 'open-ai' | 'anthropic' | { custom: string }
+export type Provider =
+  | 'open-ai'
+  | 'anthropic'
+  | 'openrouter'
+  | 'mistral'
+  | { custom: string }
+  | { ollama: string }
+  | { azure: { endpoint: string; deployment: string; api_version?: string } }
 
 export type Model =
   | 'gpt-4o'
@@ -28,6 +35,12 @@ export type Message = {
   content: MessageContent[]
 }
 
+// a model answering in place of the query's model while the ones before it keep failing
+export interface FallbackModel {
+  model: Model
+  custom_key?: string
+}
+
 export interface CreateChatCompletionOptions {
   messages: Message[]
   model: Model
@@ -38,14 +51,39 @@ export interface CreateChatCompletionOptions {
 export interface ChatMessageOptions {
   query: string
   chat_id: string
-  model: Model
+  // the model, sources and limits fall back to the chat's config
+  model?: Model
   custom_key?: string
   limit?: number
+  similarity_threshold?: number
   rag_only?: boolean
   resource_ids?: string[]
+  space_ids?: string[]
   inline_images?: string[]
   general?: boolean
   app_creation?: boolean
+  branch_from?: string
+  // add the answer to the vector index so chat history can be searched by meaning
+  embed_answer?: boolean
+  // title an untitled chat after the answer, sent as an AIChatTitleMessage on the event bus
+  generate_title?: boolean
+  // rewrite follow-up questions with the chat history and search for paraphrases and keywords too
+  plan_retrieval?: boolean
+  // id to stop the answer with through js__ai_cancel_request
+  request_id?: string
+  // answer in order with their keys while the model keeps failing
+  fallbacks?: FallbackModel[]
+  max_retries?: number
+}
+
+// defaults for the queries of a chat, used where a query leaves them unset
+export interface ChatConfig {
+  model?: Model | null
+  // pinned sources
+  resource_ids?: string[]
+  space_ids?: string[]
+  number_documents?: number | null
+  similarity_threshold?: number | null
 }
 
 export interface NoteMessageOptions {
@@ -59,6 +97,9 @@ export interface NoteMessageOptions {
   general?: boolean
   websearch?: boolean
   surflet?: boolean
+  request_id?: string
+  fallbacks?: FallbackModel[]
+  max_retries?: number
 }
 
 export interface QueryResourcesOptions {
@@ -75,6 +116,7 @@ export interface CreateAppOptions {
   model: Model
   custom_key?: string
   inline_images?: string[]
+  request_id?: string
 }
 
 export class TooManyRequestsError extends Error {
@@ -3,7 +3,12 @@ import type { ConfigService } from '../config'
 import { ResourceManager } from '../resources'
 import type { SFFS } from '../sffs'
 
-import { type App, type Message, type Model as ModelBackend } from '@deta/backend/types'
+import {
+  type App,
+  type FallbackModel,
+  type Message,
+  type Model as ModelBackend
+} from '@deta/backend/types'
 import { derived, get, writable, type Readable, type Writable } from 'svelte/store'
 import { appendURLPath, generateHash, isDev, useLocalStorageStore, useLogScope } from '@deta/utils'
 import {
@@ -23,8 +28,10 @@ import { useTabs, type TabsService } from '../tabs'
 import { AIChat, type ChatCompletionResponse, type ChatPrompt } from './chat'
 import { type ContextItem, ContextManager, ContextService } from './contextManager'
 import {
+  EventBusMessageType,
   EventContext,
   GeneratePromptsEventTrigger,
+  type EventBusMessage,
   PromptType,
   SummarizeEventContentSource
 } from '@deta/types'
@@ -63,6 +70,8 @@ export class AIService {
 
   customAIApps: Writable<App[]> = writable([])
 
+  private unregisterEventBusHandler: () => void
+
   private activeAppStreams = new Map<
     string,
     {
@@ -140,6 +149,10 @@ export class AIService {
 
     this.refreshCustomAiApps()
 
+    this.unregisterEventBusHandler = this.sffs.registerEventBustHandler((event) =>
+      this.handleEventBusMessage(event)
+    )
+
     if (isDev) {
       // @ts-ignore
       window.aiService = this
@@ -178,6 +191,34 @@ export class AIService {
     return get(this.activeSidebarChatId)
   }
 
+  private handleEventBusMessage(event: EventBusMessage) {
+    if (event.type === EventBusMessageType.AIChatTitleMessage) {
+      this.handleChatTitleMessage(event.session_id, event.title, event.tags)
+    }
+  }
+
+  // titles the backend generated after the first answer of chats created with
+  // `automaticTitleGeneration`, already stored
+  private handleChatTitleMessage(id: string, title: string, tags: string[]) {
+    this.log.debug('handling chat title message', id, title, tags)
+
+    const chats = [...this.chatsValue, this.activeSidebarChatValue].filter(
+      (chat): chat is AIChat => chat?.id === id
+    )
+    if (chats.length === 0) {
+      this.log.debug('chat not loaded, ignoring title', id)
+      return
+    }
+
+    chats.forEach((chat) => {
+      chat.title.set(title)
+      chat.tags.set(tags)
+    })
+
+    // force svelte reactivity to update
+    this.chats.update((chats) => [...chats])
+  }
+
   addMissingChats(chats: AIChat[]) {
     const missingChats = chats.filter((chat) => !this.chatsValue.find((c) => c.id === chat.id))
     this.chats.update((existingChats) => [...existingChats, ...missingChats])
@@ -206,6 +247,23 @@ export class AIService {
     return model.id as ModelBackend
   }
 
+  /** The user's fallback models for `model` in order as the backend knows them, with their keys. */
+  getFallbackModels(model: Model): FallbackModel[] {
+    const fallbackIds = this.config.settingsValue.fallback_models ?? []
+
+    return fallbackIds
+      .filter((fallbackId) => fallbackId !== model.id)
+      .flatMap((fallbackId) => {
+        const fallback = this.modelsValue.find((m) => m.id === fallbackId)
+        if (!fallback) {
+          this.log.warn('fallback model not found', fallbackId)
+          return []
+        }
+
+        return [{ model: this.modelToBackendModel(fallback), custom_key: fallback.custom_key }]
+      })
+  }
+
   changeSelectedModel(modelId: string) {
     const model = this.modelsValue.find((m) => m.id === modelId)
     if (!model) {
@@ -282,6 +340,7 @@ export class AIService {
     const createdChat = {
       id: chatId,
       title: title ?? '',
+      tags: [],
       messages: [],
       createdAt: new Date().toISOString(),
       updatedAt: new Date().toISOString()
@@ -673,7 +732,7 @@ export class AIService {
             this.activeAppStreams.delete(appId)
           }
         },
-        options
+        { ...options, requestId: appId }
       )
     } catch (error) {
       this.log.error('Error streaming app:', error)
@@ -711,6 +770,12 @@ export class AIService {
   }
 
   cleanupAppStream(appId: string): void {
+    const streamData = this.activeAppStreams.get(appId)
+    if (streamData && !streamData.isComplete) {
+      this.sffs.cancelAIRequest(appId).catch((err) => {
+        this.log.error('Failed to cancel app stream', appId, err)
+      })
+    }
     this.activeAppStreams.delete(appId)
   }
 
@@ -779,6 +844,7 @@ export class AIService {
   }
 
   onDestroy() {
+    this.unregisterEventBusHandler()
     this.fallbackContextManager?.onDestroy()
   }
 
@@ -15,7 +15,7 @@ import {
   type AIChatMessageSource
 } from '@deta/types'
 import { ChatMode, ModelTiers, Provider, type Model } from '@deta/types/src/ai.types'
-import { parseAIError, parseChatResponseSources } from './helpers'
+import { parseAIError, parseChatResponseDropped, parseChatResponseSources } from './helpers'
 import {
   PageChatMessageSentEventError,
   PageChatMessageSentEventTrigger,
@@ -73,6 +73,7 @@ export class AIChat {
   automaticTitleGeneration: boolean
 
   title: Writable<string>
+  tags: Writable<string[]>
   messages: Writable<AIChatMessage[]>
   currentParsedMessages: Writable<AIChatMessageParsed[]>
   error: Writable<ChatError | null>
@@ -108,6 +109,7 @@ export class AIChat {
     this.updatedAt = data.updatedAt
     this.automaticTitleGeneration = automaticTitleGeneration
     this.title = writable(data.title)
+    this.tags = writable(data.tags ?? [])
     this.messages = writable(data.messages)
     this.currentParsedMessages = writable([])
     this.error = writable(null)
@@ -334,6 +336,7 @@ export class AIChat {
       // Stop specific generation
       this.log.debug('Stopping generation with id', id)
       this.activeGenerations.set(id, false)
+      this.cancelBackendGeneration(id)
 
       // Resolve the promise for this specific generation if it exists
       const resolver = this.generationPromiseResolvers.get(id)
@@ -346,6 +349,7 @@ export class AIChat {
       // Stop all active generations
       for (const [genId, _] of this.activeGenerations) {
         this.activeGenerations.set(genId, false)
+        this.cancelBackendGeneration(genId)
 
         // Resolve all pending promises
         const resolver = this.generationPromiseResolvers.get(genId)
@@ -358,6 +362,12 @@ export class AIChat {
     }
   }
 
+  private cancelBackendGeneration(id: string) {
+    this.sffs.cancelAIRequest(id).catch((err) => {
+      this.log.error('Failed to cancel generation', id, err)
+    })
+  }
+
   async sendMessage(
     callback: (chunk: string) => void,
     query: string,
@@ -374,12 +384,16 @@ export class AIChat {
       surflet?: boolean
       appCreation?: boolean
       noteResourceId?: string
+      // lets stopGeneration stop the backend from streaming the answer
+      generationID?: string
     }
   ) {
     const model = opts?.model ?? this.getModel(opts)
 
     const backendModel = this.ai.modelToBackendModel(model)
     const customKey = model.custom_key
+    const fallbacks = this.ai.getFallbackModels(model)
+    const maxRetries = this.ai.config.settingsValue.llm_max_retries
 
     this.log.debug('sending chat message to chat with id', this.id, model, opts, query)
 
@@ -391,9 +405,16 @@ export class AIChat {
         inlineImages: opts?.inlineImages,
         general: opts?.general,
         websearch: opts?.websearch,
-        surflet: opts?.surflet
+        surflet: opts?.surflet,
+        requestId: opts?.generationID,
+        fallbacks,
+        maxRetries
       })
     } else {
+      // the backend titles the chat after the answer and sends the title over
+      // the event bus, see `AIService.handleChatTitleMessage`
+      const generateTitle = this.automaticTitleGeneration && !this.titleValue
+
       await this.sffs.sendAIChatMessage(callback, this.id, query, backendModel, {
         customKey: customKey,
         limit: opts?.limit,
@@ -401,7 +422,11 @@ export class AIChat {
         resourceIds: opts?.resourceIds,
         inlineImages: opts?.inlineImages,
         general: opts?.general,
-        appCreation: opts?.appCreation
+        appCreation: opts?.appCreation,
+        requestId: opts?.generationID,
+        generateTitle,
+        fallbacks,
+        maxRetries
       })
     }
 
@@ -722,7 +747,8 @@ export class AIChat {
 
       await tick()
 
-      if (!this.titleValue && this.automaticTitleGeneration) {
+      // chat answers are titled by the backend, see `sendMessage`
+      if (!this.titleValue && this.automaticTitleGeneration && options.noteResourceId) {
         // we don't need to wait for the title to be generated
         this.generateTitle(prompt).then(() => {
           this.log.debug('Title generated')
@@ -831,13 +857,15 @@ export class AIChat {
 
           if (content.includes('</sources>')) {
             const sources = parseChatResponseSources(content)
-            this.log.debug('Sources', sources)
+            const dropped = parseChatResponseDropped(content)
+            this.log.debug('Sources', sources, 'dropped', dropped)
 
             step = 'sources'
             content = ''
 
             this.updateParsedResponse(response?.id ?? '', {
-              sources
+              sources,
+              dropped
             })
           }
         } else {
@@ -868,7 +896,8 @@ export class AIChat {
         appCreation: chatMode === ChatMode.AppCreation,
         noteResourceId: options.noteResourceId,
         websearch: options.websearch,
-        surflet: options.surflet
+        surflet: options.surflet,
+        generationID: options.generationID
       })
 
       // Wait for either the generation to complete or be stopped
@@ -4,6 +4,7 @@ import {
   PromptType,
   type ChatMessageContentItem,
   type AIChatMessageSource,
+  type AIChatDroppedContext,
   type AIChatMessageParsed,
   type DetectedResource,
   type WebViewEventSendNames,
@@ -94,6 +95,18 @@ export const parseXMLChatResponseSources = (xml: Document) => {
   return sourceData
 }
 
+export const parseXMLChatResponseDropped = (xml: Document) => {
+  const dropped = xml.getElementsByTagName('dropped')
+
+  return Array.from(dropped).map((context) => {
+    return {
+      uid: context.getElementsByTagName('uid')[0]?.textContent ?? '',
+      resource_id: context.getElementsByTagName('resource_id')[0]?.textContent ?? '',
+      reason: context.getElementsByTagName('reason')[0]?.textContent ?? ''
+    } as AIChatDroppedContext
+  })
+}
+
 export const parseXMLChatResponseAnswer = (xml: Document) => {
   const answer = xml.getElementsByTagName('answer')[0] ?? xml
 
@@ -198,6 +211,23 @@ export const parseChatResponseSources = (response: string) => {
   return parseXMLChatResponseSources(xml)
 }
 
+export const parseChatResponseDropped = (response: string) => {
+  const xml = parseXML(response)
+  return parseXMLChatResponseDropped(xml)
+}
+
+/** notice for the search results left out of an answer, null if nothing was left out */
+export const describeDroppedContext = (dropped?: AIChatDroppedContext[]) => {
+  if (!dropped || dropped.length === 0) {
+    return null
+  }
+
+  const resources = new Set(dropped.map((context) => context.resource_id)).size
+  const results = dropped.length === 1 ? '1 search result' : `${dropped.length} search results`
+  const from = resources === 1 ? '1 source' : `${resources} sources`
+  return `${results} from ${from} didn't fit into the context and weren't used for this answer.`
+}
+
 export const handleInlineAI = async (
   ai: AIService,
   data: WebViewSendEvents[WebViewEventSendNames.Transform],
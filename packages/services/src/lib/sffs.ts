@@ -31,22 +31,35 @@ import type {
   AIChatRaw,
   SpaceEntrySearchOptions,
   SFFSRawBookmarkFolder,
+  AIChatBranch,
   AIChatData,
+  AIChatExportFormat,
+  AIChatSearchResult,
+  AIChatSearchResultRaw,
   AIChatMessage,
   AIChatMessageSource,
   AIDocsSimilarity,
+  LLMCacheStats,
+  LLMModelPrice,
+  LLMUsagePeriod,
+  LLMUsageTotal,
+  ModelCatalogEntry,
+  ModelCatalogOverride,
   YoutubeTranscript
 } from '@deta/types'
 
 import type {
   App,
   Model,
+  ChatConfig,
   ChatMessageOptions,
   NoteMessageOptions,
   CreateAppOptions,
   QueryResourcesOptions,
   Message,
-  CreateChatCompletionOptions
+  CreateChatCompletionOptions,
+  FallbackModel,
+  Provider as BackendProvider
 } from '@deta/backend/types'
 import {
   APIKeyMissingError,
@@ -255,7 +268,7 @@ export class SFFS {
   convertRawChatMessageToChatMessage(raw: AIChatMessageRaw, idx?: number): AIChatMessage {
     return {
       ...raw,
-      id: `${raw.ai_session_id}-${raw.role}-${idx}` // TODO: generate ids in the backend
+      id: raw.id ?? `${raw.ai_session_id}-${raw.role}-${idx}`
     }
   }
 
@@ -263,11 +276,14 @@ export class SFFS {
     return {
       id: raw.id,
       title: raw.title,
+      tags: raw.tags ?? [],
       messages: (raw.messages ?? []).map((message, idx) =>
         this.convertRawChatMessageToChatMessage(message, idx)
       ),
       updatedAt: raw.updated_at,
-      createdAt: raw.created_at
+      createdAt: raw.created_at,
+      summary: raw.summary,
+      activeMessageId: raw.active_message_id
     }
   }
 
@@ -517,7 +533,9 @@ export class SFFS {
       parameters?.semanticLimit,
       parameters?.includeAnnotations,
       parameters?.spaceId,
-      parameters?.keywordLimit
+      parameters?.keywordLimit,
+      parameters?.semanticRerank,
+      parameters?.semanticImages
     )
     const parsed = this.parseData<SFFSSearchResult>(raw)
     const parsedItems = parsed?.items ?? []
@@ -540,6 +558,13 @@ export class SFFS {
     }
   }
 
+  async searchSimilarImages(resourceId: string, limit?: number): Promise<SFFSResource[]> {
+    this.log.debug('searching images similar to resource', resourceId, 'limit:', limit)
+    const raw = await this.backend.js__store_search_similar_images(resourceId, limit)
+    const composites = this.parseData<SFFSRawCompositeResource[]>(raw) ?? []
+    return composites.map(this.convertCompositeResourceToResource)
+  }
+
   async searchChatResourcesAI(
     query: string,
     model: Model,
@@ -882,6 +907,46 @@ export class SFFS {
     await this.backend.js__store_update_ai_chat(id, title)
   }
 
+  async updateAIChatSummary(id: string, summary: string): Promise<void> {
+    this.log.debug('updating ai chat summary with id', id)
+    await this.backend.js__store_update_ai_chat_summary(id, summary)
+  }
+
+  async getAIChatConfig(
+    id: string
+  ): Promise<{ systemPrompt: string; config: ChatConfig } | null> {
+    this.log.debug('getting config of ai chat with id', id)
+    const raw = await this.backend.js__store_get_ai_chat(id)
+    const chat = this.parseData<{ system_prompt: string; config: ChatConfig }>(raw)
+    if (!chat) {
+      return null
+    }
+
+    return { systemPrompt: chat.system_prompt, config: chat.config }
+  }
+
+  async updateAIChatConfig(id: string, systemPrompt: string, config: ChatConfig): Promise<void> {
+    this.log.debug('updating config of ai chat with id', id, config)
+    await this.backend.js__store_update_ai_chat_config(id, systemPrompt, JSON.stringify(config))
+  }
+
+  async listAIChatBranches(id: string): Promise<AIChatBranch[]> {
+    this.log.debug('listing branches of ai chat with id', id)
+    const raw = await this.backend.js__store_list_ai_chat_branches(id)
+    return this.parseData<AIChatBranch[]>(raw) ?? []
+  }
+
+  async switchAIChatBranch(id: string, messageId: string): Promise<AIChatData | null> {
+    this.log.debug('switching ai chat', id, 'to the branch of message', messageId)
+    const raw = await this.backend.js__store_switch_ai_chat_branch(id, messageId)
+    const chat = this.parseData<AIChatRaw>(raw)
+    if (!chat) {
+      return null
+    }
+
+    return this.convertRawChatToChat(chat)
+  }
+
   async listAIChats(limit?: number): Promise<AIChatData[]> {
     this.log.debug('listing ai chats', limit ?? 'all')
     const raw = await this.backend.js__store_list_ai_chats(limit)
@@ -904,6 +969,31 @@ export class SFFS {
     return chats.map((chat) => this.convertRawChatToChat(chat))
   }
 
+  async searchAIChatMessages(
+    query: string,
+    opts?: {
+      limit?: number
+      /** also match embedded answers by meaning */
+      semantic?: boolean
+    }
+  ): Promise<AIChatSearchResult[]> {
+    this.log.debug('searching ai chat messages with query', query, opts)
+    const raw = await this.backend.js__store_search_ai_chat_messages(
+      query,
+      opts?.limit,
+      opts?.semantic ?? false
+    )
+    const results = this.parseData<AIChatSearchResultRaw[]>(raw)
+    if (!results) {
+      return []
+    }
+
+    return results.map((result) => ({
+      chat: this.convertRawChatToChat(result.session),
+      hits: result.hits
+    }))
+  }
+
   async deleteAIChat(id: string): Promise<void> {
     this.log.debug('deleting ai chat with id', id)
     await this.backend.js__store_remove_ai_chat(id)
@@ -921,6 +1011,12 @@ export class SFFS {
     return this.convertRawChatToChat(chat)
   }
 
+  async exportAIChat(id: string, format: AIChatExportFormat): Promise<string | null> {
+    this.log.debug('exporting ai chat with id', id, 'as', format)
+    const raw = await this.backend.js__store_export_ai_chat(id, format)
+    return this.parseData<string>(raw)
+  }
+
   async getAIDocsSimilarity(
     query: string,
     docs: string[],
@@ -932,6 +1028,71 @@ export class SFFS {
     return this.parseData<AIDocsSimilarity[]>(raw)
   }
 
+  async getLLMUsage(
+    period: LLMUsagePeriod,
+    opts?: {
+      from?: Date
+      to?: Date
+      customKeyOnly?: boolean
+    }
+  ): Promise<LLMUsageTotal[]> {
+    this.log.debug('getting llm usage', period, opts)
+    const raw = await this.backend.js__ai_get_llm_usage(
+      JSON.stringify({
+        period,
+        from: opts?.from?.toISOString(),
+        to: opts?.to?.toISOString(),
+        custom_key_only: opts?.customKeyOnly ?? false
+      })
+    )
+
+    return this.parseData<LLMUsageTotal[]>(raw) ?? []
+  }
+
+  async setLLMModelPrice(model: string, price: LLMModelPrice): Promise<void> {
+    this.log.debug('setting llm model price', model, price)
+    await this.backend.js__ai_set_llm_model_price(model, JSON.stringify(price))
+  }
+
+  async getLLMCacheStats(): Promise<LLMCacheStats | null> {
+    const raw = await this.backend.js__ai_get_llm_cache_stats()
+    return this.parseData<LLMCacheStats>(raw)
+  }
+
+  async purgeLLMCache(expiredOnly = false): Promise<number> {
+    this.log.debug('purging llm response cache, expired only:', expiredOnly)
+    const raw = await this.backend.js__ai_purge_llm_cache(expiredOnly)
+    return this.parseData<number>(raw) ?? 0
+  }
+
+  async getModelCatalog(): Promise<ModelCatalogEntry[]> {
+    const raw = await this.backend.js__ai_get_model_catalog()
+    return this.parseData<ModelCatalogEntry[]>(raw) ?? []
+  }
+
+  async setModelCatalogOverride(model: ModelCatalogOverride): Promise<void> {
+    this.log.debug('setting model catalog override', model)
+    await this.backend.js__ai_set_model_catalog_override(JSON.stringify(model))
+  }
+
+  async removeModelCatalogOverride(id: string): Promise<void> {
+    this.log.debug('removing model catalog override', id)
+    await this.backend.js__ai_remove_model_catalog_override(id)
+  }
+
+  /** updates the catalog with the models the provider lists and returns their entries */
+  async refreshModelCatalog(
+    provider: BackendProvider,
+    customKey?: string
+  ): Promise<ModelCatalogEntry[]> {
+    this.log.debug('refreshing model catalog from', provider)
+    const raw = await this.backend.js__ai_refresh_model_catalog(
+      JSON.stringify(provider),
+      customKey
+    )
+    return this.parseData<ModelCatalogEntry[]>(raw) ?? []
+  }
+
   async getAIChatDataSource(hash: string): Promise<AIChatMessageSource | null> {
     const raw = await this.backend.js__ai_get_chat_data_source(hash)
     //
@@ -1056,13 +1217,15 @@ export class SFFS {
     opts?: {
       customKey?: string
       inlineImages?: string[]
+      requestId?: string
     }
   ): Promise<string | null> {
     const data: CreateAppOptions = {
       query,
       model,
       custom_key: opts?.customKey,
-      inline_images: opts?.inlineImages
+      inline_images: opts?.inlineImages,
+      request_id: opts?.requestId
     }
 
     const raw = await this.withErrorHandling(
@@ -1079,15 +1242,31 @@ export class SFFS {
     callback: (chunk: string) => void,
     chatId: string,
     query: string,
-    model: Model,
+    // falls back to the chat's model, like the sources and limits
+    model: Model | undefined,
     opts?: {
       customKey?: string
       limit?: number
+      similarityThreshold?: number
       ragOnly?: boolean
       resourceIds?: string[]
+      spaceIds?: string[]
       inlineImages?: string[]
       general?: boolean
       appCreation?: boolean
+      /** id of a message of an earlier turn to answer the query as a new version of */
+      branchFrom?: string
+      /** make the answer searchable by meaning in the chat history */
+      embedAnswer?: boolean
+      /** title an untitled chat after the answer, the title is sent over the event bus */
+      generateTitle?: boolean
+      /** resolve follow-up questions with the chat history and search for paraphrases and keywords */
+      planRetrieval?: boolean
+      /** id to stop the generation with, see `cancelAIRequest` */
+      requestId?: string
+      /** answer in order with their keys while the model keeps failing */
+      fallbacks?: FallbackModel[]
+      maxRetries?: number
     }
   ): Promise<void> {
     this.log.debug(
@@ -1114,11 +1293,20 @@ export class SFFS {
       model,
       custom_key: opts?.customKey,
       resource_ids: opts?.resourceIds,
+      space_ids: opts?.spaceIds,
       inline_images: opts?.inlineImages,
-      limit: opts?.limit ?? 20,
+      limit: opts?.limit,
+      similarity_threshold: opts?.similarityThreshold,
       rag_only: opts?.ragOnly,
       general: opts?.general,
-      app_creation: opts?.appCreation
+      app_creation: opts?.appCreation,
+      branch_from: opts?.branchFrom,
+      embed_answer: opts?.embedAnswer,
+      generate_title: opts?.generateTitle,
+      plan_retrieval: opts?.planRetrieval,
+      request_id: opts?.requestId,
+      fallbacks: opts?.fallbacks,
+      max_retries: opts?.maxRetries
     }
     return this.withErrorHandling(
       this.backend,
@@ -1141,6 +1329,9 @@ export class SFFS {
       general?: boolean
       websearch?: boolean
       surflet?: boolean
+      requestId?: string
+      fallbacks?: FallbackModel[]
+      maxRetries?: number
     }
   ): Promise<void> {
     this.log.debug(
@@ -1169,7 +1360,10 @@ export class SFFS {
       limit: opts?.limit ?? 20,
       general: opts?.general,
       websearch: opts?.websearch,
-      surflet: opts?.surflet
+      surflet: opts?.surflet,
+      request_id: opts?.requestId,
+      fallbacks: opts?.fallbacks,
+      max_retries: opts?.maxRetries
     }
     return this.withErrorHandling(
       this.backend,
@@ -1179,6 +1373,12 @@ export class SFFS {
     )
   }
 
+  /** Stops the streamed response of the chat, note or app request started with the id. */
+  async cancelAIRequest(requestId: string): Promise<void> {
+    this.log.debug('cancelling ai request', requestId)
+    await this.backend.js__ai_cancel_request(requestId)
+  }
+
   async createAIChatCompletion(
     messages: Message[],
     model: Model,
@@ -100,7 +100,9 @@ export class ResourcesProvider implements ActionProvider {
         [...SearchResourceTags.NonHiddenDefaultTags()],
         {
           includeAnnotations: false,
-          semanticEnabled: this.resourceManager.config.settingsValue.use_semantic_search
+          semanticEnabled: this.resourceManager.config.settingsValue.use_semantic_search,
+          // screenshots and photos without any text are only found through their image embedding
+          semanticImages: true
           // semanticLimit: 0,
           // keywordLimit: 6
         }
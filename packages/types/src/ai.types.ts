@@ -40,9 +40,15 @@ export enum ChatMode {
 export type AIChatData = {
   id: string
   title: string
+  /** Topics generated along with the title */
+  tags: string[]
   messages: AIChatMessage[]
   createdAt: string
   updatedAt: string
+  /** Summary sent in place of the older messages of long chats */
+  summary?: string
+  /** Last message of the branch the messages are from */
+  activeMessageId?: string | null
 }
 
 export type AIDocsSimilarity = {
@@ -60,7 +66,8 @@ export type AIChatStatusMessage = {
 }
 
 export type AIChatMessage = {
-  id: string // generated in the frontend
+  id: string // generated in the frontend for pending messages
+  parent_id?: string | null
   ai_session_id: string
   role: AIChatMessageRole
   status: 'success' | 'pending' | 'error'
@@ -76,6 +83,8 @@ export type AIChatMessageParsed = {
   content: string
   contentItems?: ChatMessageContentItem[]
   sources?: AIChatMessageSource[]
+  /** search results that didn't fit into the context of the answer */
+  dropped?: AIChatDroppedContext[]
   usedPageScreenshot?: boolean
   usedInlineScreenshot?: boolean
   status?: 'success' | 'pending' | 'error' | 'cancelled'
@@ -92,7 +101,30 @@ export type AIChatMessageSource = {
     timestamp?: number
     url?: string
     page?: number
+    duration?: number
+    heading_path?: string[]
   }
+  /** passages the answer quotes from this source, checked against its content */
+  quotes?: AIChatMessageSourceQuote[]
+  /** chunks sent along with `uid` as a single passage, in document order */
+  chunk_uids?: string[]
+}
+
+/** a search result left out of the context of an answer to stay within the token budget */
+export type AIChatDroppedContext = {
+  uid: string
+  resource_id: string
+  reason: 'resource_budget' | 'total_budget' | 'resource_quota'
+}
+
+export type AIChatMessageSourceQuote = {
+  text: string
+  /** character offsets of the passage in the text content `uid`, only set if verified */
+  start: number | null
+  end: number | null
+  /** the chunk of the source the passage starts in */
+  uid?: string | null
+  verified: boolean
 }
 
 export type CitationInfo = {
@@ -166,6 +198,83 @@ export type ChatCompletionResponse = {
   error: ChatError | null
 }
 
+/** Alternative versions of a chat, created by editing or regenerating a message */
+export type AIChatBranch = {
+  leaf_message_id: string
+  /** first message that isn't on the active branch */
+  first_message_id: string
+  /** message the branch forks off after, null if it forks at the start */
+  fork_parent_id: string | null
+  preview: string
+  active: boolean
+  updated_at: string
+}
+
+/** A message of a chat matching a search */
+export type AIChatMessageHit = {
+  ai_session_id: string
+  message_id: string
+  role: AIChatMessageRole
+  /** matching part of the message with the matches wrapped in <mark></mark> */
+  snippet: string
+  /** matched by meaning through the embedded answers instead of by keyword */
+  semantic: boolean
+  created_at: string
+}
+
+export type AIChatSearchResult = {
+  chat: AIChatData
+  /** best matching messages first, empty if only the title matches */
+  hits: AIChatMessageHit[]
+}
+
+/** markdown and html exports list the cited sources as numbered footnotes */
+export type AIChatExportFormat = 'markdown' | 'html' | 'json'
+
+export type LLMUsagePeriod = 'daily' | 'monthly'
+
+export type LLMUsageTotal = {
+  /** YYYY-MM-DD for daily totals, YYYY-MM for monthly ones */
+  period: string
+  model: string
+  requests: number
+  prompt_tokens: number
+  completion_tokens: number
+  cached_tokens: number
+  /** in USD, null if there is no price for the model */
+  estimated_cost: number | null
+}
+
+export type LLMCacheStats = {
+  entries: number
+  size_bytes: number
+  /** since the cache was created, across all workers */
+  hits: number
+  misses: number
+}
+
+/** USD per million tokens */
+export type LLMModelPrice = {
+  input_per_mtok: number
+  output_per_mtok: number
+  cached_input_per_mtok: number
+}
+
+export type ModelCatalogEntry = {
+  /** the name the provider's api knows the model by */
+  id: string
+  provider: string | null
+  context_window: number
+  max_output_tokens: number
+  vision: boolean
+  tools: boolean
+  json_mode: boolean
+  price: LLMModelPrice | null
+}
+
+/** only the set fields replace the catalog's values */
+export type ModelCatalogOverride = Partial<Omit<ModelCatalogEntry, 'id'>> & { id: string }
+
 export namespace ChatMode {
   export function isValid(value: number): value is ChatMode {
     return Object.values(ChatMode).includes(value)
@@ -7,6 +7,16 @@ export type UserConfig = {
   show_changelog: boolean
 }
 
+export type EmbeddingIndexConfig = {
+  quantization?: 'f32' | 'f16' | 'i8'
+  /** HNSW parameters, 0 lets the index pick its own default */
+  connectivity?: number
+  expansion_add?: number
+  expansion_search?: number
+  /** memory-map the index instead of loading it into memory */
+  view?: boolean
+}
+
 export type UserSettings = {
   embedding_model: 'english_small' | 'english_large' | 'multilingual_small' | 'multilingual_large'
   tabs_orientation: 'vertical' | 'horizontal'
@@ -33,10 +43,16 @@ export type UserSettings = {
     completed_chat: boolean
     completed_stuff: boolean
   }
+  /** settings of the embeddings index, build settings only apply to a new index */
+  embedding_index?: EmbeddingIndexConfig
   sync_base_url?: string
   sync_auth_token?: string
   selected_model: string
   model_settings: Model[]
+  /** ids of the models answering in order while the selected one keeps failing */
+  fallback_models?: string[]
+  /** how often a failed answer is retried before the next fallback model takes over */
+  llm_max_retries?: number
   vision_image_tagging: boolean
   turntable_favicons: boolean
   auto_toggle_pip: boolean
@@ -6,7 +6,8 @@ export enum ResourceProcessingStateType {
 }
 
 export enum EventBusMessageType {
-  ResourceProcessingMessage = 'ResourceProcessingMessage'
+  ResourceProcessingMessage = 'ResourceProcessingMessage',
+  AIChatTitleMessage = 'AIChatTitleMessage'
 }
 
 export type ResourceProcessingState =
@@ -15,8 +16,15 @@ export type ResourceProcessingState =
   | { type: ResourceProcessingStateType.Failed; message: string }
   | { type: ResourceProcessingStateType.Finished }
 
-export type EventBusMessage = {
-  type: EventBusMessageType.ResourceProcessingMessage
-  resource_id: string
-  status: ResourceProcessingState
-}
+export type EventBusMessage =
+  | {
+      type: EventBusMessageType.ResourceProcessingMessage
+      resource_id: string
+      status: ResourceProcessingState
+    }
+  | {
+      type: EventBusMessageType.AIChatTitleMessage
+      session_id: string
+      title: string
+      tags: string[]
+    }
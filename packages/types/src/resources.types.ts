@@ -133,6 +133,8 @@ export interface SFFSSearchSemanticParameters {
   semanticEnabled?: boolean // false by default
   semanticDistanceThreshold?: number // default 0.4, inc by .25 (0.0-2.0)
   semanticLimit?: number
+  semanticRerank?: boolean // rerank semantic results with a cross-encoder, false by default
+  semanticImages?: boolean // also search the image embeddings with the query, false by default
 }
 
 export type SFFSSearchParameters = SFFSSearchGeneralParameters & SFFSSearchSemanticParameters
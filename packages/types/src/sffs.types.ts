@@ -1,7 +1,7 @@
 import type { ResourceProcessingState } from '@deta/types'
 import { type SpaceEntry } from './spaces.types'
 import { type Optional } from './utils.types'
-import type { AIChatMessageRole, AIChatMessageSource } from './ai.types'
+import type { AIChatMessageHit, AIChatMessageRole, AIChatMessageSource } from './ai.types'
 
 export type SFFSSearchResultEngineRaw = 'Keyword' | 'Proximity' | 'Semantic'
 
@@ -149,12 +149,22 @@ export interface SFFSRawBookmarkFolder {
 export type AIChatRaw = {
   id: string
   title: string
+  tags?: string[]
   messages: AIChatMessageRaw[]
   created_at: string
   updated_at: string
+  summary?: string
+  active_message_id?: string | null
+}
+
+export type AIChatSearchResultRaw = {
+  session: AIChatRaw
+  hits: AIChatMessageHit[]
 }
 
 export type AIChatMessageRaw = {
+  id: string
+  parent_id?: string | null
   ai_session_id: string
   role: AIChatMessageRole
   status: 'success' | 'pending' | 'error'